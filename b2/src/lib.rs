@@ -3,6 +3,7 @@ use serde_json as json;
 use thiserror::Error;
 
 use std::io::{prelude::*, Cursor};
+use std::path::PathBuf;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -31,8 +32,154 @@ fn unexpected(w: &str, r: &json::Value) -> Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A piece of an unfinished large file that B2 already has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// 1-based, like B2 counts them
+    pub number: u32,
+    pub length: u64,
+    /// Hex-encoded, like B2 gives them
+    pub sha1: String,
+}
+
+/// The hex SHA1 that B2 wants for each upload
+pub fn sha1_hex(contents: &[u8]) -> String {
+    use data_encoding::HEXLOWER;
+    use sha1::{Digest, Sha1};
+
+    HEXLOWER.encode(&Sha1::digest(contents))
+}
+
+/// SHA1s of an uploaded file (hex-encoded, like B2 gives them)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksums {
+    /// What we hashed while sending it
+    pub sent: String,
+    /// What B2 says it stored
+    pub stored: String,
+}
+
+/// Pull the SHA1 B2 says it stored out of an upload response.
+fn stored_sha1(response: &json::Value) -> Result<String> {
+    let sha1 = response["contentSha1"]
+        .as_str()
+        .ok_or_else(|| unexpected("didn't give the uploaded file's SHA1", response))?;
+    // B2 marks hashes it didn't check itself; the hash is still the hash.
+    Ok(sha1.strip_prefix("unverified:").unwrap_or(sha1).to_owned())
+}
+
+/// How we connect to B2, for networks that need something special.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// Proxy all requests through this URL.
+    ///
+    /// If unset, use `ALL_PROXY`, `HTTPS_PROXY`, or `HTTP_PROXY` from the environment.
+    /// Either way, hosts listed in `NO_PROXY` are connected to directly.
+    pub proxy: Option<String>,
+    /// Trust the certificates in this PEM file *instead of* the built-in roots.
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// What ureq does if you don't tell it otherwise.
+const DEFAULT_REDIRECTS: u32 = 10;
+
+/// [`HttpOptions`], resolved into what ureq wants
+#[derive(Debug, Clone)]
+struct Http {
+    proxy: Option<ureq::Proxy>,
+    no_proxy: Vec<String>,
+    tls: ureq::tls::TlsConfig,
+}
+
+impl Http {
+    fn new(options: &HttpOptions) -> Result<Self> {
+        let proxy = match &options.proxy {
+            Some(p) => Some(ureq::Proxy::new(p)?),
+            None => ureq::Proxy::try_from_env(),
+        };
+        let no_proxy = std::env::var("NO_PROXY")
+            .or_else(|_| std::env::var("no_proxy"))
+            .map(|v| parse_no_proxy(&v))
+            .unwrap_or_default();
+
+        let mut tls = ureq::tls::TlsConfig::builder();
+        if let Some(ca) = &options.ca_cert {
+            let pem = std::fs::read(ca)?;
+            let certs = ureq::tls::parse_pem(&pem)
+                .filter_map(|item| match item {
+                    Ok(ureq::tls::PemItem::Certificate(c)) => Some(Ok(c)),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            if certs.is_empty() {
+                return Err(Error::NotFound {
+                    what: format!("certificates in {}", ca.display()),
+                });
+            }
+            tls = tls.root_certs(ureq::tls::RootCerts::new_with_certs(&certs));
+        }
+
+        Ok(Self {
+            proxy,
+            no_proxy,
+            tls: tls.build(),
+        })
+    }
+
+    fn agent(&self, url: &str, max_redirects: u32) -> ureq::Agent {
+        let proxy = if bypass_proxy(&self.no_proxy, url) {
+            None
+        } else {
+            self.proxy.clone()
+        };
+        ureq::Agent::new_with_config(
+            ureq::Agent::config_builder()
+                .max_redirects(max_redirects)
+                .proxy(proxy)
+                .tls_config(self.tls.clone())
+                .build(),
+        )
+    }
+
+    // Once we authenticate in Session::new,
+    // we shouldn't have any redirects as the API gives us URLs to use.
+    fn noredir(&self, url: &str) -> ureq::Agent {
+        self.agent(url, 0)
+    }
+}
+
+fn parse_no_proxy(v: &str) -> Vec<String> {
+    v.split(',')
+        .map(|h| h.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect()
+}
+
+/// Does `NO_PROXY` say to connect to this URL's host directly?
+fn bypass_proxy(no_proxy: &[String], url: &str) -> bool {
+    let host = url
+        .split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .split(['/', '?'])
+        .next()
+        .unwrap_or_default();
+    // Strip any port
+    let host = host.rsplit_once(':').map_or(host, |(h, _)| h);
+    let host = host.to_ascii_lowercase();
+
+    no_proxy.iter().any(|np| {
+        np == "*"
+            || host == *np
+            || host
+                .strip_suffix(np.as_str())
+                .is_some_and(|h| h.ends_with('.'))
+    })
+}
+
 #[derive(Debug)]
 pub struct Session {
+    http: Http,
     token: String,
     url: String,
     upload_url: String,
@@ -41,19 +188,26 @@ pub struct Session {
     bucket_id: String,
 }
 
-// Once we authenticate in Session::new,
-// we shouldn't have any redirects as the API gives us URLs to use.
-fn noredir() -> ureq::Agent {
-    ureq::Agent::new_with_config(ureq::Agent::config_builder().max_redirects(0).build())
-}
-
 impl Session {
     pub fn new<S: Into<String>>(key_id: &str, application_key: &str, bucket: S) -> Result<Self> {
+        Self::with_options(key_id, application_key, bucket, &HttpOptions::default())
+    }
+
+    pub fn with_options<S: Into<String>>(
+        key_id: &str,
+        application_key: &str,
+        bucket: S,
+        options: &HttpOptions,
+    ) -> Result<Self> {
         let bucket = bucket.into();
+        let http = Http::new(options)?;
 
         let creds = String::from(key_id) + ":" + application_key;
         let auth = String::from("Basic") + &BASE64_STANDARD.encode(creds);
-        let v: json::Value = ureq::get("https://api.backblazeb2.com/b2api/v3/b2_authorize_account")
+        const AUTH_URL: &str = "https://api.backblazeb2.com/b2api/v3/b2_authorize_account";
+        let v: json::Value = http
+            .agent(AUTH_URL, DEFAULT_REDIRECTS)
+            .get(AUTH_URL)
             .header("Authorization", &auth)
             .call()?
             .body_mut()
@@ -87,20 +241,23 @@ impl Session {
             })
             .collect::<Result<Vec<&str>>>()?;
 
-        if !capes.iter().any(|c| *c == "listFiles") {
+        if !capes.contains(&"listFiles") {
             return Err(bad("credentials can not list files"));
         }
-        if !capes.iter().any(|c| *c == "readFiles") {
+        if !capes.contains(&"readFiles") {
             return Err(bad("credentials can not read files"));
         }
-        if !capes.iter().any(|c| *c == "writeFiles") {
+        if !capes.contains(&"writeFiles") {
             return Err(bad("credentials can not write files"));
         }
-        if !capes.iter().any(|c| *c == "deleteFiles") {
+        if !capes.contains(&"deleteFiles") {
             return Err(bad("credentials can not delete files"));
         }
 
-        let br: json::Value = ureq::get(&(url.clone() + "/b2api/v2/b2_list_buckets"))
+        let list_url = url.clone() + "/b2api/v2/b2_list_buckets";
+        let br: json::Value = http
+            .agent(&list_url, DEFAULT_REDIRECTS)
+            .get(&list_url)
             .header("Authorization", &token)
             .query("accountId", &id)
             .query("bucketName", &bucket)
@@ -124,7 +281,10 @@ impl Session {
             None => return Err(Error::NotFound { what: bucket }),
         };
 
-        let ur: json::Value = ureq::get(&(url.clone() + "/b2api/v2/b2_get_upload_url"))
+        let upload_url_url = url.clone() + "/b2api/v2/b2_get_upload_url";
+        let ur: json::Value = http
+            .agent(&upload_url_url, DEFAULT_REDIRECTS)
+            .get(&upload_url_url)
             .header("Authorization", &token)
             .query("bucketId", &bucket_id)
             .call()?
@@ -142,6 +302,7 @@ impl Session {
             .to_owned();
 
         Ok(Session {
+            http,
             token,
             url,
             upload_url,
@@ -151,11 +312,12 @@ impl Session {
         })
     }
 
+    /// List every file (with the given prefix), however many requests it takes.
     pub fn list(&self, prefix: Option<&str>) -> Result<Vec<(String, u64)>> {
-        let mut fs = vec![];
-        let mut start_name: Option<String> = None;
-        loop {
-            let mut req = noredir()
+        list_pages(|start_name| {
+            let mut req = self
+                .http
+                .noredir(&self.url)
                 .get(&(self.url.clone() + "/b2api/v2/b2_list_file_names"))
                 .header("Authorization", &self.token)
                 .query("bucketId", &self.bucket_id)
@@ -163,41 +325,17 @@ impl Session {
             if let Some(p) = prefix {
                 req = req.query("prefix", p);
             }
-            if let Some(sn) = &start_name {
+            if let Some(sn) = start_name {
                 req = req.query("startFileName", sn);
             }
-
-            let lfn = req.call()?.body_mut().read_json()?;
-
-            let bad = |s| unexpected(s, &lfn);
-
-            let files = lfn["files"]
-                .as_array()
-                .ok_or_else(|| bad("didn't list file names"))?;
-            for fj in files
-                .iter()
-                .filter(|f| f["action"].as_str() == Some("upload"))
-                .filter_map(
-                    |f| match (f["fileName"].as_str(), f["contentLength"].as_u64()) {
-                        (Some(n), Some(l)) => Some((n.to_owned(), l)),
-                        _ => None,
-                    },
-                )
-            {
-                fs.push(fj);
-            }
-
-            start_name = lfn["nextFileName"].as_str().map(|s| s.to_owned());
-            if start_name.is_none() {
-                break;
-            }
-        }
-        fs.shrink_to_fit(); // We won't be growing this any more.
-        Ok(fs)
+            Ok(req.call()?.body_mut().read_json()?)
+        })
     }
 
     pub fn get(&self, name: &str) -> Result<impl Read> {
-        let r = noredir()
+        let r = self
+            .http
+            .noredir(&self.url)
             .get(&(self.url.clone() + "/file/" + &self.bucket_name + "/" + name))
             .header("Authorization", &self.token)
             .call()?;
@@ -205,7 +343,8 @@ impl Session {
         Ok(r.into_body().into_reader())
     }
 
-    pub fn put(&self, name: &str, len: u64, contents: &mut dyn Read) -> Result<()> {
+    /// Upload a file in one go, returning the SHA1s we sent and B2 stored.
+    pub fn put(&self, name: &str, len: u64, contents: &mut dyn Read) -> Result<Checksums> {
         use data_encoding::HEXLOWER;
         use sha1::{Digest, Sha1};
 
@@ -253,20 +392,168 @@ impl Session {
 
         let mut hr = HashAppendingReader::new(contents);
 
-        noredir()
+        let uploaded: json::Value = self
+            .http
+            .noredir(&self.upload_url)
             .post(&self.upload_url)
             .header("Authorization", &self.upload_token)
             .header("Content-Length", &(len + 40).to_string()) // SHA1 is 40 hex digits long.
             .header("X-Bz-File-Name", name) // No need to URL-encode, our names are boring
             .header("Content-Type", "b2/x-auto") // Go ahead and guess
             .header("X-Bz-Content-Sha1", "hex_digits_at_end")
-            .send(ureq::SendBody::from_reader(&mut hr))?;
+            .send(ureq::SendBody::from_reader(&mut hr))?
+            .body_mut()
+            .read_json()?;
 
+        // We've sent everything, so the reader should be on (or past) the hash.
+        let sent = match hr {
+            HashAppendingReader::HashSuffix(c) => {
+                String::from_utf8(c.into_inner()).expect("hex digits aren't UTF-8")
+            }
+            HashAppendingReader::Contents { .. } => {
+                return Err(unexpected("finished before we sent everything", &uploaded));
+            }
+        };
+        let stored = stored_sha1(&uploaded)?;
+        Ok(Checksums { sent, stored })
+    }
+
+    /// Start uploading a file in parts, returning its ID.
+    ///
+    /// Parts can be uploaded (or reuploaded) in any order until
+    /// [`finish_large_file`](Self::finish_large_file) stitches them together.
+    pub fn start_large_file(&self, name: &str) -> Result<String> {
+        let start_url = self.url.clone() + "/b2api/v2/b2_start_large_file";
+        let slf: json::Value = self
+            .http
+            .noredir(&start_url)
+            .post(&start_url)
+            .header("Authorization", &self.token)
+            .send_json(json::json!({
+                "bucketId": self.bucket_id,
+                "fileName": name,
+                "contentType": "b2/x-auto",
+            }))?
+            .body_mut()
+            .read_json()?;
+        let id = slf["fileId"]
+            .as_str()
+            .ok_or_else(|| unexpected(&format!("couldn't start large file {name}"), &slf))?;
+        Ok(id.to_owned())
+    }
+
+    /// List the parts of an unfinished large file that have been uploaded so far.
+    pub fn list_parts(&self, file_id: &str) -> Result<Vec<Part>> {
+        let mut parts = vec![];
+        let mut start_part: Option<u64> = None;
+        loop {
+            let mut req = self
+                .http
+                .noredir(&self.url)
+                .get(&(self.url.clone() + "/b2api/v2/b2_list_parts"))
+                .header("Authorization", &self.token)
+                .query("fileId", file_id)
+                .query("maxPartCount", "1000");
+            if let Some(sp) = start_part {
+                req = req.query("startPartNumber", sp.to_string());
+            }
+
+            let lp: json::Value = req.call()?.body_mut().read_json()?;
+            let bad = |s| unexpected(s, &lp);
+
+            for p in lp["parts"]
+                .as_array()
+                .ok_or_else(|| bad("didn't list parts"))?
+            {
+                match (
+                    p["partNumber"].as_u64(),
+                    p["contentLength"].as_u64(),
+                    p["contentSha1"].as_str(),
+                ) {
+                    (Some(n), Some(l), Some(s)) => parts.push(Part {
+                        number: n.try_into().map_err(|_| bad("part number out of range"))?,
+                        length: l,
+                        sha1: s.to_owned(),
+                    }),
+                    _ => return Err(bad("listed a malformed part")),
+                }
+            }
+
+            start_part = lp["nextPartNumber"].as_u64();
+            if start_part.is_none() {
+                break;
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Upload the given (1-based) part of a large file,
+    /// returning the SHA1s we sent and B2 stored.
+    pub fn upload_part(&self, file_id: &str, number: u32, contents: &[u8]) -> Result<Checksums> {
+        // Each part gets its own upload URL;
+        // they can't be shared between threads like the bucket's can.
+        let part_url_url = self.url.clone() + "/b2api/v2/b2_get_upload_part_url";
+        let pu: json::Value = self
+            .http
+            .noredir(&part_url_url)
+            .get(&part_url_url)
+            .header("Authorization", &self.token)
+            .query("fileId", file_id)
+            .call()?
+            .body_mut()
+            .read_json()?;
+        let upload_url = pu["uploadUrl"]
+            .as_str()
+            .ok_or_else(|| unexpected("couldn't get part upload URL", &pu))?;
+        let upload_token = pu["authorizationToken"]
+            .as_str()
+            .ok_or_else(|| unexpected("couldn't get part upload token", &pu))?;
+
+        let sha1 = sha1_hex(contents);
+        let uploaded: json::Value = self
+            .http
+            .noredir(upload_url)
+            .post(upload_url)
+            .header("Authorization", upload_token)
+            .header("X-Bz-Part-Number", &number.to_string())
+            .header("Content-Length", &contents.len().to_string())
+            .header("X-Bz-Content-Sha1", &sha1)
+            .send(contents)?
+            .body_mut()
+            .read_json()?;
+        let stored = stored_sha1(&uploaded)?;
+        Ok(Checksums { sent: sha1, stored })
+    }
+
+    /// Assemble the uploaded parts (whose SHA1s are given in order) into the final file.
+    pub fn finish_large_file(&self, file_id: &str, part_sha1s: &[String]) -> Result<()> {
+        let finish_url = self.url.clone() + "/b2api/v2/b2_finish_large_file";
+        self.http
+            .noredir(&finish_url)
+            .post(&finish_url)
+            .header("Authorization", &self.token)
+            .send_json(json::json!({
+                "fileId": file_id,
+                "partSha1Array": part_sha1s,
+            }))?;
+        Ok(())
+    }
+
+    /// Give up on a large file, deleting any parts uploaded so far.
+    pub fn cancel_large_file(&self, file_id: &str) -> Result<()> {
+        let cancel_url = self.url.clone() + "/b2api/v2/b2_cancel_large_file";
+        self.http
+            .noredir(&cancel_url)
+            .post(&cancel_url)
+            .header("Authorization", &self.token)
+            .send_json(json::json!({ "fileId": file_id }))?;
         Ok(())
     }
 
     pub fn delete(&self, name: &str) -> Result<()> {
-        let req = noredir()
+        let req = self
+            .http
+            .noredir(&self.url)
             .get(&(self.url.clone() + "/b2api/v2/b2_list_file_versions"))
             .header("Authorization", &self.token)
             .query("bucketId", &self.bucket_id)
@@ -290,7 +577,10 @@ impl Session {
             .as_str()
             .ok_or_else(|| unexpected(&format!("couldn't find ID for {name}"), &lfv))?;
 
-        ureq::post(&(self.url.clone() + "/b2api/v2/b2_delete_file_version"))
+        let delete_url = self.url.clone() + "/b2api/v2/b2_delete_file_version";
+        self.http
+            .agent(&delete_url, DEFAULT_REDIRECTS)
+            .post(&delete_url)
             .header("Authorization", &self.token)
             .send_json(json::json!({
                 "fileName": name,
@@ -300,3 +590,119 @@ impl Session {
         Ok(())
     }
 }
+
+/// Gather the files from each page of `b2_list_file_names`,
+/// where `fetch` gets the page starting at the given name (or the first page).
+///
+/// B2 gives at most 10,000 files a page, so stopping early would silently
+/// drop files from the listing. Keep going until it stops giving us a `nextFileName`.
+fn list_pages<F>(mut fetch: F) -> Result<Vec<(String, u64)>>
+where
+    F: FnMut(Option<&str>) -> Result<json::Value>,
+{
+    let mut fs = vec![];
+    let mut start_name: Option<String> = None;
+    loop {
+        let lfn = fetch(start_name.as_deref())?;
+
+        let bad = |s| unexpected(s, &lfn);
+
+        let files = lfn["files"]
+            .as_array()
+            .ok_or_else(|| bad("didn't list file names"))?;
+        for fj in files
+            .iter()
+            .filter(|f| f["action"].as_str() == Some("upload"))
+            .filter_map(
+                |f| match (f["fileName"].as_str(), f["contentLength"].as_u64()) {
+                    (Some(n), Some(l)) => Some((n.to_owned(), l)),
+                    _ => None,
+                },
+            )
+        {
+            fs.push(fj);
+        }
+
+        let next = lfn["nextFileName"].as_str().map(|s| s.to_owned());
+        match next {
+            None => break,
+            // Don't go 'round forever if B2 gets confused.
+            Some(n) if start_name.as_ref() == Some(&n) => {
+                return Err(bad("gave the same next file name twice"));
+            }
+            Some(n) => start_name = Some(n),
+        }
+    }
+    fs.shrink_to_fit(); // We won't be growing this any more.
+    Ok(fs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_proxy() {
+        let np = parse_no_proxy("localhost, .example.com,,10.0.0.1");
+        assert_eq!(np, ["localhost", "example.com", "10.0.0.1"]);
+
+        assert!(bypass_proxy(&np, "http://localhost:8080/foo"));
+        assert!(bypass_proxy(&np, "https://example.com"));
+        assert!(bypass_proxy(&np, "https://api.Example.com/b2api"));
+        assert!(bypass_proxy(&np, "https://10.0.0.1/"));
+        assert!(!bypass_proxy(&np, "https://notexample.com/"));
+        assert!(!bypass_proxy(&np, "https://api.backblazeb2.com/b2api"));
+
+        assert!(bypass_proxy(&parse_no_proxy("*"), "https://anything/"));
+    }
+
+    #[test]
+    fn sha1() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn stored_sha1s() -> Result<()> {
+        let sha1 = sha1_hex(b"abc");
+        assert_eq!(stored_sha1(&json::json!({ "contentSha1": sha1 }))?, sha1);
+        assert_eq!(
+            stored_sha1(&json::json!({ "contentSha1": format!("unverified:{sha1}") }))?,
+            sha1
+        );
+        assert!(stored_sha1(&json::json!({ "fileName": "abc" })).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn paginated_listing() -> Result<()> {
+        let page = |names: &[&str], next: Option<&str>| {
+            let files: Vec<_> = names
+                .iter()
+                .map(|n| json::json!({"fileName": n, "contentLength": 1, "action": "upload"}))
+                .collect();
+            json::json!({"files": files, "nextFileName": next})
+        };
+        let mut asked_for = vec![];
+        let listed = list_pages(|start| {
+            asked_for.push(start.map(str::to_owned));
+            Ok(match start {
+                None => page(&["a", "b"], Some("c")),
+                Some("c") => page(&["c", "d"], Some("e")),
+                Some("e") => page(&["e"], None),
+                Some(other) => panic!("unexpected start {other}"),
+            })
+        })?;
+        assert_eq!(
+            listed.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e"]
+        );
+        assert_eq!(
+            asked_for,
+            [None, Some("c".to_owned()), Some("e".to_owned())]
+        );
+
+        // A next name that doesn't move us along is an error, not an infinite loop.
+        assert!(list_pages(|_| Ok(page(&["a"], Some("a")))).is_err());
+        Ok(())
+    }
+}
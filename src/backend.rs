@@ -3,50 +3,164 @@
 
 use std::fs::File;
 use std::io::{self, prelude::*};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use anyhow::{Context, Result, anyhow, bail, ensure};
 use byte_unit::Byte;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
 use tracing::*;
 
 use crate::{
+    chunk, config,
     counters::{Op, bump},
-    file_util::{move_opened, nice_size},
+    file_util::{is_same_file, move_opened, nice_size, safe_create_file},
     hashing::ObjectId,
-    pack, progress,
+    index, pack, progress, snapshot,
 };
 
 pub mod backblaze;
 pub mod cache;
-mod filter;
+mod error;
+pub mod filter;
 pub mod fs;
+mod listings;
 mod memory;
+pub mod mirror;
+mod rate_limited;
+pub mod retry;
+pub mod s3;
 mod semaphored;
+pub mod ssh;
+mod throttle;
 
 use cache::Cache;
+pub use error::BackendError;
+use retry::{Retries, Retrying};
 
 #[inline]
 fn defsize() -> Byte {
     pack::DEFAULT_PACK_SIZE
 }
 
+#[inline]
+fn defport() -> u16 {
+    22
+}
+
+#[inline]
+fn defconnections() -> u32 {
+    DEFAULT_CONNECTIONS
+}
+
+/// How many connections remote backends open at once unless told otherwise
+pub const DEFAULT_CONNECTIONS: u32 = 4;
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-#[serde(tag = "type")]
+#[serde(tag = "type", deny_unknown_fields)]
 pub enum Kind {
     Filesystem {
         force_cache: bool,
+        /// Reread each object after writing it and make sure it still hashes to its ID.
+        #[serde(default)]
+        verify_after_write: bool,
     },
     Backblaze {
+        /// Overridden by `BACKPAK_B2_KEY_ID`; see [`secret()`]
+        #[serde(default)]
         key_id: String,
+        /// Overridden by `BACKPAK_B2_APP_KEY`; see [`secret()`]
+        #[serde(default)]
         application_key: String,
         bucket: String,
-        concurrent_connections: u32,
+        #[serde(flatten)]
+        remote: Remote,
+        /// Proxy URL, overriding `ALL_PROXY`/`HTTPS_PROXY`/`HTTP_PROXY`.
+        /// `NO_PROXY` is honored either way.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proxy: Option<String>,
+        /// PEM file of CA certificates to trust instead of the built-in ones
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ca_cert: Option<Utf8PathBuf>,
+        /// Limit on API calls (reads, writes, deletes, lists) per second
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_requests_per_second: Option<f64>,
+        /// Upload big files in parts, remembering which ones made it
+        /// so an interrupted upload can pick up where it left off.
+        #[serde(default)]
+        resume_uploads: bool,
+    },
+    S3 {
+        /// Scheme and host, e.g., `https://s3.us-east-1.amazonaws.com` or `http://minio.local:9000`
+        endpoint: String,
+        region: String,
+        bucket: String,
+        /// Overridden by `BACKPAK_S3_ACCESS_KEY`; see [`secret()`]
+        #[serde(default)]
+        access_key: String,
+        /// Overridden by `BACKPAK_S3_SECRET_KEY`; see [`secret()`]
+        #[serde(default)]
+        secret_key: String,
+        /// Address the bucket as `endpoint/bucket` instead of `bucket.endpoint`
+        /// (MinIO and friends usually want this).
+        #[serde(default)]
+        path_style: bool,
+        #[serde(flatten)]
+        remote: Remote,
+    },
+    /// Any host we can `ssh` to and run a POSIX shell on
+    Ssh {
+        host: String,
+        #[serde(default = "defport")]
+        port: u16,
+        /// Defaults to whatever `ssh` would pick (see `~/.ssh/config`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        /// Private key to log in with. If unset, use the SSH agent (or `~/.ssh/config`).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        identity_file: Option<Utf8PathBuf>,
+        /// Directory on the host holding the repository
+        base_path: String,
+        #[serde(flatten)]
+        remote: Remote,
+    },
+    /// The same repository on several backends; see [`mirror`]
+    Mirror {
+        /// Where reads and listings go first
+        primary: Box<Kind>,
+        /// Where writes and removes also go, and reads fall back to
+        secondaries: Vec<Kind>,
+        /// Fail writes and removes that don't make it to every secondary,
+        /// instead of just warning about them.
+        #[serde(default)]
+        require_all: bool,
     }, // ...?
 }
 
+/// Settings every remote backend (B2, S3, SSH) takes,
+/// flattened into its table in the config.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Remote {
+    /// How many connections we can have open at once
+    #[serde(default = "defconnections")]
+    pub concurrent_connections: u32,
+}
+
+impl Kind {
+    /// Settings shared by remote backends,
+    /// or `None` for filesystems and mirrors (whose parts have their own)
+    pub fn remote(&self) -> Option<&Remote> {
+        match self {
+            Kind::Backblaze { remote, .. } | Kind::S3 { remote, .. } | Kind::Ssh { remote, .. } => {
+                Some(remote)
+            }
+            Kind::Filesystem { .. } | Kind::Mirror { .. } => None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ConfigFile {
     #[serde(default = "defsize")]
     pack_size: Byte,
@@ -58,6 +172,32 @@ struct ConfigFile {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     unfilter: Option<String>,
+    /// Previous `unfilter` commands, for reading objects written before `filter` changed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    legacy_unfilters: Vec<String>,
+    /// Environment variables filters can see besides the defaults
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    filter_env: Vec<String>,
+    #[serde(skip_serializing_if = "Retries::is_default")]
+    #[serde(default)]
+    retries: Retries,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    upload_limit: Option<Byte>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    download_limit: Option<Byte>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    compression: Option<pack::Compression>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    chunking: Option<chunk::ChunkConfig>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    verify_cache_on_open: bool,
 }
 
 /// Normalized version of [`ConfigFile`] where `filter` and `unfilter` must both be Some or None.
@@ -66,25 +206,102 @@ pub struct Configuration {
     pub pack_size: Byte,
     pub kind: Kind,
     pub filter: Option<(String, String)>,
+    /// Tried in order when `unfilter` fails to read an object; see [`filter::BackendFilter`]
+    pub legacy_unfilters: Vec<String>,
+    /// Environment variables filters can see besides
+    /// [the defaults](filter::DEFAULT_FILTER_ENV); see [`filter::FilterEnvironment`]
+    pub filter_env: Vec<String>,
+    /// How many times remote backends try transient failures; see [`retry::Retrying`]
+    pub retries: Retries,
+    /// Bytes per second we can send to the backend (zero or `None` for no limit)
+    pub upload_limit: Option<Byte>,
+    /// Bytes per second we can receive from the backend (zero or `None` for no limit)
+    pub download_limit: Option<Byte>,
+    /// How to compress new packs (zstd at its default level if `None`)
+    pub compression: Option<pack::Compression>,
+    /// How to cut files into chunks ([the defaults](chunk::ChunkConfig::default) if `None`)
+    pub chunking: Option<chunk::ChunkConfig>,
+    /// Evict cache entries whose size on disk doesn't match what we wrote,
+    /// e.g., because the machine crashed partway through, when opening the repository.
+    pub verify_cache_on_open: bool,
 }
 
+impl Configuration {
+    /// Where and with what variables to run the repository's filters
+    pub fn filter_environment(&self, repository: &Utf8Path) -> filter::FilterEnvironment {
+        filter::FilterEnvironment::for_repository(repository, self.filter_env.clone())
+    }
+}
+
+/// Read a repository config, in TOML, JSON, or YAML depending on its extension.
 pub fn read_config(p: &Utf8Path) -> Result<Configuration> {
+    let format = config::Format::from_path(p)?;
     let s = std::fs::read_to_string(p).with_context(|| format!("Couldn't read config from {p}"))?;
-    let cf: ConfigFile =
-        toml::from_str(&s).with_context(|| format!("Couldn't parse config in {p}"))?;
+    let cf: ConfigFile = format
+        .parse(&s)
+        .with_context(|| format!("Couldn't parse config in {p}"))?;
     let filter = match (cf.filter, cf.unfilter) {
         (Some(f), Some(u)) => Some((f, u)),
         (None, None) => None,
         _ => bail!("{p} config should set `filter` and `unfilter` or neither."),
     };
+    ensure!(
+        filter.is_some() || cf.legacy_unfilters.is_empty(),
+        "{p} config sets `legacy_unfilters` without a current `filter` and `unfilter` \
+         (use `cat` for both to stop filtering new objects)"
+    );
+    ensure!(
+        filter.is_some() || cf.filter_env.is_empty(),
+        "{p} config sets `filter_env` without a `filter` and `unfilter`"
+    );
+    // Commands that just read the repository don't care; writing packs checks for real.
+    pack::warn_on_bad_size(cf.pack_size, p);
+    ensure!(
+        cf.retries.max_attempts > 0,
+        "{p} config's retries.max_attempts must be positive"
+    );
+    if let Some(c) = &cf.compression {
+        c.check()
+            .with_context(|| format!("Bad compression in {p}"))?;
+    }
+    if let Some(c) = &cf.chunking {
+        c.check().with_context(|| format!("Bad chunking in {p}"))?;
+    }
+    if let Kind::Mirror {
+        primary,
+        secondaries,
+        ..
+    } = &cf.kind
+    {
+        mirror::check(primary, secondaries).with_context(|| format!("Bad mirror in {p}"))?;
+    }
     Ok(Configuration {
         pack_size: cf.pack_size,
         kind: cf.kind,
         filter,
+        legacy_unfilters: cf.legacy_unfilters,
+        filter_env: cf.filter_env,
+        retries: cf.retries,
+        upload_limit: cf.upload_limit,
+        download_limit: cf.download_limit,
+        compression: cf.compression,
+        chunking: cf.chunking,
+        verify_cache_on_open: cf.verify_cache_on_open,
     })
 }
 
-pub fn write_config<W: Write>(mut w: W, c: Configuration) -> Result<()> {
+/// Write a new repository config file at the given path, all at once or not at all.
+///
+/// A half-written config leaves a repository we can't open,
+/// so this goes through [`file_util::safe_create_file()`](crate::file_util::safe_create_file).
+/// Fails if there's already a file there.
+pub fn create_config(p: &Utf8Path, c: Configuration, format: config::Format) -> Result<()> {
+    let mut buf = vec![];
+    write_config(&mut buf, c, format)?;
+    safe_create_file(buf.as_slice(), p).with_context(|| format!("Couldn't create {p}"))
+}
+
+pub fn write_config<W: Write>(mut w: W, c: Configuration, format: config::Format) -> Result<()> {
     let (filter, unfilter) = match c.filter {
         Some((f, u)) => (Some(f), Some(u)),
         None => (None, None),
@@ -94,23 +311,78 @@ pub fn write_config<W: Write>(mut w: W, c: Configuration) -> Result<()> {
         kind: c.kind,
         filter,
         unfilter,
+        legacy_unfilters: c.legacy_unfilters,
+        filter_env: c.filter_env,
+        retries: c.retries,
+        upload_limit: c.upload_limit,
+        download_limit: c.download_limit,
+        compression: c.compression,
+        chunking: c.chunking,
+        verify_cache_on_open: c.verify_cache_on_open,
     };
-    w.write_all(toml::to_string(&cf)?.as_bytes())?;
+    w.write_all(format.to_string(&cf)?.as_bytes())?;
     Ok(())
 }
 
+/// Keys and their sizes, from [`Backend::list_streaming()`]
+pub type Listing = Box<dyn Iterator<Item = Result<(String, u64), BackendError>> + Send>;
+
+/// What we write to a backend: a stream we can rewind and read again
+/// if the first attempt fails partway through.
+///
+/// Everything we upload is already in a file (see [`CachedBackend::write()`]),
+/// so there's no need to copy it somewhere else first.
+pub trait Upload: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Upload for T {}
+
 /// A backend is anything we can read from, write to, list, and remove items from.
+///
+/// Each says what went wrong with a [`BackendError`],
+/// so callers can tell a missing key from a hiccup worth retrying.
 pub trait Backend {
     /// Read from the given key
-    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>>;
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError>;
 
-    /// Write the given read stream to the given key
-    fn write(&self, len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()>;
+    /// Write the given stream to the given key
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError>;
 
-    fn remove(&self, which: &str) -> Result<()>;
+    fn remove(&self, which: &str) -> Result<(), BackendError>;
 
     /// Lists all keys and their sizes with the given prefix
-    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>>;
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError>;
+
+    /// Like [`list()`](Backend::list), but yields keys as it finds them
+    /// instead of gathering them all up first.
+    ///
+    /// By default this just wraps `list()`;
+    /// backends that can walk their keys lazily should override it.
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        Ok(Box::new(self.list(prefix)?.into_iter().map(Ok)))
+    }
+}
+
+// So wrappers like Throttled can go around whatever open() built.
+impl Backend for Box<dyn Backend + Send + Sync> {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        (**self).read(from)
+    }
+
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+        (**self).write(len, from, to)
+    }
+
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
+        (**self).remove(which)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+        (**self).list(prefix)
+    }
+
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        (**self).list_streaming(prefix)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -122,6 +394,10 @@ pub enum CacheBehavior {
     /// always read from the backend (and insert in the cache).
     /// Useful for commands like `check` where we want to ensure what's actually there.
     AlwaysRead,
+    /// Always write through to the backend and always read from it,
+    /// but never put anything in the cache.
+    /// For one-off reads on machines without room to spare.
+    NoCache,
 }
 
 /// Cached backends do what they say on the tin,
@@ -137,6 +413,7 @@ enum CachedBackendKind {
     /// Just read and write files directly. Nice.
     File {
         backend: fs::FilesystemBackend,
+        verify_after_write: bool,
     },
     // The usual case: the backend is some remotely-hosted storage,
     // or local but the files are filtered first.
@@ -156,14 +433,76 @@ pub struct CachedBackend {
     inner: CachedBackendKind,
     pub bytes_downloaded: AtomicU64,
     pub bytes_uploaded: AtomicU64,
+    listings: listings::Listings,
+}
+
+// Bytes moved by every CachedBackend this run, added as each is dropped,
+// so main can print them (with --stats) without getting its hands on the backends.
+static UPLOADED_TOTAL: AtomicU64 = AtomicU64::new(0);
+static DOWNLOADED_TOTAL: AtomicU64 = AtomicU64::new(0);
+static TOTALS_APPROXIMATE: AtomicBool = AtomicBool::new(false);
+
+/// Bytes uploaded and downloaded by all the (dropped) backends this run,
+/// and whether those are approximate (see [`CachedBackend::transfer_summary()`])
+pub fn transfer_totals() -> (u64, u64, bool) {
+    (
+        UPLOADED_TOTAL.load(Ordering::Relaxed),
+        DOWNLOADED_TOTAL.load(Ordering::Relaxed),
+        TOTALS_APPROXIMATE.load(Ordering::Relaxed),
+    )
+}
+
+impl Drop for CachedBackend {
+    fn drop(&mut self) {
+        let (up, down) = self.transfer_summary();
+        UPLOADED_TOTAL.fetch_add(up, Ordering::Relaxed);
+        DOWNLOADED_TOTAL.fetch_add(down, Ordering::Relaxed);
+        if self.transfer_is_approximate() {
+            TOTALS_APPROXIMATE.store(true, Ordering::Relaxed);
+        }
+    }
 }
 
 impl CachedBackend {
+    /// Bytes uploaded to and downloaded from the backend so far
+    pub fn transfer_summary(&self) -> (u64, u64) {
+        (
+            self.bytes_uploaded.load(Ordering::Relaxed),
+            self.bytes_downloaded.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Uncached filesystem backends count whole files as they're opened and moved,
+    /// not the bytes actually read (see [`read()`](Self::read)), so they're only so accurate.
+    pub fn transfer_is_approximate(&self) -> bool {
+        matches!(self.inner, CachedBackendKind::File { .. })
+    }
+
+    /// The local cache, if this backend has one.
+    /// (Uncached filesystem and in-memory backends don't.)
+    pub fn cache(&self) -> Option<&Cache> {
+        match &self.inner {
+            CachedBackendKind::Cached { cache, .. } => Some(cache),
+            CachedBackendKind::File { .. } | CachedBackendKind::Memory { .. } => None,
+        }
+    }
+
+    /// The backend under the cache, to poke at directly
+    /// with keys that aren't backpak objects (see `backpak doctor`).
+    pub fn raw(&self) -> &dyn Backend {
+        match &self.inner {
+            CachedBackendKind::File { backend, .. } => backend,
+            CachedBackendKind::Cached { backend, .. } => backend.as_ref(),
+            CachedBackendKind::Memory { backend } => backend,
+        }
+    }
+
     fn new(inner: CachedBackendKind) -> Self {
         Self {
             inner,
             bytes_downloaded: AtomicU64::new(0),
             bytes_uploaded: AtomicU64::new(0),
+            listings: Default::default(),
         }
     }
 }
@@ -177,11 +516,12 @@ impl<T> SeekableRead for T where T: Read + Seek + Send + 'static {}
 impl CachedBackend {
     /// Read the object at the given key and return its file.
     fn read(&self, name: &str) -> Result<Box<dyn SeekableRead>> {
+        let key = destination(name)?;
         match &self.inner {
-            CachedBackendKind::File { backend } => {
+            CachedBackendKind::File { backend, .. } => {
                 debug!("Loading {name}");
                 bump(Op::BackendRead);
-                let from = backend.path_of(&destination(name));
+                let from = backend.path_of(&key);
                 let fd = File::open(&from).with_context(|| format!("Couldn't open {from}"))?;
 
                 // Sorta - wrapping the file in AtomicCountRead would give us weird stuff
@@ -197,10 +537,10 @@ impl CachedBackend {
                 behavior,
                 backend,
             } => {
-                let tr = if *behavior == CacheBehavior::AlwaysRead {
-                    None
-                } else {
+                let tr = if *behavior == CacheBehavior::Normal {
                     cache.try_read(name)?
+                } else {
+                    None
                 };
                 if let Some(hit) = tr {
                     debug!("Found {name} in the backend cache");
@@ -212,10 +552,19 @@ impl CachedBackend {
                     // NB: See backend::filter - we need this to drop _inside_
                     // cache.insert() lest its hokey "waiting on a process inside drop()"
                     // breaks things.
-                    let counter = progress::AtomicCountRead::new(
-                        backend.read(&destination(name))?,
-                        &self.bytes_downloaded,
-                    );
+                    let mut counter =
+                        progress::AtomicCountRead::new(backend.read(&key)?, &self.bytes_downloaded);
+                    if *behavior == CacheBehavior::NoCache {
+                        // Callers want to seek around, so we need _somewhere_ to put it.
+                        // An unnamed temp file goes away as soon as they're done.
+                        let mut spool = tempfile::tempfile_in(".")
+                            .with_context(|| format!("Couldn't make a temp file for {name}"))?;
+                        io::copy(&mut counter, &mut spool)
+                            .with_context(|| format!("Couldn't download {name}"))?;
+                        drop(counter);
+                        spool.seek(io::SeekFrom::Start(0))?;
+                        return Ok(Box::new(spool));
+                    }
                     let mut inserted = cache.insert(name, counter)?;
                     cache.prune()?;
                     inserted.seek(io::SeekFrom::Start(0))?;
@@ -225,7 +574,7 @@ impl CachedBackend {
             CachedBackendKind::Memory { backend } => {
                 debug!("Loading {name} (in-memory)");
                 bump(Op::BackendRead);
-                Ok(Box::new(backend.read_cursor(&destination(name))?))
+                Ok(Box::new(backend.read_cursor(&key)?))
             }
         }
     }
@@ -234,22 +583,39 @@ impl CachedBackend {
     /// store it to an object with the appropriate key per
     /// `destination()`
     pub fn write(&self, name: &str, mut fh: File) -> Result<()> {
+        let key = destination(name)?;
         bump(Op::BackendWrite);
+        self.listings.invalidate(&key);
         let len = fh.metadata()?.len();
         match &self.inner {
-            CachedBackendKind::File { backend } => {
+            CachedBackendKind::File {
+                backend,
+                verify_after_write,
+            } => {
                 debug!("Saving {name} ({})", nice_size(len));
-                let to = backend.path_of(&destination(name));
-                move_opened(name, fh, to)?;
+                let to = backend.path_of(&key);
+                move_opened(name, fh, &to)?;
                 self.bytes_uploaded.fetch_add(len, Ordering::Relaxed);
+                if *verify_after_write {
+                    verify_written(name, &to)?;
+                }
             }
-            CachedBackendKind::Cached { cache, backend, .. } => {
+            CachedBackendKind::Cached {
+                cache,
+                behavior,
+                backend,
+            } => {
                 // Write through!
                 fh.seek(std::io::SeekFrom::Start(0))?;
                 // Write it through to the backend.
                 debug!("Uploading {name} ({})", nice_size(len));
                 let mut counter = progress::AtomicCountRead::new(fh, &self.bytes_uploaded);
-                backend.write(len, &mut counter, &destination(name))?;
+                backend.write(len, &mut counter, &key)?;
+                if *behavior == CacheBehavior::NoCache {
+                    std::fs::remove_file(name)
+                        .with_context(|| format!("Couldn't remove {name} after uploading it"))?;
+                    return Ok(());
+                }
                 // Insert it into the cache.
                 cache.insert_file(name, counter.into_inner())?;
                 // Prune the cache.
@@ -258,40 +624,71 @@ impl CachedBackend {
             CachedBackendKind::Memory { backend } => {
                 debug!("Saving {name} ({}, in-memory)", nice_size(len));
                 fh.seek(std::io::SeekFrom::Start(0))?;
-                backend.write(len, &mut fh, &destination(name))?;
+                backend.write(len, &mut fh, &key)?;
                 self.bytes_uploaded.fetch_add(len, Ordering::Relaxed);
-                std::fs::remove_file(name)?;
+                // Like the other backends, clean up the temp file we were handed -
+                // if we were handed one. Tests happily write anonymous temp files.
+                if is_same_file(Utf8Path::new(name), &fh)? {
+                    std::fs::remove_file(name)
+                        .with_context(|| format!("Couldn't remove {name} after saving it"))?;
+                }
             }
         }
         Ok(())
     }
 
     fn remove(&self, name: &str) -> Result<()> {
+        let key = destination(name)?;
         debug!("Deleting {name}");
         bump(Op::BackendDelete);
+        self.listings.invalidate(&key);
         match &self.inner {
-            CachedBackendKind::File { backend } => backend.remove(&destination(name)),
+            CachedBackendKind::File { backend, .. } => backend.remove(&key)?,
             CachedBackendKind::Cached { cache, backend, .. } => {
                 // Remove it from the cache too.
                 // No worries if it isn't there, no need to prune.
                 cache.evict(name)?;
-                backend.remove(&destination(name))?;
-                Ok(())
+                backend.remove(&key)?;
             }
-            CachedBackendKind::Memory { backend } => backend.remove(&destination(name)),
+            CachedBackendKind::Memory { backend } => backend.remove(&key)?,
         }
+        Ok(())
     }
 
     // Let's put all the layout-specific stuff here so that we don't have paths
     // spread throughout the codebase.
 
+    // Listings are memoized (see the listings module) since some commands
+    // ask for the same prefix several times, and each is a round trip to the backend.
+
     fn list(&self, which: &str) -> Result<Vec<(String, u64)>> {
+        if let Some(l) = self.listings.get(which) {
+            debug!("Reusing listing of {which}*");
+            return Ok(l);
+        }
         debug!("Querying backend for {which}*");
-        match &self.inner {
-            CachedBackendKind::File { backend } => backend.list(which),
+        let generation = self.listings.generation();
+        let l = match &self.inner {
+            CachedBackendKind::File { backend, .. } => backend.list(which),
             CachedBackendKind::Cached { backend, .. } => backend.list(which),
             CachedBackendKind::Memory { backend } => backend.list(which),
+        }?;
+        self.listings.insert(generation, which, l.clone());
+        Ok(l)
+    }
+
+    fn list_streaming(&self, which: &str) -> Result<Listing> {
+        if let Some(l) = self.listings.get(which) {
+            debug!("Reusing listing of {which}*");
+            return Ok(Box::new(l.into_iter().map(Ok)));
         }
+        debug!("Querying backend for {which}*");
+        let l = match &self.inner {
+            CachedBackendKind::File { backend, .. } => backend.list_streaming(which),
+            CachedBackendKind::Cached { backend, .. } => backend.list_streaming(which),
+            CachedBackendKind::Memory { backend } => backend.list_streaming(which),
+        }?;
+        Ok(self.listings.memoize(which, l))
     }
 
     pub fn list_indexes(&self) -> Result<Vec<(String, u64)>> {
@@ -302,8 +699,9 @@ impl CachedBackend {
         self.list("snapshots/")
     }
 
-    pub fn list_packs(&self) -> Result<Vec<(String, u64)>> {
-        self.list("packs/")
+    /// Packs can number in the hundreds of thousands; stream them instead of collecting them.
+    pub fn list_packs(&self) -> Result<Listing> {
+        self.list_streaming("packs/")
     }
 
     pub fn read_pack(&self, id: &ObjectId) -> Result<Box<dyn SeekableRead>> {
@@ -313,6 +711,32 @@ impl CachedBackend {
             .with_context(|| format!("Couldn't open {}", pack_path))
     }
 
+    /// Download the given pack into the cache (if it isn't there already)
+    /// so a later [`read_pack()`](Self::read_pack) is a cache hit.
+    ///
+    /// Does nothing for backends without a cache, or ones that always read.
+    pub fn prefetch_pack(&self, id: &ObjectId) -> Result<()> {
+        if let CachedBackendKind::Cached {
+            cache,
+            behavior: CacheBehavior::Normal,
+            backend,
+        } = &self.inner
+        {
+            let name = format!("{id}.pack");
+            if cache.touch(&name)? {
+                return Ok(());
+            }
+            debug!("Prefetching {name}");
+            let key = destination(&name)?;
+            bump(Op::BackendRead);
+            let counter =
+                progress::AtomicCountRead::new(backend.read(&key)?, &self.bytes_downloaded);
+            cache.insert(&name, counter)?;
+            cache.prune()?;
+        }
+        Ok(())
+    }
+
     pub fn read_index(&self, id: &ObjectId) -> Result<Box<dyn SeekableRead>> {
         let index_path = format!("{}.index", id);
         self.read(&index_path)
@@ -328,7 +752,39 @@ impl CachedBackend {
     pub fn remove_pack(&self, id: &ObjectId) -> Result<()> {
         let base32 = id.to_string();
         let pack_path = format!("{}.pack", base32);
-        self.remove(&pack_path)
+        self.remove(&pack_path)?;
+        if let CachedBackendKind::Cached { cache, .. } = &self.inner {
+            cache.evict_manifest(&manifest_name(id))?;
+        }
+        Ok(())
+    }
+
+    /// Get a pack's (serialized) manifest from the local cache, if we have it there.
+    ///
+    /// Returns `None` for backends without a cache, or ones told to always read.
+    pub fn cached_manifest(&self, id: &ObjectId) -> Result<Option<Vec<u8>>> {
+        match &self.inner {
+            CachedBackendKind::Cached {
+                cache,
+                behavior: CacheBehavior::Normal,
+                ..
+            } => cache.try_read_manifest(&manifest_name(id)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Save a pack's (serialized) manifest in the local cache, if we have one.
+    pub fn cache_manifest(&self, id: &ObjectId, manifest: &[u8]) -> Result<()> {
+        match &self.inner {
+            CachedBackendKind::Cached {
+                behavior: CacheBehavior::NoCache,
+                ..
+            } => Ok(()),
+            CachedBackendKind::Cached { cache, .. } => {
+                cache.insert_manifest(&manifest_name(id), manifest)
+            }
+            _ => Ok(()),
+        }
     }
 
     pub fn remove_index(&self, id: &ObjectId) -> Result<()> {
@@ -354,13 +810,18 @@ pub fn probe_pack(packs: &[(String, u64)], id: &ObjectId) -> Result<()> {
     match found_packs.len() {
         0 => bail!("Couldn't find pack {}", base32),
         1 => Ok(()),
-        multiple => panic!(
-            "Expected one pack at {}, found several! {:?}",
-            pack_path, multiple
-        ),
+        // Listings can hiccup (say, a paginated one repeating a page),
+        // so this isn't worth taking the whole process down over.
+        multiple => {
+            bail!("Expected one pack at {pack_path}, but the backend listed it {multiple} times")
+        }
     }
 }
 
+fn manifest_name(pack: &ObjectId) -> String {
+    format!("{pack}.manifest")
+}
+
 /// Initializes an in-memory cache for testing purposes.
 pub fn in_memory() -> CachedBackend {
     CachedBackend::new(CachedBackendKind::Memory {
@@ -368,54 +829,141 @@ pub fn in_memory() -> CachedBackend {
     })
 }
 
+/// Like [`in_memory()`], but preloaded with the given files,
+/// named `<id>.<type>` like they'd be passed to [`CachedBackend::write()`].
+pub fn in_memory_with(files: Vec<(String, Vec<u8>)>) -> Result<CachedBackend> {
+    let backend = memory::MemoryBackend::new();
+    for (name, contents) in files {
+        backend.insert(&destination(&name)?, contents);
+    }
+    Ok(CachedBackend::new(CachedBackendKind::Memory { backend }))
+}
+
+/// Find a repository in the current directory or any of its parents,
+/// like Git looking for `.git`.
+///
+/// A repository is a directory with a `config.toml` we can parse as a repo config.
+pub fn discover_repository() -> Result<Utf8PathBuf> {
+    let cwd = std::env::current_dir().context("Couldn't get the current directory")?;
+    let cwd = Utf8PathBuf::try_from(cwd).context("Current directory isn't UTF-8")?;
+    for dir in cwd.ancestors() {
+        let cfg_file = dir.join("config.toml");
+        if cfg_file.is_file() && read_config(&cfg_file).is_ok() {
+            debug!("Found repository {dir}");
+            return Ok(dir.to_owned());
+        }
+    }
+    bail!("No repository found in {cwd} or its parents. Pass one with --repository.")
+}
+
+/// Resolve a credential, so it doesn't have to sit in the config in plain text.
+///
+/// In order of precedence:
+/// 1. The environment variable `env_var`, if it's set
+/// 2. If the config says `$SOME_VAR`, that variable (which must be set)
+/// 3. Whatever else the config says, unless it's empty
+pub fn secret(
+    what: &str,
+    configured: &str,
+    env_var: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+    if let Some(s) = env(env_var) {
+        debug!("Using {what} from ${env_var}");
+        return Ok(s);
+    }
+    if let Some(var) = configured.strip_prefix('$') {
+        return env(var).ok_or_else(|| {
+            anyhow!("Config gets {what} from ${var}, but neither it nor ${env_var} is set")
+        });
+    }
+    ensure!(
+        !configured.is_empty(),
+        "No {what} in the config; set ${env_var}"
+    );
+    Ok(configured.to_owned())
+}
+
+/// Non-empty environment variables, for [`secret()`]
+fn from_env(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|v| !v.is_empty())
+}
+
+/// The config file of the given repository:
+/// `config.toml` in a repository directory, or the repository path itself if it's a file.
+pub fn config_file(repository: &Utf8Path) -> Result<Utf8PathBuf> {
+    let stat =
+        std::fs::metadata(repository).with_context(|| format!("Couldn't stat {repository}"))?;
+    if stat.is_dir() {
+        Ok(repository.join("config.toml"))
+    } else if stat.is_file() {
+        Ok(repository.to_owned())
+    } else {
+        bail!("{repository} is not a file or directory")
+    }
+}
+
 /// Factory function to open the appropriate type of backend from the repository path
+///
+/// The repository's config comes from the repository itself (see [`config_file()`])
+/// unless `config.repo_config` says otherwise, e.g., for read-only repositories.
 pub fn open(
     repository: &Utf8Path,
-    cache_size: Byte,
+    config: &config::Configuration,
     behavior: CacheBehavior,
 ) -> Result<(Configuration, CachedBackend)> {
     info!("Opening repository {repository}");
-    let stat =
-        std::fs::metadata(repository).with_context(|| format!("Couldn't stat {repository}"))?;
-    let c = if stat.is_dir() {
-        let cfg_file = repository.join("config.toml");
-        read_config(&cfg_file)
-    } else if stat.is_file() {
-        read_config(repository)
-    } else {
-        bail!("{repository} is not a file or directory")
-    }?;
+    let config_path = match &config.repo_config {
+        Some(p) => {
+            debug!("Using repository config {p}");
+            p.clone()
+        }
+        None => config_file(repository)?,
+    };
+    let c = read_config(&config_path)?;
     debug!("Read repository config: {c:?}");
     // Don't bother checking unfilter; we ensure both are set if one is above.
     let cached_backend = match &c.kind {
-        Kind::Filesystem { force_cache: false } if c.filter.is_none() => {
+        Kind::Filesystem {
+            force_cache: false,
+            verify_after_write,
+        } if c.filter.is_none() => {
             // Uncached filesystem backends are a special case
             // (they let us directly manipulate files.)
+            if c.upload_limit.is_some() || c.download_limit.is_some() {
+                warn!(
+                    "upload_limit and download_limit don't apply to uncached filesystem repositories"
+                );
+            }
             CachedBackendKind::File {
                 backend: fs::FilesystemBackend::open(repository)?,
+                verify_after_write: *verify_after_write,
             }
         }
         some_cached => {
+            let cache = cache::setup(
+                config.cache_dir.as_deref(),
+                config.cache_size,
+                c.verify_cache_on_open,
+            )?;
+
             // It's not a filesystem backend, what is it?
-            let mut backend: Box<dyn Backend + Send + Sync> = match some_cached {
-                Kind::Filesystem { .. } => Box::new(fs::FilesystemBackend::open(repository)?),
-                Kind::Backblaze {
-                    key_id,
-                    application_key,
-                    bucket,
-                    concurrent_connections,
-                } => Box::new(semaphored::Semaphored::new(
-                    backblaze::BackblazeBackend::open(key_id, application_key, bucket)?,
-                    *concurrent_connections,
-                )),
-            };
+            let mut backend = open_kind(some_cached, repository, &cache, c.retries)?;
 
-            let cache = cache::setup(cache_size)?;
+            if c.upload_limit.is_some() || c.download_limit.is_some() {
+                backend = Box::new(throttle::Throttled::new(
+                    backend,
+                    c.upload_limit,
+                    c.download_limit,
+                ));
+            }
 
             if let Some((filter, unfilter)) = &c.filter {
                 backend = Box::new(filter::BackendFilter {
                     filter: filter.clone(),
                     unfilter: unfilter.clone(),
+                    legacy_unfilters: c.legacy_unfilters.clone(),
+                    environment: c.filter_environment(repository),
                     raw: backend,
                 });
             }
@@ -431,13 +979,171 @@ pub fn open(
     Ok((c, cached_backend))
 }
 
+/// Build the (uncached) backend described by the given kind.
+fn open_kind(
+    kind: &Kind,
+    repository: &Utf8Path,
+    cache: &Cache,
+    retries: Retries,
+) -> Result<Box<dyn Backend + Send + Sync>> {
+    let mut max_requests_per_second = None;
+    let remote: Box<dyn Backend + Send + Sync> = match kind {
+        Kind::Filesystem {
+            verify_after_write, ..
+        } => {
+            if *verify_after_write {
+                warn!(
+                    "verify_after_write only applies to unfiltered, uncached filesystem repositories"
+                );
+            }
+            return Ok(Box::new(fs::FilesystemBackend::open(repository)?));
+        }
+        Kind::Mirror {
+            primary,
+            secondaries,
+            require_all,
+        } => {
+            // Each part gets its own limits and retries.
+            let primary = open_kind(primary, repository, cache, retries)?;
+            let secondaries = secondaries
+                .iter()
+                .map(|s| open_kind(s, repository, cache, retries))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(Box::new(mirror::Mirrored::new(
+                primary,
+                secondaries,
+                *require_all,
+            )));
+        }
+        Kind::Backblaze {
+            key_id,
+            application_key,
+            bucket,
+            proxy,
+            ca_cert,
+            max_requests_per_second: rps,
+            resume_uploads,
+            ..
+        } => {
+            let resume_dir = resume_uploads.then(|| cache.directory.join("uploads"));
+            let key_id = secret("B2 key ID", key_id, "BACKPAK_B2_KEY_ID", from_env)?;
+            let application_key = secret(
+                "B2 application key",
+                application_key,
+                "BACKPAK_B2_APP_KEY",
+                from_env,
+            )?;
+            max_requests_per_second = *rps;
+            Box::new(backblaze::BackblazeBackend::open(
+                &key_id,
+                &application_key,
+                bucket,
+                proxy.as_deref(),
+                ca_cert.as_deref(),
+                resume_dir,
+            )?)
+        }
+        Kind::S3 {
+            endpoint,
+            region,
+            bucket,
+            access_key,
+            secret_key,
+            path_style,
+            ..
+        } => {
+            let access_key = secret(
+                "S3 access key",
+                access_key,
+                "BACKPAK_S3_ACCESS_KEY",
+                from_env,
+            )?;
+            let secret_key = secret(
+                "S3 secret key",
+                secret_key,
+                "BACKPAK_S3_SECRET_KEY",
+                from_env,
+            )?;
+            Box::new(s3::S3Backend::open(
+                endpoint,
+                region,
+                bucket,
+                &access_key,
+                &secret_key,
+                *path_style,
+            )?)
+        }
+        Kind::Ssh {
+            host,
+            port,
+            username,
+            identity_file,
+            base_path,
+            ..
+        } => Box::new(ssh::SshBackend::open(
+            host,
+            *port,
+            username.as_deref(),
+            identity_file.as_deref(),
+            base_path,
+        )?),
+    };
+
+    // Every remote backend gets the same treatment:
+    // only so many connections at once, maybe only so many requests per second
+    // (waiting for those outside the semaphore so we don't hog connections),
+    // and retries for transient failures.
+    let connections = kind
+        .remote()
+        .expect("remote backends have remote settings")
+        .concurrent_connections;
+    ensure!(connections > 0, "concurrent_connections must be positive");
+    let limited = semaphored::Semaphored::new(remote, connections);
+    Ok(match max_requests_per_second {
+        Some(rps) => {
+            ensure!(rps > 0.0, "max_requests_per_second must be positive");
+            Box::new(Retrying::new(
+                rate_limited::RateLimited::new(limited, rps),
+                retries,
+            ))
+        }
+        None => Box::new(Retrying::new(limited, retries)),
+    })
+}
+
+/// Reread the object we just wrote to `path` and make sure it still hashes to its ID.
+///
+/// Expensive (we read back everything we write), but catches corruption
+/// between us and the disk at write time instead of at the next `check`.
+pub fn verify_written(name: &str, path: &Utf8Path) -> Result<()> {
+    let mut fh = io::BufReader::new(
+        File::open(path).with_context(|| format!("Couldn't reopen {path} to verify it"))?,
+    );
+    verify_object(name, &mut fh)
+        .with_context(|| format!("{path} was corrupted as it was written"))?;
+    trace!("Verified {path}");
+    Ok(())
+}
+
+/// Check that the pack, index, or snapshot with the given name
+/// still hashes to the ID in that name.
+pub fn verify_object<R: Read + Seek>(name: &str, r: &mut R) -> Result<()> {
+    let id = id_from_path(name)?;
+    match Utf8Path::new(name).extension() {
+        Some("pack") => pack::verify_file(&id, r),
+        Some("index") => index::verify_file(&id, r),
+        Some("snapshot") => snapshot::verify_file(&id, r),
+        _ => bail!("Can't verify {name}; unexpected extension"),
+    }
+}
+
 /// Returns the desitnation path for the given temp file based on its extension
-fn destination(src: &str) -> String {
+fn destination(src: &str) -> Result<String> {
     match Utf8Path::new(src).extension() {
-        Some("pack") => format!("packs/{}", src),
-        Some("index") => format!("indexes/{}", src),
-        Some("snapshot") => format!("snapshots/{}", src),
-        _ => panic!("Unexpected extension on file: {}", src),
+        Some("pack") => Ok(format!("packs/{}", src)),
+        Some("index") => Ok(format!("indexes/{}", src)),
+        Some("snapshot") => Ok(format!("snapshots/{}", src)),
+        _ => bail!("Unexpected extension on {src}; expected a pack, index, or snapshot"),
     }
 }
 
@@ -450,3 +1156,375 @@ pub fn id_from_path<P: AsRef<Utf8Path>>(path: P) -> Result<ObjectId> {
         .ok_or_else(|| anyhow!("Couldn't determine ID from {}", path.as_ref()))
         .and_then(ObjectId::from_str)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use config::Format;
+
+    #[test]
+    fn no_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let backend = CachedBackend::new(CachedBackendKind::Cached {
+            cache: Cache::new(dir, Byte::MEBIBYTE)?,
+            behavior: CacheBehavior::NoCache,
+            backend: Box::new(memory::MemoryBackend::new()),
+        });
+
+        let name = "no-cache-test.snapshot";
+        std::fs::write(name, "ephemeral")?;
+        backend.write(name, File::open(name)?)?;
+        // Gone locally, but not in the cache either.
+        assert!(!Utf8Path::new(name).exists());
+
+        let mut read_back = String::new();
+        backend.read(name)?.read_to_string(&mut read_back)?;
+        assert_eq!(read_back, "ephemeral");
+        assert_eq!(backend.cache().unwrap().stats()?.entries, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn memory_writes_stay_in_memory() -> Result<()> {
+        let backend = in_memory();
+
+        // Nothing by this name on disk, and nothing should show up.
+        let name = "only-in-memory.snapshot";
+        assert!(!Utf8Path::new(name).exists());
+        let mut fh = tempfile::tempfile()?;
+        fh.write_all(b"no files were harmed")?;
+        backend.write(name, fh)?;
+        assert!(!Utf8Path::new(name).exists());
+
+        // A file that happens to share the name isn't ours to remove.
+        let bystander = "in-memory-bystander.snapshot";
+        std::fs::write(bystander, "leave me be")?;
+        let mut fh = tempfile::tempfile()?;
+        fh.write_all(b"something else")?;
+        let written = backend.write(bystander, fh);
+        let still_there = Utf8Path::new(bystander).exists();
+        std::fs::remove_file(bystander)?;
+        written?;
+        assert!(still_there);
+
+        let mut read_back = String::new();
+        backend.read(name)?.read_to_string(&mut read_back)?;
+        assert_eq!(read_back, "no files were harmed");
+
+        // The temp files we _are_ handed get cleaned up, same as other backends.
+        let owned = "in-memory-owned.snapshot";
+        std::fs::write(owned, "mine")?;
+        backend.write(owned, File::open(owned)?)?;
+        assert!(!Utf8Path::new(owned).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn probe_duplicate_packs() {
+        let id = ObjectId::hash(b"seeing double");
+        let listed = (format!("packs/{id}.pack"), 42);
+        assert!(probe_pack(std::slice::from_ref(&listed), &id).is_ok());
+
+        let e = probe_pack(&[listed.clone(), listed], &id).unwrap_err();
+        assert!(e.to_string().contains("listed it 2 times"), "{e:#}");
+        assert!(probe_pack(&[], &id).is_err());
+    }
+
+    #[test]
+    fn unexpected_extensions() -> Result<()> {
+        let backend = in_memory();
+        let e = backend
+            .write("leftover.tmp", tempfile::tempfile()?)
+            .unwrap_err();
+        assert!(e.to_string().contains("Unexpected extension"), "{e:#}");
+        assert!(backend.read("leftover.tmp").is_err());
+        assert!(backend.remove("leftover.tmp").is_err());
+        assert!(in_memory_with(vec![("leftover.tmp".to_owned(), vec![])]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn preloaded_memory() -> Result<()> {
+        let backend = in_memory_with(vec![
+            ("preloaded.snapshot".to_owned(), b"already here".to_vec()),
+            ("preloaded.index".to_owned(), vec![]),
+        ])?;
+        let mut read_back = String::new();
+        backend
+            .read("preloaded.snapshot")?
+            .read_to_string(&mut read_back)?;
+        assert_eq!(read_back, "already here");
+        assert_eq!(
+            backend.list_snapshots()?,
+            [("snapshots/preloaded.snapshot".to_owned(), 12)]
+        );
+        assert_eq!(backend.list_indexes()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn memoized_listings() -> Result<()> {
+        let backend = in_memory();
+        let names = |b: &CachedBackend| -> Result<Vec<String>> {
+            Ok(b.list_snapshots()?.into_iter().map(|(n, _)| n).collect())
+        };
+        assert!(names(&backend)?.is_empty());
+
+        // Writes through the CachedBackend show up...
+        let name = "memoized-listing-test.snapshot";
+        std::fs::write(name, "hi")?;
+        backend.write(name, File::open(name)?)?;
+        assert_eq!(names(&backend)?, [format!("snapshots/{name}")]);
+
+        // ...but anything going around it doesn't, since we reuse the last listing.
+        let CachedBackendKind::Memory { backend: raw } = &backend.inner else {
+            unreachable!()
+        };
+        raw.write(2, &mut io::Cursor::new("hi"), "snapshots/sneaky.snapshot")?;
+        assert_eq!(names(&backend)?.len(), 1);
+        // Other prefixes aren't affected by the write.
+        raw.write(2, &mut io::Cursor::new("hi"), "packs/sneaky.pack")?;
+        let packs = backend.list_packs()?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(packs.len(), 1);
+        let packs = backend.list_packs()?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(packs.len(), 1);
+
+        backend.remove(name)?;
+        assert_eq!(names(&backend)?, ["snapshots/sneaky.snapshot"]);
+        Ok(())
+    }
+
+    /// Only lists lazily, so we can tell if a wrapper fell back to `list()`.
+    struct StreamsOnly;
+
+    impl Backend for StreamsOnly {
+        fn read(&self, _from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+            unimplemented!()
+        }
+
+        fn write(&self, _len: u64, _from: &mut dyn Upload, _to: &str) -> Result<(), BackendError> {
+            unimplemented!()
+        }
+
+        fn remove(&self, _which: &str) -> Result<(), BackendError> {
+            unimplemented!()
+        }
+
+        fn list(&self, _prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+            panic!("list() instead of list_streaming()")
+        }
+
+        fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+            Ok(Box::new(std::iter::once(Ok((format!("{prefix}a"), 1)))))
+        }
+    }
+
+    #[test]
+    fn wrappers_stream_listings() -> Result<()> {
+        let wrapped: Vec<Box<dyn Backend + Send + Sync>> = vec![
+            Box::new(semaphored::Semaphored::new(StreamsOnly, 1)),
+            Box::new(Retrying::new(StreamsOnly, Retries::default())),
+            Box::new(throttle::Throttled::new(StreamsOnly, None, None)),
+            Box::new(rate_limited::RateLimited::new(StreamsOnly, 10.0)),
+            Box::new(filter::BackendFilter {
+                filter: "cat".to_owned(),
+                unfilter: "cat".to_owned(),
+                legacy_unfilters: vec![],
+                environment: filter::FilterEnvironment::for_repository(Utf8Path::new("."), vec![]),
+                raw: Box::new(StreamsOnly),
+            }),
+            Box::new(mirror::Mirrored::new(
+                Box::new(StreamsOnly),
+                vec![Box::new(StreamsOnly)],
+                false,
+            )),
+        ];
+        for b in wrapped {
+            let listed = b.list_streaming("packs/")?.collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(listed, [("packs/a".to_owned(), 1)]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn secrets() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == var)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        let none = env(&[]);
+        let ours = env(&[("BACKPAK_B2_KEY_ID", "from-env")]);
+        let theirs = env(&[("MY_KEY", "from-my-var")]);
+        let both = env(&[("BACKPAK_B2_KEY_ID", "from-env"), ("MY_KEY", "from-my-var")]);
+        let id = |configured, e| secret("key", configured, "BACKPAK_B2_KEY_ID", e);
+
+        // Plain config values are used as-is...
+        assert_eq!(id("in-config", none).unwrap(), "in-config");
+        // ...but the environment wins.
+        assert_eq!(id("in-config", ours).unwrap(), "from-env");
+        assert_eq!(id("", ours).unwrap(), "from-env");
+        assert_eq!(id("$MY_KEY", both).unwrap(), "from-env");
+
+        // $VARs get looked up
+        assert_eq!(id("$MY_KEY", theirs).unwrap(), "from-my-var");
+
+        // Missing values are errors, not empty keys.
+        let e = id("", none).unwrap_err().to_string();
+        assert!(e.contains("set $BACKPAK_B2_KEY_ID"), "{e}");
+        let e = id("$MY_KEY", none).unwrap_err().to_string();
+        assert!(e.contains("$MY_KEY"), "{e}");
+    }
+
+    #[test]
+    fn config_formats() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let kind = Kind::Backblaze {
+            key_id: "key".to_owned(),
+            application_key: "shh".to_owned(),
+            bucket: "buck".to_owned(),
+            remote: Remote {
+                concurrent_connections: 4,
+            },
+            proxy: None,
+            ca_cert: None,
+            max_requests_per_second: Some(2.5),
+            resume_uploads: true,
+        };
+        for (ext, format) in [
+            ("toml", Format::Toml),
+            ("json", Format::Json),
+            ("yaml", Format::Yaml),
+        ] {
+            let p = dir.join(format!("repo.{ext}"));
+            let c = Configuration {
+                pack_size: Byte::from_u64(1234),
+                kind: kind.clone(),
+                filter: Some(("cat".to_owned(), "cat".to_owned())),
+                legacy_unfilters: vec!["gzip -d".to_owned()],
+                filter_env: vec!["GNUPGHOME".to_owned()],
+                retries: Retries {
+                    max_attempts: 3,
+                    base_delay_ms: 250,
+                },
+                upload_limit: Some(Byte::from_u64(1_000_000)),
+                download_limit: None,
+                compression: Some(pack::Compression {
+                    algorithm: pack::Algorithm::Zstd,
+                    level: 19,
+                }),
+                chunking: Some(chunk::ChunkConfig {
+                    min: Byte::from_u64(64 * 1024),
+                    avg: Byte::from_u64(256 * 1024),
+                    max: Byte::from_u64(1024 * 1024),
+                }),
+                verify_cache_on_open: true,
+            };
+            write_config(File::create(&p)?, c, format)?;
+            let read = read_config(&p)?;
+            assert_eq!(read.pack_size, Byte::from_u64(1234));
+            assert_eq!(read.kind, kind);
+            assert_eq!(read.retries.max_attempts, 3);
+            assert_eq!(read.upload_limit, Some(Byte::from_u64(1_000_000)));
+            assert_eq!(read.download_limit, None);
+            assert_eq!(read.filter, Some(("cat".to_owned(), "cat".to_owned())));
+            assert_eq!(read.legacy_unfilters, ["gzip -d"]);
+            assert_eq!(read.filter_env, ["GNUPGHOME"]);
+            assert_eq!(read.compression.unwrap().level, 19);
+            assert_eq!(read.chunking.unwrap().avg, Byte::from_u64(256 * 1024));
+        }
+
+        let ini = dir.join("repo.ini");
+        std::fs::write(&ini, "")?;
+        let err = read_config(&ini).unwrap_err();
+        assert!(err.to_string().contains(".ini"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn mirror_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let p = dir.join("config.toml");
+        std::fs::write(
+            &p,
+            r#"
+[backend]
+type = "Mirror"
+
+[backend.primary]
+type = "Filesystem"
+force_cache = false
+
+[[backend.secondaries]]
+type = "Ssh"
+host = "example.com"
+base_path = "backpak"
+concurrent_connections = 2
+
+[[backend.secondaries]]
+type = "S3"
+endpoint = "http://minio.local:9000"
+region = "us-east-1"
+bucket = "backpak"
+"#,
+        )?;
+        let c = read_config(&p)?;
+        let Kind::Mirror {
+            primary,
+            secondaries,
+            require_all,
+        } = c.kind
+        else {
+            panic!("expected a mirror, got {:?}", c.kind);
+        };
+        assert!(matches!(*primary, Kind::Filesystem { .. }));
+        assert!(matches!(
+            secondaries[..],
+            [Kind::Ssh { port: 22, .. }, Kind::S3 { .. }]
+        ));
+        assert_eq!(primary.remote(), None);
+        assert_eq!(secondaries[0].remote().unwrap().concurrent_connections, 2);
+        assert_eq!(
+            secondaries[1].remote().unwrap().concurrent_connections,
+            DEFAULT_CONNECTIONS
+        );
+        assert!(!require_all);
+
+        // Two filesystems would be the same directory.
+        let s = std::fs::read_to_string(&p)?;
+        std::fs::write(
+            &p,
+            format!("{s}\n[[backend.secondaries]]\ntype = \"Filesystem\"\nforce_cache = true\n"),
+        )?;
+        let err = read_config(&p).unwrap_err();
+        assert!(format!("{err:#}").contains("Bad mirror"), "{err:#}");
+        Ok(())
+    }
+
+    #[test]
+    fn remote_settings() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let p = dir.join("config.toml");
+        let ssh = "[backend]\ntype = \"Ssh\"\nhost = \"example.com\"\nbase_path = \"backpak\"\n";
+
+        std::fs::write(&p, format!("{ssh}concurrent_connections = 7\n"))?;
+        let c = read_config(&p)?;
+        assert_eq!(c.kind.remote().unwrap().concurrent_connections, 7);
+
+        // Flattening the shared settings in doesn't let typos slip by.
+        std::fs::write(&p, format!("{ssh}concurent_connections = 7\n"))?;
+        let err = read_config(&p).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("unknown field `concurent_connections`"),
+            "{err:#}"
+        );
+        Ok(())
+    }
+}
@@ -1,89 +1,350 @@
 use super::*;
 
 use std::fs;
+use std::io;
 
 use anyhow::Result;
 use b2::Session;
 use backpak_b2 as b2;
 use byte_unit::Byte;
 
+/// Files bigger than this are uploaded in parts when resuming uploads.
+/// (B2 wants parts of at least 5 MB, save the last.)
+const PART_SIZE: u64 = 10_000_000;
+
+/// B2 won't take more parts than this for a single file.
+const MAX_PARTS: u64 = 10_000;
+
+/// B2 won't take anything bigger than this in a single upload;
+/// larger files must go up in parts, resuming or not.
+const SINGLE_PART_LIMIT: u64 = 5_000_000_000;
+
 pub struct BackblazeBackend {
     pub session: Session,
+    /// Where we keep track of partly-uploaded files, if we're doing that.
+    resume_dir: Option<Utf8PathBuf>,
+}
+
+/// What we remember about an unfinished large file between attempts
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct UploadState {
+    file_id: String,
+    len: u64,
+    part_size: u64,
+}
+
+fn part_size(len: u64) -> u64 {
+    PART_SIZE.max(len.div_ceil(MAX_PARTS))
+}
+
+/// Make sure B2 stored what we sent, since a checksum mismatch means
+/// something got mangled on the way and we shouldn't trust the object.
+fn check_upload(what: &str, sums: &b2::Checksums) -> Result<()> {
+    if sums.sent != sums.stored {
+        bail!(
+            "B2 stored {what} with SHA1 {}, but we sent {}",
+            sums.stored,
+            sums.sent
+        );
+    }
+    Ok(())
+}
+
+/// Should we upload a file of the given length in parts?
+fn in_parts(len: u64, resuming: bool) -> bool {
+    len > SINGLE_PART_LIMIT || (resuming && len > PART_SIZE)
 }
 
+#[expect(clippy::too_many_arguments)] // Config is config.
 pub fn initialize(
     repository: &camino::Utf8Path,
     pack_size: Byte,
     filter: Option<(String, String)>,
+    filter_env: Vec<String>,
     key_id: String,
     application_key: String,
     bucket: String,
     concurrent_connections: u32,
+    proxy: Option<String>,
+    ca_cert: Option<camino::Utf8PathBuf>,
+    max_requests_per_second: Option<f64>,
+    resume_uploads: bool,
 ) -> Result<()> {
+    let format = crate::config::Format::from_path(repository)?;
     let c = super::Configuration {
         pack_size,
         kind: super::Kind::Backblaze {
             key_id,
             application_key,
             bucket,
-            concurrent_connections,
+            remote: super::Remote {
+                concurrent_connections,
+            },
+            proxy,
+            ca_cert,
+            max_requests_per_second,
+            resume_uploads,
         },
         filter,
+        legacy_unfilters: vec![],
+        filter_env,
+        retries: Default::default(),
+        upload_limit: None,
+        download_limit: None,
+        compression: None,
+        chunking: None,
+        verify_cache_on_open: false,
     };
-    let fh = fs::OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(repository)
-        .with_context(|| format!("Couldn't create {repository}"))?;
-
-    super::write_config(fh, c)?;
-    Ok(())
+    super::create_config(repository, c, format)
 }
 
 impl BackblazeBackend {
-    pub fn open(key_id: &str, application_key: &str, bucket: &str) -> Result<Self> {
-        let session = Session::new(key_id, application_key, bucket)?;
-        Ok(Self { session })
+    pub fn open(
+        key_id: &str,
+        application_key: &str,
+        bucket: &str,
+        proxy: Option<&str>,
+        ca_cert: Option<&camino::Utf8Path>,
+        resume_dir: Option<Utf8PathBuf>,
+    ) -> Result<Self> {
+        let options = b2::HttpOptions {
+            proxy: proxy.map(str::to_owned),
+            ca_cert: ca_cert.map(|c| c.as_std_path().to_owned()),
+        };
+        let session = Session::with_options(key_id, application_key, bucket, &options)?;
+        if let Some(d) = &resume_dir {
+            fs::create_dir_all(d).with_context(|| format!("Couldn't create {d}"))?;
+        }
+        Ok(Self {
+            session,
+            resume_dir,
+        })
     }
-}
 
-// We could make the timeout configurable, make this generic across different errors, etc.
-fn retry<T, F: FnMut() -> b2::Result<T>>(mut f: F) -> b2::Result<T> {
-    loop {
-        match f() {
-            Ok(k) => return Ok(k),
-            Err(e) => {
-                // Assume IO errors are issues with our machine that won't resolve quickly,
-                // and everything else to be server-side, temporary sadness.
-                if matches!(e, b2::Error::Io(_)) {
-                    return Err(e);
-                } else {
-                    warn!("{e}, retrying");
-                    std::thread::sleep(std::time::Duration::from_secs(1));
+    /// Upload `to` in parts, skipping any that a previous attempt already uploaded
+    /// if we're keeping track of those in `state_dir`.
+    ///
+    /// We're always handed the stream from the beginning,
+    /// so we read through parts B2 already has, hashing them as we go.
+    /// If a part's contents changed (e.g., a filter that encrypts with a random nonce),
+    /// we just upload it again - B2 replaces parts with the same number.
+    fn write_in_parts(
+        &self,
+        state_dir: Option<&Utf8Path>,
+        len: u64,
+        from: &mut dyn Upload,
+        to: &str,
+    ) -> Result<(), BackendError> {
+        let state_file = state_dir.map(|d| d.join(Utf8Path::new(to).file_name().unwrap_or(to)));
+        let part_size = part_size(len);
+
+        let resumed = state_file
+            .as_deref()
+            .and_then(|sf| self.resume(sf, len, part_size));
+        let (file_id, uploaded) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                let file_id = self.session.start_large_file(to)?;
+                if let Some(state_file) = &state_file {
+                    let state = UploadState {
+                        file_id: file_id.clone(),
+                        len,
+                        part_size,
+                    };
+                    fs::write(
+                        state_file,
+                        serde_json::to_vec(&state).map_err(anyhow::Error::from)?,
+                    )
+                    .with_context(|| format!("Couldn't save upload state to {state_file}"))?;
+                }
+                (file_id, vec![])
+            }
+        };
+
+        let mut buf = vec![0; part_size as usize];
+        let mut sha1s = vec![];
+        let mut remaining = len;
+        let mut number = 1;
+        while remaining > 0 {
+            let this_part = &mut buf[..remaining.min(part_size) as usize];
+            from.read_exact(this_part)
+                .with_context(|| format!("Couldn't read part {number} of {to}"))?;
+            let sha1 = b2::sha1_hex(this_part);
+            let already_there = uploaded.iter().any(|p| {
+                p.number == number && p.length == this_part.len() as u64 && p.sha1 == sha1
+            });
+            if already_there {
+                debug!("{to} part {number} already uploaded");
+                sha1s.push(sha1);
+            } else {
+                let sums = self.session.upload_part(&file_id, number, this_part)?;
+                check_upload(&format!("part {number} of {to}"), &sums)?;
+                sha1s.push(sums.sent);
+            }
+            remaining -= this_part.len() as u64;
+            number += 1;
+        }
+        self.session.finish_large_file(&file_id, &sha1s)?;
+
+        if let Some(state_file) = &state_file {
+            match fs::remove_file(state_file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    warn!("Couldn't remove upload state {state_file}: {e}")
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Look for a previous attempt to upload this file, returning its ID and uploaded parts.
+    fn resume(
+        &self,
+        state_file: &Utf8Path,
+        len: u64,
+        part_size: u64,
+    ) -> Option<(String, Vec<b2::Part>)> {
+        let state: UploadState = match fs::read(state_file) {
+            Ok(s) => match serde_json::from_slice(&s) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Ignoring bad upload state {state_file}: {e}");
+                    return None;
                 }
+            },
+            Err(_) => return None,
+        };
+        // Different sizes mean different parts; it's not the file we were uploading before.
+        if state.len != len || state.part_size != part_size {
+            debug!("{state_file} doesn't match this upload; starting over");
+            if let Err(e) = self.session.cancel_large_file(&state.file_id) {
+                debug!("Couldn't cancel previous upload: {e}");
+            }
+            return None;
+        }
+        match self.session.list_parts(&state.file_id) {
+            Ok(parts) => {
+                info!(
+                    "Resuming upload of {} ({} parts done)",
+                    state_file.file_name().unwrap_or_default(),
+                    parts.len()
+                );
+                Some((state.file_id, parts))
+            }
+            Err(e) => {
+                warn!("Couldn't resume upload from {state_file}, starting over: {e}");
+                None
             }
         }
     }
 }
 
 impl Backend for BackblazeBackend {
-    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>> {
-        let r = retry(|| self.session.get(from))?;
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        // Large files download just like small ones.
+        let r = self.session.get(from)?;
         Ok(Box::new(r))
     }
 
-    fn write(&self, len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
-        retry(|| self.session.put(to, len, from))?;
-        Ok(())
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+        if in_parts(len, self.resume_dir.is_some()) {
+            self.write_in_parts(self.resume_dir.as_deref(), len, from, to)
+        } else {
+            let sums = self.session.put(to, len, from)?;
+            check_upload(to, &sums).map_err(|mismatch| {
+                // Don't leave a bad copy lying around where it looks like the real deal.
+                if let Err(e) = self.session.delete(to) {
+                    warn!("Couldn't remove mismatched upload {to}: {e}");
+                }
+                mismatch.into()
+            })
+        }
     }
 
-    fn remove(&self, which: &str) -> Result<()> {
-        retry(|| self.session.delete(which))?;
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
+        self.session.delete(which)?;
         Ok(())
     }
 
-    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
-        let l = retry(|| self.session.list(Some(prefix)))?;
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+        let l = self.session.list(Some(prefix))?;
         Ok(l)
     }
 }
+
+impl From<b2::Error> for BackendError {
+    fn from(e: b2::Error) -> Self {
+        // Assume IO errors are issues with our machine that won't resolve quickly,
+        // and that B2 telling us we messed up won't either.
+        // Everything else is server-side, temporary sadness.
+        let kind = match &e {
+            b2::Error::Http(h) => match **h {
+                ureq::Error::StatusCode(404) => Self::NotFound,
+                ureq::Error::StatusCode(401 | 403) => Self::Auth,
+                ureq::Error::StatusCode(code) if code != 408 && code != 429 && code < 500 => {
+                    Self::Other
+                }
+                _ => Self::Transient,
+            },
+            b2::Error::UnexpectedResponse { .. } => Self::Transient,
+            b2::Error::NotFound { .. } => Self::NotFound,
+            b2::Error::Io(_) => Self::Other,
+        };
+        kind(e.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn part_sizes() {
+        assert_eq!(part_size(100_000_000), PART_SIZE);
+        // A 5 GB pack still fits in B2's part limit...
+        assert_eq!(part_size(5_000_000_000), PART_SIZE);
+        // ...but huge files need bigger parts.
+        let huge: u64 = 1_000_000_000_000;
+        assert!(huge.div_ceil(part_size(huge)) <= MAX_PARTS);
+    }
+
+    #[test]
+    fn upload_checks() {
+        let sent = b2::sha1_hex(b"abc");
+        let good = b2::Checksums {
+            sent: sent.clone(),
+            stored: sent.clone(),
+        };
+        assert!(check_upload("abc", &good).is_ok());
+        let bad = b2::Checksums {
+            sent,
+            stored: b2::sha1_hex(b"abd"),
+        };
+        let e = check_upload("abc", &bad).unwrap_err();
+        assert!(e.to_string().contains("B2 stored abc"), "{e}");
+    }
+
+    #[test]
+    fn classified_errors() {
+        let status = |code| BackendError::from(b2::Error::from(ureq::Error::StatusCode(code)));
+        assert!(status(503).is_transient());
+        assert!(status(429).is_transient());
+        assert!(status(404).is_not_found());
+        assert!(matches!(status(401), BackendError::Auth(_)));
+        assert!(matches!(status(400), BackendError::Other(_)));
+        let missing = BackendError::from(b2::Error::NotFound {
+            what: "packs/a.pack".to_owned(),
+        });
+        assert!(missing.is_not_found());
+    }
+
+    #[test]
+    fn large_files_go_in_parts() {
+        assert!(!in_parts(100_000_000, false));
+        assert!(in_parts(100_000_000, true));
+        assert!(!in_parts(PART_SIZE, true));
+        // B2 won't take these in one go, resuming or not.
+        assert!(in_parts(SINGLE_PART_LIMIT + 1, false));
+        assert!(!in_parts(SINGLE_PART_LIMIT, false));
+    }
+}
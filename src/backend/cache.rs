@@ -7,10 +7,12 @@ use std::{
 use anyhow::{Context, Result, anyhow, bail};
 use byte_unit::Byte;
 use camino::{Utf8Path, Utf8PathBuf};
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
+use tracing::*;
 
 use crate::counters::{Op, bump};
 use crate::file_util;
+use crate::hashing::ObjectId;
 
 /// Local cache for any and all backends.
 ///
@@ -29,7 +31,39 @@ pub struct Cache {
     conn: Mutex<Connection>,
 }
 
-// 1G. Make this configurable with global settings (~/.config/backpak?)
+/// What's in the cache, and how well it's working
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub total_bytes: u64,
+    /// The most we keep before pruning (as set by whoever opened the cache last)
+    pub capacity_bytes: u64,
+    /// Reads the cache could serve, over the cache's lifetime
+    pub hits: u64,
+    /// Reads it couldn't, over the cache's lifetime
+    pub misses: u64,
+}
+
+/// A file in the cache, and when it was last read or written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub name: String,
+    pub size: u64,
+    pub last_used: jiff::Timestamp,
+}
+
+/// How [`Cache::verify()`] went
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyCounts {
+    /// Entries that still match their names
+    pub good: usize,
+    /// Entries that didn't, and were thrown out
+    pub evicted: usize,
+    /// Entries we couldn't read to check
+    pub errored: usize,
+}
+
+/// 1G, unless the user config sets `cache_size`
 pub const DEFAULT_SIZE: Byte = Byte::GIBIBYTE;
 
 impl Cache {
@@ -82,6 +116,7 @@ impl Cache {
         match File::open(self.directory.join(name)) {
             Ok(fd) => {
                 self.bump_row(name, fd.metadata()?.len())?;
+                self.tally("hits")?;
                 Ok(Some(fd))
             }
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
@@ -90,12 +125,26 @@ impl Cache {
                     .lock()
                     .unwrap()
                     .execute("DELETE FROM cache WHERE name == ?1", [name])?;
+                self.tally("misses")?;
                 Ok(None)
             }
             Err(e) => bail!(e),
         }
     }
 
+    /// Mark the given entry as recently used without reading it,
+    /// returning whether it's there.
+    pub fn touch(&self, name: &str) -> Result<bool> {
+        match fs::metadata(self.directory.join(name)) {
+            Ok(m) => {
+                self.bump_row(name, m.len())?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => bail!(e),
+        }
+    }
+
     /// Insert the given contents into the cache with the given name.
     /// Returns the file in the cache
     /// (since reads that just inserted will want to read the contents immediately).
@@ -113,14 +162,80 @@ impl Cache {
         Ok(cached)
     }
 
+    /// Mark the given entry as the most recently used.
+    ///
+    /// Recency is what [`prune()`](Self::prune) goes by, so it had better be strictly ordered:
+    /// if the clock ties or (thanks, NTP) goes backwards, nudge past the newest entry.
     fn bump_row(&self, name: &str, size: u64) -> Result<()> {
         self.conn.lock().unwrap().execute(
-            "REPLACE INTO cache(name, time, size) VALUES (?1, ?2, ?3)",
+            "REPLACE INTO cache(name, time, size) VALUES (
+                ?1, MAX(?2, (SELECT COALESCE(MAX(time), 0) + 1 FROM cache)), ?3
+            )",
             (name, now_nanos(), size),
         )?;
         Ok(())
     }
 
+    /// Count a hit or miss. These live in the database (instead of just `counters`)
+    /// so `backpak cache stats` can see how every run before it did.
+    fn tally(&self, which: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO settings(key, value) VALUES (?1, 1)
+                ON CONFLICT(key) DO UPDATE SET value = value + 1",
+            [which],
+        )?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        let c = self.conn.lock().unwrap();
+        let (entries, total_bytes): (i64, i64) = c.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM cache",
+            (),
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        let setting = |key: &str| -> Result<i64> {
+            let v: Option<i64> = c
+                .query_row("SELECT value FROM settings WHERE key = ?1", [key], |r| {
+                    r.get(0)
+                })
+                .optional()?;
+            Ok(v.unwrap_or(0))
+        };
+        Ok(CacheStats {
+            entries: entries as u64,
+            total_bytes: total_bytes as u64,
+            capacity_bytes: setting("size")? as u64,
+            hits: setting("hits")? as u64,
+            misses: setting("misses")? as u64,
+        })
+    }
+
+    /// Everything in the cache, most recently used first
+    /// (so the last entries are the next to be pruned).
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let c = self.conn.lock().unwrap();
+        let mut statement = c.prepare("SELECT name, time, size FROM cache ORDER BY time DESC")?;
+        let rows = statement
+            .query_map((), |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, i64>(1)?,
+                    r.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(name, time, size)| {
+                Ok(CacheEntry {
+                    name,
+                    size: size as u64,
+                    last_used: jiff::Timestamp::from_nanosecond(time as i128)?,
+                })
+            })
+            .collect()
+    }
+
     pub fn evict(&self, name: &str) -> Result<()> {
         self.delete_if_exists(name)?;
         let rows = self
@@ -140,7 +255,185 @@ impl Cache {
         }
     }
 
-    pub fn prune(&self) -> Result<()> {
+    /// Remove everything from the cache,
+    /// returning how many files (and how many bytes) that was.
+    pub fn clear(&self) -> Result<(usize, u64)> {
+        let mut c = self.conn.lock().unwrap();
+        let transaction = c.transaction()?;
+        let entries = transaction
+            .prepare("SELECT name, size FROM cache")?
+            .query_map((), |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (name, _) in &entries {
+            self.delete_if_exists(name)?;
+        }
+        transaction.execute("DELETE FROM cache", ())?;
+        transaction.commit()?;
+        drop(c);
+
+        let mut count = entries.len();
+        let mut bytes = entries.iter().map(|(_, s)| *s as u64).sum();
+        let manifests = self.directory.join("manifests");
+        if manifests.exists() {
+            for m in manifests
+                .read_dir_utf8()
+                .with_context(|| format!("Couldn't read {manifests}"))?
+            {
+                let m = m?;
+                count += 1;
+                bytes += m.metadata()?.len();
+            }
+            fs::remove_dir_all(&manifests)
+                .with_context(|| format!("Couldn't remove {manifests}"))?;
+        }
+        Ok((count, bytes))
+    }
+
+    /// Rehash everything in the cache, evicting anything that doesn't match its name.
+    pub fn verify(&self) -> Result<VerifyCounts> {
+        let names = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare("SELECT name FROM cache")?
+            .query_map((), |r| r.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut counts = VerifyCounts::default();
+        for name in names {
+            let path = self.directory.join(&name);
+            let mut fh = match File::open(&path) {
+                Ok(fh) => std::io::BufReader::new(fh),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    // Stale record; clean it up like try_read() would.
+                    debug!("{name} isn't in the cache after all");
+                    self.evict(&name)?;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Couldn't open {path}: {e}");
+                    counts.errored += 1;
+                    continue;
+                }
+            };
+            match super::verify_object(&name, &mut fh) {
+                Ok(()) => counts.good += 1,
+                Err(e) => {
+                    warn!("Evicting {name}: {e:#}");
+                    drop(fh);
+                    self.evict(&name)?;
+                    counts.evicted += 1;
+                }
+            }
+        }
+
+        // Manifests hash to their pack's ID, which is their name.
+        let manifests = self.directory.join("manifests");
+        if manifests.exists() {
+            for m in manifests
+                .read_dir_utf8()
+                .with_context(|| format!("Couldn't read {manifests}"))?
+            {
+                let m = m?;
+                let name = m.file_name();
+                let contents = match fs::read(m.path()) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("Couldn't read {}: {e}", m.path());
+                        counts.errored += 1;
+                        continue;
+                    }
+                };
+                let matches =
+                    super::id_from_path(name).is_ok_and(|id| ObjectId::hash(&contents) == id);
+                if matches {
+                    counts.good += 1;
+                } else {
+                    warn!("Evicting {name}: doesn't match its hash");
+                    self.evict_manifest(name)?;
+                    counts.evicted += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Evict entries whose size on disk doesn't match what we recorded when inserting them,
+    /// returning how many there were.
+    ///
+    /// A crash mid-write could leave a truncated file that would otherwise be served as a hit.
+    /// This is much cheaper than [`verify()`](Self::verify) - it's just a `stat()` per entry -
+    /// but only catches files that are the wrong length.
+    pub fn check_sizes(&self) -> Result<usize> {
+        let entries = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare("SELECT name, size FROM cache")?
+            .query_map((), |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut evicted = 0;
+        for (name, size) in entries {
+            let path = self.directory.join(&name);
+            match fs::metadata(&path) {
+                Ok(m) if m.len() == size as u64 => {}
+                Ok(m) => {
+                    warn!("Evicting {name}: it's {} bytes, expected {size}", m.len());
+                    self.evict(&name)?;
+                    evicted += 1;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    // Stale record; clean it up like try_read() would.
+                    debug!("{name} isn't in the cache after all");
+                    self.evict(&name)?;
+                }
+                Err(e) => return Err(e).with_context(|| format!("Couldn't stat {path}")),
+            }
+        }
+        Ok(evicted)
+    }
+
+    // Pack manifests get their own directory outside the LRU machinery above:
+    // they're tiny and immutable (named by their own hash, even),
+    // so we'd like to keep far more of them than we would whole packs.
+    // They only go away when their pack does.
+
+    fn manifest_path(&self, name: &str) -> Utf8PathBuf {
+        self.directory.join("manifests").join(name)
+    }
+
+    pub fn try_read_manifest(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.manifest_path(name)) {
+            Ok(m) => Ok(Some(m)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => bail!(e),
+        }
+    }
+
+    pub fn insert_manifest(&self, name: &str, manifest: &[u8]) -> Result<()> {
+        let to = self.manifest_path(name);
+        let dir = to.parent().unwrap();
+        fs::create_dir_all(dir).with_context(|| format!("Couldn't create {dir}"))?;
+        file_util::safe_copy_to_file(manifest, &to)?;
+        Ok(())
+    }
+
+    pub fn evict_manifest(&self, name: &str) -> Result<()> {
+        match fs::remove_file(self.manifest_path(name)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => bail!(e),
+        }
+    }
+
+    /// Evict the least-recently used entries until the cache fits in its size,
+    /// returning the names of whatever was evicted.
+    ///
+    /// Both reads ([`try_read()`](Self::try_read)) and inserts count as a use.
+    /// We always keep the most recent entry, even if it alone is over the limit,
+    /// since whoever just inserted it is probably about to read it.
+    pub fn prune(&self) -> Result<Vec<String>> {
         // We want this all to be atomic.
         let mut c = self.conn.lock().unwrap();
         let transaction = c.transaction()?;
@@ -150,46 +443,45 @@ impl Cache {
             transaction.query_row("SELECT value FROM settings WHERE key = 'size'", (), |r| {
                 r.get(0)
             })?;
+        if max_size <= 0 {
+            bail!("Absurd: zero-size cache");
+        }
 
-        // Find least-recently used entries that exceed our cache size.
+        // Walk from newest to oldest; once something doesn't fit,
+        // it and everything older than it goes.
         let mut statement =
             transaction.prepare("SELECT name, time, size FROM cache ORDER BY time DESC")?;
-        let mut times_and_sizes = statement.query(())?;
+        let mut rows = statement.query(())?;
 
         let mut acc = 0i64;
-        let mut oldest_that_fits = None;
-        while acc < max_size {
-            match times_and_sizes.next()? {
-                Some(row) => {
-                    let t: i64 = row.get(1)?;
-                    let s: i64 = row.get(2)?;
-                    oldest_that_fits = Some(t);
-                    acc += s;
-                }
-                None => {
-                    // The whole cache fits. We're done.
-                    return Ok(());
-                }
-            }
-        }
-        // Delete files older than this
-        while let Some(row) = times_and_sizes.next()? {
+        let mut first = true;
+        let mut evicted = vec![];
+        while let Some(row) = rows.next()? {
             let name: String = row.get(0)?;
-            bump(Op::BackendCacheSpill);
-            self.delete_if_exists(&name)?;
+            let t: i64 = row.get(1)?;
+            let s: i64 = row.get(2)?;
+            if evicted.is_empty() && (first || acc + s <= max_size) {
+                acc += s;
+            } else {
+                debug!(
+                    "Evicting {name} ({}, last used {})",
+                    file_util::nice_size(s as u64),
+                    jiff::Timestamp::from_nanosecond(t as i128)?
+                );
+                evicted.push(name);
+            }
+            first = false;
         }
-        drop(times_and_sizes);
+        drop(rows);
         drop(statement);
 
-        // Delete those least-recently used entries that are too big.
-        if let Some(o) = oldest_that_fits {
-            transaction.execute("DELETE FROM cache WHERE time < ?1", [o])?;
-            transaction.commit()?;
-        } else {
-            bail!("Absurd: zero-size cache");
+        for name in &evicted {
+            bump(Op::BackendCacheSpill);
+            self.delete_if_exists(name)?;
+            transaction.execute("DELETE FROM cache WHERE name == ?1", [name])?;
         }
-
-        Ok(())
+        transaction.commit()?;
+        Ok(evicted)
     }
 }
 
@@ -197,14 +489,39 @@ fn now_nanos() -> i64 {
     jiff::Timestamp::now().as_nanosecond() as i64
 }
 
-pub fn setup(cache_size: Byte) -> Result<Cache> {
+/// `~/.cache/backpak`
+pub fn default_directory() -> Result<Utf8PathBuf> {
     let mut cachedir: Utf8PathBuf = home::home_dir()
         .ok_or_else(|| anyhow!("Can't find home directory"))?
         .try_into()
         .context("Home directory isn't UTF-8")?;
     cachedir.extend([".cache", "backpak"]);
+    Ok(cachedir)
+}
+
+/// Open the cache in the given directory (or [`default_directory()`]),
+/// optionally evicting anything that doesn't look right (see [`Cache::check_sizes()`]).
+pub fn setup(
+    directory: Option<&Utf8Path>,
+    cache_size: Byte,
+    verify_on_open: bool,
+) -> Result<Cache> {
+    let cachedir = match directory {
+        Some(d) => d.to_owned(),
+        None => default_directory()?,
+    };
     fs::create_dir_all(&cachedir).with_context(|| format!("Couldn't create {cachedir}"))?;
-    Cache::new(&cachedir, cache_size)
+    // Better to find out now than halfway through a backup.
+    tempfile::tempfile_in(&cachedir)
+        .with_context(|| format!("Cache directory {cachedir} isn't writable"))?;
+    let cache = Cache::new(&cachedir, cache_size)?;
+    if verify_on_open {
+        let evicted = cache.check_sizes()?;
+        if evicted > 0 {
+            warn!("Evicted {evicted} cache entries that weren't the size we wrote");
+        }
+    }
+    Ok(cache)
 }
 
 #[cfg(test)]
@@ -271,4 +588,150 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn least_recently_used() -> Result<()> {
+        let td = tempdir()?;
+        let cache = Cache::new(Utf8Path::from_path(td.path()).unwrap(), Byte::from_u64(10))?;
+        let names = |c: &Cache| -> Result<Vec<String>> {
+            Ok(c.entries()?.into_iter().map(|e| e.name).collect())
+        };
+
+        cache.insert("a", &mut [0; 4].as_slice())?;
+        cache.insert("b", &mut [0; 4].as_slice())?;
+        cache.insert("c", &mut [0; 4].as_slice())?;
+        // Reading a makes it the hottest, leaving b as the coldest.
+        cache.try_read("a")?.unwrap();
+        assert_eq!(names(&cache)?, ["a", "c", "b"]);
+
+        assert_eq!(cache.prune()?, ["b"]);
+        assert_eq!(names(&cache)?, ["a", "c"]);
+        assert!(!td.path().join("b").exists());
+
+        // Now read c; inserting d should push out a.
+        cache.try_read("c")?.unwrap();
+        cache.insert("d", &mut [0; 4].as_slice())?;
+        assert_eq!(cache.prune()?, ["a"]);
+        assert_eq!(names(&cache)?, ["d", "c"]);
+        assert!(cache.try_read("a")?.is_none());
+
+        // Once something doesn't fit, everything older than it goes too.
+        cache.insert("e", &mut [0; 1].as_slice())?;
+        cache.insert("f", &mut [0; 8].as_slice())?;
+        assert_eq!(cache.prune()?, ["d", "c"]);
+        assert_eq!(names(&cache)?, ["f", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<()> {
+        let td = tempdir()?;
+        let cache = Cache::new(
+            Utf8Path::from_path(td.path()).unwrap(),
+            Byte::from_u64(1000),
+        )?;
+
+        let s = cache.stats()?;
+        assert_eq!(
+            s,
+            CacheStats {
+                entries: 0,
+                total_bytes: 0,
+                capacity_bytes: 1000,
+                hits: 0,
+                misses: 0
+            }
+        );
+
+        cache.insert("foo", &mut [1, 2, 3, 4].as_slice())?;
+        cache.insert("bar", &mut [1, 2].as_slice())?;
+        cache.try_read("foo")?.unwrap();
+        cache.try_read("foo")?.unwrap();
+        assert!(cache.try_read("baz")?.is_none());
+
+        let s = cache.stats()?;
+        assert_eq!(s.entries, 2);
+        assert_eq!(s.total_bytes, 6);
+        assert_eq!(s.hits, 2);
+        assert_eq!(s.misses, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn clear_and_verify() -> Result<()> {
+        let td = tempdir()?;
+        let cache = Cache::new(Utf8Path::from_path(td.path()).unwrap(), DEFAULT_SIZE)?;
+
+        let manifest = b"not really CBOR, but the hash is what matters";
+        let good = format!("{}.manifest", ObjectId::hash(manifest));
+        cache.insert_manifest(&good, manifest)?;
+        cache.insert_manifest(
+            "ZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZZ.manifest",
+            manifest,
+        )?;
+        let garbage = format!("{}.snapshot", ObjectId::hash(b"a snapshot"));
+        cache.insert(&garbage, &mut b"definitely not a snapshot".as_slice())?;
+
+        assert_eq!(
+            cache.verify()?,
+            VerifyCounts {
+                good: 1,
+                evicted: 2,
+                errored: 0
+            }
+        );
+        assert!(cache.try_read(&garbage)?.is_none());
+        assert!(cache.try_read_manifest(&good)?.is_some());
+        // Everything left is good.
+        assert_eq!(cache.verify()?.evicted, 0);
+
+        cache.insert("foo", &mut [1, 2, 3, 4].as_slice())?;
+        assert_eq!(cache.clear()?, (2, 4 + manifest.len() as u64));
+        assert_eq!(cache.stats()?.entries, 0);
+        assert!(cache.try_read("foo")?.is_none());
+        assert!(cache.try_read_manifest(&good)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn check_sizes() -> Result<()> {
+        let td = tempdir()?;
+        let dir = Utf8Path::from_path(td.path()).unwrap();
+        let cache = Cache::new(dir, DEFAULT_SIZE)?;
+
+        cache.insert("whole", &mut [1, 2, 3, 4].as_slice())?;
+        cache.insert("truncated", &mut [1, 2, 3, 4].as_slice())?;
+        cache.insert("gone", &mut [1, 2, 3, 4].as_slice())?;
+        fs::write(dir.join("truncated"), [1, 2])?;
+        fs::remove_file(dir.join("gone"))?;
+
+        // Only the truncated one counts as evicted; the missing one was never going to be served.
+        assert_eq!(cache.check_sizes()?, 1);
+        assert!(cache.try_read("truncated")?.is_none());
+        assert!(!dir.join("truncated").exists());
+        assert!(cache.try_read("whole")?.is_some());
+        assert_eq!(cache.stats()?.entries, 1);
+        assert_eq!(cache.check_sizes()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn manifests() -> Result<()> {
+        let td = tempdir()?;
+        let cache = Cache::new(Utf8Path::from_path(td.path()).unwrap(), DEFAULT_SIZE)?;
+
+        assert!(cache.try_read_manifest("foo.manifest")?.is_none());
+        cache.insert_manifest("foo.manifest", &[1, 2, 3])?;
+        assert_eq!(cache.try_read_manifest("foo.manifest")?.unwrap(), [1, 2, 3]);
+
+        // They don't count against the (LRU) cache...
+        cache.prune()?;
+        assert!(cache.try_read_manifest("foo.manifest")?.is_some());
+
+        // ...and only go away when asked.
+        cache.evict_manifest("foo.manifest")?;
+        assert!(cache.try_read_manifest("foo.manifest")?.is_none());
+        cache.evict_manifest("foo.manifest")?;
+        Ok(())
+    }
 }
@@ -0,0 +1,123 @@
+//! What went wrong in a [`Backend`](super::Backend) operation,
+//! sorted into the kinds callers care about.
+//!
+//! Each backend knows best what its own failures mean -
+//! a B2 503 is worth another shot, an S3 403 isn't -
+//! so it classifies them on the way out, and everyone else
+//! (like [`Retrying`](super::retry::Retrying)) just matches on the kind.
+
+use std::fmt;
+use std::io;
+
+use anyhow::anyhow;
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// There's nothing at the given key.
+    NotFound(anyhow::Error),
+    /// Something that might go away if we try again, like a 503 or a dropped connection
+    Transient(anyhow::Error),
+    /// The backend doesn't like our credentials, and asking again won't change its mind.
+    Auth(anyhow::Error),
+    /// Anything else
+    Other(anyhow::Error),
+}
+
+impl BackendError {
+    pub fn not_found(key: &str) -> Self {
+        Self::NotFound(anyhow!("Couldn't find {key}"))
+    }
+
+    /// Is it worth trying again? See [`Retrying`](super::retry::Retrying)
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Transient(_))
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound(_))
+    }
+
+    fn inner(&self) -> &anyhow::Error {
+        match self {
+            Self::NotFound(e) | Self::Transient(e) | Self::Auth(e) | Self::Other(e) => e,
+        }
+    }
+
+    /// Like [`anyhow::Context::context()`], but keeping the kind of error.
+    pub fn context<C>(self, context: C) -> Self
+    where
+        C: fmt::Display + Send + Sync + 'static,
+    {
+        match self {
+            Self::NotFound(e) => Self::NotFound(e.context(context)),
+            Self::Transient(e) => Self::Transient(e.context(context)),
+            Self::Auth(e) => Self::Auth(e.context(context)),
+            Self::Other(e) => Self::Other(e.context(context)),
+        }
+    }
+}
+
+// Print just like the underlying error, so {:#} shows the same chain it always did.
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.inner(), f)
+    }
+}
+
+impl std::error::Error for BackendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().source()
+    }
+}
+
+/// Errors we haven't classified are just errors,
+/// unless they're a classified one that took a trip through `anyhow`.
+impl From<anyhow::Error> for BackendError {
+    fn from(e: anyhow::Error) -> Self {
+        // Downcasting by value would drop any context added along the way,
+        // so keep the whole thing and just borrow its kind.
+        match e.downcast_ref::<BackendError>() {
+            Some(Self::NotFound(_)) => Self::NotFound(e),
+            Some(Self::Transient(_)) => Self::Transient(e),
+            Some(Self::Auth(_)) => Self::Auth(e),
+            Some(Self::Other(_)) | None => Self::Other(e),
+        }
+    }
+}
+
+impl From<io::Error> for BackendError {
+    fn from(e: io::Error) -> Self {
+        Self::Other(e.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use anyhow::Context;
+
+    #[test]
+    fn survives_anyhow() {
+        let e: anyhow::Error = BackendError::not_found("packs/a.pack").into();
+        let e = BackendError::from(e.context("Couldn't load a.pack"));
+        assert!(e.is_not_found());
+        assert_eq!(
+            format!("{e:#}"),
+            "Couldn't load a.pack: Couldn't find packs/a.pack"
+        );
+
+        let e = BackendError::Transient(anyhow!("503")).context("Couldn't read packs/a.pack");
+        assert!(e.is_transient());
+        let e = Err::<(), _>(e).context("Couldn't check").unwrap_err();
+        assert_eq!(
+            format!("{e:#}"),
+            "Couldn't check: Couldn't read packs/a.pack: 503"
+        );
+
+        assert!(matches!(
+            BackendError::from(anyhow!("whatever")),
+            BackendError::Other(_)
+        ));
+    }
+}
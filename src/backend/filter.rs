@@ -9,13 +9,88 @@ use std::{
 use anyhow::{Result, ensure};
 
 /// A backend that filters another backend through a pair of shell commands,
-/// `filter` and `unfilter`.
+/// `filter` and `unfilter`, run as [`FilterEnvironment`] describes.
+///
+/// If the filter changed at some point, objects written with the old one
+/// can still be read with `legacy_unfilters`.
+/// We don't record which filter wrote which object, so we try `unfilter`, then each of those,
+/// and take the first that succeeds. (This leans on them failing loudly on input they
+/// don't understand, which decompressors and decryptors do.)
 pub struct BackendFilter {
     pub filter: String,
     pub unfilter: String,
+    pub legacy_unfilters: Vec<String>,
+    pub environment: FilterEnvironment,
     pub raw: Box<dyn super::Backend + Send + Sync>,
 }
 
+/// Environment variables every filter gets, if we have them:
+/// enough to find programs, keys (for GPG and friends), and temporary space.
+pub const DEFAULT_FILTER_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "LANG",
+    "LC_ALL",
+    "LC_CTYPE",
+    "TZ",
+    "TMPDIR",
+    "XDG_RUNTIME_DIR",
+    "GNUPGHOME",
+    "GPG_TTY",
+];
+
+/// Where and how filter commands run, so they don't depend on wherever backpak was run from:
+///
+/// - With `sh -c`, in the repository's directory
+/// - With none of our environment but [`DEFAULT_FILTER_ENV`] and `filter_env` from the config
+/// - With the key of the object being read or written in `$BACKPAK_KEY`
+#[derive(Debug, Clone)]
+pub struct FilterEnvironment {
+    pub directory: Utf8PathBuf,
+    /// Variables to pass along besides [`DEFAULT_FILTER_ENV`]
+    pub pass_through: Vec<String>,
+}
+
+impl FilterEnvironment {
+    /// Run filters in the given repository, or next to its config file
+    /// if it's a remote repository (see [`config_file()`](super::config_file)).
+    pub fn for_repository(repository: &Utf8Path, pass_through: Vec<String>) -> Self {
+        let directory = if repository.is_dir() {
+            repository.to_owned()
+        } else {
+            match repository.parent() {
+                Some(p) if !p.as_str().is_empty() => p.to_owned(),
+                _ => Utf8PathBuf::from("."),
+            }
+        };
+        Self {
+            directory,
+            pass_through,
+        }
+    }
+
+    fn command(&self, command: &str, key: &str) -> Command {
+        let mut c = Command::new("sh");
+        c.arg("-c")
+            .arg(command)
+            .current_dir(&self.directory)
+            .env_clear()
+            .env("BACKPAK_KEY", key);
+        let names = DEFAULT_FILTER_ENV
+            .iter()
+            .copied()
+            .chain(self.pass_through.iter().map(String::as_str));
+        for name in names {
+            if let Some(val) = std::env::var_os(name) {
+                c.env(name, val);
+            }
+        }
+        c
+    }
+}
+
 struct UnfilterRead {
     from: String,
     unfilter: String,
@@ -60,15 +135,103 @@ impl Read for UnfilterRead {
     }
 }
 
+const PLAINTEXT: &str = r"I'd like some help remembering stuff.
+I wonder if I could come down and see you,
+and we could drink and talk and remember.
+r";
+
+/// Make sure `unfilter` undoes `filter` by running some text through both,
+/// without touching any backend.
+///
+/// Otherwise a mismatched pair writes objects we can't read back,
+/// or reads what's there as garbage that fails confusingly much later.
+pub fn round_trip_test(
+    filter: &str,
+    unfilter: &str,
+    environment: &FilterEnvironment,
+) -> Result<()> {
+    let run = |command: &str, input: &[u8]| -> Result<Vec<u8>> {
+        let mut child = environment
+            .command(command, "round-trip-test")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Couldn't run {command}"))?;
+        let mut stdin = child.stdin.take().unwrap();
+        // Write from another thread so a filter that outputs as it goes can't fill its pipe
+        // and block while we're still writing.
+        let output = thread::scope(|s| {
+            let writer = s.spawn(move || stdin.write_all(input));
+            let output = child.wait_with_output();
+            (writer.join().unwrap(), output)
+        });
+        output
+            .0
+            .with_context(|| format!("Couldn't write to {command}"))?;
+        let output = output
+            .1
+            .with_context(|| format!("Couldn't wait for {command}"))?;
+        ensure!(output.status.success(), "{command} failed");
+        Ok(output.stdout)
+    };
+    let filtered = run(filter, PLAINTEXT.as_bytes())?;
+    let unfiltered = run(unfilter, &filtered)?;
+    if unfiltered != PLAINTEXT.as_bytes() {
+        let nope = String::from_utf8_lossy(&unfiltered);
+        bail!("`{unfilter}` doesn't undo `{filter}`!\nExpected:\n{PLAINTEXT}\nGot:\n{nope}");
+    }
+    Ok(())
+}
+
+impl BackendFilter {
+    /// Read `from` with the first unfilter that succeeds.
+    ///
+    /// We can't stream the object through like we do with a single unfilter -
+    /// the first attempt might fail halfway through - so buffer it and its output to tempfiles.
+    fn read_trying_each(&self, from: &str) -> Result<File> {
+        let mut raw = tempfile::tempfile_in(".")?;
+        io::copy(&mut self.raw.read(from)?, &mut raw)
+            .with_context(|| format!("Couldn't buffer {from}"))?;
+
+        for unfilter in std::iter::once(&self.unfilter).chain(&self.legacy_unfilters) {
+            debug!("{unfilter} < {from}");
+            raw.seek(io::SeekFrom::Start(0))?;
+            let mut unfiltered = tempfile::tempfile_in(".")?;
+            let status = self
+                .environment
+                .command(unfilter, from)
+                .stdin(Stdio::from(raw.try_clone()?))
+                .stdout(Stdio::from(unfiltered.try_clone()?))
+                .stderr(Stdio::null())
+                .status()
+                .with_context(|| format!("Couldn't run {unfilter}"))?;
+            if status.success() {
+                unfiltered.seek(io::SeekFrom::Start(0))?;
+                return Ok(unfiltered);
+            }
+            debug!("{unfilter} < {from} failed; trying the next unfilter");
+        }
+        bail!(
+            "Couldn't unfilter {from} with {} or any of {:?}",
+            self.unfilter,
+            self.legacy_unfilters
+        );
+    }
+}
+
 impl Backend for BackendFilter {
-    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>> {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        if !self.legacy_unfilters.is_empty() {
+            return Ok(Box::new(self.read_trying_each(from)?));
+        }
+
         debug!("{} < {from}", self.unfilter);
 
         let mut inner_read = self.raw.read(from)?;
 
-        let mut uf = Command::new("sh")
-            .arg("-c")
-            .arg(&self.unfilter)
+        let mut uf = self
+            .environment
+            .command(&self.unfilter, from)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
             .spawn()
@@ -92,12 +255,12 @@ impl Backend for BackendFilter {
         }))
     }
 
-    fn write(&self, _len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
+    fn write(&self, _len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
         debug!("{} > {to}", self.filter);
 
-        let mut f = Command::new("sh")
-            .arg("-c")
-            .arg(&self.filter)
+        let mut f = self
+            .environment
+            .command(&self.filter, to)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
             .spawn()
@@ -135,10 +298,9 @@ impl Backend for BackendFilter {
         })
         .with_context(|| format!("Piping {to} through {} failed", self.filter))?;
 
-        ensure!(
-            f.wait().unwrap().success(),
-            format!("{} > {to} failed", self.filter)
-        );
+        if !f.wait().unwrap().success() {
+            return Err(anyhow!("{} > {to} failed", self.filter).into());
+        }
 
         // Meanwhile, in this thread, copy to the underlying backend.
         let len = filtered.stream_position()?;
@@ -148,13 +310,17 @@ impl Backend for BackendFilter {
         Ok(())
     }
 
-    fn remove(&self, which: &str) -> Result<()> {
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
         self.raw.remove(which)
     }
 
-    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
         self.raw.list(prefix)
     }
+
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        self.raw.list_streaming(prefix)
+    }
 }
 
 #[cfg(test)]
@@ -163,11 +329,20 @@ mod test {
 
     use std::io::Cursor;
 
+    fn here() -> FilterEnvironment {
+        FilterEnvironment {
+            directory: Utf8PathBuf::from("."),
+            pass_through: vec![],
+        }
+    }
+
     #[test]
     fn smoke() -> Result<()> {
         let f = BackendFilter {
             filter: "cat".to_string(),
             unfilter: "cat".to_string(),
+            legacy_unfilters: vec![],
+            environment: here(),
             raw: Box::new(crate::backend::memory::MemoryBackend::new()),
         };
 
@@ -179,4 +354,110 @@ mod test {
         assert_eq!(so_it_goes, "Everything was beautiful and nothing hurt");
         Ok(())
     }
+
+    #[test]
+    fn legacy_unfilters() -> Result<()> {
+        let raw = Box::new(crate::backend::memory::MemoryBackend::new());
+        let old = BackendFilter {
+            filter: "gzip".to_string(),
+            unfilter: "gzip -d".to_string(),
+            legacy_unfilters: vec![],
+            environment: here(),
+            raw,
+        };
+        old.write(5, &mut Cursor::new("old\n"), "old")?;
+
+        // Swap filters, but keep the same underlying storage.
+        let new = BackendFilter {
+            filter: "xz".to_string(),
+            unfilter: "xz -d".to_string(),
+            legacy_unfilters: vec!["gzip -d".to_string()],
+            environment: here(),
+            raw: old.raw,
+        };
+        new.write(5, &mut Cursor::new("new\n"), "new")?;
+
+        let mut s = String::new();
+        new.read("old")?.read_to_string(&mut s)?;
+        assert_eq!(s, "old\n");
+        s.clear();
+        new.read("new")?.read_to_string(&mut s)?;
+        assert_eq!(s, "new\n");
+
+        // Nothing reads garbage.
+        new.raw.write(7, &mut Cursor::new("garbage"), "garbage")?;
+        assert!(new.read("garbage").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips() -> Result<()> {
+        round_trip_test("gzip", "gzip -d", &here())?;
+        let err = round_trip_test("cat", "tr a-z A-Z", &here()).unwrap_err();
+        assert!(err.to_string().contains("doesn't undo"), "{err}");
+        assert!(round_trip_test("cat", "false", &here()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn environment() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path())
+            .unwrap()
+            .canonicalize_utf8()?;
+        // Cargo sets this for tests; let one through but not the other.
+        assert!(std::env::var_os("CARGO_MANIFEST_DIR").is_some());
+        assert!(std::env::var_os("CARGO_PKG_NAME").is_some());
+        let f = BackendFilter {
+            filter: "echo \"$BACKPAK_KEY $(pwd -P) ${CARGO_MANIFEST_DIR+manifest} \
+                     ${CARGO_PKG_NAME-nameless}\"; cat"
+                .to_string(),
+            unfilter: "cat".to_string(),
+            legacy_unfilters: vec![],
+            environment: FilterEnvironment {
+                directory: dir.clone(),
+                pass_through: vec!["CARGO_MANIFEST_DIR".to_string()],
+            },
+            raw: Box::new(crate::backend::memory::MemoryBackend::new()),
+        };
+        f.write(2, &mut Cursor::new("hi"), "some/key")?;
+
+        let mut s = String::new();
+        f.read("some/key")?.read_to_string(&mut s)?;
+        assert_eq!(s, format!("some/key {dir} manifest nameless\nhi"));
+        Ok(())
+    }
+
+    #[test]
+    fn remote_repository() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path())
+            .unwrap()
+            .canonicalize_utf8()?;
+        // B2, S3, and SSH repositories are just a config file.
+        let config = dir.join("b2.toml");
+        std::fs::write(&config, "")?;
+
+        let environment = FilterEnvironment::for_repository(&config, vec![]);
+        assert_eq!(environment.directory, dir);
+        round_trip_test("gzip", "gzip -d", &environment)?;
+
+        let f = BackendFilter {
+            filter: "pwd -P; cat".to_string(),
+            unfilter: "cat".to_string(),
+            legacy_unfilters: vec![],
+            environment,
+            raw: Box::new(crate::backend::memory::MemoryBackend::new()),
+        };
+        f.write(2, &mut Cursor::new("hi"), "key")?;
+        let mut s = String::new();
+        f.read("key")?.read_to_string(&mut s)?;
+        assert_eq!(s, format!("{dir}\nhi"));
+
+        assert_eq!(
+            FilterEnvironment::for_repository(Utf8Path::new("b2.toml"), vec![]).directory,
+            "."
+        );
+        Ok(())
+    }
 }
@@ -27,7 +27,9 @@ pub fn initialize(
     repository: &Utf8Path,
     pack_size: Byte,
     filter: Option<(String, String)>,
+    filter_env: Vec<String>,
     force_cache: bool,
+    verify_after_write: bool,
 ) -> Result<()> {
     if repository.exists() {
         ensure!(
@@ -47,17 +49,25 @@ pub fn initialize(
 
     let c = super::Configuration {
         pack_size,
-        kind: super::Kind::Filesystem { force_cache },
+        kind: super::Kind::Filesystem {
+            force_cache,
+            verify_after_write,
+        },
         filter,
+        legacy_unfilters: vec![],
+        filter_env,
+        retries: Default::default(),
+        upload_limit: None,
+        download_limit: None,
+        compression: None,
+        chunking: None,
+        verify_cache_on_open: false,
     };
-    let fh = fs::OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(repository.join("config.toml"))
-        .with_context(|| format!("Couldn't create {repository}/config.toml"))?;
-
-    write_config(fh, c)?;
-    Ok(())
+    create_config(
+        &repository.join("config.toml"),
+        c,
+        crate::config::Format::Toml,
+    )
 }
 
 impl FilesystemBackend {
@@ -77,26 +87,36 @@ impl FilesystemBackend {
 }
 
 impl Backend for FilesystemBackend {
-    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>> {
-        let from = self.path_of(from);
-        Ok(Box::new(
-            fs::File::open(&from).with_context(|| format!("Couldn't open {from}"))?,
-        ))
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        let path = self.path_of(from);
+        match fs::File::open(&path) {
+            Ok(fh) => Ok(Box::new(fh)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(BackendError::not_found(from)),
+            Err(e) => Err(anyhow::Error::new(e)
+                .context(format!("Couldn't open {path}"))
+                .into()),
+        }
     }
 
-    fn write(&self, _len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
+    fn write(&self, _len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
         let to = self.path_of(to);
+        // Objects live in directories made at init,
+        // but keys like `doctor`'s health check might not. (SSH does a mkdir -p too.)
+        let dir = to.parent().unwrap();
+        if !dir.exists() {
+            fs::create_dir_all(dir).with_context(|| format!("Couldn't create {dir}"))?;
+        }
         file_util::safe_copy_to_file(from, &to)?;
         Ok(())
     }
 
-    fn remove(&self, which: &str) -> Result<()> {
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
         let which = self.path_of(which);
         fs::remove_file(&which).with_context(|| format!("Couldn't remove {which}"))?;
         Ok(())
     }
 
-    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
         let prefix = self.base_directory.join(prefix);
 
         if prefix.is_file() {
@@ -114,10 +134,66 @@ impl Backend for FilesystemBackend {
             // Use the fancy new atomic file crate instead?
             .filter(|(p, _len)| p.extension() != Some("part"))
             .map(str_and_len)
-            .collect::<Result<Vec<_>>>()?;
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok(paths)
     }
+
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        let prefix = self.base_directory.join(prefix);
+
+        if prefix.is_file() {
+            let len = prefix.metadata()?.len();
+            return Ok(Box::new(std::iter::once(Ok((prefix.to_string(), len)))));
+        }
+
+        Ok(Box::new(LazyWalk {
+            base_directory: self.base_directory.clone(),
+            stack: vec![Utf8Path::read_dir_utf8(&prefix)?],
+        }))
+    }
+}
+
+/// Like [`walk_dir()`], but one entry at a time,
+/// holding only the directories we're partway through.
+struct LazyWalk {
+    base_directory: Utf8PathBuf,
+    stack: Vec<camino::ReadDirUtf8>,
+}
+
+impl Iterator for LazyWalk {
+    type Item = Result<(String, u64), BackendError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.stack.last_mut()?.next() {
+                Some(e) => e,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let step = || -> Result<Option<(String, u64)>, BackendError> {
+                let entry = entry?;
+                let path = entry.path();
+                if path.is_dir() {
+                    self.stack.push(Utf8Path::read_dir_utf8(path)?);
+                    return Ok(None);
+                }
+                // see file_utils::safe_copy_to_file()
+                if path.extension() == Some("part") {
+                    return Ok(None);
+                }
+                let s = path.strip_prefix(&self.base_directory).unwrap().to_string();
+                Ok(Some((s, entry.metadata()?.len())))
+            };
+            match step() {
+                Ok(Some(found)) => return Some(Ok(found)),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
 }
 
 fn walk_dir(dir: &Utf8Path) -> io::Result<Vec<(Utf8PathBuf, u64)>> {
@@ -134,3 +210,55 @@ fn walk_dir(dir: &Utf8Path) -> io::Result<Vec<(Utf8PathBuf, u64)>> {
     }
     Ok(paths)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn streaming_matches_list() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let repo = Utf8Path::from_path(dir.path()).unwrap().join("repo");
+        initialize(&repo, Byte::from_u64(1024), None, vec![], false, false)?;
+        let backend = FilesystemBackend::open(&repo)?;
+
+        fs::create_dir(repo.join("packs/aa"))?;
+        fs::create_dir(repo.join("packs/bb"))?;
+        for (i, pack) in [
+            "packs/aa/one.pack",
+            "packs/aa/two.pack",
+            "packs/bb/three.pack",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let contents = vec![0u8; i * 10];
+            backend.write(contents.len() as u64, &mut io::Cursor::new(contents), pack)?;
+        }
+        // Half-written files are skipped by both.
+        fs::write(repo.join("packs/bb/four.pack.part"), "in progress")?;
+
+        let mut listed = backend.list("packs/")?;
+        let mut streamed = backend
+            .list_streaming("packs/")?
+            .collect::<Result<Vec<_>, _>>()?;
+        listed.sort();
+        streamed.sort();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed, streamed);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_keys() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let repo = Utf8Path::from_path(dir.path()).unwrap().join("repo");
+        initialize(&repo, Byte::from_u64(1024), None, vec![], false, false)?;
+        let backend = FilesystemBackend::open(&repo)?;
+
+        let err = backend.read("packs/nope.pack").err().unwrap();
+        assert!(err.is_not_found());
+        assert_eq!(err.to_string(), "Couldn't find packs/nope.pack");
+        Ok(())
+    }
+}
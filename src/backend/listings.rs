@@ -0,0 +1,161 @@
+//! Remember what [`CachedBackend::list()`](super::CachedBackend) found,
+//! so commands that list the same prefix several times
+//! (say, building the master index, then checking packs against it)
+//! only make one round trip per prefix.
+//!
+//! This only lives as long as the backend does - one command.
+//! Writes and removes through the same backend forget any listing they'd change.
+
+use super::*;
+
+use std::sync::{Arc, Mutex};
+
+use rustc_hash::FxHashMap;
+
+type Entries = Vec<(String, u64)>;
+
+#[derive(Default)]
+struct Inner {
+    /// Bumped on every invalidation, so a listing that started before a write
+    /// doesn't get saved after it.
+    generation: u64,
+    by_prefix: FxHashMap<String, Entries>,
+}
+
+#[derive(Default, Clone)]
+pub struct Listings(Arc<Mutex<Inner>>);
+
+impl Listings {
+    pub fn get(&self, prefix: &str) -> Option<Entries> {
+        let found = self.0.lock().unwrap().by_prefix.get(prefix).cloned();
+        if found.is_some() {
+            bump(Op::BackendListCacheHit);
+        }
+        found
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.0.lock().unwrap().generation
+    }
+
+    /// Save a listing made at the given generation, unless something's changed since.
+    pub fn insert(&self, generation: u64, prefix: &str, entries: Entries) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.generation == generation {
+            inner.by_prefix.insert(prefix.to_owned(), entries);
+        }
+    }
+
+    /// Forget any listing that would include the given key.
+    pub fn invalidate(&self, key: &str) {
+        let mut inner = self.0.lock().unwrap();
+        inner.generation += 1;
+        inner
+            .by_prefix
+            .retain(|prefix, _| !key.starts_with(prefix.as_str()));
+    }
+
+    /// Pass a streaming listing through, saving it once it's been read to the end.
+    pub fn memoize(&self, prefix: &str, inner: Listing) -> Listing {
+        Box::new(Memoizing {
+            listings: self.clone(),
+            generation: self.generation(),
+            prefix: prefix.to_owned(),
+            seen: Some(vec![]),
+            inner,
+        })
+    }
+}
+
+struct Memoizing {
+    listings: Listings,
+    generation: u64,
+    prefix: String,
+    /// Everything so far, or `None` once we've given up (or finished).
+    seen: Option<Entries>,
+    inner: Listing,
+}
+
+impl Iterator for Memoizing {
+    type Item = Result<(String, u64), BackendError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.inner.next();
+        match &next {
+            Some(Ok(entry)) => {
+                if let Some(seen) = &mut self.seen {
+                    seen.push(entry.clone());
+                }
+            }
+            // Don't save half a listing.
+            Some(Err(_)) => self.seen = None,
+            None => {
+                if let Some(seen) = self.seen.take() {
+                    self.listings.insert(self.generation, &self.prefix, seen);
+                }
+            }
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(names: &[&str]) -> Entries {
+        names.iter().map(|n| (n.to_string(), 1)).collect()
+    }
+
+    #[test]
+    fn invalidation() {
+        let l = Listings::default();
+        l.insert(l.generation(), "packs/", entries(&["packs/a.pack"]));
+        l.insert(l.generation(), "indexes/", entries(&["indexes/b.index"]));
+        assert!(l.get("packs/").is_some());
+
+        l.invalidate("packs/c.pack");
+        assert!(l.get("packs/").is_none());
+        assert!(l.get("indexes/").is_some());
+
+        // Listings that started before a change don't stick.
+        let before = l.generation();
+        l.invalidate("snapshots/d.snapshot");
+        l.insert(before, "packs/", entries(&["packs/a.pack"]));
+        assert!(l.get("packs/").is_none());
+    }
+
+    #[test]
+    fn streams() -> Result<()> {
+        let l = Listings::default();
+        let stream: Listing = Box::new(
+            entries(&["packs/a.pack", "packs/b.pack"])
+                .into_iter()
+                .map(Ok),
+        );
+        let mut memoized = l.memoize("packs/", stream);
+
+        // Nothing until we've seen the whole thing
+        memoized.next().unwrap()?;
+        assert!(l.get("packs/").is_none());
+        memoized.next().unwrap()?;
+        assert!(memoized.next().is_none());
+        assert_eq!(
+            l.get("packs/").unwrap(),
+            entries(&["packs/a.pack", "packs/b.pack"])
+        );
+
+        // Errors spoil it.
+        let broken: Listing = Box::new(
+            vec![
+                Ok(("indexes/a.index".to_owned(), 1)),
+                Err(anyhow!("nope").into()),
+            ]
+            .into_iter(),
+        );
+        let collected: Vec<_> = l.memoize("indexes/", broken).collect();
+        assert_eq!(collected.len(), 2);
+        assert!(l.get("indexes/").is_none());
+        Ok(())
+    }
+}
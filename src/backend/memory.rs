@@ -21,16 +21,22 @@ impl MemoryBackend {
     }
 
     // Cursor is also seek - expose that to `CachedBackend`
-    pub fn read_cursor(&self, from: &str) -> Result<Cursor<Vec<u8>>> {
+    pub fn read_cursor(&self, from: &str) -> Result<Cursor<Vec<u8>>, BackendError> {
         let buf: Vec<u8> = self
             .files
             .lock()
             .unwrap()
             .get(from)
-            .ok_or_else(|| anyhow!("No file {}", from))?
+            .ok_or_else(|| BackendError::not_found(from))?
             .clone();
         Ok(Cursor::new(buf))
     }
+
+    /// Store the given bytes at the given key,
+    /// for when we have a buffer instead of something to read.
+    pub fn insert(&self, key: &str, contents: Vec<u8>) {
+        self.files.lock().unwrap().insert(key.to_owned(), contents);
+    }
 }
 
 impl Default for MemoryBackend {
@@ -40,23 +46,23 @@ impl Default for MemoryBackend {
 }
 
 impl Backend for MemoryBackend {
-    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>> {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
         Ok(Box::new(self.read_cursor(from)?))
     }
 
-    fn write(&self, _len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
+    fn write(&self, _len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
         let mut vec = Vec::new();
         io::copy(from, &mut vec)?;
-        self.files.lock().unwrap().insert(to.to_owned(), vec);
+        self.insert(to, vec);
         Ok(())
     }
 
-    fn remove(&self, which: &str) -> Result<()> {
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
         self.files.lock().unwrap().remove(which);
         Ok(())
     }
 
-    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
         let paths: Vec<_> = self
             .files
             .lock()
@@ -0,0 +1,286 @@
+//! Keep the same repository on several [`Backend`]s at once,
+//! say, a local disk for speed and a cloud bucket for when the house burns down.
+//!
+//! Writes and removes go to every backend.
+//! Reads and listings go to the primary, falling back to the secondaries
+//! (in the order they're configured) if it fails,
+//! even if it says there's nothing there. (Objects are content-addressed,
+//! so whatever a secondary has is the right thing, if a little stale.)
+//!
+//! By default, a secondary failing a write or remove is just a warning -
+//! the primary has it, and a later `copy` can catch the others up.
+//! Set `require_all` to make it an error instead.
+
+use super::*;
+
+use std::io::SeekFrom;
+
+pub struct Mirrored {
+    primary: Box<dyn Backend + Send + Sync>,
+    secondaries: Vec<Box<dyn Backend + Send + Sync>>,
+    require_all: bool,
+}
+
+/// Make sure a mirror config is something we can actually open.
+pub fn check(primary: &Kind, secondaries: &[Kind]) -> Result<()> {
+    let members = || std::iter::once(primary).chain(secondaries);
+    ensure!(
+        members().all(|k| !matches!(k, Kind::Mirror { .. })),
+        "Mirrors can't contain other mirrors"
+    );
+    // A filesystem backend is the repository directory itself,
+    // so there's only room for one.
+    ensure!(
+        members()
+            .filter(|k| matches!(k, Kind::Filesystem { .. }))
+            .count()
+            <= 1,
+        "A mirror can only have one filesystem backend (the repository directory)"
+    );
+    ensure!(!secondaries.is_empty(), "A mirror needs secondary backends");
+    Ok(())
+}
+
+impl Mirrored {
+    pub fn new(
+        primary: Box<dyn Backend + Send + Sync>,
+        secondaries: Vec<Box<dyn Backend + Send + Sync>>,
+        require_all: bool,
+    ) -> Self {
+        Self {
+            primary,
+            secondaries,
+            require_all,
+        }
+    }
+
+    /// Do the same thing to each secondary,
+    /// failing or warning depending on `require_all`.
+    fn fan_out<F>(&self, what: &str, mut f: F) -> Result<(), BackendError>
+    where
+        F: FnMut(&(dyn Backend + Send + Sync)) -> Result<(), BackendError>,
+    {
+        for (i, s) in self.secondaries.iter().enumerate() {
+            // Humans count secondaries from 1.
+            let i = i + 1;
+            if let Err(e) = f(s.as_ref()) {
+                if self.require_all {
+                    return Err(e.context(format!("Mirror {i} failed to {what}")));
+                }
+                warn!("Mirror {i} failed to {what}: {e:#}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Try the primary, then each secondary until one works.
+    fn first_success<T, F>(&self, what: &str, mut f: F) -> Result<T, BackendError>
+    where
+        F: FnMut(&(dyn Backend + Send + Sync)) -> Result<T, BackendError>,
+    {
+        let mut err = match f(self.primary.as_ref()) {
+            Ok(t) => return Ok(t),
+            Err(e) => e,
+        };
+        for (i, s) in self.secondaries.iter().enumerate() {
+            warn!("Couldn't {what} ({err:#}); trying mirror {}", i + 1);
+            match f(s.as_ref()) {
+                Ok(t) => return Ok(t),
+                Err(e) => err = e,
+            }
+        }
+        Err(err.context(format!("Couldn't {what} from any mirror")))
+    }
+}
+
+impl Backend for Mirrored {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        self.first_success(&format!("read {from}"), |b| b.read(from))
+    }
+
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+        let start = from.stream_position()?;
+        self.primary.write(len, from, to)?;
+        self.fan_out(&format!("write {to}"), |b| {
+            from.seek(SeekFrom::Start(start))?;
+            b.write(len, from, to)
+        })
+    }
+
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
+        self.primary.remove(which)?;
+        self.fan_out(&format!("remove {which}"), |b| b.remove(which))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+        self.first_success(&format!("list {prefix}"), |b| b.list(prefix))
+    }
+
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        self.first_success(&format!("list {prefix}"), |b| b.list_streaming(prefix))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    /// Fails everything, like a bucket we can't reach.
+    struct Unreachable;
+
+    impl Backend for Unreachable {
+        fn read(&self, _from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+            Err(BackendError::Transient(anyhow!("unreachable")))
+        }
+
+        fn write(&self, _len: u64, _from: &mut dyn Upload, _to: &str) -> Result<(), BackendError> {
+            Err(BackendError::Transient(anyhow!("unreachable")))
+        }
+
+        fn remove(&self, _which: &str) -> Result<(), BackendError> {
+            Err(BackendError::Transient(anyhow!("unreachable")))
+        }
+
+        fn list(&self, _prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+            Err(BackendError::Transient(anyhow!("unreachable")))
+        }
+    }
+
+    /// Share a MemoryBackend between the mirror and the test so we can peek inside.
+    struct Shared(Arc<memory::MemoryBackend>);
+
+    impl Backend for Shared {
+        fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+            self.0.read(from)
+        }
+
+        fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+            self.0.write(len, from, to)
+        }
+
+        fn remove(&self, which: &str) -> Result<(), BackendError> {
+            self.0.remove(which)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+            self.0.list(prefix)
+        }
+    }
+
+    fn contents(b: &dyn Backend, name: &str) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        b.read(name)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn fan_out() -> Result<()> {
+        let primary = Arc::new(memory::MemoryBackend::new());
+        let secondary = Arc::new(memory::MemoryBackend::new());
+        let mirror = Mirrored::new(
+            Box::new(Shared(primary.clone())),
+            vec![Box::new(Shared(secondary.clone()))],
+            true,
+        );
+
+        mirror.write(5, &mut io::Cursor::new("hello"), "snapshots/a.snapshot")?;
+        assert_eq!(contents(&*primary, "snapshots/a.snapshot")?, b"hello");
+        assert_eq!(contents(&*secondary, "snapshots/a.snapshot")?, b"hello");
+
+        mirror.remove("snapshots/a.snapshot")?;
+        assert!(primary.list("snapshots/")?.is_empty());
+        assert!(secondary.list("snapshots/")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn fall_back() -> Result<()> {
+        let secondary = Arc::new(memory::MemoryBackend::new());
+        secondary.insert("packs/b.pack", b"still here".to_vec());
+        let mirror = Mirrored::new(
+            Box::new(Unreachable),
+            vec![Box::new(Shared(secondary.clone()))],
+            false,
+        );
+        assert_eq!(contents(&mirror, "packs/b.pack")?, b"still here");
+        assert_eq!(mirror.list("packs/")?.len(), 1);
+
+        // But the primary has to take writes.
+        assert!(
+            mirror
+                .write(2, &mut io::Cursor::new("hi"), "packs/c.pack")
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fall_back_on_not_found() -> Result<()> {
+        let secondary = Arc::new(memory::MemoryBackend::new());
+        secondary.insert("packs/b.pack", b"left over".to_vec());
+        let mirror = Mirrored::new(
+            Box::new(memory::MemoryBackend::new()),
+            vec![Box::new(Shared(secondary.clone()))],
+            false,
+        );
+        let mut contents = String::new();
+        mirror.read("packs/b.pack")?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "left over");
+
+        // Nobody has it? Still not found.
+        assert!(matches!(mirror.read("packs/c.pack"), Err(e) if e.is_not_found()));
+        Ok(())
+    }
+
+    #[test]
+    fn require_all() -> Result<()> {
+        let lenient = Mirrored::new(
+            Box::new(memory::MemoryBackend::new()),
+            vec![Box::new(Unreachable)],
+            false,
+        );
+        lenient.write(2, &mut io::Cursor::new("hi"), "indexes/a.index")?;
+        lenient.remove("indexes/a.index")?;
+
+        let strict = Mirrored::new(
+            Box::new(memory::MemoryBackend::new()),
+            vec![Box::new(Unreachable)],
+            true,
+        );
+        let err = strict
+            .write(2, &mut io::Cursor::new("hi"), "indexes/a.index")
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("Mirror 1 failed"), "{err:#}");
+        assert!(strict.remove("indexes/a.index").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bad_configs() {
+        let fs = || Kind::Filesystem {
+            force_cache: false,
+            verify_after_write: false,
+        };
+        let ssh = || Kind::Ssh {
+            host: "example.com".to_owned(),
+            port: 22,
+            username: None,
+            identity_file: None,
+            base_path: "backpak".to_owned(),
+            remote: Remote {
+                concurrent_connections: 1,
+            },
+        };
+        assert!(check(&fs(), &[ssh()]).is_ok());
+        assert!(check(&fs(), &[]).is_err());
+        assert!(check(&fs(), &[fs()]).is_err());
+        let nested = Kind::Mirror {
+            primary: Box::new(ssh()),
+            secondaries: vec![ssh()],
+            require_all: false,
+        };
+        assert!(check(&fs(), &[nested]).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Limit how many requests per second we make of a [`Backend`].
+//!
+//! Some services (looking at you, B2) rate-limit by request count as well as bandwidth,
+//! and a flurry of tiny index or snapshot reads can earn us a 429.
+//! This is a token bucket on the number of calls, not the bytes they move.
+
+use super::*;
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    last_fill: Instant,
+}
+
+pub struct RateLimited<B> {
+    inner: B,
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl<B: Backend> RateLimited<B> {
+    pub fn new(inner: B, per_second: f64) -> Self {
+        assert!(per_second > 0.0);
+        Self {
+            inner,
+            per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: burst(per_second),
+                last_fill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token at the given time,
+    /// returning how long we have to wait if there wasn't one.
+    ///
+    /// Tokens can go negative: each caller reserves its spot in line,
+    /// then sleeps until the bucket would have refilled to it.
+    fn take_at(&self, now: Instant) -> Option<Duration> {
+        let mut b = self.bucket.lock().unwrap();
+        let refill = now.saturating_duration_since(b.last_fill).as_secs_f64() * self.per_second;
+        b.tokens = (b.tokens + refill).min(burst(self.per_second));
+        b.last_fill = now;
+        b.tokens -= 1.0;
+        (b.tokens < 0.0).then(|| Duration::from_secs_f64(-b.tokens / self.per_second))
+    }
+
+    fn wait(&self, what: &str) {
+        if let Some(nap) = self.take_at(Instant::now()) {
+            debug!("Rate limiting: waiting {nap:?} before {what}");
+            thread::sleep(nap);
+        }
+    }
+}
+
+/// Let a second's worth of requests through at once (but always at least one).
+fn burst(per_second: f64) -> f64 {
+    per_second.max(1.0)
+}
+
+impl<B: Backend> Backend for RateLimited<B> {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        self.wait(from);
+        self.inner.read(from)
+    }
+
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+        self.wait(to);
+        self.inner.write(len, from, to)
+    }
+
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
+        self.wait(which);
+        self.inner.remove(which)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+        self.wait(prefix);
+        self.inner.list(prefix)
+    }
+
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        self.wait(prefix);
+        self.inner.list_streaming(prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let rl = RateLimited::new(memory::MemoryBackend::new(), 10.0);
+        let start = rl.bucket.lock().unwrap().last_fill;
+
+        // A second's worth goes through right away...
+        for _ in 0..10 {
+            assert_eq!(rl.take_at(start), None);
+        }
+        // ...then everyone waits their turn.
+        let nap = rl.take_at(start).unwrap();
+        assert!((nap.as_secs_f64() - 0.1).abs() < 1e-6);
+        let nap = rl.take_at(start).unwrap();
+        assert!((nap.as_secs_f64() - 0.2).abs() < 1e-6);
+
+        // After a while the bucket fills back up, but only so far.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert_eq!(rl.take_at(later), None);
+        }
+        assert!(rl.take_at(later).is_some());
+    }
+}
@@ -0,0 +1,207 @@
+//! Retry a [`Backend`]'s operations when they fail for reasons that might go away,
+//! like a 503 or a dropped connection.
+//!
+//! Each backend decides what's worth retrying by returning [`BackendError::Transient`].
+//! We back off exponentially (with jitter, so a bunch of threads that failed together
+//! don't all come back together) until we run out of attempts.
+
+use super::*;
+
+use std::io::SeekFrom;
+use std::thread;
+use std::time::Duration;
+
+/// How hard to try when a remote backend hiccups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retries {
+    /// Give up after this many tries (including the first)
+    #[serde(default = "defattempts")]
+    pub max_attempts: u32,
+    /// Wait about this long before the first retry, doubling each time after
+    #[serde(default = "defdelay")]
+    pub base_delay_ms: u64,
+}
+
+#[inline]
+fn defattempts() -> u32 {
+    5
+}
+
+#[inline]
+fn defdelay() -> u64 {
+    500
+}
+
+impl Default for Retries {
+    fn default() -> Self {
+        Self {
+            max_attempts: defattempts(),
+            base_delay_ms: defdelay(),
+        }
+    }
+}
+
+impl Retries {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// How long to wait after the given (1-based) failed attempt:
+    /// somewhere between half and all of `base_delay * 2^(attempt - 1)`.
+    fn backoff(&self, attempt: u32, jitter: f64) -> Duration {
+        let full = Duration::from_millis(self.base_delay_ms)
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        full.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+pub struct Retrying<B> {
+    inner: B,
+    retries: Retries,
+}
+
+impl<B: Backend> Retrying<B> {
+    pub fn new(inner: B, retries: Retries) -> Self {
+        Self { inner, retries }
+    }
+
+    fn retry<T, F>(&self, what: &str, mut f: F) -> Result<T, BackendError>
+    where
+        F: FnMut() -> Result<T, BackendError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Ok(t) => return Ok(t),
+                Err(e) if attempt < self.retries.max_attempts && e.is_transient() => {
+                    let nap = self.retries.backoff(attempt, fastrand::f64());
+                    warn!(
+                        "{what} failed (attempt {attempt} of {}): {e:#}; retrying in {nap:?}",
+                        self.retries.max_attempts
+                    );
+                    thread::sleep(nap);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<B: Backend> Backend for Retrying<B> {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        self.retry(from, || self.inner.read(from))
+    }
+
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+        // If a write fails partway through, start the next one from the top.
+        let start = from.stream_position()?;
+        let mut first = true;
+        self.retry(to, || {
+            if !first {
+                from.seek(SeekFrom::Start(start))?;
+            }
+            first = false;
+            self.inner.write(len, from, to)
+        })
+    }
+
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
+        self.retry(which, || self.inner.remove(which))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+        self.retry(prefix, || self.inner.list(prefix))
+    }
+
+    // Retries starting the listing; we can't go back for entries that fail partway through.
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        self.retry(prefix, || self.inner.list_streaming(prefix))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails the first few writes halfway through.
+    struct Flaky {
+        inner: memory::MemoryBackend,
+        failures_left: AtomicU32,
+    }
+
+    impl Backend for Flaky {
+        fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+            self.inner.read(from)
+        }
+
+        fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+            if self.failures_left.load(Ordering::SeqCst) > 0 {
+                self.failures_left.fetch_sub(1, Ordering::SeqCst);
+                let mut half = vec![0; len as usize / 2];
+                from.read_exact(&mut half)?;
+                return Err(BackendError::Transient(anyhow!("hiccup")));
+            }
+            self.inner.write(len, from, to)
+        }
+
+        fn remove(&self, which: &str) -> Result<(), BackendError> {
+            self.inner.remove(which)
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+            self.inner.list(prefix)
+        }
+    }
+
+    fn flaky(failures: u32) -> Retrying<Flaky> {
+        Retrying::new(
+            Flaky {
+                inner: memory::MemoryBackend::new(),
+                failures_left: AtomicU32::new(failures),
+            },
+            Retries {
+                max_attempts: 3,
+                base_delay_ms: 1,
+            },
+        )
+    }
+
+    #[test]
+    fn replays_partial_writes() -> Result<()> {
+        let contents = b"It's a dangerous business, going out your door.";
+        let r = flaky(2);
+        r.write(contents.len() as u64, &mut Cursor::new(contents), "frodo")?;
+
+        let mut read_back = vec![];
+        r.read("frodo")?.read_to_end(&mut read_back)?;
+        assert_eq!(read_back, contents);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up() {
+        let contents = b"One does not simply walk into Mordor.";
+        let r = flaky(3);
+        let e = r
+            .write(contents.len() as u64, &mut Cursor::new(contents), "boromir")
+            .unwrap_err();
+        assert!(e.is_transient());
+        assert_eq!(e.to_string(), "hiccup");
+    }
+
+    #[test]
+    fn backoff() {
+        let r = Retries {
+            max_attempts: 5,
+            base_delay_ms: 100,
+        };
+        assert_eq!(r.backoff(1, 1.0), Duration::from_millis(100));
+        assert_eq!(r.backoff(3, 1.0), Duration::from_millis(400));
+        assert_eq!(r.backoff(3, 0.0), Duration::from_millis(200));
+    }
+}
@@ -50,10 +50,12 @@ fn dec(count: &AtomicU32) -> SemaphoreGuard {
 
 impl Drop for SemaphoreGuard<'_> {
     fn drop(&mut self) {
-        if self.count.fetch_add(1, Ordering::Release) == 0 {
-            // real shit
-            wake_one(self.count);
-        }
+        self.count.fetch_add(1, Ordering::Release);
+        // Wake someone up every time, not just when we went from 0 to 1.
+        // Otherwise, if two guards drop (0 -> 1 -> 2) before the one thread we woke
+        // gets to take the count, everyone else stays asleep with a positive count.
+        // A syscall per release is nothing next to a trip over the network.
+        wake_one(self.count);
     }
 }
 
@@ -72,23 +74,103 @@ impl<B: Backend> Semaphored<B> {
 }
 
 impl<B: Backend> Backend for Semaphored<B> {
-    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>> {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
         let _sem = dec(&self.count);
         self.inner.read(from)
     }
 
-    fn write(&self, len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
         let _sem = dec(&self.count);
         self.inner.write(len, from, to)
     }
 
-    fn remove(&self, which: &str) -> Result<()> {
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
         let _sem = dec(&self.count);
         self.inner.remove(which)
     }
 
-    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
         let _sem = dec(&self.count);
         self.inner.list(prefix)
     }
+
+    // Holds a connection while the listing starts, not while it's read.
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        let _sem = dec(&self.count);
+        self.inner.list_streaming(prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::backend::memory::MemoryBackend;
+
+    /// A [`MemoryBackend`] that's slow on purpose,
+    /// keeping track of the most calls into it at once.
+    #[derive(Default)]
+    struct Crowded {
+        inner: MemoryBackend,
+        in_flight: AtomicUsize,
+        most: AtomicUsize,
+    }
+
+    impl Crowded {
+        fn crowd<T>(&self, f: impl FnOnce() -> T) -> T {
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            let res = f();
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            res
+        }
+    }
+
+    impl Backend for Crowded {
+        fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+            self.crowd(|| self.inner.read(from))
+        }
+
+        fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+            self.crowd(|| self.inner.write(len, from, to))
+        }
+
+        fn remove(&self, which: &str) -> Result<(), BackendError> {
+            self.crowd(|| self.inner.remove(which))
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+            self.crowd(|| self.inner.list(prefix))
+        }
+    }
+
+    #[test]
+    fn caps_in_flight() -> Result<()> {
+        let limited = Semaphored::new(Crowded::default(), 3);
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..12)
+                .map(|i| {
+                    let limited = &limited;
+                    s.spawn(move || -> Result<(), BackendError> {
+                        let key = format!("packs/{i}");
+                        limited.write(2, &mut io::Cursor::new("hi"), &key)?;
+                        limited.list("packs/")?;
+                        limited.read(&key)?;
+                        limited.remove(&key)
+                    })
+                })
+                .collect();
+            handles.into_iter().try_for_each(|h| h.join().unwrap())
+        })?;
+        assert_eq!(limited.inner.most.load(Ordering::SeqCst), 3);
+        assert_eq!(limited.inner.in_flight.load(Ordering::SeqCst), 0);
+        assert_eq!(limited.count.load(Ordering::SeqCst), 3);
+        assert!(limited.inner.inner.list("")?.is_empty());
+        Ok(())
+    }
 }
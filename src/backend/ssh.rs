@@ -0,0 +1,348 @@
+//! Backups to any box we can SSH into.
+//!
+//! Rather than linking an SSH library, this drives the system's `ssh` client,
+//! so it picks up `~/.ssh/config`, known hosts, agents, jump hosts, and so on for free.
+//! Each operation is its own `ssh` invocation running a POSIX shell command on the remote,
+//! so consider setting up `ControlMaster` to reuse connections.
+//!
+//! This isn't SFTP: the remote needs a POSIX shell with `cat`, `find`, `mkdir`,
+//! `mv`, `rm`, `test`, and `wc`. Hosts that only allow SFTP
+//! (like a chrooted `internal-sftp` account) won't work.
+
+use super::*;
+
+use std::io;
+use std::process::{Child, Command, Stdio};
+
+use anyhow::Result;
+use byte_unit::Byte;
+use camino::{Utf8Path, Utf8PathBuf};
+
+pub struct SshBackend {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    /// Use this key instead of whatever the agent (or `~/.ssh/config`) offers
+    identity_file: Option<Utf8PathBuf>,
+    base_path: String,
+}
+
+#[expect(clippy::too_many_arguments)] // Such is SSH.
+pub fn initialize(
+    repository: &Utf8Path,
+    pack_size: Byte,
+    filter: Option<(String, String)>,
+    filter_env: Vec<String>,
+    host: String,
+    port: u16,
+    username: Option<String>,
+    identity_file: Option<Utf8PathBuf>,
+    base_path: String,
+    concurrent_connections: u32,
+) -> Result<()> {
+    let format = crate::config::Format::from_path(repository)?;
+
+    let backend = SshBackend {
+        host: host.clone(),
+        port,
+        username: username.clone(),
+        identity_file: identity_file.clone(),
+        base_path: base_path.clone(),
+    };
+    let b = quote(&base_path);
+    backend
+        .run(&format!("mkdir -p {b}/packs {b}/indexes {b}/snapshots"))
+        .with_context(|| format!("Couldn't create {base_path} on {host}"))?;
+
+    let c = super::Configuration {
+        pack_size,
+        kind: super::Kind::Ssh {
+            host,
+            port,
+            username,
+            identity_file,
+            base_path,
+            remote: super::Remote {
+                concurrent_connections,
+            },
+        },
+        filter,
+        legacy_unfilters: vec![],
+        filter_env,
+        retries: Default::default(),
+        upload_limit: None,
+        download_limit: None,
+        compression: None,
+        chunking: None,
+        verify_cache_on_open: false,
+    };
+    super::create_config(repository, c, format)
+}
+
+/// `ssh` exits with 255 when it couldn't connect (or lost the connection),
+/// as opposed to the remote command failing.
+#[derive(Debug)]
+struct ConnectionFailed {
+    host: String,
+    stderr: String,
+}
+
+impl std::fmt::Display for ConnectionFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Couldn't connect to {}: {}", self.host, self.stderr)
+    }
+}
+
+impl std::error::Error for ConnectionFailed {}
+
+/// Single-quote the given string for the remote shell.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl SshBackend {
+    pub fn open(
+        host: &str,
+        port: u16,
+        username: Option<&str>,
+        identity_file: Option<&Utf8Path>,
+        base_path: &str,
+    ) -> Result<Self> {
+        let backend = Self {
+            host: host.to_owned(),
+            port,
+            username: username.map(str::to_owned),
+            identity_file: identity_file.map(Utf8Path::to_owned),
+            base_path: base_path.to_owned(),
+        };
+        let b = quote(base_path);
+        backend
+            .run(&format!(
+                "test -d {b}/packs && test -d {b}/indexes && test -d {b}/snapshots"
+            ))
+            .with_context(|| format!("{base_path} on {host} isn't a backpak repository"))?;
+        Ok(backend)
+    }
+
+    /// `ssh` with our options, ready to run the given remote command
+    fn ssh(&self, remote_command: &str) -> Command {
+        let mut cmd = Command::new("ssh");
+        // Never prompt - we're usually not attached to anything that can answer.
+        cmd.args(["-o", "BatchMode=yes", "-p"])
+            .arg(self.port.to_string());
+        if let Some(i) = &self.identity_file {
+            cmd.arg("-i").arg(i).args(["-o", "IdentitiesOnly=yes"]);
+        }
+        if let Some(u) = &self.username {
+            cmd.arg("-l").arg(u);
+        }
+        cmd.arg(&self.host).arg(remote_command);
+        debug!("ssh {}: {remote_command}", self.host);
+        cmd
+    }
+
+    /// Run the given remote command, returning its output.
+    fn run(&self, remote_command: &str) -> Result<Vec<u8>> {
+        let output = self
+            .ssh(remote_command)
+            .stdin(Stdio::null())
+            .output()
+            .context("Couldn't run ssh")?;
+        self.check_connection(&output)?;
+        ensure!(
+            output.status.success(),
+            "ssh {} `{remote_command}` failed: {}",
+            self.host,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(output.stdout)
+    }
+
+    /// Dropped connections are worth retrying; see [`BackendError::Transient`]
+    fn check_connection(&self, output: &std::process::Output) -> Result<(), BackendError> {
+        if output.status.code() == Some(255) {
+            return Err(BackendError::Transient(
+                ConnectionFailed {
+                    host: self.host.clone(),
+                    stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+                }
+                .into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn path_of(&self, p: &str) -> String {
+        format!("{}/{p}", self.base_path.trim_end_matches('/'))
+    }
+}
+
+/// Streams a remote file from `ssh ... cat`,
+/// failing at the end if `ssh` did.
+struct SshRead {
+    what: String,
+    child: Child,
+}
+
+impl Read for SshRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.child.stdout.as_mut().unwrap().read(buf)?;
+        if n == 0 && !buf.is_empty() && !self.child.wait()?.success() {
+            return Err(io::Error::other(format!("Couldn't read {}", self.what)));
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for SshRead {
+    fn drop(&mut self) {
+        // If we were dropped before reading everything, don't leave ssh hanging.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Backend for SshBackend {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        let path = quote(&self.path_of(from));
+        // Once we're streaming from `cat`, all we can say is that it failed,
+        // so check if there's anything there first.
+        let exists = self
+            .ssh(&format!("test -f {path}"))
+            .stdin(Stdio::null())
+            .output()
+            .context("Couldn't run ssh")?;
+        self.check_connection(&exists)?;
+        if !exists.status.success() {
+            return Err(BackendError::NotFound(anyhow!(
+                "Couldn't find {from} on {}",
+                self.host
+            )));
+        }
+        let child = self
+            .ssh(&format!("cat {path}"))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Couldn't run ssh")?;
+        Ok(Box::new(SshRead {
+            what: format!("{from} from {}", self.host),
+            child,
+        }))
+    }
+
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+        let path = self.path_of(to);
+        let part = quote(&format!("{path}.part"));
+        let dir = Utf8Path::new(&path).parent().map_or(".", |d| d.as_str());
+        // Like file_util::safe_copy_to_file(), write to a .part file then move it into place
+        // so nobody sees half an object. (Including if we lose the connection partway through.)
+        let mut child = self
+            .ssh(&format!(
+                "mkdir -p {} && cat > {part} && [ $(wc -c < {part}) -eq {len} ] && mv {part} {}",
+                quote(dir),
+                quote(&path)
+            ))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Couldn't run ssh")?;
+        let mut stdin = child.stdin.take().unwrap();
+        let copied = io::copy(&mut from.take(len), &mut stdin);
+        drop(stdin); // EOF for cat
+        let output = child.wait_with_output()?;
+        self.check_connection(&output)?;
+        let copied = copied.with_context(|| format!("Couldn't upload {to}"))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Couldn't write {to} to {}: {}",
+                self.host,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        if copied != len {
+            return Err(anyhow!("Uploaded {copied} bytes of {to}, expected {len}").into());
+        }
+        Ok(())
+    }
+
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
+        self.run(&format!("rm {}", quote(&self.path_of(which))))
+            .with_context(|| format!("Couldn't remove {which}"))?;
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+        // Walk the directory the prefix is in, then filter by the prefix.
+        let dir = match prefix.rsplit_once('/') {
+            Some((d, _)) if !d.is_empty() => d,
+            _ => ".",
+        };
+        // wc -c is the most portable way to get sizes - find -printf is a GNU-ism.
+        let listing = self.run(&format!(
+            "cd {} && if [ -d {d} ]; then find {d} -type f -exec wc -c {{}} +; fi",
+            quote(&self.base_path),
+            d = quote(dir)
+        ))?;
+        let listing = String::from_utf8(listing).context("Remote listing wasn't UTF-8")?;
+        Ok(parse_listing(&listing, prefix)?)
+    }
+}
+
+/// Parse `wc -c` output, keeping paths that start with the prefix.
+fn parse_listing(listing: &str, prefix: &str) -> Result<Vec<(String, u64)>> {
+    let mut files = vec![];
+    for line in listing.lines() {
+        let (len, path) = line
+            .trim_start()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Unexpected remote listing: {line}"))?;
+        let path = path.trim_start();
+        // wc's sum line, when it got multiple files.
+        if path == "total" {
+            continue;
+        }
+        let path = path.strip_prefix("./").unwrap_or(path);
+        // See file_util::safe_copy_to_file()
+        if !path.starts_with(prefix) || path.ends_with(".part") {
+            continue;
+        }
+        let len = len
+            .parse::<u64>()
+            .with_context(|| format!("Couldn't parse size of {path}"))?;
+        files.push((path.to_owned(), len));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("/srv/backups"), "'/srv/backups'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn listing() -> Result<()> {
+        let listing =
+            "  123 packs/aa.pack\n 45 packs/bb.pack.part\n   6 packs/cc.pack\n  174 total\n";
+        assert_eq!(
+            parse_listing(listing, "packs/")?,
+            [
+                ("packs/aa.pack".to_owned(), 123),
+                ("packs/cc.pack".to_owned(), 6)
+            ]
+        );
+        assert_eq!(
+            parse_listing("9 ./indexes/x.index\n", "indexes/")?,
+            [("indexes/x.index".to_owned(), 9)]
+        );
+        assert!(parse_listing("", "snapshots/")?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+//! Cap the bandwidth a [`Backend`] uses, for metered or shared connections.
+//!
+//! Where [`rate_limited`](super::rate_limited) counts requests,
+//! this is a token bucket on the bytes flowing through reads and writes,
+//! with separate buckets for each direction.
+
+use super::*;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    last_fill: Instant,
+}
+
+/// A token bucket of bytes
+struct ByteBucket {
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl ByteBucket {
+    fn new(per_second: u64) -> Self {
+        assert!(per_second > 0);
+        let per_second = per_second as f64;
+        Self {
+            per_second,
+            bucket: Mutex::new(Bucket {
+                // Let a second's worth through at once.
+                tokens: per_second,
+                last_fill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `n` bytes' worth of tokens at the given time,
+    /// returning how long we have to wait if there weren't enough.
+    ///
+    /// Like [`RateLimited`](super::rate_limited::RateLimited), tokens can go negative:
+    /// each caller takes what it needs, then sleeps until the bucket would have refilled.
+    fn take_at(&self, n: usize, now: Instant) -> Option<Duration> {
+        let mut b = self.bucket.lock().unwrap();
+        let refill = now.saturating_duration_since(b.last_fill).as_secs_f64() * self.per_second;
+        b.tokens = (b.tokens + refill).min(self.per_second);
+        b.last_fill = now;
+        b.tokens -= n as f64;
+        (b.tokens < 0.0).then(|| Duration::from_secs_f64(-b.tokens / self.per_second))
+    }
+
+    fn take(&self, n: usize) {
+        if let Some(nap) = self.take_at(n, Instant::now()) {
+            thread::sleep(nap);
+        }
+    }
+}
+
+/// Sleeps as needed after each read to stay under the bucket's rate.
+struct ThrottledRead<R> {
+    inner: R,
+    bucket: Arc<ByteBucket>,
+}
+
+impl<R: Read> Read for ThrottledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bucket.take(n);
+        Ok(n)
+    }
+}
+
+// Rewinding for a retry costs nothing; it's the reads after that we pay for.
+impl<R: Seek> Seek for ThrottledRead<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+pub struct Throttled<B> {
+    inner: B,
+    upload: Option<Arc<ByteBucket>>,
+    download: Option<Arc<ByteBucket>>,
+}
+
+impl<B: Backend> Throttled<B> {
+    /// Limits are in bytes per second; zero (or `None`) means unlimited.
+    pub fn new(inner: B, upload_limit: Option<Byte>, download_limit: Option<Byte>) -> Self {
+        let bucket = |limit: Option<Byte>| {
+            limit
+                .map(|l| l.as_u64())
+                .filter(|l| *l > 0)
+                .map(|l| Arc::new(ByteBucket::new(l)))
+        };
+        Self {
+            inner,
+            upload: bucket(upload_limit),
+            download: bucket(download_limit),
+        }
+    }
+}
+
+impl<B: Backend> Backend for Throttled<B> {
+    fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>, BackendError> {
+        let r = self.inner.read(from)?;
+        match &self.download {
+            Some(bucket) => Ok(Box::new(ThrottledRead {
+                inner: r,
+                bucket: bucket.clone(),
+            })),
+            None => Ok(r),
+        }
+    }
+
+    fn write(&self, len: u64, from: &mut dyn Upload, to: &str) -> Result<(), BackendError> {
+        match &self.upload {
+            Some(bucket) => {
+                let mut throttled = ThrottledRead {
+                    inner: from,
+                    bucket: bucket.clone(),
+                };
+                self.inner.write(len, &mut throttled, to)
+            }
+            None => self.inner.write(len, from, to),
+        }
+    }
+
+    fn remove(&self, which: &str) -> Result<(), BackendError> {
+        self.inner.remove(which)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>, BackendError> {
+        self.inner.list(prefix)
+    }
+
+    fn list_streaming(&self, prefix: &str) -> Result<Listing, BackendError> {
+        self.inner.list_streaming(prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn byte_bucket() {
+        let b = ByteBucket::new(1000);
+        let start = b.bucket.lock().unwrap().last_fill;
+
+        // A second's worth goes through right away...
+        assert_eq!(b.take_at(600, start), None);
+        assert_eq!(b.take_at(400, start), None);
+        // ...then we wait for the bytes to "drain".
+        let nap = b.take_at(500, start).unwrap();
+        assert!((nap.as_secs_f64() - 0.5).abs() < 1e-6);
+
+        // After a while the bucket fills back up, but only so far.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(b.take_at(1000, later), None);
+        assert!(b.take_at(1, later).is_some());
+    }
+
+    #[test]
+    fn zero_is_unlimited() -> Result<()> {
+        let t = Throttled::new(
+            memory::MemoryBackend::new(),
+            Some(Byte::from_u64(0)),
+            Some(Byte::from_u64(1_000_000)),
+        );
+        assert!(t.upload.is_none());
+        assert!(t.download.is_some());
+
+        let contents = b"slow and steady";
+        t.write(
+            contents.len() as u64,
+            &mut io::Cursor::new(contents),
+            "tortoise",
+        )?;
+        let mut read_back = vec![];
+        t.read("tortoise")?.read_to_end(&mut read_back)?;
+        assert_eq!(read_back, contents);
+        Ok(())
+    }
+}
@@ -27,6 +27,8 @@
 //!   (i.e., its [`ObjectId`]!) and manifest are sent to the indexer.
 //!   Each backup creates a single index file that contains
 //!   an [`Index`](crate::index::Index) which maps pack IDs to their manifests.
+//!   (Long backups can also upload checkpoint indexes along the way,
+//!   which the final one supersedes.)
 //!   (We can also pass a starting index containing previously existing packs.
 //!   This isn't necessary for a normal backup, since
 //!   [`build_master_index()`](crate::index::build_master_index) merges all
@@ -47,6 +49,7 @@
 //! the partially-used packs to the packers for compaction. And so on, and so forth.
 
 use std::fs::{self, File};
+use std::num::NonZeroUsize;
 use std::str::FromStr;
 use std::sync::{
     atomic::AtomicU64,
@@ -61,6 +64,7 @@ use tracing::*;
 
 use crate::backend;
 use crate::blob::Blob;
+use crate::chunk;
 use crate::hashing::ObjectId;
 use crate::index;
 use crate::pack;
@@ -75,6 +79,8 @@ pub struct Backup<'scope, 'env> {
     pub chunk_tx: SyncSender<Blob>,
     pub tree_tx: SyncSender<Blob>,
     pub upload_tx: SyncSender<(String, File)>,
+    /// How to cut files into chunks before sending them to `chunk_tx`
+    pub chunking: chunk::ChunkConfig,
     pub statistics: &'env BackupStatistics,
     threads: thread::ScopedJoinHandle<'scope, Result<()>>,
 }
@@ -116,6 +122,7 @@ pub fn spawn_backup_threads<'scope, 'env>(
     backend_config: &'env backend::Configuration,
     cached_backend: &'env backend::CachedBackend,
     starting_index: index::Index,
+    checkpoint_interval: Option<NonZeroUsize>,
     statistics: &'env BackupStatistics,
 ) -> Backup<'scope, 'env> {
     // Channels are all handoffs holding no elements - this simplifies reasoning about:
@@ -144,6 +151,7 @@ pub fn spawn_backup_threads<'scope, 'env>(
                 cached_backend,
                 statistics,
                 starting_index,
+                checkpoint_interval,
             )
         })
         .unwrap();
@@ -152,6 +160,7 @@ pub fn spawn_backup_threads<'scope, 'env>(
         chunk_tx,
         tree_tx,
         upload_tx,
+        chunking: backend_config.chunking.unwrap_or_default(),
         statistics,
         threads,
     }
@@ -168,6 +177,7 @@ fn backup_master_thread<'env>(
     cached_backend: &'env backend::CachedBackend,
     statistics: &'env BackupStatistics,
     starting_index: index::Index,
+    checkpoint_interval: Option<NonZeroUsize>,
 ) -> Result<()> {
     // ALL THE CONCURRENCY
 
@@ -179,6 +189,7 @@ fn backup_master_thread<'env>(
     let tree_pack_upload_tx = chunk_pack_upload_tx.clone();
     let index_upload_tx = chunk_pack_upload_tx.clone();
     let pack_size = backend_config.pack_size;
+    let compression = backend_config.compression.unwrap_or_default();
 
     let chunk_bytes = &statistics.chunk_bytes;
     let tree_bytes = &statistics.tree_bytes;
@@ -191,6 +202,7 @@ fn backup_master_thread<'env>(
             .spawn_scoped(s, move || {
                 pack::pack(
                     pack_size,
+                    compression,
                     chunk_rx,
                     chunk_index_tx,
                     chunk_pack_upload_tx,
@@ -205,6 +217,7 @@ fn backup_master_thread<'env>(
             .spawn_scoped(s, move || {
                 pack::pack(
                     pack_size,
+                    compression,
                     tree_rx,
                     tree_index_tx,
                     tree_pack_upload_tx,
@@ -225,6 +238,7 @@ fn backup_master_thread<'env>(
                 index::index(
                     resumable,
                     starting_index,
+                    checkpoint_interval,
                     index_rx,
                     index_upload_tx,
                     indexed_packs,
@@ -305,7 +319,7 @@ pub fn find_resumable(backend: &backend::CachedBackend) -> Result<Option<Resumab
         debug!("Checking backend for other packfiles in the index...");
         // (We want to make sure that everything the index contains is backed up,
         // or just has to be uploaded, so it's a valid starting point).
-        let packs = backend.list_packs()?;
+        let packs = backend.list_packs()?.collect::<Result<Vec<_>, _>>()?;
         let mut errs = false;
         for p in &missing_packfiles {
             if let Err(e) = backend::probe_pack(&packs, p) {
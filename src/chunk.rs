@@ -1,18 +1,21 @@
 //! Cut files into content-based chunks.
 
+use std::io::Read;
 use std::sync::{Arc, mpsc};
 use std::thread;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, ensure};
+use byte_unit::Byte;
 use camino::Utf8Path;
-use fastcdc::v2020::{Chunk, FastCDC};
+use fastcdc::v2020::{self as cdc, Chunk, FastCDC, StreamCDC};
 use ouroboros::self_referencing;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::blob::{self, Blob};
-use crate::file_util::{self, LoadedFile};
+use crate::file_util::{self, LoadedFile, nice_size};
 use crate::hashing::ObjectId;
 
-/// Cuts a file into content-based chunks between 512kiB and 8MiB, aiming for 1MiB.
+/// Cuts a file into content-based chunks, by default between 512kiB and 8MiB, aiming for 1MiB.
 ///
 /// Duplicati makes a convincing argument that heavyweight attempts to
 /// deduplicate data at the chunk level (as opposed to the file level) isn't
@@ -36,18 +39,116 @@ use crate::hashing::ObjectId;
 /// (almost) every chunk in the backup. So let's find one that works pretty well
 /// ASAP.
 ///
+/// Repositories that want something else can set [`ChunkConfig`].
+///
 /// See <https://crates.io/crates/fastcdc>
-pub fn chunk_file<P: AsRef<Utf8Path>>(path: P) -> Result<impl Iterator<Item = Blob>> {
+pub fn chunk_file<P: AsRef<Utf8Path>>(
+    path: P,
+    config: &ChunkConfig,
+) -> Result<impl Iterator<Item = Blob>> {
     let path: &Utf8Path = path.as_ref();
     let file = file_util::read_file(path).with_context(|| format!("Couldn't read {path}"))?;
-    Ok(ChunkIterator::new(file))
+    Ok(ChunkIterator::new(file, *config))
+}
+
+/// Cuts a stream (like stdin) into chunks, the same way [`chunk_file()`] cuts a file.
+///
+/// Same cut points means the same chunks, so piping a file in dedupes against backing it up.
+/// Each chunk gets its own buffer since we can't map a stream into memory.
+pub fn chunk_reader<R: Read>(
+    reader: R,
+    config: &ChunkConfig,
+) -> impl Iterator<Item = Result<Blob>> {
+    let (min, avg, max) = config.sizes();
+    StreamCDC::new(reader, min, avg, max).map(|c| {
+        let c = c.context("Couldn't read chunk")?;
+        Ok(Blob {
+            id: ObjectId::hash(&c.data),
+            contents: blob::Contents::Buffer(c.data),
+            kind: blob::Type::Chunk,
+        })
+    })
+}
+
+const MIN_SIZE: u32 = 1024 * 512;
+const TARGET_SIZE: u32 = 1024 * 1024;
+const MAX_SIZE: u32 = 1024 * 1024 * 8;
+
+/// The biggest chunk any [`ChunkConfig`] can make
+pub const LARGEST_CHUNK: u32 = cdc::MAXIMUM_MAX;
+
+/// Content-defined chunking sizes, for repositories that want something besides the defaults.
+///
+/// Bigger chunks mean fewer blobs and smaller indexes,
+/// but a change anywhere in a chunk means backing up the whole thing again.
+/// Since chunk IDs depend on where we cut, changing these means (almost) nothing
+/// in new backups dedupes against older ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkConfig {
+    pub min: Byte,
+    pub avg: Byte,
+    pub max: Byte,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            min: Byte::from_u64(MIN_SIZE as u64),
+            avg: Byte::from_u64(TARGET_SIZE as u64),
+            max: Byte::from_u64(MAX_SIZE as u64),
+        }
+    }
+}
+
+impl ChunkConfig {
+    pub fn check(&self) -> Result<()> {
+        let (min, avg, max) = (self.min.as_u64(), self.avg.as_u64(), self.max.as_u64());
+        ensure!(
+            min < avg && avg < max,
+            "Chunk sizes should go min < avg < max, not {self}"
+        );
+        let in_range = |what, size, lo: u32, hi: u32| {
+            ensure!(
+                (lo as u64..=hi as u64).contains(&size),
+                "{what} chunk size {} is out of range; it should be between {} and {}",
+                nice_size(size),
+                nice_size(lo as u64),
+                nice_size(hi as u64)
+            );
+            Ok(())
+        };
+        in_range("Minimum", min, cdc::MINIMUM_MIN, cdc::MINIMUM_MAX)?;
+        in_range("Average", avg, cdc::AVERAGE_MIN, cdc::AVERAGE_MAX)?;
+        in_range("Maximum", max, cdc::MAXIMUM_MIN, cdc::MAXIMUM_MAX)?;
+        Ok(())
+    }
+
+    /// Assumes we've been [checked](Self::check), which keeps everything well under `u32::MAX`.
+    fn sizes(&self) -> (u32, u32, u32) {
+        (
+            self.min.as_u64() as u32,
+            self.avg.as_u64() as u32,
+            self.max.as_u64() as u32,
+        )
+    }
 }
 
-fn new_cdc(src: &[u8]) -> FastCDC {
-    const MIN_SIZE: u32 = 1024 * 512;
-    const TARGET_SIZE: u32 = 1024 * 1024;
-    const MAX_SIZE: u32 = 1024 * 1024 * 8;
-    FastCDC::new(src, MIN_SIZE, TARGET_SIZE, MAX_SIZE)
+impl std::fmt::Display for ChunkConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} / {} / {}",
+            nice_size(self.min.as_u64()),
+            nice_size(self.avg.as_u64()),
+            nice_size(self.max.as_u64())
+        )
+    }
+}
+
+fn new_cdc(src: &[u8], config: ChunkConfig) -> FastCDC<'_> {
+    let (min, avg, max) = config.sizes();
+    FastCDC::new(src, min, avg, max)
 }
 
 /// For small files, use a simple iterator that just wraps the file and FastCDC iterator.
@@ -58,11 +159,11 @@ enum ChunkIterator {
 }
 
 impl ChunkIterator {
-    fn new(file: Arc<LoadedFile>) -> Self {
+    fn new(file: Arc<LoadedFile>, config: ChunkConfig) -> Self {
         // "small" is decided by whether we read or memory-mapped the file in `read_file()`
         match *file {
-            LoadedFile::Buffered(_) => ChunkIterator::Simple(SmallFileChunker::from(file)),
-            LoadedFile::Mapped(_) => ChunkIterator::Threaded(ThreadedChunker::from(file)),
+            LoadedFile::Buffered(_) => ChunkIterator::Simple(SmallFileChunker::from(file, config)),
+            LoadedFile::Mapped(_) => ChunkIterator::Threaded(ThreadedChunker::from(file, config)),
         }
     }
 }
@@ -89,11 +190,11 @@ struct SmallFileChunker {
 }
 
 impl SmallFileChunker {
-    fn from(file: Arc<LoadedFile>) -> Self {
+    fn from(file: Arc<LoadedFile>, config: ChunkConfig) -> Self {
         assert!(matches!(*file, LoadedFile::Buffered(_)));
         SmallFileChunkerBuilder {
             file,
-            chunker_builder: |f: &Arc<LoadedFile>| new_cdc(f.bytes()),
+            chunker_builder: |f: &Arc<LoadedFile>| new_cdc(f.bytes(), config),
         }
         .build()
     }
@@ -110,7 +211,7 @@ impl Iterator for SmallFileChunker {
 struct ThreadedChunker(mpsc::IntoIter<Blob>);
 
 impl ThreadedChunker {
-    fn from(file: Arc<LoadedFile>) -> Self {
+    fn from(file: Arc<LoadedFile>, config: ChunkConfig) -> Self {
         assert!(matches!(*file, LoadedFile::Mapped(_)));
         // Arbitrary-sized channels, but bust our usual "no buffering" rule -
         // the code that calls `chunk_file()` is only doing this once at a time,
@@ -119,7 +220,7 @@ impl ThreadedChunker {
         let (blobs_tx, blobs_rx) = mpsc::sync_channel(128);
         let file2 = file.clone();
         thread::spawn(move || {
-            for cut in new_cdc(file.bytes()) {
+            for cut in new_cdc(file.bytes(), config) {
                 if cuts_tx.send(cut).is_err() {
                     break;
                 }
@@ -181,7 +282,8 @@ mod test {
 
     #[test]
     fn smoke() -> Result<()> {
-        let chunked: Vec<_> = chunk_file("tests/references/sr71.txt")?.collect();
+        let chunked: Vec<_> =
+            chunk_file("tests/references/sr71.txt", &ChunkConfig::default())?.collect();
         assert_eq!(chunked.len(), 1);
 
         let chunked = &chunked[0];
@@ -192,4 +294,48 @@ mod test {
         );
         Ok(())
     }
+
+    #[test]
+    fn stream_matches_file() -> Result<()> {
+        let path = "tests/references/sr71.txt";
+        let config = ChunkConfig::default();
+        let from_file: Vec<ObjectId> = chunk_file(path, &config)?.map(|c| c.id).collect();
+        let from_stream: Vec<ObjectId> = chunk_reader(std::fs::File::open(path)?, &config)
+            .map(|c| c.map(|c| c.id))
+            .collect::<Result<_>>()?;
+        assert_eq!(from_file, from_stream);
+
+        assert_eq!(chunk_reader(std::io::empty(), &config).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn configured_sizes() -> Result<()> {
+        ChunkConfig::default().check()?;
+
+        let kb = |k: u64| Byte::from_u64(k * 1024);
+        let small = ChunkConfig {
+            min: kb(1),
+            avg: kb(2),
+            max: kb(4),
+        };
+        small.check()?;
+        // sr71.txt is one chunk with the defaults, but not with these.
+        let path = "tests/references/sr71.txt";
+        let chunks: Vec<_> = chunk_file(path, &small)?.collect();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.bytes().len() <= 4096));
+
+        let backwards = ChunkConfig {
+            min: kb(4),
+            ..small
+        };
+        assert!(backwards.check().is_err());
+        let huge = ChunkConfig {
+            max: Byte::from_u64(u32::MAX as u64 + 1),
+            ..small
+        };
+        assert!(huge.check().is_err());
+        Ok(())
+    }
 }
@@ -1,8 +1,9 @@
-use std::{fs, io};
+use std::{fs, io, num::NonZeroUsize};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use byte_unit::Byte;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Serialize, de::DeserializeOwned};
 use serde_derive::Deserialize;
 use tracing::*;
 
@@ -14,22 +15,147 @@ fn defcachesize() -> Byte {
     cache::DEFAULT_SIZE
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Configuration {
     #[serde(default = "defcachesize")]
     pub cache_size: Byte,
 
+    /// Keep the cache here instead of `~/.cache/backpak` (see `--cache-dir`)
+    #[serde(default)]
+    pub cache_dir: Option<Utf8PathBuf>,
+
+    /// Skip rules for every backup, before any given with `--skip`
+    /// (see [`Skips`](crate::filter::Skips) for the syntax)
     #[serde(default)]
     pub skips: Vec<String>,
+
+    /// Upload an index every this many packs during a backup,
+    /// so a crash doesn't leave everything so far unindexed.
+    #[serde(default)]
+    pub checkpoint_interval: Option<NonZeroUsize>,
+
+    /// Read the repository's config from here instead of the repository itself
+    /// (see `--repo-config`)
+    #[serde(skip)]
+    pub repo_config: Option<Utf8PathBuf>,
 }
 
 impl Default for Configuration {
     fn default() -> Self {
         Self {
             cache_size: cache::DEFAULT_SIZE,
+            cache_dir: None,
             skips: vec![],
+            checkpoint_interval: None,
+            repo_config: None,
+        }
+    }
+}
+
+impl Configuration {
+    /// The same settings, but for opening some other repository,
+    /// which `--repo-config` doesn't describe.
+    pub fn without_repo_config(&self) -> Self {
+        Self {
+            repo_config: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// Which language a config file is written in, based on its extension
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// TOML unless the extension says otherwise, but complain about extensions we don't know.
+    pub fn from_path(p: &Utf8Path) -> Result<Self> {
+        match p.extension() {
+            None | Some("toml") => Ok(Self::Toml),
+            Some("json") => Ok(Self::Json),
+            Some("yaml") | Some("yml") => Ok(Self::Yaml),
+            Some(other) => {
+                bail!("Don't know how to read a .{other} config ({p}); use .toml, .json, or .yaml")
+            }
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, s: &str) -> Result<T> {
+        let parsed: Result<T> = match self {
+            Self::Toml => toml::from_str(s).map_err(Into::into),
+            Self::Json => serde_json::from_str(s).map_err(Into::into),
+            Self::Yaml => serde_norway::from_str(s).map_err(Into::into),
+        };
+        parsed.map_err(|e| match suggest(&e.to_string()) {
+            Some(friendly) => e.context(friendly),
+            None => e,
+        })
+    }
+
+    pub fn to_string<T: Serialize>(self, t: &T) -> Result<String> {
+        Ok(match self {
+            Self::Toml => toml::to_string(t)?,
+            Self::Json => serde_json::to_string_pretty(t)? + "\n",
+            Self::Yaml => serde_norway::to_string(t)?,
+        })
+    }
+}
+
+/// Turn serde's "unknown field `pakc_size`, expected one of ..."
+/// into "unknown key `pakc_size`, did you mean `pack_size`?"
+fn suggest(error: &str) -> Option<String> {
+    let (what, rest) = if let Some(rest) = error.split_once("unknown field `") {
+        ("key", rest.1)
+    } else if let Some(rest) = error.split_once("unknown variant `") {
+        ("value", rest.1)
+    } else {
+        return None;
+    };
+    let (unknown, expected) = rest.split_once('`')?;
+    // Expected values are the backticked ones until the end of the line.
+    let expected = expected.lines().next().unwrap_or_default();
+    let closest = expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| (edit_distance(unknown, candidate), candidate))
+        .min()
+        // Don't suggest something totally different.
+        .filter(|(distance, candidate)| *distance <= candidate.len().max(unknown.len()) / 2);
+    Some(match closest {
+        Some((_, c)) => format!("unknown {what} `{unknown}`, did you mean `{c}`?"),
+        None => format!("unknown {what} `{unknown}`"),
+    })
+}
+
+/// Levenshtein distance, for suggestions
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ac) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, bc) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ac != *bc);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
         }
+        previous = current;
     }
+    previous[b.len()]
+}
+
+/// `~/.config/backpak.toml`
+pub fn default_path() -> Result<Utf8PathBuf> {
+    let mut c: Utf8PathBuf = home::home_dir()
+        .ok_or_else(|| anyhow!("Can't find home directory"))?
+        .try_into()
+        .context("Home directory isn't UTF-8")?;
+    c.extend([".config", "backpak.toml"]);
+    Ok(c)
 }
 
 pub fn load(p: Option<Utf8PathBuf>) -> Result<Configuration> {
@@ -42,14 +168,7 @@ pub fn load(p: Option<Utf8PathBuf>) -> Result<Configuration> {
                 Ok(p)
             }
         }
-        None => {
-            let mut c: Utf8PathBuf = home::home_dir()
-                .ok_or_else(|| anyhow!("Can't find home directory"))?
-                .try_into()
-                .context("Home directory isn't UTF-8")?;
-            c.extend([".config", "backpak.toml"]);
-            Ok(c)
-        }
+        None => default_path(),
     };
     let confpath = confpath?;
     let s = match fs::read_to_string(&confpath) {
@@ -57,6 +176,46 @@ pub fn load(p: Option<Utf8PathBuf>) -> Result<Configuration> {
         found => found,
     }
     .with_context(|| format!("Couldn't open {confpath}"))?;
-    let conf = toml::from_str(&s).with_context(|| format!("Couldn't parse {confpath}"))?;
+    let conf = Format::from_path(&confpath)?
+        .parse(&s)
+        .with_context(|| format!("Couldn't parse {confpath}"))?;
     Ok(conf)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(edit_distance("pakc_size", "pack_size"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+
+        assert_eq!(
+            suggest("unknown field `pakc_size`, expected one of `pack_size`, `backend`, `filter`")
+                .unwrap(),
+            "unknown key `pakc_size`, did you mean `pack_size`?"
+        );
+        assert_eq!(
+            suggest("unknown variant `Backblase`, expected `Filesystem` or `Backblaze`").unwrap(),
+            "unknown value `Backblase`, did you mean `Backblaze`?"
+        );
+        // Nothing close
+        assert_eq!(
+            suggest("unknown field `zzz`, expected `cache_size` or `skips`").unwrap(),
+            "unknown key `zzz`"
+        );
+        assert_eq!(suggest("invalid type: string, expected u32"), None);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let e = Format::Toml
+            .parse::<Configuration>("cache_sise = \"1 GB\"")
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "unknown key `cache_sise`, did you mean `cache_size`?"
+        );
+    }
+}
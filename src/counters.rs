@@ -1,5 +1,6 @@
 //! Performance counters: Count how many times we do various important operations.
 
+use std::collections::BTreeMap;
 use std::sync::{
     LazyLock,
     atomic::{AtomicUsize, Ordering, fence},
@@ -8,7 +9,7 @@ use std::sync::{
 use enum_map::{Enum, EnumMap};
 use tracing::*;
 
-#[derive(Debug, Copy, Clone, Enum)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Enum)]
 pub enum Op {
     SnapshotLoad,
     IndexLoad,
@@ -17,6 +18,7 @@ pub enum Op {
     BackendDelete,
     BackendCacheHit,
     BackendCacheSpill,
+    BackendListCacheHit,
     FileToBuffer,
     FileToMmap,
     TreeCacheHit,
@@ -24,6 +26,33 @@ pub enum Op {
     ChunkCacheHit,
     ChunkCacheMiss,
     PackRereads,
+    ManifestCacheHit,
+    ManifestCacheMiss,
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Op::SnapshotLoad => "snapshots loaded",
+            Op::IndexLoad => "indexes loaded",
+            Op::BackendRead => "backend reads",
+            Op::BackendWrite => "backend writes",
+            Op::BackendDelete => "backend delete (and cache evictions)",
+            Op::BackendCacheHit => "backend cache hits",
+            Op::BackendCacheSpill => "backend cache spills",
+            Op::BackendListCacheHit => "backend listings reused",
+            Op::FileToBuffer => "input files buffered",
+            Op::FileToMmap => "input files memory mapped",
+            Op::TreeCacheHit => "tree cache hits",
+            Op::TreeCacheMiss => "tree cache misses",
+            Op::ChunkCacheHit => "chunk cache hits",
+            Op::ChunkCacheMiss => "chunk cache misses",
+            Op::PackRereads => "packs reread",
+            Op::ManifestCacheHit => "pack manifest cache hits",
+            Op::ManifestCacheMiss => "pack manifest cache misses",
+        };
+        f.write_str(name)
+    }
 }
 
 static COUNTER_MAP: LazyLock<EnumMap<Op, AtomicUsize>> = LazyLock::new(EnumMap::default);
@@ -37,13 +66,45 @@ pub fn add(to: Op, amount: usize) {
     COUNTER_MAP[to].fetch_add(amount, Ordering::Relaxed);
 }
 
+/// A point-in-time copy of every counter,
+/// for programs embedding backpak that want to export them elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct CounterSnapshot {
+    pub counts: EnumMap<Op, usize>,
+}
+
+impl CounterSnapshot {
+    pub fn get(&self, op: Op) -> usize {
+        self.counts[op]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Op, usize)> + '_ {
+        self.counts.iter().map(|(k, v)| (k, *v))
+    }
+
+    /// Every counter, zeroes included (no cache hits can be telling!),
+    /// in a stable order for reports
+    pub fn to_map(&self) -> BTreeMap<Op, u64> {
+        self.iter().map(|(k, v)| (k, v as u64)).collect()
+    }
+}
+
+/// Read all current counter values.
+///
+/// Each is loaded individually (and relaxed), so counters bumped concurrently
+/// with this call may be slightly out of step with each other.
+pub fn snapshot() -> CounterSnapshot {
+    CounterSnapshot {
+        counts: EnumMap::from_fn(|op| COUNTER_MAP[op].load(Ordering::Relaxed)),
+    }
+}
+
 pub fn log_counts() {
     // Probably not needed; but we're probably calling this once at program exit.
     fence(Ordering::SeqCst);
 
-    let counts = COUNTER_MAP
+    let counts = snapshot()
         .iter()
-        .map(|(k, v)| (k, v.load(Ordering::Relaxed)))
         .filter(|(_k, v)| *v > 0) // Ignore things we didn't do
         .collect::<Vec<_>>();
 
@@ -51,25 +112,31 @@ pub fn log_counts() {
         return;
     }
 
-    let opname = |op| match op {
-        Op::SnapshotLoad => "snapshots loaded",
-        Op::IndexLoad => "indexes loaded",
-        Op::BackendRead => "backend reads",
-        Op::BackendWrite => "backend writes",
-        Op::BackendDelete => "backend delete (and cache evictions)",
-        Op::BackendCacheHit => "backend cache hits",
-        Op::BackendCacheSpill => "backend cache spills",
-        Op::FileToBuffer => "input files buffered",
-        Op::FileToMmap => "input files memory mapped",
-        Op::TreeCacheHit => "tree cache hits",
-        Op::TreeCacheMiss => "tree cache misses",
-        Op::ChunkCacheHit => "chunk cache hits",
-        Op::ChunkCacheMiss => "chunk cache misses",
-        Op::PackRereads => "packs reread",
-    };
-
     debug!("Counters:");
     for (op, count) in &counts {
-        debug!("{:6} {}", count, opname(*op),);
+        debug!("{:6} {}", count, op);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_sees_bumps() {
+        // Other tests run concurrently and bump counters too,
+        // so we can only say counts went up by at least our amount.
+        let before = snapshot().get(Op::PackRereads);
+        add(Op::PackRereads, 3);
+        let after = snapshot().get(Op::PackRereads);
+        assert!(after >= before + 3);
+    }
+
+    #[test]
+    fn map_has_everything() {
+        let map = snapshot().to_map();
+        assert_eq!(map.len(), Op::LENGTH);
+        assert_eq!(map.keys().next(), Some(&Op::SnapshotLoad));
+        assert_eq!(Op::BackendCacheHit.to_string(), "backend cache hits");
     }
 }
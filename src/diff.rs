@@ -1,15 +1,56 @@
 //! Diffs two trees and runs a set of callbacks for each difference.
+//!
+//! Callbacks can end the walk early by returning [`StopWalking`],
+//! which [`compare_trees`] and [`compare_nodes`] pass straight back up.
+//! Use [`walk_stopped`] to tell that apart from a real error.
+//!
+//! Trees come from fully-loaded [`Forest`]s, or with [`compare_trees_in`],
+//! can be read as the walk needs them (see [`Trees`]).
 
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::BTreeSet;
-use std::sync::LazyLock;
+use std::fmt;
+use std::sync::{Arc, LazyLock};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use camino::Utf8Path;
 
+use crate::filter::Glob;
 use crate::hashing::ObjectId;
-use crate::tree::{self, Forest, Node, NodeType, Tree};
+use crate::tree::{self, Forest, Node, NodeContents, NodeType, Tree};
+
+/// What [`compare_nodes`] checks for changes
+///
+/// Don't expect big speedups from turning either off:
+/// comparing contents is just comparing lists of chunk IDs
+/// (we never read file contents to diff), and comparing metadata is a struct comparison.
+/// Either way, we still walk into every directory whose subtree changed,
+/// since its ID changes when anything inside it does.
+/// The win is mostly in not making callbacks for changes you don't care about.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    /// Compare file contents and symlink targets, and notice type changes
+    pub contents: bool,
+    /// Compare permissions, ownership, times, etc.
+    pub metadata: bool,
+}
+
+impl Default for Comparison {
+    fn default() -> Self {
+        Self {
+            contents: true,
+            metadata: true,
+        }
+    }
+}
 
 pub trait Callbacks {
+    /// What to compare. Changes in anything else are ignored.
+    fn comparison(&self) -> Comparison {
+        Comparison::default()
+    }
+
     /// A tree node with the given path was added
     fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()>;
 
@@ -38,6 +79,27 @@ pub trait Callbacks {
         new_node: &Node,
     ) -> Result<()>;
 
+    /// Something somewhere under the given directory changed.
+    ///
+    /// Called once per directory, just before we descend into it
+    /// (and make other callbacks for what changed inside).
+    fn directory_changed(
+        &mut self,
+        _node_path: &Utf8Path,
+        _old_node: &Node,
+        _new_node: &Node,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Should we look at this node (and, if it's a directory, anything inside it)?
+    ///
+    /// Asked before every node the walk reaches; returning false prunes it.
+    /// See [`Filtered`].
+    fn should_visit(&mut self, _node_path: &Utf8Path, _node: &Node) -> bool {
+        true
+    }
+
     /// A node didn't change.
     fn nothing_changed(&mut self, _node_path: &Utf8Path, _node: &Node) -> Result<()> {
         Ok(())
@@ -61,6 +123,70 @@ pub trait Callbacks {
     }
 }
 
+/// Return this (as an error) from any [`Callbacks`] method to stop comparing trees.
+///
+/// It unwinds out of the recursion like any other error,
+/// so nothing past the current node gets visited.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StopWalking;
+
+impl fmt::Display for StopWalking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("tree comparison stopped early")
+    }
+}
+
+impl std::error::Error for StopWalking {}
+
+/// Given the result of [`compare_trees`] or [`compare_nodes`],
+/// return true if a callback stopped the walk early, false if it ran to completion,
+/// or any other error that happened along the way.
+pub fn walk_stopped(res: Result<()>) -> Result<bool> {
+    match res {
+        Ok(()) => Ok(false),
+        Err(e) if e.is::<StopWalking>() => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns true if the two trees differ at all, stopping at the first difference.
+///
+/// If `metadata` is false, only differences in contents count.
+pub fn any_differences(
+    first: (&ObjectId, &Forest),
+    second: (&ObjectId, &Forest),
+    metadata: bool,
+) -> Result<bool> {
+    let mut cb = FirstDifference { metadata };
+    walk_stopped(compare_trees(first, second, Utf8Path::new(""), &mut cb))
+}
+
+struct FirstDifference {
+    metadata: bool,
+}
+
+impl Callbacks for FirstDifference {
+    fn node_added(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+        Err(StopWalking.into())
+    }
+
+    fn node_removed(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+        Err(StopWalking.into())
+    }
+
+    fn contents_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+        Err(StopWalking.into())
+    }
+
+    fn metadata_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+        if self.metadata {
+            Err(StopWalking.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// Provide an empty forest and a ID to the empty tree.
 /// Useful for comparisons to nothing (e.g., the first snapshot's diff)
 pub fn null_forest() -> &'static (ObjectId, Forest) {
@@ -74,45 +200,140 @@ pub fn null_forest() -> &'static (ObjectId, Forest) {
     &NF
 }
 
+/// Reads a tree by its ID, usually `|id| cache.read(id)` for some [`tree::Cache`]
+pub type TreeLoader<'a> = dyn FnMut(&ObjectId) -> Result<Arc<Tree>> + 'a;
+
+/// Where a comparison gets its trees
+pub enum Trees<'a> {
+    /// A whole forest, already in memory (e.g., from [`tree::forest_from_root`])
+    Loaded(&'a Forest),
+    /// Read trees only as the walk reaches them,
+    /// so we never load the parts two snapshots have in common.
+    /// Both sides of a comparison can share a loader.
+    Lazy(&'a RefCell<TreeLoader<'a>>),
+}
+
+impl Trees<'_> {
+    fn tree(&self, id: &ObjectId) -> Result<Arc<Tree>> {
+        match self {
+            Trees::Loaded(forest) => forest
+                .get(id)
+                .cloned()
+                .ok_or_else(|| anyhow!("Missing tree {id}")),
+            Trees::Lazy(loader) => (loader.borrow_mut())(id),
+        }
+    }
+
+    /// A forest with everything under the given node,
+    /// for callbacks that want to walk what was added or removed.
+    fn forest_under(&self, node: &Node) -> Result<Cow<'_, Forest>> {
+        match (self, &node.contents) {
+            (Trees::Loaded(forest), _) => Ok(Cow::Borrowed(*forest)),
+            (Trees::Lazy(loader), NodeContents::Directory { subtree }) => Ok(Cow::Owned(
+                tree::forest_from_loader(subtree, &mut *loader.borrow_mut())?,
+            )),
+            (Trees::Lazy(_), _) => Ok(Cow::Owned(Forest::default())),
+        }
+    }
+}
+
 pub fn compare_trees(
     (id1, forest1): (&ObjectId, &Forest),
     (id2, forest2): (&ObjectId, &Forest),
     tree_path: &Utf8Path,
     callbacks: &mut dyn Callbacks,
 ) -> Result<()> {
-    let tree1: &Tree = forest1
-        .get(id1)
-        .unwrap_or_else(|| panic!("Missing tree {}", id1));
+    compare_trees_in(
+        (id1, &Trees::Loaded(forest1)),
+        (id2, &Trees::Loaded(forest2)),
+        tree_path,
+        callbacks,
+    )
+}
 
-    let tree2: &Tree = forest2
-        .get(id2)
-        .unwrap_or_else(|| panic!("Missing tree {}", id2));
+/// Like [`compare_trees`], but with trees from anywhere.
+///
+/// Callbacks see the same thing in the same order either way,
+/// save that a lazy side only hands them the forest under the node in question.
+pub fn compare_trees_in(
+    (id1, trees1): (&ObjectId, &Trees),
+    (id2, trees2): (&ObjectId, &Trees),
+    tree_path: &Utf8Path,
+    callbacks: &mut dyn Callbacks,
+) -> Result<()> {
+    let tree1 = trees1.tree(id1)?;
+
+    // Same tree, same everything beneath it; don't bother walking (or loading) it.
+    // Callers still hear about each entry, as if we'd compared them one by one.
+    if id1 == id2 {
+        for (path, node) in tree1.iter() {
+            let mut node_path = tree_path.to_owned();
+            node_path.push(path);
+            if callbacks.should_visit(&node_path, node) {
+                callbacks.nothing_changed(&node_path, node)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let tree2 = trees2.tree(id2)?;
 
     let all_paths = tree1.keys().chain(tree2.keys()).collect::<BTreeSet<_>>();
     for path in all_paths {
         let mut node_path = tree_path.to_owned();
         node_path.push(path);
-        match (tree1.get(path), tree2.get(path)) {
+        let (old, new) = (tree1.get(path), tree2.get(path));
+        if !callbacks.should_visit(&node_path, new.or(old).unwrap()) {
+            continue;
+        }
+        match (old, new) {
             (None, None) => unreachable!(),
-            (None, Some(new_node)) => callbacks.node_added(&node_path, new_node, forest2),
-            (Some(old_node), None) => callbacks.node_removed(&node_path, old_node, forest1),
-            (Some(l), Some(r)) => compare_nodes((l, forest1), (r, forest2), &node_path, callbacks),
+            (None, Some(new_node)) => {
+                let forest = trees2.forest_under(new_node)?;
+                callbacks.node_added(&node_path, new_node, &forest)
+            }
+            (Some(old_node), None) => {
+                let forest = trees1.forest_under(old_node)?;
+                callbacks.node_removed(&node_path, old_node, &forest)
+            }
+            (Some(l), Some(r)) => compare_nodes_in((l, trees1), (r, trees2), &node_path, callbacks),
         }?;
     }
     Ok(())
 }
 
+/// Compare two nodes at the same path, recursing into directories.
+///
+/// Directories with the same subtree ID are identical all the way down,
+/// so we never look at (or need the forests to contain) anything below them.
+/// That's what keeps diffing huge, mostly-unchanged snapshots fast.
 pub fn compare_nodes(
     (node1, forest1): (&Node, &Forest),
     (node2, forest2): (&Node, &Forest),
     path: &Utf8Path,
     callbacks: &mut dyn Callbacks,
 ) -> Result<()> {
+    compare_nodes_in(
+        (node1, &Trees::Loaded(forest1)),
+        (node2, &Trees::Loaded(forest2)),
+        path,
+        callbacks,
+    )
+}
+
+/// [`compare_nodes`] for [`compare_trees_in`]
+pub fn compare_nodes_in(
+    (node1, trees1): (&Node, &Trees),
+    (node2, trees2): (&Node, &Trees),
+    path: &Utf8Path,
+    callbacks: &mut dyn Callbacks,
+) -> Result<()> {
+    let cmp = callbacks.comparison();
     match (node1.kind(), node2.kind()) {
         (NodeType::File, NodeType::File) | (NodeType::Symlink, NodeType::Symlink) => {
-            if node1.contents != node2.contents {
+            if cmp.contents && node1.contents != node2.contents {
                 callbacks.contents_changed(path, node1, node2)
-            } else if node1.metadata != node2.metadata {
+            } else if cmp.metadata && !tree::same_metadata(&node1.metadata, &node2.metadata) {
                 // trace!("{:#?} != {:#?}", node1.metadata, node2.metadata);
                 callbacks.metadata_changed(path, node1, node2)
             } else {
@@ -121,17 +342,18 @@ pub fn compare_nodes(
         }
         (NodeType::Directory, NodeType::Directory) => {
             let mut changed = false;
-            // Both are directories
+            // Both are directories. Same subtree, same everything beneath it.
             if node1.contents != node2.contents {
-                compare_trees(
-                    (node1.contents.subtree(), forest1),
-                    (node2.contents.subtree(), forest2),
+                callbacks.directory_changed(path, node1, node2)?;
+                compare_trees_in(
+                    (node1.contents.subtree(), trees1),
+                    (node2.contents.subtree(), trees2),
                     path,
                     callbacks,
                 )?;
                 changed = true;
             }
-            if node1.metadata != node2.metadata {
+            if cmp.metadata && !tree::same_metadata(&node1.metadata, &node2.metadata) {
                 // trace!("{:#?} != {:#?}", node1.metadata, node2.metadata);
                 callbacks.metadata_changed(path, node1, node2)?;
                 changed = true;
@@ -141,6 +363,626 @@ pub fn compare_nodes(
             }
             Ok(())
         }
-        _ => callbacks.type_changed(path, node1, forest1, node2, forest2),
+        _ if cmp.contents => {
+            let forest1 = trees1.forest_under(node1)?;
+            let forest2 = trees2.forest_under(node2)?;
+            callbacks.type_changed(path, node1, &forest1, node2, &forest2)
+        }
+        _ => callbacks.nothing_changed(path, node2),
+    }
+}
+
+/// Which paths a [`Filtered`] comparison looks at
+#[derive(Debug, Default, Clone)]
+pub struct PathFilter {
+    /// If any, only paths matching these (and what's inside them)
+    pub includes: Vec<Glob>,
+    /// Never paths matching these (or anything inside them)
+    pub excludes: Vec<Glob>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Visit {
+    /// Not this or anything in it
+    Skip,
+    /// Not this directory, but maybe something inside it
+    Inside,
+    /// This, and whatever's inside it that isn't excluded
+    Whole,
+}
+
+impl PathFilter {
+    fn visit(&self, path: &Utf8Path, node: &Node) -> Visit {
+        if self.excludes.iter().any(|g| g.is_match(path)) {
+            return Visit::Skip;
+        }
+        if self.includes.is_empty()
+            || path
+                .ancestors()
+                .any(|a| self.includes.iter().any(|g| g.is_match(a)))
+        {
+            return Visit::Whole;
+        }
+        if node.kind() == NodeType::Directory
+            && self.includes.iter().any(|g| g.could_match_inside(path))
+        {
+            return Visit::Inside;
+        }
+        Visit::Skip
+    }
+}
+
+/// Wraps a set of callbacks so they only hear about paths a [`PathFilter`] lets through.
+///
+/// Skipped directories aren't walked at all.
+/// Directories we only want part of (ones that don't match an include
+/// but might have something inside that does, or that have something excluded inside)
+/// are broken up into their contents.
+pub struct Filtered<'a> {
+    pub filter: &'a PathFilter,
+    pub inner: &'a mut dyn Callbacks,
+}
+
+impl Filtered<'_> {
+    /// Add or remove a node, splitting up directories if we don't want everything in them.
+    fn added_or_removed(
+        &mut self,
+        node_path: &Utf8Path,
+        node: &Node,
+        forest: &Forest,
+        added: bool,
+    ) -> Result<()> {
+        let visit = self.filter.visit(node_path, node);
+        let NodeContents::Directory { subtree } = &node.contents else {
+            return match (visit, added) {
+                (Visit::Skip | Visit::Inside, _) => Ok(()),
+                (Visit::Whole, true) => self.inner.node_added(node_path, node, forest),
+                (Visit::Whole, false) => self.inner.node_removed(node_path, node, forest),
+            };
+        };
+        if visit == Visit::Skip {
+            return Ok(());
+        }
+        // Easy case: all of it.
+        if visit == Visit::Whole && self.filter.excludes.is_empty() {
+            return if added {
+                self.inner.node_added(node_path, node, forest)
+            } else {
+                self.inner.node_removed(node_path, node, forest)
+            };
+        }
+        if visit == Visit::Whole {
+            // Just the directory itself: hand the callbacks a forest where it's empty.
+            let mut just_this = Forest::default();
+            just_this.insert(*subtree, Arc::new(Tree::new()));
+            if added {
+                self.inner.node_added(node_path, node, &just_this)?;
+            } else {
+                self.inner.node_removed(node_path, node, &just_this)?;
+            }
+        }
+        let tree = forest
+            .get(subtree)
+            .ok_or_else(|| anyhow!("Missing tree {subtree}"))?;
+        for (path, child) in tree.iter() {
+            let mut child_path = node_path.to_owned();
+            child_path.push(path);
+            self.added_or_removed(&child_path, child, forest, added)?;
+        }
+        Ok(())
+    }
+
+    fn wants_whole(&self, node_path: &Utf8Path, node: &Node) -> bool {
+        self.filter.visit(node_path, node) == Visit::Whole
+    }
+}
+
+impl Callbacks for Filtered<'_> {
+    fn comparison(&self) -> Comparison {
+        self.inner.comparison()
+    }
+
+    fn should_visit(&mut self, node_path: &Utf8Path, node: &Node) -> bool {
+        self.filter.visit(node_path, node) != Visit::Skip
+            && self.inner.should_visit(node_path, node)
+    }
+
+    fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
+        self.added_or_removed(node_path, new_node, forest, true)
+    }
+
+    fn node_removed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        forest: &Forest,
+    ) -> Result<()> {
+        self.added_or_removed(node_path, old_node, forest, false)
+    }
+
+    fn contents_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        if self.wants_whole(node_path, new_node) {
+            self.inner.contents_changed(node_path, old_node, new_node)?;
+        }
+        Ok(())
+    }
+
+    fn metadata_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        if self.wants_whole(node_path, new_node) {
+            self.inner.metadata_changed(node_path, old_node, new_node)?;
+        }
+        Ok(())
+    }
+
+    fn directory_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        if self.wants_whole(node_path, new_node) {
+            self.inner
+                .directory_changed(node_path, old_node, new_node)?;
+        }
+        Ok(())
+    }
+
+    fn nothing_changed(&mut self, node_path: &Utf8Path, node: &Node) -> Result<()> {
+        if self.wants_whole(node_path, node) {
+            self.inner.nothing_changed(node_path, node)?;
+        }
+        Ok(())
+    }
+
+    fn type_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        old_forest: &Forest,
+        new_node: &Node,
+        new_forest: &Forest,
+    ) -> Result<()> {
+        if self.wants_whole(node_path, old_node)
+            && self.wants_whole(node_path, new_node)
+            && self.filter.excludes.is_empty()
+        {
+            self.inner
+                .type_changed(node_path, old_node, old_forest, new_node, new_forest)
+        } else {
+            self.node_removed(node_path, old_node, old_forest)?;
+            self.node_added(node_path, new_node, new_forest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use camino::Utf8PathBuf;
+
+    use crate::tree::{NodeContents, NodeMetadata, PosixMetadata};
+
+    fn file(contents: &[u8], mtime: &str) -> Node {
+        Node {
+            contents: NodeContents::File {
+                chunks: vec![ObjectId::hash(contents)],
+            },
+            metadata: NodeMetadata::Posix(PosixMetadata {
+                mode: 0o100644,
+                size: Some(contents.len() as u64),
+                user_id: 1000,
+                group_id: 1000,
+                access_time: mtime.parse().unwrap(),
+                modify_time: mtime.parse().unwrap(),
+                birth_time: None,
+            }),
+        }
+    }
+
+    fn forest_of(files: &[(&str, Node)]) -> (ObjectId, Forest) {
+        let tree: Tree = files
+            .iter()
+            .map(|(p, n)| (Utf8PathBuf::from(p), n.clone()))
+            .collect();
+        let (_, id) = tree::serialize_and_hash(&tree).unwrap();
+        let mut forest = Forest::default();
+        forest.insert(id, Arc::new(tree));
+        (id, forest)
+    }
+
+    fn dir(forest: &mut Forest, children: &[(&str, Node)]) -> Node {
+        let tree: Tree = children
+            .iter()
+            .map(|(p, n)| (Utf8PathBuf::from(p), n.clone()))
+            .collect();
+        let (_, subtree) = tree::serialize_and_hash(&tree).unwrap();
+        forest.insert(subtree, Arc::new(tree));
+        Node {
+            contents: NodeContents::Directory { subtree },
+            metadata: NodeMetadata::Posix(PosixMetadata {
+                mode: 0o040755,
+                size: None,
+                user_id: 1000,
+                group_id: 1000,
+                access_time: T1.parse().unwrap(),
+                modify_time: T1.parse().unwrap(),
+                birth_time: None,
+            }),
+        }
+    }
+
+    /// Counts changes and stops after the first one.
+    #[derive(Default)]
+    struct StopAtFirst {
+        seen: usize,
+    }
+
+    impl Callbacks for StopAtFirst {
+        fn node_added(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            self.seen += 1;
+            Err(StopWalking.into())
+        }
+
+        fn node_removed(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            self.seen += 1;
+            Err(StopWalking.into())
+        }
+
+        fn contents_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.seen += 1;
+            Err(StopWalking.into())
+        }
+
+        fn metadata_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.seen += 1;
+            Err(StopWalking.into())
+        }
+    }
+
+    const T1: &str = "2020-10-30T06:30:25Z";
+    const T2: &str = "2021-10-30T06:30:25Z";
+
+    #[test]
+    fn stop_early() -> Result<()> {
+        let (id1, f1) = forest_of(&[("a", file(b"a", T1)), ("b", file(b"b", T1))]);
+        let (id2, f2) = forest_of(&[("a", file(b"A", T1)), ("b", file(b"B", T1))]);
+
+        let mut cb = StopAtFirst::default();
+        let res = compare_trees((&id1, &f1), (&id2, &f2), Utf8Path::new(""), &mut cb);
+        assert!(walk_stopped(res)?);
+        assert_eq!(cb.seen, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn differences() -> Result<()> {
+        let (id1, f1) = forest_of(&[("a", file(b"a", T1))]);
+        let (meta_id, meta_f) = forest_of(&[("a", file(b"a", T2))]);
+        let (new_id, new_f) = forest_of(&[("a", file(b"a", T1)), ("b", file(b"b", T1))]);
+
+        assert!(!any_differences((&id1, &f1), (&id1, &f1), true)?);
+        assert!(!any_differences((&id1, &f1), (&meta_id, &meta_f), false)?);
+        assert!(any_differences((&id1, &f1), (&meta_id, &meta_f), true)?);
+        assert!(any_differences((&id1, &f1), (&new_id, &new_f), false)?);
+        Ok(())
+    }
+
+    /// Records which directories had changes in them.
+    #[derive(Default)]
+    struct ChangedDirs {
+        dirs: Vec<String>,
+    }
+
+    impl Callbacks for ChangedDirs {
+        fn node_added(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            Ok(())
+        }
+
+        fn node_removed(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            Ok(())
+        }
+
+        fn contents_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            Ok(())
+        }
+
+        fn metadata_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            Ok(())
+        }
+
+        fn directory_changed(&mut self, p: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.dirs.push(p.to_string());
+            Ok(())
+        }
+    }
+
+    /// Counts contents and metadata changes, comparing only what it's told to.
+    struct CountChanges {
+        comparison: Comparison,
+        contents: usize,
+        metadata: usize,
+    }
+
+    impl CountChanges {
+        fn new(contents: bool, metadata: bool) -> Self {
+            Self {
+                comparison: Comparison { contents, metadata },
+                contents: 0,
+                metadata: 0,
+            }
+        }
+    }
+
+    impl Callbacks for CountChanges {
+        fn comparison(&self) -> Comparison {
+            self.comparison
+        }
+
+        fn node_added(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            Ok(())
+        }
+
+        fn node_removed(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            Ok(())
+        }
+
+        fn contents_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.contents += 1;
+            Ok(())
+        }
+
+        fn metadata_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.metadata += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn comparison_dimensions() -> Result<()> {
+        let (id1, f1) = forest_of(&[("a", file(b"a", T1)), ("b", file(b"b", T1))]);
+        // a's contents changed, b's times changed.
+        let (id2, f2) = forest_of(&[("a", file(b"A", T1)), ("b", file(b"b", T2))]);
+
+        let count = |contents, metadata| -> Result<(usize, usize)> {
+            let mut cb = CountChanges::new(contents, metadata);
+            compare_trees((&id1, &f1), (&id2, &f2), Utf8Path::new(""), &mut cb)?;
+            Ok((cb.contents, cb.metadata))
+        };
+        assert_eq!(count(true, true)?, (1, 1));
+        assert_eq!(count(true, false)?, (1, 0));
+        assert_eq!(count(false, true)?, (0, 1));
+        assert_eq!(count(false, false)?, (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn changed_directories() -> Result<()> {
+        let build = |f: &[u8]| {
+            let mut forest = Forest::default();
+            let b = dir(&mut forest, &[("f", file(f, T1))]);
+            let a = dir(&mut forest, &[("b", b)]);
+            let same = dir(&mut forest, &[("g", file(b"g", T1))]);
+            let root = dir(&mut forest, &[("a", a), ("same", same)]);
+            (*root.contents.subtree(), forest)
+        };
+        let (id1, f1) = build(b"before");
+        let (id2, f2) = build(b"after");
+
+        let mut cb = ChangedDirs::default();
+        compare_trees((&id1, &f1), (&id2, &f2), Utf8Path::new(""), &mut cb)?;
+        assert_eq!(cb.dirs, ["a", "a/b"]);
+        Ok(())
+    }
+
+    /// Records every path the walk reports on.
+    #[derive(Default)]
+    struct Visited {
+        paths: Vec<String>,
+    }
+
+    impl Callbacks for Visited {
+        fn node_added(&mut self, p: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            self.paths.push(p.to_string());
+            Ok(())
+        }
+
+        fn node_removed(&mut self, p: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+            self.paths.push(p.to_string());
+            Ok(())
+        }
+
+        fn contents_changed(&mut self, p: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.paths.push(p.to_string());
+            Ok(())
+        }
+
+        fn metadata_changed(&mut self, p: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.paths.push(p.to_string());
+            Ok(())
+        }
+
+        fn nothing_changed(&mut self, p: &Utf8Path, _: &Node) -> Result<()> {
+            self.paths.push(p.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn identical_subtrees_are_skipped() -> Result<()> {
+        let build = |f: &[u8]| {
+            let mut forest = Forest::default();
+            let big = dir(&mut forest, &[("deep", file(b"deep", T1))]);
+            let same = dir(&mut forest, &[("big", big.clone())]);
+            // Drop everything under the shared directory from the forest.
+            // If the walk goes looking for it, it'll panic.
+            forest.remove(same.contents.subtree());
+            forest.remove(big.contents.subtree());
+            let root = dir(&mut forest, &[("f", file(f, T1)), ("same", same)]);
+            (*root.contents.subtree(), forest)
+        };
+        let (id1, f1) = build(b"before");
+        let (id2, f2) = build(b"after");
+
+        let mut cb = Visited::default();
+        compare_trees((&id1, &f1), (&id2, &f2), Utf8Path::new(""), &mut cb)?;
+        assert_eq!(cb.paths, ["f", "same"]);
+        Ok(())
+    }
+
+    /// Records every callback, including how much of a forest
+    /// added and removed directories come with.
+    #[derive(Default)]
+    struct Events {
+        events: Vec<String>,
+    }
+
+    fn nodes_under(node: &Node, forest: &Forest) -> usize {
+        match &node.contents {
+            NodeContents::Directory { subtree } => forest[subtree]
+                .values()
+                .map(|n| 1 + nodes_under(n, forest))
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    impl Callbacks for Events {
+        fn node_added(&mut self, p: &Utf8Path, n: &Node, f: &Forest) -> Result<()> {
+            self.events.push(format!("+ {p} ({})", nodes_under(n, f)));
+            Ok(())
+        }
+
+        fn node_removed(&mut self, p: &Utf8Path, n: &Node, f: &Forest) -> Result<()> {
+            self.events.push(format!("- {p} ({})", nodes_under(n, f)));
+            Ok(())
+        }
+
+        fn contents_changed(&mut self, p: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.events.push(format!("C {p}"));
+            Ok(())
+        }
+
+        fn metadata_changed(&mut self, p: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.events.push(format!("M {p}"));
+            Ok(())
+        }
+
+        fn directory_changed(&mut self, p: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+            self.events.push(format!("D {p}"));
+            Ok(())
+        }
+
+        fn nothing_changed(&mut self, p: &Utf8Path, _: &Node) -> Result<()> {
+            self.events.push(format!("= {p}"));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lazy_matches_eager() -> Result<()> {
+        let mut forest1 = Forest::default();
+        let mut forest2 = Forest::default();
+
+        let big = dir(&mut forest1, &[("deep", file(b"deep", T1))]);
+        let same = dir(&mut forest1, &[("big", big.clone())]);
+        dir(&mut forest2, &[("deep", file(b"deep", T1))]);
+        dir(&mut forest2, &[("big", big)]);
+
+        let gone = dir(&mut forest1, &[("x", file(b"x", T1))]);
+        let new = dir(
+            &mut forest2,
+            &[("y", file(b"y", T1)), ("z", file(b"z", T1))],
+        );
+        let changed1 = dir(&mut forest1, &[("f", file(b"f", T1))]);
+        let changed2 = dir(&mut forest2, &[("f", file(b"f", T2))]);
+        let was_file = dir(&mut forest2, &[("w", file(b"w", T1))]);
+
+        let root1 = dir(
+            &mut forest1,
+            &[
+                ("a", file(b"a", T1)),
+                ("changed", changed1),
+                ("gone", gone),
+                ("same", same.clone()),
+                ("t", file(b"t", T1)),
+            ],
+        );
+        let root2 = dir(
+            &mut forest2,
+            &[
+                ("a", file(b"A", T1)),
+                ("changed", changed2),
+                ("new", new),
+                ("same", same.clone()),
+                ("t", was_file),
+            ],
+        );
+        let (id1, id2) = (root1.contents.subtree(), root2.contents.subtree());
+
+        let mut eager = Events::default();
+        compare_trees(
+            (id1, &forest1),
+            (id2, &forest2),
+            Utf8Path::new(""),
+            &mut eager,
+        )?;
+
+        let mut everything = forest1.clone();
+        everything.extend(forest2.clone());
+        let mut loaded = vec![];
+        let loader = RefCell::new(|id: &ObjectId| {
+            loaded.push(*id);
+            everything
+                .get(id)
+                .cloned()
+                .ok_or_else(|| anyhow!("No tree {id}"))
+        });
+        let trees = Trees::Lazy(&loader);
+        let mut lazy = Events::default();
+        compare_trees_in((id1, &trees), (id2, &trees), Utf8Path::new(""), &mut lazy)?;
+
+        assert_eq!(eager.events, lazy.events);
+        assert!(eager.events.contains(&"+ t (1)".to_owned()));
+        // We never needed what the two had in common.
+        assert!(!loaded.contains(same.contents.subtree()));
+        Ok(())
+    }
+
+    #[test]
+    fn identical_roots_are_skipped() -> Result<()> {
+        let mut forest = Forest::default();
+        let sub = dir(&mut forest, &[("deep", file(b"deep", T1))]);
+        let root = dir(&mut forest, &[("f", file(b"f", T1)), ("sub", sub)]);
+        let id = *root.contents.subtree();
+
+        let mut loaded = vec![];
+        let loader = RefCell::new(|id: &ObjectId| {
+            loaded.push(*id);
+            forest
+                .get(id)
+                .cloned()
+                .ok_or_else(|| anyhow!("No tree {id}"))
+        });
+        let trees = Trees::Lazy(&loader);
+        let mut cb = Visited::default();
+        compare_trees_in((&id, &trees), (&id, &trees), Utf8Path::new(""), &mut cb)?;
+
+        // Everything at the top is unchanged, and we didn't look any further.
+        assert_eq!(cb.paths, ["f", "sub"]);
+        assert_eq!(loaded, [id]);
+        Ok(())
     }
 }
@@ -60,6 +60,47 @@ pub fn read_file(path: &Utf8Path) -> Result<Arc<LoadedFile>> {
     Ok(Arc::new(file))
 }
 
+/// Is `path` the very file `fh` has open?
+///
+/// Lets code that's handed a file and its name check that the name
+/// isn't just a coincidence before doing anything drastic to it.
+#[cfg(unix)]
+pub fn is_same_file(path: &Utf8Path, fh: &File) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let on_disk = match std::fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Couldn't stat {path}")),
+    };
+    let opened = fh.metadata()?;
+    Ok(on_disk.dev() == opened.dev() && on_disk.ino() == opened.ino())
+}
+
+/// Is `path` the very file `fh` has open?
+///
+/// Windows doesn't give us file IDs on stable Rust, so take its existence as a yes.
+#[cfg(windows)]
+pub fn is_same_file(path: &Utf8Path, _fh: &File) -> Result<bool> {
+    Ok(path.exists())
+}
+
+/// The ID of the filesystem holding `path`, and how many bytes we can write to it
+#[cfg(unix)]
+pub fn free_space(path: &Utf8Path) -> Result<Option<(u64, u64)>> {
+    let stat = rustix::fs::statvfs(path.as_std_path())
+        .with_context(|| format!("Couldn't get free space for {path}"))?;
+    Ok(Some((stat.f_fsid, stat.f_bavail * stat.f_frsize)))
+}
+
+/// The ID of the filesystem holding `path`, and how many bytes we can write to it
+///
+/// We don't ask Windows, so callers should skip their checks.
+#[cfg(windows)]
+pub fn free_space(_path: &Utf8Path) -> Result<Option<(u64, u64)>> {
+    Ok(None)
+}
+
 /// Move the given file `from -> to`, renaming if possible.
 ///
 /// If a rename isn't possible, write out a copy.
@@ -141,16 +182,163 @@ pub fn safe_copy_to_file<R: Read>(mut from: R, to: &Utf8Path) -> Result<File> {
     Ok(persisted)
 }
 
+/// Creates `to` from the reader, all or nothing: either `to` ends up with everything
+/// (synced to disk), or it doesn't exist.
+///
+/// Like [`safe_copy_to_file()`], we write to a temporary file next to `to` and rename it,
+/// but we sync _before_ the rename (so a crash can't leave a renamed but empty file),
+/// and we won't replace a file that's already there.
+/// Meant for small, precious things like repository configs.
+pub fn safe_create_file<R: Read>(mut from: R, to: &Utf8Path) -> Result<()> {
+    let dir = match to.parent() {
+        Some(p) if !p.as_str().is_empty() => p,
+        _ => Utf8Path::new("."),
+    };
+    let pre = to.file_name().unwrap().to_owned() + ".";
+    let mut to_fh = tempfile::Builder::new()
+        .prefix(&pre)
+        .suffix(".part")
+        .tempfile_in(dir)
+        .with_context(|| format!("Couldn't open temporary {to}.part"))?;
+    let temp_path = format!("{}", to_fh.path().display());
+
+    // If anything here fails, dropping to_fh deletes the temp file.
+    std::io::copy(&mut from, &mut to_fh)
+        .with_context(|| format!("Couldn't write to {temp_path}"))?;
+    to_fh
+        .as_file()
+        .sync_all()
+        .with_context(|| format!("Couldn't sync {temp_path}"))?;
+    to_fh
+        .persist_noclobber(to)
+        .with_context(|| format!("Couldn't persist {temp_path} to {to}"))?;
+
+    // Make the rename itself durable.
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .with_context(|| format!("Couldn't sync {dir}"))?;
+    Ok(())
+}
+
 /// File size but nice.
 pub fn nice_size(s: u64) -> String {
+    nice_size_in(s, byte_unit::UnitType::Decimal) // Human units please.
+}
+
+fn nice_size_in(s: u64, units: byte_unit::UnitType) -> String {
     use byte_unit::Unit::*;
     use byte_unit::*;
 
     let b = Byte::from_u64(s);
-    let a = b.get_appropriate_unit(UnitType::Decimal); // Human units please.
+    let a = b.get_appropriate_unit(units);
     match a.get_unit() {
         // Don't split hairs, or KB.
         Bit | B | Kbit | Kibit | KB | KiB => format!("{a:.0}"),
         _ => format!("{a:.2}"),
     }
 }
+
+/// How sizes in end-of-run summaries are printed.
+///
+/// Progress and log lines always use [`nice_size`] to stay terse,
+/// but folks doing capacity planning want to know if "100 MB" means
+/// 100,000,000 or 104,857,600 bytes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum SizeUnits {
+    /// Powers of 1000 (kB, MB, GB...)
+    #[default]
+    Si,
+    /// Powers of 1024 (KiB, MiB, GiB...)
+    Iec,
+    /// Both, e.g., "95.37 MiB (100.00 MB)"
+    Both,
+}
+
+static mut SIZE_UNITS: SizeUnits = SizeUnits::Si;
+
+/// Set the units used by [`summary_size`].
+///
+/// # Safety
+/// Like [`prettify_serialize()`](crate::prettify::prettify_serialize),
+/// this should be called once at program start before threads get spun up.
+pub unsafe fn set_size_units(u: SizeUnits) {
+    unsafe {
+        SIZE_UNITS = u;
+    }
+}
+
+/// File size for summary lines, in the units chosen with [`set_size_units`].
+pub fn summary_size(s: u64) -> String {
+    // SAFETY: Callers are trusted to call `set_size_units()`
+    // once, at the start of a run, before threads are spun up.
+    let units = unsafe { SIZE_UNITS };
+    summary_size_in(s, units)
+}
+
+fn summary_size_in(s: u64, units: SizeUnits) -> String {
+    use byte_unit::UnitType;
+
+    match units {
+        SizeUnits::Si => nice_size_in(s, UnitType::Decimal),
+        SizeUnits::Iec => nice_size_in(s, UnitType::Binary),
+        SizeUnits::Both => {
+            let iec = nice_size_in(s, UnitType::Binary);
+            let si = nice_size_in(s, UnitType::Decimal);
+            // No need to say "1 B (1 B)"
+            if iec == si {
+                iec
+            } else {
+                format!("{iec} ({si})")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summary_units() {
+        let hundred_megs = 100 * 1000 * 1000;
+        assert_eq!(summary_size_in(hundred_megs, SizeUnits::Si), "100.00 MB");
+        assert_eq!(summary_size_in(hundred_megs, SizeUnits::Iec), "95.37 MiB");
+        assert_eq!(
+            summary_size_in(hundred_megs, SizeUnits::Both),
+            "95.37 MiB (100.00 MB)"
+        );
+        assert_eq!(summary_size_in(12, SizeUnits::Both), "12 B");
+    }
+
+    #[test]
+    fn create_all_or_nothing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let to = dir.join("config.toml");
+
+        /// Gets partway through, then the power goes out.
+        struct Interrupted(bool);
+        impl Read for Interrupted {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0 {
+                    return Err(std::io::Error::other("power's out"));
+                }
+                self.0 = true;
+                buf[..4].copy_from_slice(b"pack");
+                Ok(4)
+            }
+        }
+        assert!(safe_create_file(Interrupted(false), &to).is_err());
+        // No truncated config, and no leftovers.
+        assert!(!to.exists());
+        assert_eq!(std::fs::read_dir(dir)?.count(), 0);
+
+        safe_create_file(&b"pack_size = 42"[..], &to)?;
+        assert_eq!(std::fs::read_to_string(&to)?, "pack_size = 42");
+        // Don't trample what's there.
+        assert!(safe_create_file(&b"oops"[..], &to).is_err());
+        assert_eq!(std::fs::read_to_string(&to)?, "pack_size = 42");
+        Ok(())
+    }
+}
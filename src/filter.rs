@@ -1,10 +1,387 @@
 use anyhow::{Context, Result};
 use camino::Utf8Path;
-use regex::RegexSet;
+use regex::Regex;
+use rustc_hash::FxHashSet;
 
+use crate::tree::{Node, NodeType};
+
+/// A set of skip rules, as given to `backup --skip`, put in a config's `skips`, etc.
+///
+/// Each rule is one of:
+///
+/// - A regular expression, matched against the whole path (absolute for backups).
+///   `/target$` skips anything named `target`; `\.log$` anything ending in `.log`.
+///
+/// - `glob:` and a gitignore-style [`Glob`]. A glob without a `/` matches a name anywhere
+///   (`glob:*.log`); one with a `/` matches from the root (`glob:/home/*/Downloads`),
+///   so use `**/` for any number of directories (`glob:**/node_modules/.cache`).
+///   A trailing `/` only matches directories (`glob:target/`).
+///
+/// Any rule starting with `!` un-skips what it matches instead.
+/// Like .gitignore, later rules win (`glob:*.log`, then `!glob:keep.log`),
+/// and nothing inside a skipped directory can be un-skipped, since we never look inside.
+#[derive(Debug, Clone)]
+pub struct Skips {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    matcher: Matcher,
+    negated: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Regex(Regex),
+    Glob { glob: Glob, dirs_only: bool },
+}
+
+impl Rule {
+    fn new(rule: &str) -> Result<Self> {
+        let (negated, rule) = match rule.strip_prefix('!') {
+            Some(r) => (true, r),
+            None => (false, rule),
+        };
+        let matcher = match rule.strip_prefix("glob:") {
+            Some(g) => {
+                let dirs_only = g.len() > 1 && g.ends_with('/');
+                let g = if dirs_only { &g[..g.len() - 1] } else { g };
+                Matcher::Glob {
+                    glob: Glob::new(g)?,
+                    dirs_only,
+                }
+            }
+            None => Matcher::Regex(
+                Regex::new(rule).with_context(|| format!("Skip rule {rule} is not valid regex"))?,
+            ),
+        };
+        Ok(Self { matcher, negated })
+    }
+
+    fn is_match(&self, path: &Utf8Path, is_dir: &mut dyn FnMut() -> bool) -> bool {
+        match &self.matcher {
+            Matcher::Regex(r) => r.is_match(path.as_str()),
+            Matcher::Glob { glob, dirs_only } => {
+                // Globs match from the root without a leading slash.
+                let relative = Utf8Path::new(path.as_str().trim_start_matches('/'));
+                glob.is_match(relative) && (!dirs_only || is_dir())
+            }
+        }
+    }
+}
+
+impl Skips {
+    pub fn new<S: AsRef<str>>(rules: &[S]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|r| Rule::new(r.as_ref()))
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Should we keep the given path?
+    ///
+    /// `is_dir` is only asked if a directory-only rule would otherwise match.
+    pub fn keep(&self, path: &Utf8Path, is_dir: impl FnOnce() -> bool) -> bool {
+        // Ask at most once.
+        let mut is_dir = Some(is_dir);
+        let mut dir = None;
+        let mut check_dir = || *dir.get_or_insert_with(|| is_dir.take().unwrap()());
+        match self
+            .rules
+            .iter()
+            .rev()
+            .find(|r| r.is_match(path, &mut check_dir))
+        {
+            Some(rule) => rule.negated,
+            None => true,
+        }
+    }
+}
+
+/// Turn a gitignore-style glob (maybe with a leading `!`) into a skip rule.
+pub fn glob_rule(glob: &str) -> String {
+    match glob.strip_prefix('!') {
+        Some(g) => format!("!glob:{g}"),
+        None => format!("glob:{glob}"),
+    }
+}
+
+/// Put skip rules from the command line after the config's,
+/// keeping only the last copy of any rule given more than once.
+///
+/// Since the last matching rule wins, earlier copies never decide anything,
+/// and dropping them keeps `--skip` from undoing a later `!` rule.
+pub fn merge_skips(config: Vec<String>, args: Vec<String>) -> Vec<String> {
+    let mut seen = FxHashSet::default();
+    let mut merged: Vec<String> = config
+        .into_iter()
+        .chain(args)
+        .rev()
+        .filter(|r| seen.insert(r.clone()))
+        .collect();
+    merged.reverse();
+    merged
+}
+
+/// Filter paths on disk with the given [`Skips`] rules; true means keep.
 pub fn skip_matching_paths(skips: &[String]) -> Result<impl Fn(&Utf8Path) -> bool> {
-    let skipset = RegexSet::new(skips).context("Skip rules are not valid regex")?;
+    let skips = Skips::new(skips)?;
+    let filter = move |path: &Utf8Path| {
+        skips.keep(path, || {
+            std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir())
+        })
+    };
+    Ok(filter)
+}
 
-    let filter = move |path: &Utf8Path| !skipset.is_match(path.as_str());
+/// Filter the nodes of a snapshot with the given [`Skips`] rules; true means keep.
+pub fn skip_matching_nodes(skips: &[String]) -> Result<impl Fn(&Utf8Path, &Node) -> bool> {
+    let skips = Skips::new(skips)?;
+    let filter =
+        move |path: &Utf8Path, node: &Node| skips.keep(path, || node.kind() == NodeType::Directory);
     Ok(filter)
 }
+
+/// Read skip rules from a file, one per line, ignoring blank lines and `#` comments.
+pub fn read_patterns(p: &Utf8Path) -> Result<Vec<String>> {
+    let s =
+        std::fs::read_to_string(p).with_context(|| format!("Couldn't read patterns from {p}"))?;
+    Ok(parse_patterns(&s))
+}
+
+fn parse_patterns(s: &str) -> Vec<String> {
+    s.lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// A shell-style glob, matched against relative paths:
+///
+/// - `*` matches anything but `/`, and `?` any one character but `/`
+/// - `**` matches anything, including `/`, and `**/` matches any number of directories
+/// - `[...]` is a character class, just like in a regex
+///
+/// Like .gitignore, a glob without a `/` matches the name of a file or directory anywhere;
+/// one with a `/` matches from the root.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    whole: Regex,
+    /// Each `/`-separated part, for seeing if a directory could contain a match.
+    /// `None` for `**`.
+    components: Vec<Option<Regex>>,
+    anywhere: bool,
+}
+
+/// Turns a glob (or part of one) into an unanchored regex.
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re
+}
+
+fn anchored(re: &str) -> Result<Regex> {
+    Regex::new(&format!("^(?:{re})$")).map_err(Into::into)
+}
+
+impl Glob {
+    pub fn new(glob: &str) -> Result<Self> {
+        let trimmed = glob.trim_start_matches('/').trim_end_matches('/');
+        let anywhere = !glob.contains('/');
+        let components = trimmed
+            .split('/')
+            .map(|c| {
+                if c == "**" {
+                    Ok(None)
+                } else {
+                    anchored(&glob_to_regex(c)).map(Some)
+                }
+            })
+            .collect::<Result<_>>()
+            .with_context(|| format!("{glob} is not a valid glob"))?;
+        let whole = anchored(&glob_to_regex(trimmed))
+            .with_context(|| format!("{glob} is not a valid glob"))?;
+        Ok(Self {
+            whole,
+            components,
+            anywhere,
+        })
+    }
+
+    /// Does the glob match this path?
+    pub fn is_match(&self, path: &Utf8Path) -> bool {
+        if self.anywhere {
+            path.file_name().is_some_and(|n| self.whole.is_match(n))
+        } else {
+            self.whole.is_match(path.as_str())
+        }
+    }
+
+    /// Could the glob match something inside this directory?
+    pub fn could_match_inside(&self, dir: &Utf8Path) -> bool {
+        if self.anywhere {
+            return true;
+        }
+        let mut dir = dir.components();
+        for component in &self.components {
+            let Some(component) = component else {
+                return true; // ** matches whatever's left.
+            };
+            match dir.next() {
+                Some(d) => {
+                    if !component.is_match(d.as_str()) {
+                        return false;
+                    }
+                }
+                // The directory is a prefix of the glob.
+                None => return true,
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn globs() -> Result<()> {
+        let p = Utf8Path::new;
+
+        let rs = Glob::new("*.rs")?;
+        assert!(rs.is_match(p("main.rs")));
+        assert!(rs.is_match(p("src/ui/diff.rs")));
+        assert!(!rs.is_match(p("src/ui")));
+        assert!(rs.could_match_inside(p("anywhere/at/all")));
+
+        let src = Glob::new("crate/src/*.rs")?;
+        assert!(src.is_match(p("crate/src/lib.rs")));
+        assert!(!src.is_match(p("crate/src/ui/diff.rs")));
+        assert!(!src.is_match(p("other/crate/src/lib.rs")));
+        assert!(src.could_match_inside(p("crate")));
+        assert!(src.could_match_inside(p("crate/src")));
+        assert!(!src.could_match_inside(p("crate/src/ui")));
+        assert!(!src.could_match_inside(p("crate/node_modules")));
+
+        let deep = Glob::new("crate/**/mod.rs")?;
+        assert!(deep.is_match(p("crate/mod.rs")));
+        assert!(deep.is_match(p("crate/a/b/c/mod.rs")));
+        assert!(deep.could_match_inside(p("crate/a/b")));
+        assert!(!deep.could_match_inside(p("elsewhere")));
+
+        let class = Glob::new("[!a-c]?.txt")?;
+        assert!(class.is_match(p("dz.txt")));
+        assert!(!class.is_match(p("az.txt")));
+        assert!(!class.is_match(p("d/.txt")));
+
+        assert!(Glob::new("a.b")?.is_match(p("a.b")));
+        assert!(!Glob::new("a.b")?.is_match(p("axb")));
+        Ok(())
+    }
+
+    #[test]
+    fn skip_rules() -> Result<()> {
+        let p = Utf8Path::new;
+        let keep = |rules: &[&str], path: &str, is_dir: bool| -> Result<bool> {
+            Ok(Skips::new(rules)?.keep(p(path), || is_dir))
+        };
+
+        // Plain regexes, as ever
+        assert!(!keep(&["/target$"], "/home/me/crate/target", true)?);
+        assert!(keep(&["/target$"], "/home/me/crate/targets", true)?);
+
+        // Names anywhere
+        assert!(!keep(&["glob:*.log"], "/var/log/syslog.log", false)?);
+        assert!(keep(&["glob:*.log"], "/var/log/syslog", false)?);
+
+        // Directories only
+        assert!(!keep(&["glob:**/target/"], "/home/me/crate/target", true)?);
+        assert!(keep(&["glob:**/target/"], "/home/me/crate/target", false)?);
+        assert!(!keep(&["glob:target/"], "/home/me/crate/target", true)?);
+
+        // Anchored to the root, absolute or not
+        let downloads = ["glob:/home/*/Downloads"];
+        assert!(!keep(&downloads, "/home/me/Downloads", true)?);
+        assert!(!keep(&downloads, "home/me/Downloads", true)?);
+        assert!(keep(&downloads, "/mnt/home/me/Downloads", true)?);
+
+        // Negation, where the last match wins
+        let logs = ["glob:*.log", "!glob:keep.log"];
+        assert!(!keep(&logs, "/a/b.log", false)?);
+        assert!(keep(&logs, "/a/keep.log", false)?);
+        let logs = ["!glob:keep.log", "glob:*.log"];
+        assert!(!keep(&logs, "/a/keep.log", false)?);
+        assert!(keep(&["!\\.rs$"], "/a/b.rs", false)?);
+
+        assert!(Skips::new(&["("]).is_err());
+
+        assert_eq!(glob_rule("*.log"), "glob:*.log");
+        assert_eq!(glob_rule("!keep.log"), "!glob:keep.log");
+        Ok(())
+    }
+
+    #[test]
+    fn merged_skips() -> Result<()> {
+        let v = |rules: &[&str]| rules.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+
+        // The command line's trailing *.log comes after its !keep.log, so it wins.
+        let merged = merge_skips(v(&["glob:*.log"]), v(&["!glob:keep.log", "glob:*.log"]));
+        assert_eq!(merged, ["!glob:keep.log", "glob:*.log"]);
+        assert!(!Skips::new(&merged)?.keep(Utf8Path::new("/a/keep.log"), || false));
+
+        // Same with nothing in the config
+        assert_eq!(
+            merge_skips(vec![], v(&["glob:*.log", "!glob:keep.log", "glob:*.log"])),
+            ["!glob:keep.log", "glob:*.log"]
+        );
+        assert_eq!(
+            merge_skips(v(&["/target$", "glob:*.log"]), v(&["!glob:keep.log"])),
+            ["/target$", "glob:*.log", "!glob:keep.log"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn patterns_from_file() {
+        let file = "# Caches\n\n/target$\n\\.log$   \n";
+        // Trailing whitespace goes, but the rest is up to the regex.
+        assert_eq!(parse_patterns(file), ["/target$", "\\.log$"]);
+    }
+}
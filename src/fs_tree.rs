@@ -1,14 +1,14 @@
 //! Walk filesystem trees and indicate if files have changed.
 
 use std::collections::BTreeSet;
-use std::io;
 use std::sync::Arc;
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
 use camino::{Utf8Path, Utf8PathBuf};
 use tracing::*;
 
 use crate::chunk;
+use crate::filter;
 use crate::hashing::ObjectId;
 use crate::tree;
 
@@ -48,6 +48,19 @@ pub fn file_changed(
     false
 }
 
+/// What to do with files whose names aren't valid UTF-8.
+///
+/// We use UTF-8 paths everywhere (and in the tree format),
+/// so we can't back them up. We can at least say so.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum InvalidNames {
+    /// Stop with an error.
+    #[default]
+    Error,
+    /// Warn and leave them out.
+    Skip,
+}
+
 /// Information about a directory entry when walking a filesystem tree,
 /// comparing it to a previous tree.
 pub enum DirectoryEntry<T> {
@@ -75,8 +88,10 @@ pub enum DirectoryEntry<T> {
 /// The entire thing acts as a map-reduce, where `visit()` maps and `finalize()`
 /// reduces everything visited in that directory.
 /// See [`forest_from_fs`] or [`crate::ui::backup`]'s `backup_tree` for examples.
+#[expect(clippy::too_many_arguments)] // We know, sit down.
 pub fn walk_fs<T, Intermediate, Filter, Visit, Finalize>(
     symlink_behavior: tree::Symlink,
+    invalid_names: InvalidNames,
     paths: &BTreeSet<Utf8PathBuf>,
     previous_tree: Option<&ObjectId>,
     previous_forest: &tree::Forest,
@@ -98,6 +113,45 @@ where
     ) -> Result<()>,
     Finalize: FnMut(Intermediate) -> Result<T>,
     Intermediate: Default,
+{
+    walk_fs_under(
+        symlink_behavior,
+        invalid_names,
+        paths,
+        previous_tree,
+        previous_forest,
+        filter,
+        visit,
+        finalize,
+        &mut vec![],
+    )
+}
+
+/// [`walk_fs()`], keeping track of the (canonical) directories we're in
+/// so that following symlinks can't send us around in circles forever.
+#[expect(clippy::too_many_arguments)] // Still know.
+fn walk_fs_under<T, Intermediate, Filter, Visit, Finalize>(
+    symlink_behavior: tree::Symlink,
+    invalid_names: InvalidNames,
+    paths: &BTreeSet<Utf8PathBuf>,
+    previous_tree: Option<&ObjectId>,
+    previous_forest: &tree::Forest,
+    filter: &mut Filter,
+    visit: &mut Visit,
+    finalize: &mut Finalize,
+    ancestors: &mut Vec<Utf8PathBuf>,
+) -> Result<T>
+where
+    Filter: FnMut(&Utf8Path) -> bool,
+    Visit: FnMut(
+        &mut Intermediate,
+        &Utf8Path,
+        tree::NodeMetadata,
+        Option<&tree::Node>,
+        DirectoryEntry<T>,
+    ) -> Result<()>,
+    Finalize: FnMut(Intermediate) -> Result<T>,
+    Intermediate: Default,
 {
     let mut intermediate = Intermediate::default();
 
@@ -122,10 +176,7 @@ where
             tree::NodeType::Directory => {
                 // Gather the dir entries in `path`, recurse into it,
                 // and add the subtree to the tree.
-                let subpaths = Utf8Path::read_dir_utf8(path)?
-                    .map(|entry| entry.map(|e| e.path().to_owned()))
-                    .collect::<io::Result<BTreeSet<Utf8PathBuf>>>()
-                    .with_context(|| format!("Failed iterating subdirectory {path}"))?;
+                let subpaths = read_dir(path, invalid_names)?;
 
                 let previous_subtree = previous_node.and_then(|n| match &n.contents {
                     tree::NodeContents::Directory { subtree } => Some(subtree),
@@ -139,16 +190,34 @@ where
                     }
                 });
 
-                let sub_result: T = walk_fs(
+                // Without dereferencing, we never leave the tree we started in.
+                // With it, a symlink can point back to a directory we're already inside.
+                let following = symlink_behavior == tree::Symlink::Dereference;
+                if following {
+                    let canonical = path
+                        .canonicalize_utf8()
+                        .with_context(|| format!("Couldn't canonicalize {path}"))?;
+                    if let Some(a) = ancestors.iter().find(|a| **a == canonical) {
+                        bail!("Symlink loop: {path} leads back to {a}");
+                    }
+                    ancestors.push(canonical);
+                }
+                let sub_result = walk_fs_under(
                     symlink_behavior,
+                    invalid_names,
                     &subpaths,
                     previous_subtree,
                     previous_forest,
                     filter,
                     visit,
                     finalize,
+                    ancestors,
                 )
-                .with_context(|| format!("Failed to walk subdirectory {path}"))?;
+                .with_context(|| format!("Failed to walk subdirectory {path}"));
+                if following {
+                    ancestors.pop();
+                }
+                let sub_result: T = sub_result?;
 
                 DirectoryEntry::Directory(sub_result)
             }
@@ -175,22 +244,52 @@ where
     finalize(intermediate)
 }
 
+fn read_dir(path: &Utf8Path, invalid_names: InvalidNames) -> Result<BTreeSet<Utf8PathBuf>> {
+    let mut subpaths = BTreeSet::new();
+    for entry in path
+        .as_std_path()
+        .read_dir()
+        .with_context(|| format!("Couldn't read directory {path}"))?
+    {
+        let entry = entry.with_context(|| format!("Failed iterating subdirectory {path}"))?;
+        match Utf8PathBuf::from_path_buf(entry.path()) {
+            Ok(p) => {
+                subpaths.insert(p);
+            }
+            Err(p) => match invalid_names {
+                InvalidNames::Error => {
+                    bail!("{} isn't valid UTF-8 (see --on-invalid-name)", p.display())
+                }
+                InvalidNames::Skip => warn!("Skipping {}; its name isn't valid UTF-8", p.display()),
+            },
+        }
+    }
+    Ok(subpaths)
+}
+
 /// Hashes the forest for the given paths,
 /// reusing chunks from the previous tree when able.
+///
+/// Changed files are cut with the given chunk sizes,
+/// which should be the ones the previous tree was made with
+/// (or else every changed file looks different from what's in the tree).
+///
+/// Files with non-UTF-8 names are skipped since they can't be in any snapshot.
 pub fn forest_from_fs(
     symlink_behavior: tree::Symlink,
+    chunking: &chunk::ChunkConfig,
     paths: &BTreeSet<Utf8PathBuf>,
+    skips: &[String],
     previous_tree: Option<&ObjectId>,
     previous_forest: &tree::Forest,
 ) -> Result<(ObjectId, tree::Forest)> {
-    let mut filter = |_: &Utf8Path| true;
-    fn visit(
-        (tree, forest): &mut (tree::Tree, tree::Forest),
-        path: &Utf8Path,
-        metadata: tree::NodeMetadata,
-        previous_node: Option<&tree::Node>,
-        entry: DirectoryEntry<(ObjectId, tree::Forest)>,
-    ) -> Result<()> {
+    let mut filter = filter::skip_matching_paths(skips)?;
+    let mut visit = |(tree, forest): &mut (tree::Tree, tree::Forest),
+                     path: &Utf8Path,
+                     metadata: tree::NodeMetadata,
+                     previous_node: Option<&tree::Node>,
+                     entry: DirectoryEntry<(ObjectId, tree::Forest)>|
+     -> Result<()> {
         let node = match entry {
             DirectoryEntry::Directory((subtree, subforest)) => {
                 forest.extend(subforest);
@@ -208,7 +307,7 @@ pub fn forest_from_fs(
                 contents: previous_node.unwrap().contents.clone(),
             },
             DirectoryEntry::ChangedFile => {
-                let chunks = chunk::chunk_file(path)?.map(|c| c.id).collect();
+                let chunks = chunk::chunk_file(path, chunking)?.map(|c| c.id).collect();
                 tree::Node {
                     metadata,
                     contents: tree::NodeContents::File { chunks },
@@ -221,7 +320,7 @@ pub fn forest_from_fs(
             "Duplicate tree entries"
         );
         Ok(())
-    }
+    };
 
     // Turn the tree into its ID and add it to the forest.
     fn finalize(
@@ -239,6 +338,7 @@ pub fn forest_from_fs(
 
     walk_fs(
         symlink_behavior,
+        InvalidNames::Skip,
         paths,
         previous_tree,
         previous_forest,
@@ -5,7 +5,7 @@ use std::io;
 use std::io::prelude::*;
 use std::sync::LazyLock;
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
 use data_encoding::{Encoding, Specification};
 use sha2::{Digest, Sha224, digest::Output};
 
@@ -36,6 +36,11 @@ impl ObjectId {
         Self { digest }
     }
 
+    /// The raw hash
+    pub fn as_bytes(&self) -> &[u8] {
+        self.digest.as_slice()
+    }
+
     /// Gets a git-like shortened version of the hash that's unique enough
     /// for most UI uses.
     pub fn short_name(&self) -> String {
@@ -108,6 +113,42 @@ impl<'de> serde::Deserialize<'de> for ObjectId {
     }
 }
 
+/// Find the one ID that starts with the given prefix, Git-style.
+///
+/// `what` is what we're looking for ("snapshot", "pack"...), for error messages.
+/// Several matches is an error listing them all -
+/// guessing would be a great way to restore (or forget!) the wrong thing.
+pub fn find_by_prefix<'a, I>(prefix: &str, what: &str, ids: I) -> Result<&'a ObjectId>
+where
+    I: IntoIterator<Item = &'a ObjectId>,
+{
+    // Like Git, require at least a few digits of an ID.
+    if prefix.len() < 4 {
+        bail!("Provide a {what} ID with at least 4 digits!");
+    }
+
+    let mut matches = ids
+        .into_iter()
+        .filter(|id| id.to_string().starts_with(prefix))
+        .collect::<Vec<_>>();
+    // Listings can repeat themselves (say, a pack in a couple indexes).
+    matches.sort();
+    matches.dedup();
+
+    match matches.len() {
+        0 => bail!("No {what}s start with {prefix}"),
+        1 => Ok(matches[0]),
+        multiple => {
+            let candidates = matches
+                .iter()
+                .map(|id| format!("  {id}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            bail!("{multiple} different {what}s start with {prefix}:\n{candidates}")
+        }
+    }
+}
+
 pub struct HashingReader<R> {
     inner: R,
     hasher: Sha224,
@@ -173,6 +214,39 @@ mod test {
     const EXPECTED: &[u8] =
         &hex_literal::hex!("354e63924f01c3b921222ab4d5b4a77ef67d04bedf437eef66d2e0d6");
 
+    #[test]
+    fn prefixes() -> Result<()> {
+        // Same but for the last byte, so they share every digit we'd reasonably type.
+        let mut bytes = [0u8; 28];
+        let zero = ObjectId::from_digest(*Sha224Digest::from_slice(&bytes));
+        bytes[27] = 1;
+        let one = ObjectId::from_digest(*Sha224Digest::from_slice(&bytes));
+        let other = ObjectId::hash(DEVELOPERS);
+        let ids = [zero, one, other];
+
+        let other_name = other.to_string();
+        assert_eq!(*find_by_prefix(&other_name[..8], "blob", &ids)?, other);
+        assert_eq!(*find_by_prefix(&one.to_string(), "blob", &ids)?, one);
+        // Duplicates aren't ambiguous.
+        assert_eq!(
+            *find_by_prefix(&other_name[..8], "blob", ids.iter().chain(&ids))?,
+            other
+        );
+
+        let err = find_by_prefix("000", "blob", &ids).unwrap_err();
+        assert!(err.to_string().contains("at least 4"), "{err}");
+        let err = find_by_prefix("vvvv", "blob", &ids).unwrap_err();
+        assert_eq!(err.to_string(), "No blobs start with vvvv");
+
+        // Don't pick one; list them.
+        let err = find_by_prefix("00000000", "blob", &ids).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("2 different blobs start with 00000000:\n  {zero}\n  {one}")
+        );
+        Ok(())
+    }
+
     #[test]
     fn smoke() {
         let id = ObjectId::hash(DEVELOPERS);
@@ -9,6 +9,9 @@
 //!    after uploading the new index but *before* deleting the old ones,
 //!    future commands will safely ignore the old indexes.)
 //!
+//! 3. When each pack was created, if known.
+//!    (Older indexes didn't record this, and `rebuild-index` can't recover it from packs.)
+//!
 //! Each backup makes an index of the packs it uploaded.
 //! By gathering all of these (minus superseded ones) into a master index,
 //! we get the contents of every pack in the repo without having to download them
@@ -21,13 +24,17 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
 use std::io::prelude::*;
+use std::num::NonZeroUsize;
 use std::sync::{
     Mutex,
-    atomic::{AtomicU64, Ordering},
+    atomic::{AtomicU64, AtomicUsize, Ordering},
     mpsc::{Receiver, SyncSender},
 };
+use std::thread;
 
 use anyhow::{Context, Result, anyhow, bail, ensure};
+use console::Term;
+use jiff::Timestamp;
 use rayon::prelude::*;
 use rustc_hash::{FxHashMap, FxHashSet};
 use serde_derive::{Deserialize, Serialize};
@@ -38,6 +45,8 @@ use crate::counters;
 use crate::file_util::{check_magic, nice_size};
 use crate::hashing::{HashingReader, HashingWriter, ObjectId};
 use crate::pack::{PackManifest, PackMetadata};
+use crate::prettify;
+use crate::progress::{ProgressThread, spinner};
 
 const MAGIC_BYTES: &[u8] = b"MKBAKIDX1";
 
@@ -54,6 +63,10 @@ pub type PackMap = BTreeMap<ObjectId, PackManifest>;
 pub struct Index {
     pub supersedes: BTreeSet<ObjectId>,
     pub packs: PackMap,
+    /// When each pack was created. Packs missing from this map are of unknown age.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[serde(with = "prettify::instant_map")]
+    pub pack_times: BTreeMap<ObjectId, Timestamp>,
 }
 
 impl Index {
@@ -75,15 +88,25 @@ pub enum Resumable {
 
 /// Gather metadata for completed packs from `rx` into an index file,
 /// and upload the index files when they reach a sufficient size.
+///
+/// If resumable and given a `checkpoint_interval`, also upload the index so far
+/// every that many packs. Each checkpoint supersedes the last,
+/// and the final index supersedes them all.
+///
+/// Returns the ID of the final index, if there was anything to write.
+/// Since packers send each pack to the uploader before sending us its metadata,
+/// and the uploader works in order, a checkpoint never lands before the packs it lists.
 pub fn index(
     resumable: Resumable,
     starting_index: Index,
+    checkpoint_interval: Option<NonZeroUsize>,
     rx: Receiver<PackMetadata>,
     to_upload: SyncSender<(String, File)>,
     indexed_packs: &AtomicU64,
-) -> Result<bool> {
+) -> Result<Option<ObjectId>> {
     let mut index = starting_index;
     let mut persisted = None;
+    let mut since_checkpoint = 0;
 
     // If we're given a non-empty index, write that out to start with.
     // (For example, it could be an index from `prune` that omits packs
@@ -93,12 +116,20 @@ pub fn index(
     }
 
     // For each pack...
-    while let Ok(PackMetadata { id, manifest }) = rx.recv() {
+    while let Ok(PackMetadata {
+        id,
+        manifest,
+        created,
+    }) = rx.recv()
+    {
         ensure!(
             index.packs.insert(id, manifest).is_none(),
             "Duplicate pack received: {}",
             id
         );
+        if let Some(t) = created {
+            index.pack_times.insert(id, t);
+        }
 
         indexed_packs.fetch_add(1, Ordering::Relaxed);
 
@@ -107,6 +138,16 @@ pub fn index(
             // That way the temp index should always contain a complete list of packs,
             // allowing us to resume a backup from the last finished pack.
             persisted = Some(to_temp_file(&index)?);
+
+            since_checkpoint += 1;
+            if checkpoint_interval.is_some_and(|n| since_checkpoint >= n.get()) {
+                let checkpoint_id = checkpoint(&index, &to_upload)?;
+                since_checkpoint = 0;
+                // Whatever we write next (another checkpoint, the final index)
+                // replaces this one. Make sure the WIP index knows that too.
+                index.supersedes.insert(checkpoint_id);
+                persisted = Some(to_temp_file(&index)?);
+            }
         }
     }
     // If we haven't been saving a WIP index, write it all out now.
@@ -144,13 +185,32 @@ pub fn index(
         to_upload
             .send((index_name, renamed))
             .context("indexer -> uploader channel exited early")?;
-        Ok(true)
+        Ok(Some(index_id))
     } else {
         debug!("No new indexes created - nothing changed");
-        Ok(false)
+        Ok(None)
     }
 }
 
+/// Upload a copy of the index so far.
+fn checkpoint(index: &Index, to_upload: &SyncSender<(String, File)>) -> Result<ObjectId> {
+    let mut tf = tempfile::Builder::new()
+        .prefix("temp-backpak-")
+        .suffix(".index")
+        .tempfile_in(".")
+        .context("Couldn't open temporary index for writing")?;
+    let id = to_file(tf.as_file_mut(), index)?;
+    let index_name = format!("{id}.index");
+    let fh = tf
+        .persist(&index_name)
+        .with_context(|| format!("Couldn't persist checkpoint index to {index_name}"))?;
+    info!("Checkpointing index {id} ({} packs)", index.packs.len());
+    to_upload
+        .send((index_name, fh))
+        .context("indexer -> uploader channel exited early")?;
+    Ok(id)
+}
+
 fn to_temp_file(index: &Index) -> Result<(ObjectId, File)> {
     // Could we speed things up by reusing the same file handle instead of
     // opening, writing, and closing each time we update the WIP index file?
@@ -189,6 +249,9 @@ fn to_file(fh: &mut fs::File, index: &Index) -> Result<ObjectId> {
 
 /// Load all indexes from the provided backend and combines them into a master
 /// index, removing any superseded ones.
+///
+/// Indexes are fetched in parallel (remote backends bound this with their connection limit),
+/// but merged only once they're all loaded, so the result doesn't depend on download order.
 pub fn build_master_index(cached_backend: &backend::CachedBackend) -> Result<Index> {
     build_master_index_with_sizes(cached_backend).map(|(mi, _ts)| mi)
 }
@@ -203,49 +266,78 @@ pub fn build_master_index_with_sizes(
 
     #[derive(Debug, Default)]
     struct Results {
-        bad_indexes: BTreeSet<ObjectId>,
+        /// Reported once loading is done so they don't garble the progress line
+        bad_indexes: BTreeMap<ObjectId, anyhow::Error>,
         superseded_indexes: BTreeSet<ObjectId>,
-        loaded_indexes: BTreeMap<ObjectId, PackMap>,
+        loaded_indexes: BTreeMap<ObjectId, Index>,
         sizes: Vec<u64>,
     }
 
     let shared = Mutex::new(Results::default());
 
-    cached_backend
-        .list_indexes()?
-        .par_iter()
-        .try_for_each_with(&shared, |shared, (index_file, index_len)| {
-            let index_id = backend::id_from_path(index_file)?;
-            let mut loaded_index = match load(&index_id, cached_backend) {
-                Ok(l) => l,
-                Err(e) => {
-                    error!("{:?}", e);
-                    shared.lock().unwrap().bad_indexes.insert(index_id);
-                    return Ok(());
-                }
-            };
-            let mut guard = shared.lock().unwrap();
-            guard.sizes.push(*index_len);
-            guard
-                .superseded_indexes
-                .append(&mut loaded_index.supersedes);
-            ensure!(
+    let index_files = cached_backend.list_indexes()?;
+    let total = index_files.len();
+    // Indexes we've tried, and how many of those we couldn't load
+    let loaded = AtomicUsize::new(0);
+    let bad = AtomicUsize::new(0);
+
+    let load_all = || {
+        index_files
+            .par_iter()
+            .try_for_each_with(&shared, |shared, (index_file, index_len)| {
+                let index_id = backend::id_from_path(index_file)?;
+                let loaded_index = load(&index_id, cached_backend);
+                let n = loaded.fetch_add(1, Ordering::Relaxed) + 1;
+                let mut loaded_index = match loaded_index {
+                    Ok(l) => l,
+                    Err(e) => {
+                        bad.fetch_add(1, Ordering::Relaxed);
+                        shared.lock().unwrap().bad_indexes.insert(index_id, e);
+                        return Ok(());
+                    }
+                };
+                debug!("Loaded index {} ({n}/{total})", index_id);
+                let mut guard = shared.lock().unwrap();
+                guard.sizes.push(*index_len);
                 guard
-                    .loaded_indexes
-                    .insert(index_id, loaded_index.packs)
-                    .is_none(),
-                "Duplicate index {} read from backend!",
-                index_file
-            );
-            Ok(())
+                    .superseded_indexes
+                    .append(&mut loaded_index.supersedes);
+                ensure!(
+                    guard
+                        .loaded_indexes
+                        .insert(index_id, loaded_index)
+                        .is_none(),
+                    "Duplicate index {} read from backend!",
+                    index_file
+                );
+                Ok(())
+            })
+    };
+
+    // A handful of indexes load in a blink; don't bother.
+    // Print to stderr so we don't muddle the output of whatever command wanted the index.
+    let term = Term::stderr();
+    if total >= PROGRESS_THRESHOLD && term.is_term() {
+        thread::scope(|s| {
+            let progress =
+                ProgressThread::spawn(s, |i| print_merge_progress(i, &term, &loaded, &bad, total));
+            let res = load_all();
+            progress.join();
+            res
         })?;
+    } else {
+        load_all()?;
+    }
 
     let mut shared = shared.into_inner().unwrap();
 
     if !shared.bad_indexes.is_empty() {
+        for e in shared.bad_indexes.values() {
+            error!("{:?}", e);
+        }
         bail!(
             "Errors loading indexes {:?}. Consider running backpak rebuild-index.",
-            shared.bad_indexes
+            shared.bad_indexes.keys().collect::<Vec<_>>()
         );
     }
 
@@ -257,19 +349,45 @@ pub fn build_master_index_with_sizes(
     }
 
     let mut master_pack_map = BTreeMap::new();
+    let mut master_pack_times = BTreeMap::new();
     for index in shared.loaded_indexes.values_mut() {
-        master_pack_map.append(index);
+        master_pack_map.append(&mut index.packs);
+        master_pack_times.append(&mut index.pack_times);
     }
 
     Ok((
         Index {
             supersedes: shared.superseded_indexes,
             packs: master_pack_map,
+            pack_times: master_pack_times,
         },
         shared.sizes,
     ))
 }
 
+/// Show progress loading indexes if there are at least this many
+const PROGRESS_THRESHOLD: usize = 20;
+
+fn print_merge_progress(
+    i: usize,
+    term: &Term,
+    loaded: &AtomicUsize,
+    bad: &AtomicUsize,
+    total: usize,
+) -> Result<()> {
+    if i > 0 {
+        term.clear_last_lines(1)?;
+    }
+    let s = spinner(i);
+    let n = loaded.load(Ordering::Relaxed);
+    let bad = match bad.load(Ordering::Relaxed) {
+        0 => String::new(),
+        b => format!(" ({b} bad)"),
+    };
+    term.write_line(&format!("{s} merging index {n}/{total}{bad}"))?;
+    Ok(())
+}
+
 /// A result of [`blob_to_pack_map()`],
 /// mapping [`Blob`](crate::blob::Blob) IDs to the the pack where each is stored
 pub type BlobMap = FxHashMap<ObjectId, ObjectId>;
@@ -360,6 +478,18 @@ pub fn read_wip() -> Result<Option<Index>> {
     Ok(Some(index))
 }
 
+/// Verify that the index in the given reader hashes to the given ID.
+pub fn verify_file<R: Read>(id: &ObjectId, r: &mut R) -> Result<()> {
+    let (_, calculated_id) = from_reader(r)?;
+    ensure!(
+        *id == calculated_id,
+        "Index {}'s contents changed! Now hashes to {}",
+        id,
+        calculated_id
+    );
+    Ok(())
+}
+
 /// Load the index with the given ID from the backend,
 /// verifying its contents match its ID.
 pub fn load(id: &ObjectId, cached_backend: &backend::CachedBackend) -> Result<Index> {
@@ -425,7 +555,11 @@ mod test {
                 },
             ],
         );
-        Index { supersedes, packs }
+        Index {
+            supersedes,
+            packs,
+            pack_times: BTreeMap::new(),
+        }
     }
 
     #[test]
@@ -21,6 +21,7 @@ pub mod progress;
 pub mod rcu;
 pub mod read;
 pub mod repack;
+pub mod restore;
 pub mod snapshot;
 pub mod tree;
 pub mod upload;
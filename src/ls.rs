@@ -2,9 +2,11 @@
 
 use anyhow::anyhow;
 use camino::Utf8Path;
+use jiff::Timestamp;
+use serde_derive::Serialize;
 
 use crate::hashing::ObjectId;
-use crate::tree::{Forest, Node, NodeContents, Tree};
+use crate::tree::{Forest, Node, NodeContents, NodeMetadata, Tree};
 
 // Should this live somewhere else?
 #[cfg(windows)]
@@ -18,20 +20,57 @@ pub fn has_trailing_slash(p: &Utf8Path) -> bool {
     p.as_str().as_bytes().last() == Some(&b'/')
 }
 
-fn printer(prefix: &str, path: &Utf8Path, node: &Node) {
-    print!("{prefix}{path}");
+/// What color to print a line in, by the change it shows:
+/// green for additions, red for removals, and yellow for other changes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Highlight {
+    Plain,
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Highlight {
+    /// The highlight for a diff mark (`+`, `-`, `C`, a metadata letter, etc.)
+    pub fn of_mark(mark: &str) -> Self {
+        match mark.trim() {
+            "+" => Self::Added,
+            "-" => Self::Removed,
+            "" | "=" => Self::Plain,
+            _ => Self::Changed,
+        }
+    }
+
+    /// Color the given line for stdout.
+    ///
+    /// Does nothing unless colors are enabled there
+    /// (see `--color` and [`console::set_colors_enabled()`]).
+    pub fn apply<D>(self, line: D) -> console::StyledObject<D> {
+        let style = console::Style::new();
+        let style = match self {
+            Self::Plain => style,
+            Self::Added => style.green(),
+            Self::Removed => style.red(),
+            Self::Changed => style.yellow(),
+        };
+        style.apply_to(line)
+    }
+}
+
+fn printer(prefix: &str, highlight: Highlight, path: &Utf8Path, node: &Node) {
+    let mut line = format!("{prefix}{path}");
     match &node.contents {
         NodeContents::Directory { .. } => {
             if !has_trailing_slash(path) {
-                print!("{}", std::path::MAIN_SEPARATOR);
+                line.push(std::path::MAIN_SEPARATOR);
             }
         }
         NodeContents::File { .. } => {}
         NodeContents::Symlink { target } => {
-            print!(" -> {target}");
+            line += &format!(" -> {target}");
         }
     };
-    println!();
+    println!("{}", highlight.apply(line));
 }
 
 // I tried turning walk_node() and walk_tree() into something general we could use for all
@@ -78,12 +117,72 @@ where
     }
 }
 
-pub fn print_node(prefix: &str, path: &Utf8Path, node: &Node, should_recurse: Recurse) {
-    let mut v = |p: &Utf8Path, n: &Node| printer(prefix, p, n);
+pub fn print_node(
+    prefix: &str,
+    highlight: Highlight,
+    path: &Utf8Path,
+    node: &Node,
+    should_recurse: Recurse,
+) {
+    let mut v = |p: &Utf8Path, n: &Node| printer(prefix, highlight, p, n);
     walk_node(&mut v, path, node, should_recurse);
 }
 
 pub fn print_tree(prefix: &str, tree_path: &Utf8Path, tree_id: &ObjectId, forest: &Forest) {
-    let mut v = |p: &Utf8Path, n: &Node| printer(prefix, p, n);
+    let mut v = |p: &Utf8Path, n: &Node| printer(prefix, Highlight::Plain, p, n);
+    walk_tree(&mut v, tree_path, tree_id, forest);
+}
+
+/// A node flattened into a line of JSON, for `ls --json-lines`
+#[derive(Debug, Serialize)]
+pub struct JsonLine<'a> {
+    pub path: &'a Utf8Path,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<&'a Utf8Path>,
+}
+
+impl<'a> JsonLine<'a> {
+    pub fn new(path: &'a Utf8Path, node: &'a Node) -> Self {
+        let (kind, target) = match &node.contents {
+            NodeContents::Directory { .. } => ("directory", None),
+            NodeContents::File { .. } => ("file", None),
+            NodeContents::Symlink { target } => ("symlink", Some(target.as_path())),
+        };
+        let (mode, uid, gid) = match &node.metadata {
+            NodeMetadata::Posix(p) => (Some(p.mode), Some(p.user_id), Some(p.group_id)),
+            NodeMetadata::Windows(_) => (None, None, None),
+        };
+        Self {
+            path,
+            kind,
+            size: node.metadata.size(),
+            mtime: node.metadata.modification_time(),
+            mode,
+            uid,
+            gid,
+            target,
+        }
+    }
+}
+
+/// Like [`print_tree`], but print each node as a line of JSON as we go.
+///
+/// Trees are sorted by name, and each directory comes before its contents.
+pub fn print_tree_json_lines(tree_path: &Utf8Path, tree_id: &ObjectId, forest: &Forest) {
+    let mut v = |p: &Utf8Path, n: &Node| {
+        println!("{}", serde_json::to_string(&JsonLine::new(p, n)).unwrap());
+    };
     walk_tree(&mut v, tree_path, tree_id, forest);
 }
@@ -1,10 +1,12 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use camino::Utf8PathBuf;
 use clap::{ArgAction, Parser, Subcommand};
 use tracing::*;
 
+use backpak::backend;
 use backpak::config;
 use backpak::counters;
+use backpak::file_util;
 use backpak::ui::*;
 
 #[derive(Debug, Parser)]
@@ -13,7 +15,9 @@ struct Args {
     #[clap(short, long, action(ArgAction::Count))]
     verbose: u8,
 
-    #[clap(short, long, value_enum, default_value = "auto")]
+    /// Color output and log messages.
+    /// "auto" colors terminals unless $NO_COLOR is set.
+    #[clap(short, long, value_enum, default_value = "auto", verbatim_doc_comment)]
     color: Color,
 
     /// Specify a different config file than the default
@@ -31,8 +35,36 @@ struct Args {
     #[clap(short = 'C', long, name = "PATH")]
     working_directory: Option<Utf8PathBuf>,
 
-    #[clap(short, long)]
-    repository: Utf8PathBuf,
+    /// The repository to use. If not given, look for one in the current
+    /// directory, then its parents (like Git looks for .git).
+    #[clap(short, long, verbatim_doc_comment)]
+    repository: Option<Utf8PathBuf>,
+
+    /// Read the repository's config from this file
+    /// instead of the repository itself (e.g., for a read-only copy).
+    /// The repository's data is still read from --repository.
+    #[clap(long, verbatim_doc_comment)]
+    repo_config: Option<Utf8PathBuf>,
+
+    /// Keep the cache here instead of ~/.cache/backpak
+    /// (overrides cache_dir in the config file).
+    #[clap(long, verbatim_doc_comment)]
+    cache_dir: Option<Utf8PathBuf>,
+
+    /// Print how many bytes were uploaded to and downloaded from the backend
+    /// when the command finishes.
+    #[clap(long, verbatim_doc_comment)]
+    stats: bool,
+
+    /// Print how many times each important operation happened
+    /// (backend reads and writes, cache hits and misses, etc.)
+    /// when the command finishes. Handy for figuring out why something is slow.
+    #[clap(long, verbatim_doc_comment)]
+    counters: bool,
+
+    /// Units for sizes in end-of-run summaries
+    #[clap(long, value_enum, default_value = "si")]
+    size_units: file_util::SizeUnits,
 
     #[clap(subcommand)]
     subcommand: Command,
@@ -50,63 +82,161 @@ enum Command {
     /// Initialize a backup repository
     Init(init::Args),
     Backup(backup::Args),
+    Cache(cache::Args),
     Cat(cat::Args),
     Check(check::Args),
+    Config(backpak::ui::config::Args),
     Copy(copy::Args),
     Diff(diff::Args),
+    Doctor(doctor::Args),
     Dump(dump::Args),
     FilterSnapshot(filter_snapshot::Args),
+    Find(find::Args),
     Forget(forget::Args),
     Ls(ls::Args),
+    Pack(pack::Args),
+    Packs(packs::Args),
     Prune(prune::Args),
+    Reachable(reachable::Args),
     Restore(restore::Args),
+    Snapshot(snapshot::Args),
     Snapshots(snapshots::Args),
     /// Build a new index from all existing packs and delete all old ones.
     RebuildIndex(rebuild_index::Args),
     /// Print repository size stats.
-    Usage,
+    Usage(usage::Args),
 }
 
 fn main() {
     run().unwrap_or_else(|e| {
+        // Not an error, just `diff --exit-code` saying so.
+        if e.is::<diff::DifferencesFound>() {
+            std::process::exit(1);
+        }
         error!("{:?}", e);
-        std::process::exit(1);
+        // diff --exit-code already uses 1 for differences.
+        let code = if e.is::<diff::DiffFailed>() { 2 } else { 1 };
+        std::process::exit(code);
     });
 }
 
 fn run() -> Result<()> {
     let args = Args::parse();
     let logmode = match args.subcommand {
-        Command::Cat(_) | Command::Diff(_) | Command::Dump(_) | Command::Ls(_) => LogMode::Quiet,
+        Command::Cache(_)
+        | Command::Cat(_)
+        | Command::Diff(_)
+        | Command::Dump(_)
+        | Command::Find(_)
+        | Command::Ls(_)
+        | Command::Pack(_)
+        | Command::Packs(_)
+        | Command::Reachable(_) => LogMode::Quiet,
         _ => LogMode::InfoStdout,
     };
     init_logger(&args, logmode);
-    let conf = config::load(args.config)?;
+    // Diff and ls output (see ls::Highlight)
+    console::set_colors_enabled(match args.color {
+        Color::Always => true,
+        Color::Auto => !no_color() && console::colors_enabled(),
+        Color::Never => false,
+    });
+    // SAFETY: We're still single-threaded here.
+    unsafe { file_util::set_size_units(args.size_units) };
+    // Checking configs shouldn't fail because we couldn't load them.
+    let mut conf = match args.subcommand {
+        Command::Config(_) => config::Configuration::default(),
+        _ => config::load(args.config.clone())?,
+    };
+    conf.repo_config = args.repo_config.clone();
+    if args.cache_dir.is_some() {
+        conf.cache_dir = args.cache_dir.clone();
+    }
 
     if let Some(dir) = &args.working_directory {
         std::env::set_current_dir(dir).expect("Couldn't change working directory");
     }
 
-    match args.subcommand {
-        Command::Init(i) => init::run(&args.repository, i),
-        Command::Backup(b) => backup::run(conf, &args.repository, b),
-        Command::Cat(c) => cat::run(&conf, &args.repository, c),
-        Command::Check(c) => check::run(&conf, &args.repository, c),
-        Command::Copy(c) => copy::run(&conf, &args.repository, c),
-        Command::Diff(d) => diff::run(&conf, &args.repository, d),
-        Command::Dump(d) => dump::run(&conf, &args.repository, d),
-        Command::FilterSnapshot(f) => filter_snapshot::run(&conf, &args.repository, f),
-        Command::Forget(f) => forget::run(&conf, &args.repository, f),
-        Command::Ls(l) => ls::run(&conf, &args.repository, l),
-        Command::Prune(p) => prune::run(&conf, &args.repository, p),
-        Command::Restore(r) => restore::run(&conf, &args.repository, r),
-        Command::Snapshots(s) => snapshots::run(&conf, &args.repository, s),
-        Command::RebuildIndex(r) => rebuild_index::run(&conf, &args.repository, r),
-        Command::Usage => usage::run(&conf, &args.repository),
-    }?;
+    if let Command::Config(c) = args.subcommand {
+        return backpak::ui::config::run(args.config, args.repository, args.repo_config, c);
+    }
+
+    let repository = match (args.repository, &args.subcommand) {
+        (Some(r), _) => r,
+        (None, Command::Init(_)) => bail!("Give a --repository to initialize"),
+        (None, _) => backend::discover_repository()?,
+    };
+    let repository = &repository;
+
+    let res = match args.subcommand {
+        Command::Init(i) => init::run(repository, i),
+        Command::Backup(b) => backup::run(conf, repository, b),
+        Command::Cache(c) => cache::run(&conf, repository, c),
+        Command::Cat(c) => cat::run(&conf, repository, c),
+        Command::Check(c) => check::run(&conf, repository, c),
+        Command::Config(_) => unreachable!("config commands run before we load configs"),
+        Command::Copy(c) => copy::run(&conf, repository, c),
+        Command::Diff(d) => diff::run(&conf, repository, d),
+        Command::Doctor(d) => doctor::run(&conf, repository, d),
+        Command::Dump(d) => dump::run(&conf, repository, d),
+        Command::FilterSnapshot(f) => filter_snapshot::run(&conf, repository, f),
+        Command::Find(f) => find::run(&conf, repository, f),
+        Command::Forget(f) => forget::run(&conf, repository, f),
+        Command::Ls(l) => ls::run(&conf, repository, l),
+        Command::Pack(p) => pack::run(&conf, repository, p),
+        Command::Packs(p) => packs::run(&conf, repository, p),
+        Command::Prune(p) => prune::run(&conf, repository, p),
+        Command::Reachable(r) => reachable::run(&conf, repository, r),
+        Command::Restore(r) => restore::run(&conf, repository, r),
+        Command::Snapshot(s) => snapshot::run(&conf, repository, s),
+        Command::Snapshots(s) => snapshots::run(&conf, repository, s),
+        Command::RebuildIndex(r) => rebuild_index::run(&conf, repository, r),
+        Command::Usage(u) => usage::run(&conf, repository, u),
+    };
+    // Finding differences isn't a failure, so we still want stats for it.
+    if res
+        .as_ref()
+        .is_err_and(|e| !e.is::<diff::DifferencesFound>())
+    {
+        return res;
+    }
 
+    if args.stats {
+        print_transfer_stats();
+    }
+    if args.counters {
+        print_counters();
+    }
     counters::log_counts();
-    Ok(())
+    res
+}
+
+fn print_transfer_stats() {
+    let (up, down, approximate) = backend::transfer_totals();
+    // stderr so we don't muddle the output of `dump`, `cat`, etc.
+    eprintln!(
+        "Uploaded {}, downloaded {}{}",
+        file_util::nice_size(up),
+        file_util::nice_size(down),
+        if approximate {
+            " (approximately; filesystem repositories count whole files)"
+        } else {
+            ""
+        }
+    );
+}
+
+fn print_counters() {
+    // stderr for the same reason as above
+    eprintln!("Operation counts:");
+    for (op, count) in counters::snapshot().to_map() {
+        eprintln!("{count:>8} {op}");
+    }
+}
+
+/// Is $NO_COLOR set? See <https://no-color.org>
+fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
 }
 
 enum LogMode {
@@ -131,7 +261,7 @@ fn init_logger(args: &Args, m: LogMode) {
         Color::Always => true,
         Color::Auto => {
             use std::io::IsTerminal;
-            std::io::stderr().is_terminal()
+            !no_color() && std::io::stderr().is_terminal()
         }
         Color::Never => false,
     };
@@ -2,8 +2,9 @@
 //! suitable for storing in a [backend]
 //!
 //! A pack file contains:
-//! 1. Magic bytes
-//! 2. A zstd-compressed stream of all blobs in the file
+//! 1. Magic bytes, which also say how the blob stream is compressed
+//! 2. A zstd-compressed (or, if the repository asked for it, uncompressed) stream
+//!    of all blobs in the file
 //! 3. A *separate* zstd stream of the CBOR-encoded manifest.
 //!    Each manifest entry contains its blob's type, length, and ID.
 //! 4. A 32-bit, big-endian manifest length.
@@ -20,28 +21,148 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::io::{self, SeekFrom};
 use std::sync::{
+    Once,
     atomic::{AtomicU64, Ordering},
     mpsc::{Receiver, SyncSender},
 };
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
 use byte_unit::Byte;
+use camino::Utf8Path;
+use jiff::Timestamp;
 use serde_derive::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use tracing::*;
 
 use crate::backend;
 use crate::blob::{self, Blob};
-use crate::file_util::{self, nice_size};
+use crate::chunk;
+use crate::counters;
+use crate::file_util::nice_size;
 use crate::hashing::{HashingReader, ObjectId};
 use crate::progress::AtomicCountWrite;
 use crate::tree;
 
 pub const MAGIC_BYTES: &[u8] = b"MKBAKPAK1";
 
+/// Like [`MAGIC_BYTES`], but for packs whose blob stream isn't compressed
+pub const UNCOMPRESSED_MAGIC_BYTES: &[u8] = b"MKBAKRAW1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Zstd,
+    /// For data that's already compressed (photos, video, etc.)
+    None,
+}
+
+/// How to compress blobs in new packs.
+///
+/// Readers go by each pack's magic bytes, not this,
+/// so changing it doesn't strand existing packs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    /// Zstd's level, where 0 is its default. Ignored for no compression.
+    #[serde(default)]
+    pub level: i32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Zstd,
+            level: 0,
+        }
+    }
+}
+
+impl Compression {
+    pub fn check(&self) -> Result<()> {
+        if self.algorithm == Algorithm::Zstd {
+            let range = zstd::compression_level_range();
+            ensure!(
+                range.contains(&self.level),
+                "zstd compression level {} is out of range ({} to {})",
+                self.level,
+                range.start(),
+                range.end()
+            );
+        }
+        Ok(())
+    }
+}
+
 /// The desired size of [crate::pack] files
 pub const DEFAULT_PACK_SIZE: Byte = Byte::from_u64(100_000_000); // 100 MB
 
+/// Bounds for the configured pack size.
+/// Any smaller and a repository turns into a pile of tiny packs.
+/// Packs are built in memory, and B2 wants anything over 5 GB uploaded in parts.
+pub const MIN_PACK_SIZE: Byte = Byte::from_u64(1024 * 1024); // 1 MiB
+pub const MAX_PACK_SIZE: Byte = Byte::from_u64(5_000_000_000); // 5 GB
+
+/// Set to lower [`MIN_PACK_SIZE`] so tests can make multi-pack repos out of a little data.
+/// Not for anything but tests.
+pub const TINY_PACKS_VAR: &str = "BACKPAK_TEST_TINY_PACKS";
+
+fn min_size() -> Byte {
+    if std::env::var_os(TINY_PACKS_VAR).is_some() {
+        Byte::from_u64(10_000) // 10 KB
+    } else {
+        MIN_PACK_SIZE
+    }
+}
+
+/// Makes sure a configured pack size is something we can live with.
+pub fn check_size(size: Byte) -> Result<()> {
+    let min = min_size();
+    ensure!(
+        (min..=MAX_PACK_SIZE).contains(&size),
+        "Pack size {} is out of range; it should be between {} and {}",
+        nice_size(size.as_u64()),
+        nice_size(min.as_u64()),
+        nice_size(MAX_PACK_SIZE.as_u64()),
+    );
+    Ok(())
+}
+
+/// Like [`check_size`], but for configs we're just reading:
+/// warns (once) instead of failing, since we only need a good size to write packs.
+pub fn warn_on_bad_size(size: Byte, config: &Utf8Path) {
+    static WARNED: Once = Once::new();
+    if let Err(e) = check_size(size) {
+        WARNED.call_once(|| {
+            warn!("{e} (in {config}); fix it before writing any more packs");
+        });
+    }
+}
+
+/// Warns if the packs we found look like they were made with a bigger pack size
+/// than the one configured.
+///
+/// Packs fill up to (at most a chunk past) the target size before they're closed,
+/// so none should be much bigger than it.
+/// (Smaller ones are normal - each backup closes out a partial pack or two.)
+pub fn warn_on_size_mismatch(configured: Byte, pack_sizes: impl IntoIterator<Item = u64>) {
+    let configured = configured.as_u64();
+    let (count, biggest) = pack_sizes
+        .into_iter()
+        .fold((0usize, 0u64), |(c, b), s| (c + 1, b.max(s)));
+    let expected_max = configured
+        .saturating_mul(2)
+        .saturating_add(chunk::LARGEST_CHUNK as u64);
+    if biggest > expected_max {
+        warn!(
+            "Configured pack size is {}, but the biggest of {count} packs is {}. \
+             Was the pack size changed after packs were written?",
+            nice_size(configured),
+            nice_size(biggest),
+        );
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct PackManifestEntry {
     #[serde(rename = "type")]
@@ -56,6 +177,32 @@ pub type PackManifest = Vec<PackManifestEntry>;
 pub struct PackMetadata {
     pub id: ObjectId,
     pub manifest: PackManifest,
+    /// When the pack was written, if known.
+    /// (Recorded in the index, not the pack itself.)
+    pub created: Option<Timestamp>,
+}
+
+/// Look for things in a manifest that the packer should never have written,
+/// returning a description of each.
+///
+/// Blobs are stored back-to-back in manifest order, so each one's offset is the sum
+/// of the lengths before it - they can't overlap or go backwards.
+/// What _can_ go wrong is the same blob showing up twice, or an empty blob.
+pub fn check_manifest(manifest: &[PackManifestEntry]) -> Vec<String> {
+    let mut anomalies = vec![];
+    let mut seen = rustc_hash::FxHashMap::default();
+    for (i, entry) in manifest.iter().enumerate() {
+        if let Some(first) = seen.insert(entry.id, i) {
+            anomalies.push(format!(
+                "Blob {} appears more than once (entries {first} and {i})",
+                entry.id
+            ));
+        }
+        if entry.length == 0 {
+            anomalies.push(format!("Blob {} (entry {i}) is empty", entry.id));
+        }
+    }
+    anomalies
 }
 
 /// Serializes a pack's manifest and get its ID.
@@ -73,14 +220,16 @@ fn serialize_and_hash(manifest: &[PackManifestEntry]) -> Result<(Vec<u8>, Object
 /// Returns the number of bytes packed
 pub fn pack(
     target_size: Byte,
+    compression: Compression,
     rx: Receiver<Blob>,
     to_index: SyncSender<PackMetadata>,
     to_upload: SyncSender<(String, File)>,
     total_bytes_packed: &AtomicU64,
     total_bytes_compressed: &AtomicU64,
 ) -> Result<()> {
+    check_size(target_size).context("Won't write packs")?;
     let target_size = target_size.as_u64();
-    let mut writer = PackfileWriter::new(total_bytes_compressed)?;
+    let mut writer = PackfileWriter::new(compression, total_bytes_compressed)?;
 
     let mut pass_bytes_written: u64 = 0; // Bytes written since the last size check
     let mut bytes_in_pack: u64 = 0;
@@ -179,7 +328,7 @@ pub fn pack(
                 .send(metadata)
                 .context("packer -> indexer channel exited early")?;
 
-            writer = PackfileWriter::new(total_bytes_compressed)?;
+            writer = PackfileWriter::new(compression, total_bytes_compressed)?;
             pass_bytes_written = 0;
             bytes_in_pack = 0;
             bytes_before_next_check = target_size;
@@ -201,27 +350,110 @@ pub fn pack(
 type ZstdEncoder<W> = zstd::stream::write::Encoder<'static, W>;
 type ZstdDecoder<R> = zstd::stream::read::Decoder<'static, R>;
 
+/// Where blobs go in a pack we're writing
+enum BlobWriter<'a> {
+    Zstd(ZstdEncoder<AtomicCountWrite<'a, NamedTempFile>>),
+    Uncompressed(AtomicCountWrite<'a, NamedTempFile>),
+}
+
+impl<'a> BlobWriter<'a> {
+    fn get_ref(&self) -> &AtomicCountWrite<'a, NamedTempFile> {
+        match self {
+            Self::Zstd(z) => z.get_ref(),
+            Self::Uncompressed(w) => w,
+        }
+    }
+
+    fn finish(self) -> io::Result<NamedTempFile> {
+        let w = match self {
+            Self::Zstd(z) => z.finish()?,
+            Self::Uncompressed(w) => w,
+        };
+        Ok(w.into_inner())
+    }
+}
+
+impl Write for BlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Zstd(z) => z.write(buf),
+            Self::Uncompressed(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Zstd(z) => z.flush(),
+            Self::Uncompressed(w) => w.flush(),
+        }
+    }
+}
+
+/// Reads blobs out of a pack, however they were compressed
+pub enum BlobReader<R: Read> {
+    Zstd(ZstdDecoder<io::BufReader<R>>),
+    Uncompressed(R),
+}
+
+impl<R: Read> BlobReader<R> {
+    /// Checks the pack's magic bytes and starts reading its blob stream.
+    pub fn new(mut packfile: R) -> Result<Self> {
+        match check_magic(&mut packfile)? {
+            Algorithm::Zstd => Ok(Self::Zstd(
+                ZstdDecoder::new(packfile).context("Decompression of blob stream failed")?,
+            )),
+            Algorithm::None => Ok(Self::Uncompressed(packfile)),
+        }
+    }
+
+    /// Get back the underlying reader, somewhere past the blob stream.
+    pub fn into_inner(self) -> R {
+        match self {
+            Self::Zstd(z) => z.finish().into_inner(),
+            Self::Uncompressed(r) => r,
+        }
+    }
+}
+
+impl<R: Read> Read for BlobReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Zstd(z) => z.read(buf),
+            Self::Uncompressed(r) => r.read(buf),
+        }
+    }
+}
+
 struct PackfileWriter<'a> {
-    writer: ZstdEncoder<AtomicCountWrite<'a, NamedTempFile>>,
+    writer: BlobWriter<'a>,
     manifest: PackManifest,
 }
 
 // TODO: Obviously this should all take place in a configurable temp directory
 
 impl<'a> PackfileWriter<'a> {
-    fn new(byte_count: &'a AtomicU64) -> Result<Self> {
+    fn new(compression: Compression, byte_count: &'a AtomicU64) -> Result<Self> {
         let mut fh = tempfile::Builder::new()
             .prefix("temp-backpak-")
             .suffix(".pack")
             .tempfile_in(".")
             .context("Couldn't open temporary packfile for writing")?;
 
-        fh.write_all(MAGIC_BYTES)?;
-        let acw = AtomicCountWrite::new(fh, byte_count);
-        let mut zstd = ZstdEncoder::new(acw, 0)?;
-        zstd.multithread(num_cpus::get_physical() as u32)?;
+        let writer = match compression.algorithm {
+            Algorithm::Zstd => {
+                fh.write_all(MAGIC_BYTES)?;
+                let acw = AtomicCountWrite::new(fh, byte_count);
+                let mut zstd = ZstdEncoder::new(acw, compression.level)?;
+                zstd.multithread(num_cpus::get_physical() as u32)?;
+                BlobWriter::Zstd(zstd)
+            }
+            Algorithm::None => {
+                fh.write_all(UNCOMPRESSED_MAGIC_BYTES)?;
+                BlobWriter::Uncompressed(AtomicCountWrite::new(fh, byte_count))
+            }
+        };
         Ok(Self {
-            writer: zstd,
+            writer,
             manifest: Vec::new(),
         })
     }
@@ -268,7 +500,7 @@ impl<'a> PackfileWriter<'a> {
         // Finish the compression stream for blobs and trees.
         // We'll compress the manifest separately so we can decompress it
         // without reading everything before it.
-        let mut fh: NamedTempFile = self.writer.finish()?.into_inner();
+        let mut fh: NamedTempFile = self.writer.finish()?;
 
         // The manifest CBOR will have lots of redundant data - compress it down.
         // TODO: Is multithreading worth it here?
@@ -294,6 +526,7 @@ impl<'a> PackfileWriter<'a> {
             PackMetadata {
                 id,
                 manifest: self.manifest,
+                created: Some(Timestamp::now()),
             },
             persisted,
         ))
@@ -306,9 +539,7 @@ pub fn verify<R: Read + Seek>(
     manifest_from_index: &[PackManifestEntry],
     blobs_read: &AtomicU64,
 ) -> Result<()> {
-    check_magic(packfile)?;
-
-    let mut decoder = ZstdDecoder::new(packfile).context("Decompression of blob stream failed")?;
+    let mut decoder = BlobReader::new(packfile)?;
 
     for entry in manifest_from_index {
         let mut hashing_decoder = HashingReader::new((&mut decoder).take(entry.length as u64));
@@ -330,8 +561,8 @@ pub fn verify<R: Read + Seek>(
     // Should we rearrange the file so that isn't a problem?
     // Or is that fine, since verification isn't as performance critical
     // as other interactions?
-    let mut packfile = decoder.finish();
-    let (manifest_from_file, _id) = manifest_from_reader(&mut packfile)?;
+    let packfile = decoder.into_inner();
+    let (manifest_from_file, _id) = manifest_from_reader(packfile)?;
 
     ensure!(
         manifest_from_index == manifest_from_file,
@@ -341,6 +572,70 @@ pub fn verify<R: Read + Seek>(
     Ok(())
 }
 
+/// A blob whose contents don't hash to its ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptBlob {
+    pub id: ObjectId,
+    /// Where the blob starts in the pack's (decompressed) blob stream
+    pub offset: u64,
+    pub actual: ObjectId,
+}
+
+/// Hashes every blob in the packfile, returning those that don't match their IDs.
+///
+/// Unlike [`verify`], this keeps going after a bad blob
+/// (the manifest tells us where the next one starts),
+/// so we can report everything wrong with a pack.
+/// Errors are for things we can't read past, like a truncated pack.
+pub fn find_corrupt_blobs<R: Read>(
+    packfile: &mut R,
+    manifest: &[PackManifestEntry],
+    blobs_read: &AtomicU64,
+) -> Result<Vec<CorruptBlob>> {
+    let mut decoder = BlobReader::new(packfile)?;
+
+    let mut corrupt = vec![];
+    let mut offset = 0u64;
+    for entry in manifest {
+        let length = entry.length as u64;
+        let mut hashing_decoder = HashingReader::new((&mut decoder).take(length));
+        let read = io::copy(&mut hashing_decoder, &mut io::sink())
+            .with_context(|| format!("Couldn't read blob {} at offset {offset}", entry.id))?;
+        ensure!(
+            read == length,
+            "Pack ends in the middle of blob {} (offset {offset})",
+            entry.id
+        );
+
+        let (hash, _) = hashing_decoder.finalize();
+        if hash != entry.id {
+            corrupt.push(CorruptBlob {
+                id: entry.id,
+                offset,
+                actual: hash,
+            });
+        }
+        offset += length;
+        blobs_read.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(corrupt)
+}
+
+/// Verifies a whole packfile against its own manifest,
+/// and that manifest against the pack's ID.
+pub fn verify_file<R: Read + Seek>(id: &ObjectId, packfile: &mut R) -> Result<()> {
+    check_magic(packfile)?;
+    let (manifest, calculated_id) = manifest_from_reader(packfile)?;
+    ensure!(
+        *id == calculated_id,
+        "Pack {}'s manifest changed! Now hashes to {}",
+        id,
+        calculated_id
+    );
+    packfile.seek(SeekFrom::Start(0))?;
+    verify(packfile, &manifest, &AtomicU64::new(0))
+}
+
 /// Reads the pack manifest from the back of the given reader,
 /// also returning its calculated ID.
 ///
@@ -370,10 +665,28 @@ fn manifest_from_reader<R: Seek + Read>(r: &mut R) -> Result<(PackManifest, Obje
 
 /// Loads the manifest of the pack with the given ID from the backend,
 /// verifying its contents match its ID.
+///
+/// Manifests are cached locally (see [`backend::CachedBackend::cached_manifest`])
+/// so later loads skip reading the pack.
 pub fn load_manifest(
     id: &ObjectId,
     cached_backend: &backend::CachedBackend,
 ) -> Result<PackManifest> {
+    if let Some(cbor) = cached_backend.cached_manifest(id)? {
+        // Like the pack itself, the manifest hashes to the pack's ID,
+        // so we can tell if the cached copy is any good.
+        let manifest: Option<PackManifest> = (ObjectId::hash(&cbor) == *id)
+            .then(|| ciborium::from_reader(cbor.as_slice()).ok())
+            .flatten();
+        if let Some(manifest) = manifest {
+            debug!("Found pack manifest {id} in the cache");
+            counters::bump(counters::Op::ManifestCacheHit);
+            return Ok(manifest);
+        }
+        warn!("Cached manifest for pack {id} is corrupt; rereading it");
+    }
+    counters::bump(counters::Op::ManifestCacheMiss);
+
     debug!("Loading pack manifest {}", id);
     let mut fh = cached_backend.read_pack(id)?;
     check_magic(&mut fh)?;
@@ -386,6 +699,9 @@ pub fn load_manifest(
         id,
         calculated_id
     );
+
+    let (cbor, _) = serialize_and_hash(&manifest)?;
+    cached_backend.cache_manifest(id, &cbor)?;
     Ok(manifest)
 }
 
@@ -401,9 +717,7 @@ pub fn extract_blob<R: Read>(
         "Given blob ID isn't in the given index"
     );
 
-    check_magic(packfile)?;
-
-    let mut decoder = ZstdDecoder::new(packfile).context("Decompression of blob stream failed")?;
+    let mut decoder = BlobReader::new(packfile)?;
 
     let mut sink = io::sink();
 
@@ -437,9 +751,7 @@ pub fn append_to_forest<R: Read + Seek>(
     manifest_from_index: &[PackManifestEntry],
     forest: &mut tree::Forest,
 ) -> Result<()> {
-    check_magic(packfile)?;
-
-    let mut decoder = ZstdDecoder::new(packfile).context("Decompression of blob stream failed")?;
+    let mut decoder = BlobReader::new(packfile)?;
 
     for entry in manifest_from_index {
         // If it's not a tree, or if we have it already, skip it!
@@ -483,8 +795,21 @@ pub fn append_to_forest<R: Read + Seek>(
     Ok(())
 }
 
-pub fn check_magic<R: Read>(r: &mut R) -> Result<()> {
-    file_util::check_magic(r, MAGIC_BYTES).context("Wrong magic bytes for packfile")
+/// Checks the packfile's magic bytes, returning how its blob stream is compressed.
+pub fn check_magic<R: Read>(r: &mut R) -> Result<Algorithm> {
+    let mut magic = [0u8; MAGIC_BYTES.len()];
+    r.read_exact(&mut magic)
+        .context("Couldn't read magic bytes for packfile")?;
+    match &magic[..] {
+        MAGIC_BYTES => Ok(Algorithm::Zstd),
+        UNCOMPRESSED_MAGIC_BYTES => Ok(Algorithm::None),
+        _ => bail!(
+            "Wrong magic bytes for packfile: expected {} or {}, found {}",
+            String::from_utf8_lossy(MAGIC_BYTES),
+            String::from_utf8_lossy(UNCOMPRESSED_MAGIC_BYTES),
+            String::from_utf8_lossy(&magic)
+        ),
+    }
 }
 
 #[cfg(test)]
@@ -494,7 +819,119 @@ mod test {
     use std::fs;
     use std::sync::mpsc::sync_channel;
 
-    use crate::chunk;
+    #[test]
+    fn manifest_anomalies() {
+        let entry = |id: &[u8], length| PackManifestEntry {
+            blob_type: blob::Type::Chunk,
+            length,
+            id: ObjectId::hash(id),
+        };
+        assert!(check_manifest(&[entry(b"a", 1), entry(b"b", 2)]).is_empty());
+
+        let anomalies = check_manifest(&[entry(b"a", 1), entry(b"b", 0), entry(b"a", 1)]);
+        assert_eq!(anomalies.len(), 2);
+        assert!(anomalies[0].contains("empty"));
+        assert!(anomalies[1].contains("entries 0 and 2"));
+    }
+
+    #[test]
+    fn size_bounds() {
+        assert!(check_size(DEFAULT_PACK_SIZE).is_ok());
+        assert!(check_size(MIN_PACK_SIZE).is_ok());
+        // What the CLI tests use (with TINY_PACKS_VAR)
+        assert!(check_size(Byte::from_u64(20_000)).is_err());
+        assert!(check_size(Byte::from_u64(100)).is_err());
+        assert!(check_size(Byte::from_u64(10_000_000_000)).is_err());
+    }
+
+    #[test]
+    fn compression_round_trip() -> Result<()> {
+        let cc = chunk::ChunkConfig::default();
+        let mut chunks: Vec<_> = chunk::chunk_file("tests/references/sr71.txt", &cc)?.collect();
+        chunks.extend(chunk::chunk_file("tests/references/README.md", &cc)?);
+        let contents: usize = chunks.iter().map(|c| c.bytes().len()).sum();
+        for algorithm in [Algorithm::Zstd, Algorithm::None] {
+            let compression = Compression {
+                algorithm,
+                level: 0,
+            };
+            let compressed = AtomicU64::new(0);
+            let mut writer = PackfileWriter::new(compression, &compressed)?;
+            for chunk in &chunks {
+                writer.write_blob(chunk.clone())?;
+            }
+            let (metadata, mut fh) = writer.finalize()?;
+            fs::remove_file(format!("{}.pack", metadata.id))?;
+
+            fh.seek(SeekFrom::Start(0))?;
+            assert_eq!(check_magic(&mut fh)?, algorithm);
+            let written = compressed.load(Ordering::Relaxed) as usize;
+            match algorithm {
+                Algorithm::Zstd => assert!(written < contents),
+                Algorithm::None => assert_eq!(written, contents),
+            }
+
+            fh.seek(SeekFrom::Start(0))?;
+            verify_file(&metadata.id, &mut fh)?;
+            fh.seek(SeekFrom::Start(0))?;
+            let (_, blob) = extract_blob(&mut fh, &chunks.last().unwrap().id, &metadata.manifest)?;
+            assert_eq!(blob, chunks.last().unwrap().bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compression_levels() {
+        let level = |level| Compression {
+            algorithm: Algorithm::Zstd,
+            level,
+        };
+        assert!(level(19).check().is_ok());
+        assert!(level(9000).check().is_err());
+        assert!(
+            Compression {
+                algorithm: Algorithm::None,
+                level: 9000
+            }
+            .check()
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn corrupt_blobs() -> Result<()> {
+        let blobs: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let mut manifest: Vec<_> = blobs
+            .iter()
+            .map(|b| PackManifestEntry {
+                blob_type: blob::Type::Chunk,
+                length: b.len() as u32,
+                id: ObjectId::hash(b),
+            })
+            .collect();
+        // Lie about the second one.
+        manifest[1].id = ObjectId::hash(b"2nd");
+
+        let mut packfile = MAGIC_BYTES.to_vec();
+        packfile.extend(zstd::bulk::compress(&blobs.concat(), 0)?);
+
+        let read = AtomicU64::new(0);
+        let corrupt = find_corrupt_blobs(&mut io::Cursor::new(&packfile), &manifest, &read)?;
+        assert_eq!(
+            corrupt,
+            [CorruptBlob {
+                id: ObjectId::hash(b"2nd"),
+                offset: 5,
+                actual: ObjectId::hash(b"second"),
+            }]
+        );
+        assert_eq!(read.load(Ordering::Relaxed), 3);
+
+        // Running out of pack is an error, not a corrupt blob.
+        manifest.push(manifest[0]);
+        assert!(find_corrupt_blobs(&mut io::Cursor::new(&packfile), &manifest, &read).is_err());
+        Ok(())
+    }
 
     #[test]
     /// Pack manifest and ID remains stable from build to build.
@@ -534,7 +971,8 @@ mod test {
 
     #[test]
     fn smoke() -> Result<()> {
-        let chunks: Vec<_> = chunk::chunk_file("tests/references/sr71.txt")
+        let cc = chunk::ChunkConfig::default();
+        let chunks: Vec<_> = chunk::chunk_file("tests/references/sr71.txt", &cc)
             .context("Couldn't chunk reference file")?
             .collect();
         let (chunk_tx, chunk_rx) = sync_channel(0);
@@ -545,6 +983,7 @@ mod test {
         let chunk_packer = std::thread::spawn(move || {
             pack(
                 DEFAULT_PACK_SIZE,
+                Compression::default(),
                 chunk_rx,
                 pack_tx,
                 upload_tx,
@@ -81,3 +81,39 @@ pub mod instant_option {
             .map_err(serde::de::Error::custom)
     }
 }
+
+pub mod instant_map {
+    use std::collections::BTreeMap;
+
+    use jiff::Timestamp;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K, S>(m: &BTreeMap<K, Timestamp>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize + Ord,
+        S: Serializer,
+    {
+        if super::should_prettify() {
+            m.serialize(serializer)
+        } else {
+            let nanos: BTreeMap<&K, i64> = m
+                .iter()
+                .map(|(k, t)| (k, t.as_nanosecond() as i64))
+                .collect();
+            nanos.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, K, D>(d: D) -> Result<BTreeMap<K, Timestamp>, D::Error>
+    where
+        K: Deserialize<'de> + Ord,
+        D: Deserializer<'de>,
+    {
+        let nanos = BTreeMap::<K, i64>::deserialize(d)?;
+        nanos
+            .into_iter()
+            .map(|(k, i)| Ok((k, Timestamp::from_nanosecond(i as i128)?)))
+            .collect::<Result<_, jiff::Error>>()
+            .map_err(serde::de::Error::custom)
+    }
+}
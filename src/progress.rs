@@ -1,5 +1,5 @@
 use std::{
-    io::{self, Read, Write},
+    io::{self, Read, Seek, Write},
     sync::{
         Arc,
         atomic::{AtomicBool, AtomicU64, Ordering},
@@ -41,6 +41,17 @@ impl<R: Read> Read for AtomicCountRead<'_, R> {
     }
 }
 
+impl<R: Seek> Seek for AtomicCountRead<'_, R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let before = self.inner.stream_position()?;
+        let after = self.inner.seek(pos)?;
+        // Don't count bytes twice if we're going back to read them again.
+        self.count
+            .fetch_sub(before.saturating_sub(after), Ordering::Relaxed);
+        Ok(after)
+    }
+}
+
 pub struct AtomicCountWrite<'a, W> {
     inner: W,
     count: &'a AtomicU64,
@@ -1,11 +1,17 @@
 //! Tools to traverse a repository, reading blobs
 //!
 //! This is ultimately how we read backups back out for restore, repack, etc.
-use std::{cmp::Ordering, io::prelude::*, rc::Rc, time::Instant};
+use std::{
+    cmp::Ordering,
+    io::prelude::*,
+    rc::Rc,
+    sync::{Condvar, Mutex},
+    time::Instant,
+};
 
 use anyhow::{Context, Result, anyhow, ensure};
 use mut_binary_heap::{BinaryHeap, FnComparator};
-use rustc_hash::FxHashSet;
+use rustc_hash::{FxHashMap, FxHashSet};
 use tracing::*;
 
 use crate::backend;
@@ -16,8 +22,6 @@ use crate::hashing::{HashingReader, ObjectId};
 use crate::index;
 use crate::pack;
 
-type ZstdDecoder<R> = zstd::stream::read::Decoder<'static, R>;
-
 struct TimestampedChunk {
     stamp: Instant,
     chunk: Rc<Vec<u8>>,
@@ -92,6 +96,96 @@ impl ChunkCache {
     }
 }
 
+/// Downloads packs into the backend's cache ahead of a [`ChunkReader`],
+/// so that they're cache hits by the time it gets to them.
+///
+/// Give it the packs in the order they'll be read,
+/// then run [`run()`](Self::run) on as many threads as you'd like downloads in flight.
+/// Workers stay at most `window` packs ahead of the reader
+/// so we don't fill the cache (and evict packs before they're read)
+/// with ones we won't need for a while.
+pub struct Prefetch {
+    order: Vec<ObjectId>,
+    positions: FxHashMap<ObjectId, usize>,
+    window: usize,
+    state: Mutex<PrefetchState>,
+    wakeup: Condvar,
+}
+
+struct PrefetchState {
+    /// The next pack (in `order`) to fetch
+    next: usize,
+    /// The pack the reader is on
+    reading: usize,
+    done: bool,
+}
+
+impl Prefetch {
+    pub fn new(order: Vec<ObjectId>, window: usize) -> Self {
+        let mut positions = FxHashMap::default();
+        for (i, id) in order.iter().enumerate() {
+            positions.entry(*id).or_insert(i);
+        }
+        Self {
+            order,
+            positions,
+            window,
+            state: Mutex::new(PrefetchState {
+                // The reader fetches the first one itself.
+                next: 1,
+                reading: 0,
+                done: false,
+            }),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Fetch packs until we run out or [`finish()`](Self::finish) is called.
+    ///
+    /// Failures are only logged - the reader will try again (and report any error)
+    /// when it gets there.
+    pub fn run(&self, cached_backend: &backend::CachedBackend) {
+        while let Some(id) = self.claim() {
+            if let Err(e) = cached_backend.prefetch_pack(&id) {
+                warn!("Couldn't prefetch pack {id}: {e:#}");
+            }
+        }
+    }
+
+    /// Wait until the next pack is in our window, and take it.
+    fn claim(&self) -> Option<ObjectId> {
+        let mut s = self.state.lock().unwrap();
+        while !s.done && s.next < self.order.len() && s.next > s.reading + self.window {
+            s = self.wakeup.wait(s).unwrap();
+        }
+        if s.done || s.next >= self.order.len() {
+            return None;
+        }
+        s.next += 1;
+        Some(self.order[s.next - 1])
+    }
+
+    /// The reader is loading the given pack; slide the window forward.
+    fn reached(&self, id: &ObjectId) {
+        let Some(&i) = self.positions.get(id) else {
+            return;
+        };
+        let mut s = self.state.lock().unwrap();
+        if i > s.reading {
+            s.reading = i;
+            // If we fell behind, don't bother fetching what the reader already went and got.
+            s.next = s.next.max(i + 1);
+            self.wakeup.notify_all();
+        }
+    }
+
+    /// Stop all workers, e.g., once the reader is done (or gave up).
+    pub fn finish(&self) {
+        self.state.lock().unwrap().done = true;
+        self.wakeup.notify_all();
+    }
+}
+
 pub struct ChunkReader<'a> {
     cached_backend: &'a backend::CachedBackend,
     index: &'a index::Index,
@@ -99,6 +193,7 @@ pub struct ChunkReader<'a> {
     cache: ChunkCache,
     read_packs: FxHashSet<ObjectId>,
     biggest_pack_size: usize,
+    prefetch: Option<&'a Prefetch>,
 }
 
 impl<'a> ChunkReader<'a> {
@@ -115,9 +210,16 @@ impl<'a> ChunkReader<'a> {
             cache,
             read_packs: FxHashSet::default(),
             biggest_pack_size: 0,
+            prefetch: None,
         }
     }
 
+    /// Let the given [`Prefetch`] know which packs we're reading as we go.
+    pub fn with_prefetch(mut self, prefetch: &'a Prefetch) -> Self {
+        self.prefetch = Some(prefetch);
+        self
+    }
+
     /// Just get a blob's size from the index. Much cheaper than actually reading the blob.
     pub fn blob_size(&mut self, id: &ObjectId) -> Result<u32> {
         let pack_id: ObjectId = *self
@@ -177,8 +279,10 @@ impl<'a> ChunkReader<'a> {
     }
 
     fn load_pack(&mut self, id: ObjectId) -> Result<usize> {
-        let mut file = self.cached_backend.read_pack(&id)?;
-        pack::check_magic(&mut file)?;
+        if let Some(p) = self.prefetch {
+            p.reached(&id);
+        }
+        let file = self.cached_backend.read_pack(&id)?;
 
         let manifest = self
             .index
@@ -186,8 +290,7 @@ impl<'a> ChunkReader<'a> {
             .get(&id)
             .ok_or_else(|| anyhow!("Couldn't find pack {} manifest in the index", id))?;
 
-        let mut blob_stream =
-            ZstdDecoder::new(file).context("Decompression of blob stream failed")?;
+        let mut blob_stream = pack::BlobReader::new(file)?;
 
         let mut bytes_read = 0;
         let mut blob_buf = vec![];
@@ -226,12 +329,38 @@ impl<'a> ChunkReader<'a> {
 mod test {
     use super::*;
 
-    use std::collections::BTreeSet;
+    use std::collections::{BTreeMap, BTreeSet};
     use std::sync::mpsc::sync_channel;
 
     use crate::blob;
     use crate::chunk;
 
+    #[test]
+    fn prefetch_window() {
+        let packs: Vec<ObjectId> = (0..5u8).map(|i| ObjectId::hash(&[i])).collect();
+        let p = Prefetch::new(packs.clone(), 2);
+
+        // The reader gets the first; we get the next two.
+        assert_eq!(p.claim(), Some(packs[1]));
+        assert_eq!(p.claim(), Some(packs[2]));
+        // Moving the reader along lets us get one more.
+        p.reached(&packs[1]);
+        assert_eq!(p.claim(), Some(packs[3]));
+
+        // If the reader skips ahead, so do we.
+        p.reached(&packs[4]);
+        assert_eq!(p.claim(), None);
+
+        // Workers waiting on the reader stop when we're done.
+        let p = Prefetch::new(packs.clone(), 1);
+        assert_eq!(p.claim(), Some(packs[1]));
+        std::thread::scope(|s| {
+            let waiting = s.spawn(|| p.claim());
+            p.finish();
+            assert_eq!(waiting.join().unwrap(), None);
+        });
+    }
+
     #[test]
     fn smoke() -> Result<()> {
         // Create a backend with a single pack from our reference files
@@ -239,10 +368,11 @@ mod test {
 
         let mut chunks = Vec::new();
 
-        chunks.extend(chunk::chunk_file("tests/references/sr71.txt")?);
-        chunks.extend(chunk::chunk_file("tests/references/index.stability")?);
-        chunks.extend(chunk::chunk_file("tests/references/pack.stability")?);
-        chunks.extend(chunk::chunk_file("tests/references/README.md")?);
+        let cc = chunk::ChunkConfig::default();
+        chunks.extend(chunk::chunk_file("tests/references/sr71.txt", &cc)?);
+        chunks.extend(chunk::chunk_file("tests/references/index.stability", &cc)?);
+        chunks.extend(chunk::chunk_file("tests/references/pack.stability", &cc)?);
+        chunks.extend(chunk::chunk_file("tests/references/README.md", &cc)?);
         assert_eq!(chunks.len(), 4);
 
         let (chunk_tx, chunk_rx) = sync_channel(0);
@@ -253,6 +383,7 @@ mod test {
         let chunk_packer = std::thread::spawn(move || {
             pack::pack(
                 pack::DEFAULT_PACK_SIZE,
+                pack::Compression::default(),
                 chunk_rx,
                 pack_tx,
                 upload_tx,
@@ -285,7 +416,11 @@ mod test {
             let mut packs = index::PackMap::new();
             packs.insert(metadata.id, metadata.manifest);
 
-            index::Index { packs, supersedes }
+            index::Index {
+                packs,
+                supersedes,
+                pack_times: BTreeMap::new(),
+            }
         };
         let blob_map = index::blob_to_pack_map(&index)?;
 
@@ -77,10 +77,7 @@ pub fn walk_snapshots<Filter>(
     stats: &WalkStatistics,
 ) -> Result<Vec<Snapshot>>
 where
-    Filter: FnMut(
-        &Utf8Path,
-        // More some day?
-    ) -> bool,
+    Filter: FnMut(&Utf8Path, &tree::Node) -> bool,
 {
     let new_snaps = snapshots_and_forests
         .iter()
@@ -100,10 +97,7 @@ fn walk_snapshot<Filter>(
     stats: &WalkStatistics,
 ) -> Result<Snapshot>
 where
-    Filter: FnMut(
-        &Utf8Path,
-        // More some day?
-    ) -> bool,
+    Filter: FnMut(&Utf8Path, &tree::Node) -> bool,
 {
     let action = match op {
         Op::Copy => "Copying snapshot",
@@ -147,10 +141,7 @@ fn walk_tree<Filter>(
     stats: &WalkStatistics,
 ) -> Result<ObjectId>
 where
-    Filter: FnMut(
-        &Utf8Path,
-        // More some day?
-    ) -> bool,
+    Filter: FnMut(&Utf8Path, &tree::Node) -> bool,
 {
     let tree: &tree::Tree = forest
         .get(tree_id)
@@ -166,7 +157,7 @@ where
     for (path, node) in tree {
         let mut node_path = tree_path.to_owned();
         node_path.push(path);
-        if !filter(&node_path) {
+        if !filter(&node_path, node) {
             debug!("  {:>9} {node_path}", "skip");
             continue;
         }
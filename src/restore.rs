@@ -0,0 +1,327 @@
+//! Where [restore](crate::ui::restore) writes things.
+//!
+//! Restoring is a diff between the filesystem and a snapshot where we act on each difference.
+//! Those actions go through a [`RestoreSink`] so we can swap the real filesystem
+//! for an in-memory one when testing, or for nothing at all when
+//! [checking](crate::ui::check) that files can be put back together.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::prelude::*,
+};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
+use jiff::Timestamp;
+
+/// The operations restore needs to put a snapshot somewhere.
+///
+/// All paths are already translated to their destinations.
+pub trait RestoreSink {
+    /// Create (or truncate) a file, returning something to write its contents to.
+    fn create_file(&mut self, path: &Utf8Path) -> Result<Box<dyn Write + '_>>;
+
+    fn create_dir(&mut self, path: &Utf8Path) -> Result<()>;
+
+    /// Make `path` a symbolic link pointing to `target`.
+    fn symlink(&mut self, target: &Utf8Path, path: &Utf8Path) -> Result<()>;
+
+    /// Remove a file or symlink (without following it).
+    fn remove_file(&mut self, path: &Utf8Path) -> Result<()>;
+
+    /// Remove a directory and everything in it.
+    fn remove_dir_all(&mut self, path: &Utf8Path) -> Result<()>;
+
+    /// Set access and modification times (without following symlinks).
+    fn set_times(&mut self, path: &Utf8Path, atime: Timestamp, mtime: Timestamp) -> Result<()>;
+
+    fn set_permissions(&mut self, path: &Utf8Path, mode: u32) -> Result<()>;
+}
+
+/// Restores to the actual filesystem.
+#[derive(Debug, Default)]
+pub struct FilesystemSink;
+
+impl RestoreSink for FilesystemSink {
+    fn create_file(&mut self, path: &Utf8Path) -> Result<Box<dyn Write + '_>> {
+        let fh = File::create(path).with_context(|| format!("Couldn't create file {path}"))?;
+        Ok(Box::new(fh))
+    }
+
+    fn create_dir(&mut self, path: &Utf8Path) -> Result<()> {
+        fs::create_dir(path).with_context(|| format!("Couldn't create dir {path}"))
+    }
+
+    #[cfg(windows)]
+    fn symlink(&mut self, _target: &Utf8Path, _path: &Utf8Path) -> Result<()> {
+        // Uhh, we need to figure out if it's a directory?
+        // This is likely to fail without elevated perms?
+        // https://doc.rust-lang.org/std/os/windows/fs/fn.symlink_file.html
+        todo!("Windows symlink creation is tricky");
+    }
+
+    #[cfg(unix)]
+    fn symlink(&mut self, target: &Utf8Path, path: &Utf8Path) -> Result<()> {
+        std::os::unix::fs::symlink(target, path)
+            .with_context(|| format!("Couldn't create symlink {path}"))
+    }
+
+    fn remove_file(&mut self, path: &Utf8Path) -> Result<()> {
+        fs::remove_file(path).with_context(|| format!("Couldn't remove {path}"))
+    }
+
+    fn remove_dir_all(&mut self, path: &Utf8Path) -> Result<()> {
+        fs::remove_dir_all(path).with_context(|| format!("Couldn't remove dir {path}"))
+    }
+
+    #[cfg(unix)]
+    fn set_times(&mut self, path: &Utf8Path, atime: Timestamp, mtime: Timestamp) -> Result<()> {
+        use rustix::fs::*;
+        let stamps = Timestamps {
+            last_access: to_timespec(atime),
+            last_modification: to_timespec(mtime),
+        };
+        utimensat(CWD, path.as_str(), &stamps, AtFlags::SYMLINK_NOFOLLOW)
+            .with_context(|| format!("Couldn't set timestamps for {path}"))
+    }
+
+    #[cfg(windows)]
+    fn set_times(&mut self, _path: &Utf8Path, _atime: Timestamp, _mtime: Timestamp) -> Result<()> {
+        todo!("lol windows metadata");
+    }
+
+    #[cfg(unix)]
+    fn set_permissions(&mut self, path: &Utf8Path, mode: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Couldn't chmod {path}"))
+    }
+
+    #[cfg(windows)]
+    fn set_permissions(&mut self, _path: &Utf8Path, _mode: u32) -> Result<()> {
+        todo!("lol windows metadata");
+    }
+}
+
+#[cfg(unix)]
+fn to_timespec(t: Timestamp) -> rustix::fs::Timespec {
+    rustix::fs::Timespec {
+        tv_sec: t.as_second(),
+        tv_nsec: t.subsec_nanosecond() as i64,
+    }
+}
+
+/// Throws away everything restored to it, just counting the bytes of file contents.
+#[derive(Debug, Default)]
+pub struct DiscardSink {
+    pub bytes_written: u64,
+}
+
+struct CountingWriter<'a>(&'a mut u64);
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        *self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RestoreSink for DiscardSink {
+    fn create_file(&mut self, _path: &Utf8Path) -> Result<Box<dyn Write + '_>> {
+        Ok(Box::new(CountingWriter(&mut self.bytes_written)))
+    }
+
+    fn create_dir(&mut self, _path: &Utf8Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn symlink(&mut self, _target: &Utf8Path, _path: &Utf8Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_file(&mut self, _path: &Utf8Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir_all(&mut self, _path: &Utf8Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_times(&mut self, _path: &Utf8Path, _atime: Timestamp, _mtime: Timestamp) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_permissions(&mut self, _path: &Utf8Path, _mode: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryContents {
+    File(Vec<u8>),
+    Directory,
+    Symlink(Utf8PathBuf),
+}
+
+/// Something "restored" to a [`MemorySink`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryEntry {
+    pub contents: MemoryContents,
+    /// (access, modification), if they were set
+    pub times: Option<(Timestamp, Timestamp)>,
+    /// Permissions, if they were set
+    pub mode: Option<u32>,
+}
+
+impl MemoryEntry {
+    fn new(contents: MemoryContents) -> Self {
+        Self {
+            contents,
+            times: None,
+            mode: None,
+        }
+    }
+}
+
+/// Records what restore would write instead of writing it, for testing.
+///
+/// Starts empty; a directory needs to exist (or be created) before things go in it.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    pub entries: BTreeMap<Utf8PathBuf, MemoryEntry>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an existing directory to restore into (e.g., `--output`).
+    pub fn with_dir(mut self, path: impl Into<Utf8PathBuf>) -> Self {
+        self.entries
+            .insert(path.into(), MemoryEntry::new(MemoryContents::Directory));
+        self
+    }
+
+    fn check_parent(&self, path: &Utf8Path) -> Result<()> {
+        match path.parent() {
+            None => Ok(()),
+            Some(p) if p.as_str().is_empty() => Ok(()),
+            Some(p) => match self.entries.get(p) {
+                Some(MemoryEntry {
+                    contents: MemoryContents::Directory,
+                    ..
+                }) => Ok(()),
+                Some(_) => Err(anyhow!("{p} is not a directory")),
+                None => Err(anyhow!("{p} doesn't exist")),
+            },
+        }
+    }
+
+    fn get_mut(&mut self, path: &Utf8Path) -> Result<&mut MemoryEntry> {
+        self.entries
+            .get_mut(path)
+            .ok_or_else(|| anyhow!("{path} doesn't exist"))
+    }
+}
+
+impl RestoreSink for MemorySink {
+    fn create_file(&mut self, path: &Utf8Path) -> Result<Box<dyn Write + '_>> {
+        self.check_parent(path)?;
+        let entry = self
+            .entries
+            .entry(path.to_owned())
+            .or_insert_with(|| MemoryEntry::new(MemoryContents::File(vec![])));
+        match &mut entry.contents {
+            MemoryContents::File(contents) => {
+                contents.clear();
+                Ok(Box::new(contents))
+            }
+            _ => Err(anyhow!("Couldn't create file {path}: not a file")),
+        }
+    }
+
+    fn create_dir(&mut self, path: &Utf8Path) -> Result<()> {
+        self.check_parent(path)?;
+        ensure!(
+            !self.entries.contains_key(path),
+            "Couldn't create dir {path}: already exists"
+        );
+        self.entries
+            .insert(path.to_owned(), MemoryEntry::new(MemoryContents::Directory));
+        Ok(())
+    }
+
+    fn symlink(&mut self, target: &Utf8Path, path: &Utf8Path) -> Result<()> {
+        self.check_parent(path)?;
+        ensure!(
+            !self.entries.contains_key(path),
+            "Couldn't create symlink {path}: already exists"
+        );
+        self.entries.insert(
+            path.to_owned(),
+            MemoryEntry::new(MemoryContents::Symlink(target.to_owned())),
+        );
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: &Utf8Path) -> Result<()> {
+        ensure!(
+            !matches!(self.get_mut(path)?.contents, MemoryContents::Directory),
+            "Couldn't remove {path}: is a directory"
+        );
+        self.entries.remove(path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&mut self, path: &Utf8Path) -> Result<()> {
+        ensure!(
+            matches!(self.get_mut(path)?.contents, MemoryContents::Directory),
+            "Couldn't remove dir {path}: not a directory"
+        );
+        self.entries.retain(|p, _| !p.starts_with(path));
+        Ok(())
+    }
+
+    fn set_times(&mut self, path: &Utf8Path, atime: Timestamp, mtime: Timestamp) -> Result<()> {
+        self.get_mut(path)?.times = Some((atime, mtime));
+        Ok(())
+    }
+
+    fn set_permissions(&mut self, path: &Utf8Path, mode: u32) -> Result<()> {
+        self.get_mut(path)?.mode = Some(mode);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_sink() -> Result<()> {
+        let mut sink = MemorySink::new().with_dir("/out");
+        sink.create_dir("/out/d".into())?;
+        sink.create_file("/out/d/f".into())?.write_all(b"hi")?;
+        sink.symlink("f".into(), "/out/d/l".into())?;
+        sink.set_permissions("/out/d/f".into(), 0o600)?;
+
+        // Like the real thing, we need parents and can't clobber.
+        assert!(sink.create_file("/out/nope/f".into()).is_err());
+        assert!(sink.create_dir("/out/d".into()).is_err());
+        assert!(sink.remove_file("/out/d".into()).is_err());
+
+        let f = &sink.entries[Utf8Path::new("/out/d/f")];
+        assert_eq!(f.contents, MemoryContents::File(b"hi".to_vec()));
+        assert_eq!(f.mode, Some(0o600));
+
+        sink.remove_dir_all("/out/d".into())?;
+        assert_eq!(sink.entries.len(), 1);
+        Ok(())
+    }
+}
@@ -12,11 +12,21 @@
 //!
 //! - Metadata like time, author, and tags.
 //!
+//! - The skip rules used to take the backup, so that comparing it to the filesystem
+//!   later doesn't report skipped files as new ones.
+//!
+//! - The repository settings (pack size, filters, chunk sizes) used to write it,
+//!   so the next backup can warn if they changed.
+//!
+//! - The parent snapshot the backup was taken on top of, if any,
+//!   so `diff --parent` can show what a backup added.
+//!
 //! Like Git commits, this makes them very lightweight - this is so little data
 //! we don't bother with compression.
 //!
-//! Unlike Git commits, they don't record their ancestor(s) - we don't especially care
-//! about the order of the snapshots so long as all the blobs in their tree are reachable.
+//! Unlike Git commits, the parent is just a note - nothing needs it to be reachable,
+//! and we don't especially care about the order of the snapshots
+//! so long as all the blobs in their tree are reachable.
 
 use std::collections::BTreeSet;
 use std::fs;
@@ -32,11 +42,12 @@ use jiff::{Timestamp, Zoned, tz::TimeZone};
 use rayon::prelude::*;
 use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
+use tracing::*;
 
 use crate::{
-    backend, counters,
-    file_util::check_magic,
-    hashing::{HashingReader, HashingWriter, ObjectId},
+    backend, chunk, counters,
+    file_util::{check_magic, nice_size},
+    hashing::{self, HashingReader, HashingWriter, ObjectId},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -47,6 +58,7 @@ pub struct Snapshot {
     /// Snapshot author, defaulting to the machine's hostname
     pub author: String,
     /// Arbitrary user tags
+    #[serde(default)]
     pub tags: BTreeSet<String>,
     /// The _absolute_ paths backed up in this snapshot,
     /// each of which will be a child in the top-level tree.
@@ -65,6 +77,145 @@ pub struct Snapshot {
     pub paths: BTreeSet<Utf8PathBuf>,
     /// A tree where each path is a child node.
     pub tree: ObjectId,
+    /// Regexes of absolute paths skipped when taking the snapshot
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub skips: Vec<String>,
+    /// True if the (single) path was read from stdin (`backup --stdin`)
+    /// instead of the filesystem.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub from_stdin: bool,
+    /// The repository settings this snapshot was taken with
+    /// (older snapshots don't have them)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub settings: Option<RepoSettings>,
+    /// The most recent snapshot of the same paths when this one was taken
+    /// (older snapshots don't know)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub parent: Option<ObjectId>,
+}
+
+impl Snapshot {
+    /// How this snapshot's files were cut into chunks
+    /// (the defaults if it predates configurable chunking)
+    pub fn chunking(&self) -> chunk::ChunkConfig {
+        self.settings
+            .as_ref()
+            .map(RepoSettings::chunking)
+            .unwrap_or_default()
+    }
+}
+
+/// The parts of a repository's [configuration](backend::Configuration) that shape its packs,
+/// recorded in each snapshot.
+///
+/// Compression and hashing aren't configurable, so there's nothing to record for those.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepoSettings {
+    pub pack_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub filter: Option<FilterFingerprint>,
+    /// `None` for the defaults (and snapshots older than configurable chunking)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub chunking: Option<chunk::ChunkConfig>,
+}
+
+/// Hashes of the filter and unfilter commands, so we can tell when they change.
+///
+/// Snapshots aren't secret (they aren't filtered), but filter commands can be
+/// (keys, passwords, ...), so we don't record the commands themselves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterFingerprint {
+    pub filter: ObjectId,
+    pub unfilter: ObjectId,
+}
+
+impl FilterFingerprint {
+    pub fn new(filter: &str, unfilter: &str) -> Self {
+        Self {
+            filter: ObjectId::hash(filter.as_bytes()),
+            unfilter: ObjectId::hash(unfilter.as_bytes()),
+        }
+    }
+}
+
+impl RepoSettings {
+    pub fn new(config: &backend::Configuration) -> Self {
+        Self {
+            pack_size: config.pack_size.as_u64(),
+            filter: config
+                .filter
+                .as_ref()
+                .map(|(f, u)| FilterFingerprint::new(f, u)),
+            chunking: config.chunking,
+        }
+    }
+
+    /// How files were cut into chunks
+    pub fn chunking(&self) -> chunk::ChunkConfig {
+        self.chunking.unwrap_or_default()
+    }
+
+    /// Describe what changed between these settings (from the given snapshot)
+    /// and `now`, in order of how much we care.
+    ///
+    /// `legacy_unfilters` are the repository's current ones;
+    /// no need to tell people to add an unfilter they already have.
+    pub fn changes(
+        &self,
+        snapshot: &ObjectId,
+        now: &Self,
+        legacy_unfilters: &[String],
+    ) -> Vec<String> {
+        let snapshot = snapshot.short_name();
+        let mut changes = vec![];
+        if self.filter != now.filter {
+            let change = match (&self.filter, &now.filter) {
+                (None, _) => "added",
+                (_, None) => "removed",
+                _ => "changed",
+            };
+            let unfilter_note = match &self.filter {
+                Some(old)
+                    if now
+                        .filter
+                        .as_ref()
+                        .is_none_or(|n| n.unfilter != old.unfilter)
+                        && !legacy_unfilters
+                            .iter()
+                            .any(|u| ObjectId::hash(u.as_bytes()) == old.unfilter) =>
+                {
+                    " Add the previous unfilter to `legacy_unfilters` to keep reading older packs."
+                }
+                _ => "",
+            };
+            changes.push(format!(
+                "Filter {change} since snapshot {snapshot}.{unfilter_note}"
+            ));
+        }
+        if self.chunking() != now.chunking() {
+            changes.push(format!(
+                "Chunk sizes (min / avg / max) changed from {} to {} since snapshot {snapshot}; \
+                 files will be cut differently and won't dedupe well against older backups.",
+                self.chunking(),
+                now.chunking()
+            ));
+        }
+        if self.pack_size != now.pack_size {
+            changes.push(format!(
+                "Pack size changed from {} to {} since snapshot {snapshot}; \
+                 new packs will be a different size than older ones.",
+                nice_size(self.pack_size),
+                nice_size(now.pack_size)
+            ));
+        }
+        changes
+    }
 }
 
 // Older snapshots saved with chrono will be yyyy-mm-ddTH:M:S.f:z
@@ -105,6 +256,22 @@ struct SnapshotV2 {
 
     paths: BTreeSet<Utf8PathBuf>,
     tree: ObjectId,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    skips: Vec<String>,
+
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    from_stdin: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    settings: Option<RepoSettings>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    parent: Option<ObjectId>,
 }
 
 fn diskfmt(s: &Snapshot) -> SnapshotV2 {
@@ -115,6 +282,10 @@ fn diskfmt(s: &Snapshot) -> SnapshotV2 {
         tags: s.tags.clone(),
         paths: s.paths.clone(),
         tree: s.tree.clone(),
+        skips: s.skips.clone(),
+        from_stdin: s.from_stdin,
+        settings: s.settings.clone(),
+        parent: s.parent,
     }
 }
 
@@ -125,6 +296,10 @@ fn undiskfmt(s2: SnapshotV2) -> Snapshot {
         tags: s2.tags,
         paths: s2.paths,
         tree: s2.tree,
+        skips: s2.skips,
+        from_stdin: s2.from_stdin,
+        settings: s2.settings,
+        parent: s2.parent,
     }
 }
 
@@ -187,6 +362,18 @@ fn from_reader<R: Read>(r: &mut R) -> Result<(Snapshot, ObjectId)> {
     Ok((snapshot, id))
 }
 
+/// Verify that the snapshot in the given reader hashes to the given ID.
+pub fn verify_file<R: Read>(id: &ObjectId, r: &mut R) -> Result<()> {
+    let (_, calculated_id) = from_reader(r)?;
+    ensure!(
+        *id == calculated_id,
+        "Snapshot {}'s contents changed! Now hashes to {}",
+        id,
+        calculated_id
+    );
+    Ok(())
+}
+
 /// Loads the snapshot with the given ID from the backend,
 /// verifying its contents match its ID.
 pub fn load(id: &ObjectId, cached_backend: &backend::CachedBackend) -> Result<Snapshot> {
@@ -227,10 +414,39 @@ pub fn load_chronologically_with_total_size(
             Ok((snap, snapshot_id))
         })
         .collect::<Result<Vec<_>>>()?;
-    snapshots.sort_by_key(|(snap, _)| snap.time.timestamp());
+    sort_chronologically(&mut snapshots);
+    warn_on_future_snapshots(&snapshots, Timestamp::now());
     Ok((snapshots, total.load(Ordering::SeqCst)))
 }
 
+/// Sort by the time each snapshot was taken, breaking ties by ID
+/// so that every run (and every machine) agrees on the order.
+fn sort_chronologically(snapshots: &mut [(Snapshot, ObjectId)]) {
+    snapshots.sort_by_key(|(snap, id)| (snap.time.timestamp(), *id));
+}
+
+/// Parents only link snapshots of the same paths (and older snapshots don't have them),
+/// so we can only order snapshots by their timestamps.
+/// If some machine's clock is way off, that order is wrong.
+/// The most we can notice is a snapshot from the future.
+fn warn_on_future_snapshots(snapshots: &[(Snapshot, ObjectId)], now: Timestamp) -> usize {
+    // Don't cry wolf over a few seconds of NTP drift.
+    let slop = jiff::SignedDuration::from_mins(5);
+    let mut future = 0;
+    for (snap, id) in snapshots {
+        if snap.time.timestamp().duration_since(now) > slop {
+            warn!(
+                "Snapshot {id} from {} is dated {}, in the future! \
+                 Its clock might be off, which puts it out of order (e.g., for LAST).",
+                snap.author,
+                snap.time.datetime()
+            );
+            future += 1;
+        }
+    }
+    future
+}
+
 /// Find a given snapshot and its ID from the loaded chronological list
 pub fn find<'a>(
     chronological_snapshots: &'a [(Snapshot, ObjectId)],
@@ -266,21 +482,22 @@ pub fn find<'a>(
         };
     }
 
-    // Like Git, require at least a few digits of an ID.
-    if prefix.len() < 4 {
-        bail!("Provide a snapshot ID with at least 4 digits!");
-    }
-
-    let matches = chronological_snapshots
+    let id = hashing::find_by_prefix(
+        prefix,
+        "snapshot",
+        chronological_snapshots.iter().map(|(_s, id)| id),
+    )?;
+    Ok(chronological_snapshots
         .iter()
-        .filter(|(_s, id)| id.to_string().starts_with(prefix))
-        .collect::<Vec<_>>();
+        .find(|(_s, i)| i == id)
+        .unwrap())
+}
 
-    match matches.len() {
-        0 => bail!("No snapshots start with {}", prefix),
-        1 => Ok(matches[0]),
-        multiple => bail!("{} different snapshots start with {}", multiple, prefix,),
-    }
+/// Keep only the snapshots with all of the given tags (or all snapshots, if no tags are given).
+///
+/// Do this before [`find`] so that LAST and friends mean "the last one with those tags".
+pub fn retain_tagged(snapshots: &mut Vec<(Snapshot, ObjectId)>, tags: &[String]) {
+    snapshots.retain(|(s, _)| tags.iter().all(|t| s.tags.contains(t)));
 }
 
 /// Find the listed snapshots and their IDs, and return them chronologically and deduplicated.
@@ -294,7 +511,7 @@ pub fn from_args_list(
         desired_snaps.push((s.clone(), *i));
     }
     // Take whatever the user asked for and make it chronological with no duplicates.
-    desired_snaps.sort_by_key(|(snap, _)| snap.time.timestamp());
+    sort_chronologically(&mut desired_snaps);
     desired_snaps.dedup_by(|(_, id1), (_, id2)| id1 == id2);
     Ok(desired_snaps)
 }
@@ -318,6 +535,10 @@ mod test {
                 .map(Utf8PathBuf::from)
                 .collect::<BTreeSet<_>>(),
             tree: ObjectId::hash(b"One small step"),
+            skips: vec![],
+            from_stdin: false,
+            settings: None,
+            parent: None,
         }
     }
 
@@ -350,6 +571,71 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn untagged_v1() -> Result<()> {
+        // Make a version 1 snapshot without tags, like some older ones.
+        let snapshot = build_test_snapshot();
+        let mut value = ciborium::Value::serialized(&snapshot)?;
+        value
+            .as_map_mut()
+            .unwrap()
+            .retain(|(k, _)| k.as_text() != Some("tags"));
+        let mut bytes = MAGIC_BYTES.to_vec();
+        bytes.push(b'1');
+        ciborium::into_writer(&value, &mut bytes)?;
+
+        let (read_snapshot, _) = from_reader(&mut bytes.as_slice())?;
+        assert!(read_snapshot.tags.is_empty());
+        assert_eq!(read_snapshot.tree, snapshot.tree);
+        Ok(())
+    }
+
+    #[test]
+    fn tag_filtering() {
+        let tagged = build_test_snapshot();
+        let mut untagged = build_test_snapshot();
+        untagged.tags.clear();
+        let all = vec![
+            (tagged, ObjectId::hash(b"tagged")),
+            (untagged, ObjectId::hash(b"untagged")),
+        ];
+
+        let mut s = all.clone();
+        retain_tagged(&mut s, &[]);
+        assert_eq!(s.len(), 2);
+
+        let mut s = all.clone();
+        retain_tagged(&mut s, &["NASA".to_owned()]);
+        assert_eq!(s.len(), 1);
+        assert_eq!(s[0].1, ObjectId::hash(b"tagged"));
+
+        let mut s = all;
+        retain_tagged(&mut s, &["NASA".to_owned(), "Soyuz".to_owned()]);
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn same_time_ordering() {
+        let a = (build_test_snapshot(), ObjectId::hash(b"a"));
+        let b = (build_test_snapshot(), ObjectId::hash(b"b"));
+        let mut later = build_test_snapshot();
+        later.time = "1969-07-21T17:54:00Z[UTC]".parse().unwrap();
+        let later = (later, ObjectId::hash(b"later"));
+
+        // However they're listed, snapshots taken at the same time come out the same way.
+        let mut one = vec![later.clone(), a.clone(), b.clone()];
+        let mut two = vec![b, later, a];
+        sort_chronologically(&mut one);
+        sort_chronologically(&mut two);
+        let ids = |v: &[(Snapshot, ObjectId)]| v.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+        assert_eq!(ids(&one), ids(&two));
+        assert_eq!(one[2].1, ObjectId::hash(b"later"));
+
+        let landing: Timestamp = "1969-07-20T20:17:40Z".parse().unwrap();
+        assert_eq!(warn_on_future_snapshots(&one, landing), 1);
+        assert_eq!(warn_on_future_snapshots(&one, Timestamp::now()), 0);
+    }
+
     #[test]
     fn round_trip() -> Result<()> {
         let snapshot = build_test_snapshot();
@@ -363,4 +649,88 @@ mod test {
         assert_eq!(written_id, read_id);
         Ok(())
     }
+
+    #[test]
+    fn corrupt_write_detected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = camino::Utf8Path::from_path(dir.path()).unwrap();
+
+        let snapshot = build_test_snapshot();
+        let mut fh = tempfile()?;
+        let id = to_file(&mut fh, &snapshot)?;
+        let name = format!("{id}.snapshot");
+        let path = dir.join(&name);
+
+        fh.seek(std::io::SeekFrom::Start(0))?;
+        let mut bytes = Vec::new();
+        fh.read_to_end(&mut bytes)?;
+        fs::write(&path, &bytes)?;
+        backend::verify_written(&name, &path)?;
+
+        // Flip a bit past the magic bytes, as a bad disk might.
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, &bytes)?;
+        assert!(backend::verify_written(&name, &path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn settings_changes() {
+        let id = ObjectId::hash(b"Some snapshot");
+        let old = RepoSettings {
+            pack_size: 100_000_000,
+            filter: Some(FilterFingerprint::new("gzip", "gzip -d")),
+            chunking: None,
+        };
+        assert!(old.changes(&id, &old, &[]).is_empty());
+
+        // Spelling out the defaults is no change.
+        let explicit = RepoSettings {
+            chunking: Some(chunk::ChunkConfig::default()),
+            ..old.clone()
+        };
+        assert!(old.changes(&id, &explicit, &[]).is_empty());
+        let finer = RepoSettings {
+            chunking: Some(chunk::ChunkConfig {
+                avg: byte_unit::Byte::from_u64(768 * 1024),
+                ..chunk::ChunkConfig::default()
+            }),
+            ..old.clone()
+        };
+        let changes = old.changes(&id, &finer, &[]);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].starts_with("Chunk sizes"));
+
+        let bigger = RepoSettings {
+            pack_size: 200_000_000,
+            ..old.clone()
+        };
+        let changes = old.changes(&id, &bigger, &[]);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].starts_with("Pack size changed"));
+
+        // Changing the unfilter means we need to hang onto the old one.
+        let xz = RepoSettings {
+            filter: Some(FilterFingerprint::new("xz", "xz -d")),
+            ..old.clone()
+        };
+        let changes = old.changes(&id, &xz, &[]);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].starts_with("Filter changed"));
+        assert!(changes[0].contains("to `legacy_unfilters`"));
+        // ...unless we already are.
+        let changes = old.changes(&id, &xz, &["gzip -d".to_owned()]);
+        assert!(!changes[0].contains("legacy_unfilters"));
+
+        // Turning filtering on doesn't.
+        let unfiltered = RepoSettings {
+            filter: None,
+            ..old.clone()
+        };
+        let changes = unfiltered.changes(&id, &old, &[]);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].starts_with("Filter added"));
+        assert!(!changes[0].contains("legacy_unfilters"));
+    }
 }
@@ -4,7 +4,7 @@ use std::collections::BTreeMap;
 use std::fs;
 use std::sync::Arc;
 
-use anyhow::{Context, Result, anyhow, ensure};
+use anyhow::{Context, Result, anyhow, bail, ensure};
 use camino::{Utf8Path, Utf8PathBuf};
 use jiff::Timestamp;
 use rustc_hash::{FxHashMap, FxHashSet};
@@ -99,6 +99,12 @@ pub struct PosixMetadata {
     // No change time - it's when the metadata changes, and since we can't set that
     // when restoring a file, nor compare it meaningfully between snapshots,
     // just leave it off.
+    /// Creation time, if the filesystem has it and we were asked to save it
+    /// (see `backup --btime`)
+    #[serde(rename = "btime", with = "prettify::instant_option")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub birth_time: Option<Timestamp>,
 }
 
 /// Backup-relevant metadata taken from a `GetFileInformationByHandle()` call
@@ -128,33 +134,57 @@ pub enum NodeMetadata {
     Windows(WindowsMetadata),
 }
 
-/// For printing metadata diffs. See the `backpak diff` helptext
-pub fn meta_diff_char(l: &NodeMetadata, r: &NodeMetadata) -> Option<char> {
-    if l == r {
-        return None;
+/// Is this the same metadata, as far as diffs are concerned?
+///
+/// Birth time is only compared if both sides have it -
+/// snapshots taken without `--btime` (or on filesystems without it)
+/// shouldn't look different from the files they came from.
+pub fn same_metadata(l: &NodeMetadata, r: &NodeMetadata) -> bool {
+    match (l.birth_time(), r.birth_time()) {
+        (Some(_), Some(_)) | (None, None) => l == r,
+        _ => l.clone().without_birth_time() == r.clone().without_birth_time(),
+    }
+}
+
+/// For printing metadata diffs: a letter for each kind of metadata that changed,
+/// in the order the `backpak diff` helptext lists them (so `OP` if both ownership
+/// and permissions changed). Empty if nothing did.
+pub fn meta_diff_chars(l: &NodeMetadata, r: &NodeMetadata) -> Vec<char> {
+    if same_metadata(l, r) {
+        return vec![];
     }
     use NodeMetadata::*;
-    let c = match (l, r) {
+    match (l, r) {
         (Posix(lp), Posix(rp)) => {
+            let mut cs = vec![];
             if lp.user_id != rp.user_id || lp.group_id != rp.group_id {
-                'O'
-            } else if lp.mode != rp.mode {
-                'P'
-            } else if lp.modify_time != rp.modify_time {
-                'T'
-            } else if lp.access_time != rp.access_time {
-                'A'
-            } else {
-                'M'
+                cs.push('O');
             }
+            if lp.mode != rp.mode {
+                cs.push('P');
+            }
+            if lp.modify_time != rp.modify_time {
+                cs.push('T');
+            }
+            if lp.access_time != rp.access_time {
+                cs.push('A');
+            }
+            // Like same_metadata(), a missing birth time isn't a change.
+            if matches!((lp.birth_time, rp.birth_time), (Some(lb), Some(rb)) if lb != rb) {
+                cs.push('B');
+            }
+            // Something else (like the size) changed.
+            if cs.is_empty() {
+                cs.push('M');
+            }
+            cs
         }
         // TODO: Compare Windows
-        (Windows(_), Windows(_)) => 'M',
+        (Windows(_), Windows(_)) => vec!['M'],
         // TODO: Compare across  kinds
-        (Posix(_), Windows(_)) => 'M',
-        (Windows(_), Posix(_)) => 'M',
-    };
-    Some(c)
+        (Posix(_), Windows(_)) => vec!['M'],
+        (Windows(_), Posix(_)) => vec!['M'],
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -223,6 +253,23 @@ impl NodeMetadata {
             NodeMetadata::Windows(w) => w.access_time,
         }
     }
+
+    pub fn birth_time(&self) -> Option<Timestamp> {
+        match self {
+            NodeMetadata::Posix(p) => p.birth_time,
+            NodeMetadata::Windows(w) => w.creation_time,
+        }
+    }
+
+    /// Drop birth time, for when we weren't asked to save it.
+    ///
+    /// (Windows has always saved creation time, so leave that alone.)
+    pub fn without_birth_time(mut self) -> Self {
+        if let NodeMetadata::Posix(p) = &mut self {
+            p.birth_time = None;
+        }
+        self
+    }
 }
 
 #[cfg(unix)]
@@ -240,6 +287,11 @@ pub fn get_metadata(symlink_behavior: Symlink, path: &Utf8Path) -> Result<NodeMe
     let group_id = meta.gid();
     let access_time = Timestamp::new(meta.atime(), meta.atime_nsec() as i32).unwrap();
     let modify_time = Timestamp::new(meta.mtime(), meta.mtime_nsec() as i32).unwrap();
+    // statx() on Linux, st_birthtime on the BSDs and macOS, an error where it isn't available.
+    let birth_time = meta
+        .created()
+        .ok()
+        .and_then(|t| Timestamp::try_from(t).ok());
 
     Ok(NodeMetadata::Posix(PosixMetadata {
         mode,
@@ -248,6 +300,7 @@ pub fn get_metadata(symlink_behavior: Symlink, path: &Utf8Path) -> Result<NodeMe
         group_id,
         access_time,
         modify_time,
+        birth_time,
     }))
 }
 
@@ -275,6 +328,34 @@ pub fn get_metadata(symlink_behavior: Symlink, path: &Utf8Path) -> Result<NodeMe
     }))
 }
 
+/// Metadata for a file that never existed on disk (e.g., `backup --stdin`):
+/// owned by us, `rw-r--r--`, and modified just now.
+#[cfg(unix)]
+pub fn synthetic_file_metadata(size: u64) -> NodeMetadata {
+    let now = Timestamp::now();
+    NodeMetadata::Posix(PosixMetadata {
+        mode: 0o100644,
+        size: Some(size),
+        user_id: rustix::process::getuid().as_raw(),
+        group_id: rustix::process::getgid().as_raw(),
+        access_time: now,
+        modify_time: now,
+        birth_time: None,
+    })
+}
+
+#[cfg(windows)]
+pub fn synthetic_file_metadata(size: u64) -> NodeMetadata {
+    let now = Some(Timestamp::now());
+    NodeMetadata::Windows(WindowsMetadata {
+        attributes: 0x80, // FILE_ATTRIBUTE_NORMAL
+        size: Some(size),
+        creation_time: now,
+        access_time: now,
+        write_time: now,
+    })
+}
+
 #[cfg(windows)]
 fn windows_timestamp(ts: u64) -> Option<Timestamp> {
     // Windows returns 100ns intervals since January 1, 1601
@@ -420,31 +501,43 @@ impl<'a> Cache<'a> {
 
 /// Reads the given tree and all its subtrees from the given tree cache.
 pub fn forest_from_root(root: &ObjectId, cache: &mut Cache) -> Result<Forest> {
+    forest_from_loader(root, &mut |id| cache.read(id))
+}
+
+/// Like [`forest_from_root`], but reading trees with any function,
+/// e.g., a [`diff::TreeLoader`](crate::diff::TreeLoader).
+pub fn forest_from_loader<F>(root: &ObjectId, read_tree: &mut F) -> Result<Forest>
+where
+    F: FnMut(&ObjectId) -> Result<Arc<Tree>> + ?Sized,
+{
     trace!("Assembling forest from root {}", root);
     let mut forest = Forest::default();
     let mut stack_set = FxHashSet::default();
-    append_tree(root, &mut forest, cache, &mut stack_set)?;
+    append_tree(root, &mut forest, read_tree, &mut stack_set)?;
     Ok(forest)
 }
 
-fn append_tree(
+fn append_tree<F>(
     tree_id: &ObjectId,
     forest: &mut Forest,
-    cache: &mut Cache,
+    read_tree: &mut F,
     stack_set: &mut FxHashSet<ObjectId>,
-) -> Result<()> {
+) -> Result<()>
+where
+    F: FnMut(&ObjectId) -> Result<Arc<Tree>> + ?Sized,
+{
     ensure!(
         stack_set.insert(*tree_id),
         "Cycle detected! Tree {} loops up",
         tree_id
     );
 
-    let tree = cache.read(tree_id)?;
+    let tree = read_tree(tree_id)?;
     forest.insert(*tree_id, tree.clone());
     for val in tree.values().map(|v| &v.contents) {
         match val {
             NodeContents::Directory { subtree } => {
-                append_tree(subtree, forest, cache, stack_set)?;
+                append_tree(subtree, forest, read_tree, stack_set)?;
             }
             NodeContents::File { .. } | NodeContents::Symlink { .. } => {}
         };
@@ -454,6 +547,49 @@ fn append_tree(
     Ok(())
 }
 
+/// Finds the node at the given path (relative to the root tree, e.g., `me/foo.txt`)
+/// by reading only the trees along the way, not the whole forest.
+///
+/// `read_tree` is usually `|id| cache.read(id)` for some [`Cache`].
+/// Returns `None` if nothing's at that path,
+/// and an error if part of the path isn't a directory.
+pub fn walk_path<F>(root: &ObjectId, path: &Utf8Path, mut read_tree: F) -> Result<Option<Node>>
+where
+    F: FnMut(&ObjectId) -> Result<Arc<Tree>>,
+{
+    use camino::Utf8Component;
+
+    let mut components = path
+        .components()
+        .filter(|c| *c != Utf8Component::CurDir)
+        .peekable();
+    ensure!(components.peek().is_some(), "No path given");
+
+    let mut current_tree_id = *root;
+    let mut path_so_far = Utf8PathBuf::new();
+    while let Some(component) = components.next() {
+        let component = match component {
+            Utf8Component::Normal(c) => c,
+            _ => bail!("{path}: absolute paths, .., etc. aren't supported"),
+        };
+        trace!("Looking for {component} in tree {current_tree_id}");
+        path_so_far.push(component);
+        let tree = read_tree(&current_tree_id)?;
+        let Some(node) = tree.get(Utf8Path::new(component)) else {
+            return Ok(None);
+        };
+        if components.peek().is_none() {
+            return Ok(Some(node.clone()));
+        }
+        match &node.contents {
+            NodeContents::Directory { subtree } => current_tree_id = *subtree,
+            NodeContents::File { .. } => bail!("{path_so_far} is a file, not a directory"),
+            NodeContents::Symlink { .. } => bail!("{path_so_far} is a symlink, not a directory"),
+        }
+    }
+    unreachable!()
+}
+
 /// Collect the set of chunks for the files in the given tree
 pub fn chunks_in_tree(tree: &Tree) -> FxHashSet<ObjectId> {
     tree.values()
@@ -466,6 +602,56 @@ pub fn chunks_in_tree(tree: &Tree) -> FxHashSet<ObjectId> {
         })
 }
 
+/// Collect all blobs (trees and chunks) in the given forest
+pub fn blobs_in_forest(forest: &Forest) -> FxHashSet<ObjectId> {
+    let mut blobs = FxHashSet::default();
+    for (f, t) in forest {
+        blobs.insert(*f);
+        blobs.extend(chunks_in_tree(t));
+    }
+    blobs
+}
+
+/// How many snapshots use each blob, for figuring out what forgetting some of them frees.
+#[derive(Debug, Default)]
+pub struct BlobRefCounts(FxHashMap<ObjectId, usize>);
+
+impl BlobRefCounts {
+    /// Count the blobs of another snapshot, as given by [`blobs_in_forest`].
+    pub fn add(&mut self, blobs: &FxHashSet<ObjectId>) {
+        for b in blobs {
+            *self.0.entry(*b).or_default() += 1;
+        }
+    }
+
+    /// How many bytes of blobs only the given snapshots use,
+    /// i.e., what the next prune frees if they're forgotten.
+    ///
+    /// Each snapshot's blobs should've been [added](Self::add) already.
+    pub fn freed_by(
+        &self,
+        forgotten: &[&FxHashSet<ObjectId>],
+        size_map: &FxHashMap<ObjectId, u32>,
+    ) -> Result<u64> {
+        let mut uses: FxHashMap<&ObjectId, usize> = FxHashMap::default();
+        for b in forgotten.iter().flat_map(|f| f.iter()) {
+            *uses.entry(b).or_default() += 1;
+        }
+        let mut freed = 0;
+        for (b, n) in uses {
+            let total = self.0.get(b).copied().unwrap_or(0);
+            ensure!(n <= total, "Blob {b} wasn't counted");
+            if n == total {
+                let size = size_map
+                    .get(b)
+                    .ok_or_else(|| anyhow!("Couldn't find blob {b} to get size"))?;
+                freed += *size as u64;
+            }
+        }
+        Ok(freed)
+    }
+}
+
 /// Return the slice of chunks in a file node,
 /// or an empty slice if `node` is a directory or symlink
 fn chunks_in_node(node: &Node) -> &[ObjectId] {
@@ -625,6 +811,7 @@ mod test {
                     group_id: 5678,
                     access_time: "2020-10-30T06:30:25.157873535Z".parse().unwrap(),
                     modify_time: "2020-10-30T06:30:25.034542588Z".parse().unwrap(),
+                    birth_time: None,
                 }),
             },
         );
@@ -664,4 +851,187 @@ mod test {
         assert_eq!(serialized_tree, from_example);
         Ok(())
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn birth_times() -> Result<()> {
+        let f = tempfile::NamedTempFile::new()?;
+        let path = Utf8Path::from_path(f.path()).unwrap();
+        let meta = get_metadata(Symlink::Read, path)?;
+        // Only some platforms (and filesystems) have them.
+        if fs::metadata(path)?.created().is_ok() {
+            assert!(meta.birth_time().is_some());
+        }
+
+        // Not having a birth time doesn't count as a change...
+        let without = meta.clone().without_birth_time();
+        assert_eq!(without.birth_time(), None);
+        assert!(same_metadata(&meta, &without));
+        assert!(meta_diff_chars(&meta, &without).is_empty());
+
+        // ...but having a different one does.
+        let mut other = without.clone();
+        if let NodeMetadata::Posix(p) = &mut other {
+            p.birth_time = Some(Timestamp::UNIX_EPOCH);
+        }
+        let mut another = without;
+        if let NodeMetadata::Posix(p) = &mut another {
+            p.birth_time = Some(Timestamp::MAX);
+        }
+        assert_ne!(other, another);
+        assert_eq!(meta_diff_chars(&other, &another), ['B']);
+        Ok(())
+    }
+
+    #[test]
+    fn several_metadata_changes() {
+        let base = PosixMetadata {
+            mode: 0o100644,
+            size: Some(42),
+            user_id: 1000,
+            group_id: 1000,
+            access_time: "2020-10-30T06:30:25Z".parse().unwrap(),
+            modify_time: "2020-10-30T06:30:25Z".parse().unwrap(),
+            birth_time: None,
+        };
+        let chars = |changed: PosixMetadata| {
+            meta_diff_chars(
+                &NodeMetadata::Posix(base.clone()),
+                &NodeMetadata::Posix(changed),
+            )
+        };
+        let later: Timestamp = "2024-01-01T00:00:00Z".parse().unwrap();
+
+        assert!(chars(base.clone()).is_empty());
+        // chown + chmod
+        assert_eq!(
+            chars(PosixMetadata {
+                group_id: 0,
+                mode: 0o100600,
+                ..base.clone()
+            }),
+            ['O', 'P']
+        );
+        // Touched and read
+        assert_eq!(
+            chars(PosixMetadata {
+                access_time: later,
+                modify_time: later,
+                ..base.clone()
+            }),
+            ['T', 'A']
+        );
+        // Everything (and a birth time we didn't have before, which doesn't count)
+        assert_eq!(
+            chars(PosixMetadata {
+                mode: 0o100755,
+                user_id: 0,
+                access_time: later,
+                modify_time: later,
+                birth_time: Some(later),
+                ..base.clone()
+            }),
+            ['O', 'P', 'T', 'A']
+        );
+        // Only something we don't have a letter for
+        assert_eq!(
+            chars(PosixMetadata {
+                size: Some(43),
+                ..base.clone()
+            }),
+            ['M']
+        );
+    }
+
+    #[test]
+    fn walk_path_reads_only_whats_needed() -> Result<()> {
+        let meta = |mode| {
+            NodeMetadata::Posix(PosixMetadata {
+                mode,
+                size: None,
+                user_id: 1000,
+                group_id: 1000,
+                access_time: "2020-10-30T06:30:25Z".parse().unwrap(),
+                modify_time: "2020-10-30T06:30:25Z".parse().unwrap(),
+                birth_time: None,
+            })
+        };
+        let file = |contents: &[u8]| Node {
+            contents: NodeContents::File {
+                chunks: vec![ObjectId::hash(contents)],
+            },
+            metadata: meta(0o100644),
+        };
+
+        let mut forest = Forest::default();
+        let mut add = |tree: Tree| {
+            let (_, id) = serialize_and_hash(&tree).unwrap();
+            forest.insert(id, Arc::new(tree));
+            id
+        };
+        let dir = |subtree| Node {
+            contents: NodeContents::Directory { subtree },
+            metadata: meta(0o040755),
+        };
+
+        // root/
+        //   me/
+        //     deep/
+        //       foo.txt
+        //   you/
+        //     bar.txt
+        let deep = add(Tree::from([("foo.txt".into(), file(b"foo"))]));
+        let me = add(Tree::from([("deep".into(), dir(deep))]));
+        let you = add(Tree::from([("bar.txt".into(), file(b"bar"))]));
+        let root = add(Tree::from([
+            ("me".into(), dir(me)),
+            ("you".into(), dir(you)),
+        ]));
+
+        let read = std::cell::RefCell::new(vec![]);
+        let read_tree = |id: &ObjectId| {
+            read.borrow_mut().push(*id);
+            Ok(forest.get(id).unwrap().clone())
+        };
+
+        let found = walk_path(&root, Utf8Path::new("me/deep/foo.txt"), read_tree)?;
+        assert_eq!(found, Some(file(b"foo")));
+        // We never looked at you/
+        assert_eq!(*read.borrow(), [root, me, deep]);
+
+        read.borrow_mut().clear();
+        let found = walk_path(&root, Utf8Path::new("me/deep"), read_tree)?;
+        assert_eq!(found, Some(dir(deep)));
+        assert_eq!(*read.borrow(), [root, me]);
+
+        assert_eq!(walk_path(&root, Utf8Path::new("me/nope"), read_tree)?, None);
+        assert!(walk_path(&root, Utf8Path::new("you/bar.txt/baz"), read_tree).is_err());
+        assert!(walk_path(&root, Utf8Path::new("/me"), read_tree).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn freed_by() -> Result<()> {
+        let [a, b, c, d] = [b"a", b"b", b"c", b"d"].map(|x| ObjectId::hash(x));
+        let size_map = [(a, 1), (b, 10), (c, 100), (d, 1000)].into_iter().collect();
+        let first: FxHashSet<_> = [a, b].into_iter().collect();
+        let second: FxHashSet<_> = [b, c].into_iter().collect();
+        let third: FxHashSet<_> = [c, d].into_iter().collect();
+
+        let mut counts = BlobRefCounts::default();
+        for s in [&first, &second, &third] {
+            counts.add(s);
+        }
+        // Each snapshot frees only what nothing else uses...
+        assert_eq!(counts.freed_by(&[&first], &size_map)?, 1);
+        assert_eq!(counts.freed_by(&[&second], &size_map)?, 0);
+        assert_eq!(counts.freed_by(&[&third], &size_map)?, 1000);
+        // ...but forgetting several frees what they share with each other.
+        assert_eq!(counts.freed_by(&[&first, &second], &size_map)?, 11);
+        assert_eq!(
+            counts.freed_by(&[&first, &second, &third], &size_map)?,
+            1111
+        );
+        Ok(())
+    }
 }
@@ -1,15 +1,23 @@
 pub mod backup;
+pub mod cache;
 pub mod cat;
 pub mod check;
+pub mod config;
 pub mod copy;
 pub mod diff;
+pub mod doctor;
 pub mod dump;
 pub mod filter_snapshot;
+pub mod find;
 pub mod forget;
 pub mod init;
 pub mod ls;
+pub mod pack;
+pub mod packs;
 pub mod prune;
+pub mod reachable;
 pub mod rebuild_index;
 pub mod restore;
+pub mod snapshot;
 pub mod snapshots;
 pub mod usage;
@@ -1,10 +1,12 @@
 use std::cell::RefCell;
 use std::collections::BTreeSet;
+use std::fmt;
 use std::io;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
 
 use anyhow::{Context, Result, bail, ensure};
+use byte_unit::Byte;
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use console::Term;
@@ -16,7 +18,7 @@ use crate::backup::{self, *};
 use crate::blob::{self, Blob};
 use crate::chunk;
 use crate::config::Configuration;
-use crate::file_util::nice_size;
+use crate::file_util::{nice_size, summary_size};
 use crate::filter;
 use crate::fs_tree;
 use crate::hashing::{HashingWriter, ObjectId};
@@ -33,6 +35,16 @@ pub struct Args {
     #[clap(short = 'L', long)]
     dereference: bool,
 
+    /// Save files' creation (birth) times, where the filesystem has them
+    ///
+    /// Restoring them depends on the platform; Linux can't set birth times at all.
+    #[clap(long, verbatim_doc_comment)]
+    btime: bool,
+
+    /// What to do with files whose names aren't valid UTF-8
+    #[clap(long, value_enum, default_value_t)]
+    on_invalid_name: fs_tree::InvalidNames,
+
     /// The author of the snapshot (otherwise the hostname is used)
     #[clap(short, long, name = "name")]
     author: Option<String>,
@@ -41,35 +53,131 @@ pub struct Args {
     #[clap(short = 't', long = "tag", name = "tag")]
     tags: Vec<String>,
 
-    /// Skip anything whose absolute path matches the given regular expression
-    #[clap(short = 's', long = "skip", name = "regex")]
+    /// Skip anything whose absolute path matches the given regular expression,
+    /// or with glob:, gitignore-style glob. Start with ! to un-skip matches instead;
+    /// the last matching rule wins. (See config skips, which come first.)
+    #[clap(short = 's', long = "skip", name = "regex", verbatim_doc_comment)]
     skips: Vec<String>,
 
+    /// Read skip rules (regexes, glob:..., and !..., like --skip) from the given file,
+    /// one per line
+    ///
+    /// Blank lines and lines starting with # are ignored.
+    #[clap(long, name = "PATTERN_FILE", verbatim_doc_comment)]
+    exclude_from: Vec<Utf8PathBuf>,
+
+    /// Skip anything matching the given gitignore-style glob (like --skip glob:...)
+    ///
+    /// Rules apply in this order, and the last one to match a path wins:
+    /// config skips, --exclude-from, --exclude-file, --skip, then --exclude.
+    #[clap(short = 'x', long, name = "GLOB", verbatim_doc_comment)]
+    exclude: Vec<String>,
+
+    /// Read globs (like --exclude, not regexes) from the given file,
+    /// one per line, like a .gitignore
+    #[clap(
+        long,
+        name = "GLOB_FILE",
+        visible_alias = "exclude-glob-file",
+        verbatim_doc_comment
+    )]
+    exclude_file: Vec<Utf8PathBuf>,
+
+    /// Skip any directory containing a file with the given name (e.g., .nobackup)
+    ///
+    /// Skipped directories are saved as skip rules in the snapshot,
+    /// so `diff` and `restore` leave them alone too.
+    #[clap(long, name = "FILE", verbatim_doc_comment)]
+    exclude_if_present: Vec<String>,
+
     #[clap(short = 'n', long)]
     dry_run: bool,
 
+    /// Stop the backup once this much has been uploaded (e.g., 5GiB)
+    ///
+    /// The pack in progress is finished and indexed first,
+    /// so expect to go over by about a pack.
+    /// Run the backup again to pick up where it left off.
+    #[clap(long, name = "SIZE", verbatim_doc_comment)]
+    upload_budget: Option<String>,
+
+    /// Back up stdin as a single file instead of walking the filesystem
+    ///
+    /// For piping in database dumps and the like:
+    ///     pg_dump mydb | backpak backup --stdin --stdin-name db/dump.sql
+    /// The file is owned by you, rw-r--r--, and modified when the backup ran.
+    #[clap(
+        long,
+        requires = "stdin_name",
+        conflicts_with = "paths",
+        verbatim_doc_comment
+    )]
+    stdin: bool,
+
+    /// Where the file read with --stdin goes (relative to the current directory)
+    #[clap(long, name = "stdin_name", value_name = "PATH", requires = "stdin")]
+    stdin_name: Option<Utf8PathBuf>,
+
+    /// Make a separate snapshot of each path instead of one snapshot of them all
+    ///
+    /// Each can then be restored, diffed, and forgotten on its own,
+    /// but they're all packed (and deduplicated) together.
+    #[clap(
+        long,
+        visible_alias = "one-snapshot-per-path",
+        conflicts_with = "stdin",
+        verbatim_doc_comment
+    )]
+    separate: bool,
+
     /// The paths to back up
     ///
     /// These paths are canonicalized into absolute ones.
     /// Snapshots can be restored to either the same absolute paths,
     /// or to a given directory with `restore -o some/dir`
-    #[clap(required = true, verbatim_doc_comment)]
+    #[clap(required_unless_present = "stdin", verbatim_doc_comment)]
     paths: Vec<Utf8PathBuf>,
 }
 
-pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+pub fn run(mut config: Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
     // Let's canonicalize our paths (and make sure they're real!)
     // before we spin up a bunch of supporting infrastructure.
-    let paths: BTreeSet<Utf8PathBuf> = args
-        .paths
-        .into_iter()
-        .map(|p| {
-            p.canonicalize_utf8()
-                .with_context(|| format!("Couldn't canonicalize {p}"))
-        })
-        .collect::<Result<BTreeSet<Utf8PathBuf>>>()?;
+    let paths: BTreeSet<Utf8PathBuf> = match &args.stdin_name {
+        // There's nothing to canonicalize - it doesn't exist -
+        // but make it absolute like any other snapshot path.
+        Some(name) => {
+            ensure!(
+                name.file_name().is_some(),
+                "--stdin-name {name} doesn't end in a file name"
+            );
+            let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)
+                .context("Current directory isn't UTF-8")?;
+            BTreeSet::from([cwd.join(name)])
+        }
+        None => args
+            .paths
+            .into_iter()
+            .map(|p| {
+                p.canonicalize_utf8()
+                    .with_context(|| format!("Couldn't canonicalize {p}"))
+            })
+            .collect::<Result<BTreeSet<Utf8PathBuf>>>()?,
+    };
+
+    // Each snapshot has its own top-level tree, so names only need to be unique within one.
+    let snapshot_paths: Vec<BTreeSet<Utf8PathBuf>> = if args.separate {
+        paths.iter().map(|p| BTreeSet::from([p.clone()])).collect()
+    } else {
+        reject_matching_directories(&paths)?;
+        vec![paths.clone()]
+    };
 
-    reject_matching_directories(&paths)?;
+    let upload_budget = args
+        .upload_budget
+        .map(|s| Byte::parse_str(s, true)) // Don't interpret b as bits.
+        .transpose()
+        .context("Couldn't parse --upload-budget")?
+        .map(|b| b.as_u64());
 
     let symlink_behavior = if args.dereference {
         tree::Symlink::Dereference
@@ -77,59 +185,94 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
         tree::Symlink::Read
     };
 
-    let skips = {
-        if config.skips.is_empty() {
-            args.skips
-        } else {
-            let mut s = config.skips;
-            s.extend(args.skips);
-            s.sort();
-            s.dedup();
-            // Dumb, but makes it less ambiguous as to what escapes are for the regex
-            // and which are for str's Display instance
-            debug!("Config merged with args for skip list:");
-            for a in &s {
-                debug!("skip {a}");
-            }
-            s
-        }
-    };
+    // Files first, so one-off rules on the command line can override them.
+    let mut arg_skips = vec![];
+    for f in &args.exclude_from {
+        arg_skips.extend(filter::read_patterns(f)?);
+    }
+    for f in &args.exclude_file {
+        arg_skips.extend(
+            filter::read_patterns(f)?
+                .iter()
+                .map(|g| filter::glob_rule(g)),
+        );
+    }
+    arg_skips.extend(args.skips);
+    arg_skips.extend(args.exclude.iter().map(|g| filter::glob_rule(g)));
+
+    let mut skips = filter::merge_skips(std::mem::take(&mut config.skips), arg_skips);
+    // Dumb, but makes it less ambiguous as to what escapes are for the regex
+    // and which are for str's Display instance
+    debug!("Config merged with args for skip list:");
+    for a in &skips {
+        debug!("skip {a}");
+    }
 
     // Do a quick scan of the paths to make sure we can read them and get
     // metadata before we get backends and indexes
     // and threads and all manner of craziness going.
+    // (Unless we're reading stdin - there's nothing to scan.)
     let bytes_checked = AtomicU64::default();
-    thread::scope(|s| -> Result<_> {
-        let progress_thread =
-            ProgressThread::spawn(s, |i| print_path_check(i, &Term::stdout(), &bytes_checked));
+    let marked_dirs = if args.stdin {
+        vec![]
+    } else {
+        thread::scope(|s| -> Result<_> {
+            let progress_thread =
+                ProgressThread::spawn(s, |i| print_path_check(i, &Term::stdout(), &bytes_checked));
 
-        let check_res = check_paths(symlink_behavior, &paths, &skips, &bytes_checked)
+            let check_res = check_paths(
+                symlink_behavior,
+                args.on_invalid_name,
+                &paths,
+                &skips,
+                &args.exclude_if_present,
+                &bytes_checked,
+            )
             .context("Failed FS check prior to backup");
-        progress_thread.join();
-        check_res
-    })?;
+            progress_thread.join();
+            check_res
+        })?
+    };
+    // Skip directories with marker files by their exact path from here on out.
+    skips.extend(
+        marked_dirs
+            .iter()
+            .map(|d| format!("^{}$", regex::escape(d.as_str()))),
+    );
 
-    let (backend_config, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (backend_config, cached_backend) =
+        backend::open(repository, &config, backend::CacheBehavior::Normal)?;
 
     let index = index::build_master_index(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
 
     info!("Finding a parent snapshot");
     let snapshots = snapshot::load_chronologically(&cached_backend)?;
-    let parent = parent_snapshot(&paths, snapshots);
-    let parent = parent.as_ref();
-
+    let settings = snapshot::RepoSettings::new(&backend_config);
+    warn_on_settings_changes(&snapshots, &settings, &backend_config.legacy_unfilters);
     trace!("Loading all trees from the parent snapshot");
     let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
-    let parent_forest = parent
-        .map(|p| tree::forest_from_root(&p.tree, &mut tree_cache))
-        .transpose()?
-        .unwrap_or_default();
+    let parent_snapshots: Vec<_> = snapshot_paths
+        .iter()
+        .map(|paths| parent_snapshot(paths, &snapshots))
+        .collect();
+    // Remember which snapshots those were so the new ones can point back to them.
+    let parent_ids: Vec<Option<ObjectId>> = parent_snapshots
+        .iter()
+        .map(|p| p.map(|(_, id)| *id))
+        .collect();
+    let parents = parent_snapshots
+        .into_iter()
+        .map(|parent| {
+            let parent_forest = parent
+                .map(|(p, _)| tree::forest_from_root(&p.tree, &mut tree_cache))
+                .transpose()?
+                .unwrap_or_default();
+            Ok((parent.map(|(p, _)| p.tree), parent_forest))
+        })
+        .collect::<Result<Vec<_>>>()?;
     drop(tree_cache);
+    drop(snapshots);
 
     // Track all the blobs we've already backed up and use that set to deduplicate.
     let mut packed_blobs = index::blob_id_set(&index)?;
@@ -152,13 +295,14 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
     };
     let back_stats = BackupStatistics::default();
     let walk_stats = WalkStatistics::default();
-    let root = thread::scope(|s| -> Result<_> {
+    let backup_res = thread::scope(|s| -> Result<_> {
         let mut backup = spawn_backup_threads(
             s,
             bmode,
             &backend_config,
             &cached_backend,
             wip_index,
+            config.checkpoint_interval,
             &back_stats,
         );
 
@@ -182,17 +326,37 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
 
             info!("Running backup...");
 
-            let root = backup_tree(
-                symlink_behavior,
-                &paths,
-                &skips,
-                parent.map(|p| &p.tree),
-                &parent_forest,
-                &mut packed_blobs,
-                &mut backup,
-                &walk_stats,
-            )?;
-            drop(parent_forest);
+            let upload_budget = upload_budget.map(|limit| UploadBudget {
+                limit,
+                uploaded: &cached_backend.bytes_uploaded,
+            });
+            let roots = if args.stdin {
+                vec![backup_stdin(
+                    paths.first().unwrap(),
+                    &mut packed_blobs,
+                    &backup,
+                    &walk_stats,
+                    upload_budget,
+                )?]
+            } else {
+                let mut roots = Vec::with_capacity(snapshot_paths.len());
+                for (paths, (parent, parent_forest)) in snapshot_paths.iter().zip(parents) {
+                    roots.push(backup_tree(
+                        symlink_behavior,
+                        args.on_invalid_name,
+                        args.btime,
+                        paths,
+                        &skips,
+                        parent.as_ref(),
+                        &parent_forest,
+                        &mut packed_blobs,
+                        &mut backup,
+                        &walk_stats,
+                        upload_budget.as_ref(),
+                    )?);
+                }
+                roots
+            };
             drop(packed_blobs);
 
             // Important: make sure all blobs and the index is written BEFORE
@@ -200,25 +364,56 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
             // It's meaningless unless everything else is there first!
             backup.join()?;
 
-            Ok(root)
+            Ok(roots)
         })();
 
         progress_thread.join();
         run_res
-    })?;
+    });
+
+    // Stopping early still finishes (and uploads) whatever pack was in progress,
+    // along with an index of everything uploaded, so we can pick back up next time.
+    let roots = match backup_res {
+        Err(e) if e.is::<OverBudget>() && args.stdin => {
+            // We can't rewind stdin, so there's no resuming.
+            let ub = cached_backend.bytes_uploaded.load(Ordering::Relaxed);
+            bail!(
+                "Stopped after uploading {} (--upload-budget is {}) of stdin. \
+                 Uploaded chunks will be reused if you pipe the same data in again.",
+                summary_size(ub),
+                summary_size(upload_budget.unwrap()),
+            );
+        }
+        Err(e) if e.is::<OverBudget>() => {
+            let ub = cached_backend.bytes_uploaded.load(Ordering::Relaxed);
+            let done = walk_stats.reused_bytes.load(Ordering::Relaxed)
+                + back_stats.chunk_bytes.load(Ordering::Relaxed);
+            let left = bytes_checked.load(Ordering::Relaxed).saturating_sub(done);
+            bail!(
+                "Stopped after uploading {} (--upload-budget is {}); about {} left to back up. \
+                 Run the backup again to continue.",
+                summary_size(ub),
+                summary_size(upload_budget.unwrap()),
+                summary_size(left)
+            );
+        }
+        res => res?,
+    };
 
-    debug!("Root tree packed as {}", root);
+    for root in &roots {
+        debug!("Root tree packed as {}", root);
+    }
 
     // Print the same stats we shoed as progress to the debug log.
-    let chunk_bytes = nice_size(back_stats.chunk_bytes.load(Ordering::Relaxed));
-    let tree_bytes = nice_size(back_stats.tree_bytes.load(Ordering::Relaxed));
+    let chunk_bytes = summary_size(back_stats.chunk_bytes.load(Ordering::Relaxed));
+    let tree_bytes = summary_size(back_stats.tree_bytes.load(Ordering::Relaxed));
     let np = nice_size(back_stats.indexed_packs.load(Ordering::Relaxed));
     debug!("{chunk_bytes} new files, {tree_bytes} new metadata into {np} packs");
-    let rb = nice_size(walk_stats.reused_bytes.load(Ordering::Relaxed));
+    let rb = summary_size(walk_stats.reused_bytes.load(Ordering::Relaxed));
     debug!("{rb} reused");
-    let zbytes = nice_size(back_stats.compressed_bytes.load(Ordering::Relaxed));
-    let ubytes = nice_size(cached_backend.bytes_uploaded.load(Ordering::Relaxed));
-    let dbytes = nice_size(cached_backend.bytes_downloaded.load(Ordering::Relaxed));
+    let zbytes = summary_size(back_stats.compressed_bytes.load(Ordering::Relaxed));
+    let ubytes = summary_size(cached_backend.bytes_uploaded.load(Ordering::Relaxed));
+    let dbytes = summary_size(cached_backend.bytes_downloaded.load(Ordering::Relaxed));
     debug!("{zbytes} compressed, {ubytes} uploaded, {dbytes} downloaded");
 
     let author = match args.author {
@@ -230,26 +425,42 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
     };
 
     let time = jiff::Zoned::now();
+    let tags: BTreeSet<String> = args.tags.into_iter().collect();
+
+    println!();
+    for ((paths, root), parent) in snapshot_paths.into_iter().zip(roots).zip(parent_ids) {
+        let snapshot = Snapshot {
+            time: time.clone(),
+            author: author.clone(),
+            tags: tags.clone(),
+            paths,
+            tree: root,
+            skips: skips.clone(),
+            from_stdin: args.stdin,
+            settings: Some(settings.clone()),
+            parent,
+        };
+        trace!("{snapshot:?}");
 
-    let snapshot = Snapshot {
-        time,
-        author,
-        tags: args.tags.into_iter().collect(),
-        paths,
-        tree: root,
-    };
-    trace!("{snapshot:?}");
-
-    let snap_id = if !args.dry_run {
-        snapshot::upload(&snapshot, &cached_backend)?
-    } else {
-        let mut hasher = HashingWriter::new(io::sink());
-        ciborium::into_writer(&snapshot, &mut hasher)?;
-        let (id, _) = hasher.finalize();
-        id
-    };
+        let snap_id = if !args.dry_run {
+            snapshot::upload(&snapshot, &cached_backend)?
+        } else {
+            let mut hasher = HashingWriter::new(io::sink());
+            ciborium::into_writer(&snapshot, &mut hasher)?;
+            let (id, _) = hasher.finalize();
+            id
+        };
 
-    println!("\nSnaphsot {} done", snap_id.short_name());
+        if args.separate {
+            println!(
+                "Snaphsot {} of {} done",
+                snap_id.short_name(),
+                snapshot.paths.first().unwrap()
+            );
+        } else {
+            println!("Snaphsot {} done", snap_id.short_name());
+        }
+    }
     Ok(())
 }
 
@@ -313,30 +524,60 @@ fn reject_matching_directories(paths: &BTreeSet<Utf8PathBuf>) -> Result<()> {
     Ok(())
 }
 
-fn parent_snapshot(
-    paths: &BTreeSet<Utf8PathBuf>,
-    snapshots: Vec<(Snapshot, ObjectId)>,
-) -> Option<Snapshot> {
-    let parent = snapshots
-        .into_iter()
+/// Complain if the repository's settings changed since the last snapshot that recorded them.
+///
+/// Not fatal (changing them is legit), but a changed filter can make older packs unreadable,
+/// and we'd rather say so now than when someone needs a restore.
+fn warn_on_settings_changes(
+    snapshots: &[(Snapshot, ObjectId)],
+    now: &snapshot::RepoSettings,
+    legacy_unfilters: &[String],
+) {
+    let last = snapshots
+        .iter()
         .rev()
-        .find(|snap| snap.0.paths == *paths);
+        .find_map(|(snap, id)| snap.settings.as_ref().map(|s| (s, id)));
+    if let Some((then, id)) = last {
+        for change in then.changes(id, now, legacy_unfilters) {
+            warn!("{change}");
+        }
+    }
+}
+
+fn parent_snapshot<'a>(
+    paths: &BTreeSet<Utf8PathBuf>,
+    snapshots: &'a [(Snapshot, ObjectId)],
+) -> Option<&'a (Snapshot, ObjectId)> {
+    let parent = snapshots.iter().rev().find(|snap| snap.0.paths == *paths);
     match &parent {
-        Some(p) => debug!("Using snapshot {} as a parent", p.1),
-        None => debug!("No parent snapshot found based on absolute paths"),
+        Some(p) => debug!("Using snapshot {} as a parent of {paths:?}", p.1),
+        None => debug!("No parent snapshot found for {paths:?} based on absolute paths"),
     };
-    parent.map(|(snap, _)| snap)
+    parent
 }
 
 fn check_paths(
     symlink_behavior: tree::Symlink,
+    invalid_names: fs_tree::InvalidNames,
     paths: &BTreeSet<Utf8PathBuf>,
     skips: &[String],
+    markers: &[String],
     bytes_checked: &AtomicU64,
-) -> Result<()> {
+) -> Result<Vec<Utf8PathBuf>> {
     info!("Walking {paths:?} to see what we've got...");
     let mf = filter::skip_matching_paths(skips)?;
-    let mut filter = move |path: &Utf8Path| mf(path);
+    let mut marked_dirs = vec![];
+    let mut filter = |path: &Utf8Path| {
+        if !mf(path) {
+            return false;
+        }
+        if let Some(marker) = marker_in(symlink_behavior, path, markers) {
+            info!("Skipping {path}, which contains {marker}");
+            marked_dirs.push(path.to_owned());
+            return false;
+        }
+        true
+    };
     let mut visit = |_nope: &mut (),
                      path: &Utf8Path,
                      metadata: tree::NodeMetadata,
@@ -358,18 +599,67 @@ fn check_paths(
     let mut no_op_finalize = |()| Ok(());
     fs_tree::walk_fs(
         symlink_behavior,
+        invalid_names,
         paths,
         None,
         &tree::Forest::default(),
         &mut filter,
         &mut visit,
         &mut no_op_finalize,
-    )
+    )?;
+    Ok(marked_dirs)
+}
+
+/// If the given path is a directory containing one of the given marker files,
+/// return that marker.
+fn marker_in<'a>(
+    symlink_behavior: tree::Symlink,
+    path: &Utf8Path,
+    markers: &'a [String],
+) -> Option<&'a str> {
+    // Look for the marker first - that fails fast for anything that isn't a directory.
+    let marker = markers.iter().find(|m| path.join(m).exists())?;
+    // But make sure we're not looking through a symlink we'd save as a symlink.
+    let is_dir = match symlink_behavior {
+        tree::Symlink::Read => path.symlink_metadata().is_ok_and(|m| m.is_dir()),
+        tree::Symlink::Dereference => path.is_dir(),
+    };
+    is_dir.then_some(marker.as_str())
+}
+
+/// How much a backup can upload before it stops with [`OverBudget`]
+struct UploadBudget<'a> {
+    limit: u64,
+    uploaded: &'a AtomicU64,
 }
 
+impl UploadBudget<'_> {
+    fn check(&self) -> Result<()> {
+        if self.uploaded.load(Ordering::Relaxed) >= self.limit {
+            Err(OverBudget.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returned (as an error) from [`backup_tree`] once we've used up the `--upload-budget`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct OverBudget;
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("upload budget exceeded")
+    }
+}
+
+impl std::error::Error for OverBudget {}
+
 #[expect(clippy::too_many_arguments)] // Stop shame culture
 fn backup_tree(
     symlink_behavior: tree::Symlink,
+    invalid_names: fs_tree::InvalidNames,
+    birth_times: bool,
     paths: &BTreeSet<Utf8PathBuf>,
     skips: &[String],
     previous_tree: Option<&ObjectId>,
@@ -377,6 +667,7 @@ fn backup_tree(
     packed_blobs: &mut FxHashSet<ObjectId>,
     backup: &mut Backup,
     walk_stats: &WalkStatistics,
+    upload_budget: Option<&UploadBudget>,
 ) -> Result<ObjectId> {
     use fs_tree::DirectoryEntry;
 
@@ -399,6 +690,11 @@ fn backup_tree(
                      entry: DirectoryEntry<ObjectId>|
      -> Result<()> {
         walk_stats.current_file.update(path.to_owned());
+        let metadata = if birth_times {
+            metadata
+        } else {
+            metadata.without_birth_time()
+        };
         let subnode = match entry {
             DirectoryEntry::Directory(subtree) => {
                 /*
@@ -436,33 +732,15 @@ fn backup_tree(
                 }
             }
             DirectoryEntry::ChangedFile => {
-                let chunks = chunk::chunk_file(path)?;
-
-                let mut chunk_ids = Vec::new();
-                let mut new_chunks = false;
-                let mut total_chunks = 0usize;
-                for chunk in chunks {
-                    chunk_ids.push(chunk.id);
-                    if packed_blobs.borrow_mut().insert(chunk.id) {
-                        new_chunks = true;
-                        backup
-                            .chunk_tx
-                            .send(chunk)
-                            .context("backup -> chunk packer channel exited early")?;
-                    } else {
-                        walk_stats
-                            .reused_bytes
-                            .fetch_add(chunk.bytes().len() as u64, Ordering::Relaxed);
-                    }
-                    total_chunks += 1;
-                }
-                // We made it through the whole file without finding new data!
-                let maybe_plural = if total_chunks == 1 { "chunk" } else { "chunks" };
-                if !new_chunks {
-                    debug!("{:>9} {path} ({} {maybe_plural})", "deduped", total_chunks);
-                } else {
-                    debug!("{:>9} {path} ({} {maybe_plural})", "backup", total_chunks);
-                }
+                let chunks = chunk::chunk_file(path, &backup.chunking)?.map(Ok);
+                let chunk_ids = pack_chunks(
+                    path,
+                    chunks,
+                    &mut packed_blobs.borrow_mut(),
+                    backup,
+                    walk_stats,
+                    upload_budget,
+                )?;
 
                 tree::Node {
                     metadata,
@@ -512,6 +790,7 @@ fn backup_tree(
 
     fs_tree::walk_fs(
         symlink_behavior,
+        invalid_names,
         paths,
         previous_tree,
         previous_forest,
@@ -520,3 +799,93 @@ fn backup_tree(
         &mut finalize,
     )
 }
+
+/// Send any chunks we haven't already packed off to the packer, returning all their IDs.
+fn pack_chunks(
+    path: &Utf8Path,
+    chunks: impl Iterator<Item = Result<Blob>>,
+    packed_blobs: &mut FxHashSet<ObjectId>,
+    backup: &Backup,
+    walk_stats: &WalkStatistics,
+    upload_budget: Option<&UploadBudget>,
+) -> Result<Vec<ObjectId>> {
+    let mut chunk_ids = Vec::new();
+    let mut new_chunks = false;
+    for chunk in chunks {
+        let chunk = chunk?;
+        chunk_ids.push(chunk.id);
+        if packed_blobs.insert(chunk.id) {
+            if let Some(b) = upload_budget {
+                b.check()?;
+            }
+            new_chunks = true;
+            backup
+                .chunk_tx
+                .send(chunk)
+                .context("backup -> chunk packer channel exited early")?;
+        } else {
+            walk_stats
+                .reused_bytes
+                .fetch_add(chunk.bytes().len() as u64, Ordering::Relaxed);
+        }
+    }
+    // We made it through the whole file without finding new data!
+    let total_chunks = chunk_ids.len();
+    let maybe_plural = if total_chunks == 1 { "chunk" } else { "chunks" };
+    if !new_chunks {
+        debug!("{:>9} {path} ({} {maybe_plural})", "deduped", total_chunks);
+    } else {
+        debug!("{:>9} {path} ({} {maybe_plural})", "backup", total_chunks);
+    }
+    Ok(chunk_ids)
+}
+
+/// Back up stdin as a single file at `path`, returning the top-level tree.
+fn backup_stdin(
+    path: &Utf8Path,
+    packed_blobs: &mut FxHashSet<ObjectId>,
+    backup: &Backup,
+    walk_stats: &WalkStatistics,
+    upload_budget: Option<UploadBudget>,
+) -> Result<ObjectId> {
+    walk_stats.current_file.update(path.to_owned());
+
+    // Count as we go; we don't know how big stdin is until we hit the end.
+    let mut size = 0u64;
+    let chunks = chunk::chunk_reader(io::stdin().lock(), &backup.chunking).inspect(|c| {
+        if let Ok(c) = c {
+            size += c.bytes().len() as u64;
+        }
+    });
+    let chunks = pack_chunks(
+        path,
+        chunks,
+        packed_blobs,
+        backup,
+        walk_stats,
+        upload_budget.as_ref(),
+    )
+    .context("Couldn't back up stdin")?;
+
+    let mut top = tree::Tree::new();
+    top.insert(
+        Utf8PathBuf::from(path.file_name().unwrap()),
+        tree::Node {
+            metadata: tree::synthetic_file_metadata(size),
+            contents: tree::NodeContents::File { chunks },
+        },
+    );
+
+    let (bytes, id) = tree::serialize_and_hash(&top)?;
+    if packed_blobs.insert(id) {
+        backup
+            .tree_tx
+            .send(Blob {
+                contents: blob::Contents::Buffer(bytes),
+                id,
+                kind: blob::Type::Tree,
+            })
+            .context("backup -> tree packer channel exited early")?;
+    }
+    Ok(id)
+}
@@ -0,0 +1,116 @@
+use anyhow::{Result, bail};
+use clap::{Parser, Subcommand};
+use jiff::tz::TimeZone;
+
+use crate::backend;
+use crate::config::Configuration;
+use crate::file_util::nice_size;
+use crate::snapshot;
+
+/// Inspect the local cache of backend files
+///
+/// The cache (in ~/.cache/backpak, unless you set cache_dir
+/// or pass --cache-dir) is shared by every repository
+/// that needs one - any remote or filtered repository.
+/// Unfiltered filesystem repositories are read directly and don't use it.
+///
+/// Once the cache grows past `cache_size` (see your config file),
+/// the least-recently used files are evicted first.
+#[derive(Debug, Parser)]
+#[clap(verbatim_doc_comment)]
+pub struct Args {
+    #[clap(subcommand)]
+    subcommand: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print how full the cache is and how often it's been hit
+    Stats,
+    /// List what's in the cache, most recently used first.
+    /// The last entries are the next to be evicted.
+    #[clap(verbatim_doc_comment)]
+    List,
+    /// Remove everything from the cache
+    Clear,
+    /// Rehash everything in the cache and evict anything that's corrupted.
+    /// Fails if anything was.
+    #[clap(verbatim_doc_comment)]
+    Verify,
+}
+
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
+    let Some(cache) = cached_backend.cache() else {
+        println!(
+            "{repository} doesn't use a cache: it's an unfiltered filesystem repository, \
+             which backpak reads directly."
+        );
+        return Ok(());
+    };
+
+    match args.subcommand {
+        Command::Stats => {
+            let stats = cache.stats()?;
+            println!("Cache:   {}", cache.directory);
+            println!("Entries: {}", stats.entries);
+            let full = if stats.capacity_bytes > 0 {
+                format!(
+                    " ({:.0}% full)",
+                    stats.total_bytes as f64 / stats.capacity_bytes as f64 * 100.0
+                )
+            } else {
+                String::new()
+            };
+            println!(
+                "Size:    {} of {}{full}",
+                nice_size(stats.total_bytes),
+                nice_size(stats.capacity_bytes)
+            );
+            let reads = stats.hits + stats.misses;
+            println!("Policy:  least-recently used files are evicted first");
+            if reads > 0 {
+                println!(
+                    "Hits:    {} of {reads} reads ({:.1}%), {} misses",
+                    stats.hits,
+                    stats.hits as f64 / reads as f64 * 100.0,
+                    stats.misses
+                );
+            } else {
+                println!("Hits:    no reads yet");
+            }
+        }
+        Command::List => {
+            for e in cache.entries()? {
+                println!(
+                    "{} {:>9} {}",
+                    snapshot::strftime(&e.last_used.to_zoned(TimeZone::system())),
+                    nice_size(e.size),
+                    e.name
+                );
+            }
+        }
+        Command::Clear => {
+            let (count, bytes) = cache.clear()?;
+            println!(
+                "Removed {count} files ({}) from {}",
+                nice_size(bytes),
+                cache.directory
+            );
+        }
+        Command::Verify => {
+            let counts = cache.verify()?;
+            println!(
+                "{} good, {} evicted, {} couldn't be read",
+                counts.good, counts.evicted, counts.errored
+            );
+            if counts.evicted > 0 || counts.errored > 0 {
+                bail!(
+                    "Cache had problems; evicted {} corrupted files",
+                    counts.evicted
+                );
+            }
+        }
+    }
+    Ok(())
+}
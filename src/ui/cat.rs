@@ -1,14 +1,15 @@
 use std::io;
 use std::io::prelude::*;
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, ensure};
 use clap::Parser;
+use serde::Serialize;
 use tracing::*;
 
 use crate::backend;
 use crate::blob;
 use crate::config::Configuration;
-use crate::hashing::ObjectId;
+use crate::hashing::{self, ObjectId};
 use crate::index;
 use crate::pack;
 use crate::snapshot;
@@ -17,6 +18,15 @@ use crate::tree;
 /// Print objects (as JSON) to stdout
 #[derive(Debug, Parser)]
 pub struct Args {
+    /// Print the object's bytes as stored instead of decoding it.
+    /// (Blobs and trees are printed uncompressed.)
+    #[clap(long, global = true, verbatim_doc_comment)]
+    raw: bool,
+
+    /// Indent JSON output for human eyes
+    #[clap(short, long, global = true, conflicts_with = "raw")]
+    pretty: bool,
+
     #[clap(subcommand)]
     subcommand: Subcommand,
 }
@@ -27,14 +37,21 @@ pub enum Subcommand {
     ///
     /// A blob is either a chunk (of a file) or a tree (representing a directory).
     #[clap(verbatim_doc_comment)]
-    Blob { id: ObjectId },
+    Blob { id_prefix: String },
+
+    /// Print the tree with the given ID
+    ///
+    /// A tree is a blob representing a directory:
+    /// a map of names to nodes (files, directories, and symlinks) and their metadata.
+    #[clap(verbatim_doc_comment)]
+    Tree { id_prefix: String },
 
     /// Print the pack with the given ID
     ///
     /// A pack is a compressed collection of blobs,
     /// with a manifest at the end for reassembling the index (if needed).
     #[clap(verbatim_doc_comment)]
-    Pack { id: ObjectId },
+    Pack { id_prefix: String },
 
     /// Print the index with the given ID
     ///
@@ -42,7 +59,7 @@ pub enum Subcommand {
     /// Each backup stores a new index.
     /// They can be coalesced with `rebuild-index`
     #[clap(verbatim_doc_comment)]
-    Index { id: ObjectId },
+    Index { id_prefix: String },
 
     /// Print the snapshot with the given ID
     ///
@@ -58,50 +75,123 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
         crate::prettify::prettify_serialize();
     }
 
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
-
-    match args.subcommand {
-        Subcommand::Blob { id } => {
-            let index = index::build_master_index(&cached_backend)?;
-            let blob_map = index::blob_to_pack_map(&index)?;
-            let containing_pack_id = blob_map
-                .get(&id)
-                .ok_or_else(|| anyhow!("Can't find blob {} in the index", id))?;
-            info!("Blob {} found in pack {}", id, containing_pack_id);
-            let index_manifest = index.packs.get(containing_pack_id).unwrap();
-
-            let mut reader = cached_backend.read_pack(containing_pack_id)?;
-
-            let (manifest_entry, blob) = pack::extract_blob(&mut reader, &id, index_manifest)?;
-
-            debug_assert!(manifest_entry.id == id);
-            assert!(!blob.is_empty());
-            match manifest_entry.blob_type {
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
+
+    let pretty = args.pretty;
+
+    match &args.subcommand {
+        Subcommand::Blob { id_prefix } => {
+            let (id, blob_type, blob) = read_blob(&cached_backend, id_prefix)?;
+            match blob_type {
                 blob::Type::Chunk => io::stdout().write_all(&blob)?,
-                blob::Type::Tree => {
-                    let tree: tree::Tree = ciborium::from_reader(&*blob)
-                        .with_context(|| format!("CBOR decoding of tree {} failed", id))?;
-                    serde_json::to_writer(io::stdout(), &tree)?;
-                }
+                blob::Type::Tree if args.raw => io::stdout().write_all(&blob)?,
+                blob::Type::Tree => print_json(&decode_tree(&id, &blob)?, pretty)?,
             }
         }
-        Subcommand::Pack { id } => {
-            let manifest = pack::load_manifest(&id, &cached_backend)?;
-            serde_json::to_writer(io::stdout(), &manifest)?;
+        Subcommand::Tree { id_prefix } => {
+            let (id, blob_type, blob) = read_blob(&cached_backend, id_prefix)?;
+            ensure!(
+                blob_type == blob::Type::Tree,
+                "{id} is a file chunk, not a tree"
+            );
+            if args.raw {
+                io::stdout().write_all(&blob)?;
+            } else {
+                print_json(&decode_tree(&id, &blob)?, pretty)?;
+            }
         }
-        Subcommand::Index { id } => {
-            let index = index::load(&id, &cached_backend)?;
-            serde_json::to_writer(io::stdout(), &index)?;
+        Subcommand::Pack { id_prefix } => {
+            let id = find_listed(id_prefix, "pack", || {
+                Ok(cached_backend
+                    .list_packs()?
+                    .collect::<Result<Vec<_>, _>>()?)
+            })?;
+            if args.raw {
+                print_raw(cached_backend.read_pack(&id)?)?;
+            } else {
+                let manifest = pack::load_manifest(&id, &cached_backend)?;
+                print_json(&manifest, pretty)?;
+            }
+        }
+        Subcommand::Index { id_prefix } => {
+            let id = find_listed(id_prefix, "index", || cached_backend.list_indexes())?;
+            if args.raw {
+                print_raw(cached_backend.read_index(&id)?)?;
+            } else {
+                let index = index::load(&id, &cached_backend)?;
+                print_json(&index, pretty)?;
+            }
         }
         Subcommand::Snapshot { id_prefix } => {
             let chrono_list = snapshot::load_chronologically(&cached_backend)?;
-            let (snapshot, _id) = snapshot::find(&chrono_list, &id_prefix)?;
-            serde_json::to_writer(io::stdout(), &snapshot)?;
+            let (snapshot, id) = snapshot::find(&chrono_list, id_prefix)?;
+            if args.raw {
+                print_raw(cached_backend.read_snapshot(id)?)?;
+            } else {
+                print_json(&snapshot, pretty)?;
+            }
         }
     }
     Ok(())
 }
+
+/// Find the blob whose ID starts with the given prefix
+/// and read it (uncompressed) out of its pack.
+fn read_blob(
+    cached_backend: &backend::CachedBackend,
+    id_prefix: &str,
+) -> Result<(ObjectId, blob::Type, Vec<u8>)> {
+    let index = index::build_master_index(cached_backend)?;
+    let blob_map = index::blob_to_pack_map(&index)?;
+    let id = hashing::find_by_prefix(id_prefix, "blob", blob_map.keys())?;
+    let containing_pack_id = blob_map
+        .get(id)
+        .ok_or_else(|| anyhow!("Can't find blob {} in the index", id))?;
+    info!("Blob {} found in pack {}", id, containing_pack_id);
+    let index_manifest = index.packs.get(containing_pack_id).unwrap();
+
+    let mut reader = cached_backend.read_pack(containing_pack_id)?;
+
+    let (manifest_entry, blob) = pack::extract_blob(&mut reader, id, index_manifest)?;
+
+    debug_assert!(manifest_entry.id == *id);
+    assert!(!blob.is_empty());
+    Ok((*id, manifest_entry.blob_type, blob))
+}
+
+/// Find the one listed pack or index whose ID starts with the given prefix.
+///
+/// Listing every pack can take a while on a remote backend,
+/// so skip it if we were given a whole ID.
+fn find_listed<F>(id_prefix: &str, what: &str, list: F) -> Result<ObjectId>
+where
+    F: FnOnce() -> Result<Vec<(String, u64)>>,
+{
+    if let Ok(id) = id_prefix.parse::<ObjectId>() {
+        return Ok(id);
+    }
+    let ids = list()?
+        .iter()
+        .map(|(path, _len)| backend::id_from_path(path))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(*hashing::find_by_prefix(id_prefix, what, &ids)?)
+}
+
+fn print_json<T: Serialize>(value: &T, pretty: bool) -> Result<()> {
+    if pretty {
+        serde_json::to_writer_pretty(io::stdout(), value)?;
+        println!();
+    } else {
+        serde_json::to_writer(io::stdout(), value)?;
+    }
+    Ok(())
+}
+
+fn print_raw(mut from: Box<dyn backend::SeekableRead>) -> Result<()> {
+    io::copy(&mut from, &mut io::stdout().lock())?;
+    Ok(())
+}
+
+fn decode_tree(id: &ObjectId, blob: &[u8]) -> Result<tree::Tree> {
+    ciborium::from_reader(blob).with_context(|| format!("CBOR decoding of tree {} failed", id))
+}
@@ -1,7 +1,8 @@
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::thread;
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use console::Term;
 use rayon::prelude::*;
@@ -14,8 +15,10 @@ use crate::hashing::ObjectId;
 use crate::index;
 use crate::pack;
 use crate::progress::{ProgressThread, print_download_line, spinner};
+use crate::read::ChunkReader;
+use crate::restore::{DiscardSink, RestoreSink};
 use crate::snapshot;
-use crate::tree;
+use crate::tree::{self, Forest, NodeContents};
 
 /// Check the repository for errors
 ///
@@ -23,12 +26,71 @@ use crate::tree;
 /// and only ensures that needed files can be found and downloaded.
 /// If `--read-packs` is specified, ensure that each pack has the expected blobs,
 /// that those blobs match its manifest, and that those blobs match the index.
+/// If `--dereference-and-hash` is specified, put every file in every snapshot
+/// back together (then throw it away) to make sure it can be restored.
 #[derive(Debug, Parser)]
 #[clap(verbatim_doc_comment)]
 pub struct Args {
     /// Check the contents of packs, not just that they exist
     #[clap(short, long)]
     read_packs: bool,
+
+    /// Reassemble every file in every snapshot from its chunks,
+    /// checking each chunk's hash and the file's size.
+    /// The most thorough (and by far the slowest) check.
+    #[clap(long, visible_alias = "full", verbatim_doc_comment)]
+    dereference_and_hash: bool,
+
+    /// Hash every blob in every pack (even those no index knows about)
+    /// against its manifest, reporting each corrupt blob.
+    #[clap(long, verbatim_doc_comment)]
+    read_data: bool,
+
+    /// Like --read-data, but only read some packs, e.g., 1/10 for the first tenth.
+    /// Packs are picked by ID, so checking 1/10 through 10/10 covers everything.
+    #[clap(long, value_name = "N/M", verbatim_doc_comment)]
+    read_data_subset: Option<Subset>,
+
+    /// Don't put anything we read in the local cache
+    /// (for a one-off check on a machine short on disk).
+    #[clap(long, verbatim_doc_comment)]
+    no_cache: bool,
+}
+
+/// Which packs `--read-data-subset` reads
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Subset {
+    /// Which part (1-based)...
+    n: u64,
+    /// ...of this many
+    m: u64,
+}
+
+impl Subset {
+    const ALL: Self = Self { n: 1, m: 1 };
+
+    fn contains(&self, pack: &ObjectId) -> bool {
+        let mut first = [0u8; 8];
+        first.copy_from_slice(&pack.as_bytes()[..8]);
+        u64::from_be_bytes(first) % self.m == self.n - 1
+    }
+}
+
+impl std::str::FromStr for Subset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (n, m) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Expected a subset like 1/10"))?;
+        let n: u64 = n.trim().parse().context("Bad subset numerator")?;
+        let m: u64 = m.trim().parse().context("Bad subset denominator")?;
+        ensure!(
+            n >= 1 && n <= m,
+            "Subset {n}/{m} should be between 1/{m} and {m}/{m}"
+        );
+        Ok(Self { n, m })
+    }
 }
 
 #[derive(Default)]
@@ -44,16 +106,24 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
 
     // NB: We always want to read when checking the backend!
     // Just because it's in-cache doesn't mean it's backed up.
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::AlwaysRead,
-    )?;
+    let behavior = if args.no_cache {
+        backend::CacheBehavior::NoCache
+    } else {
+        backend::CacheBehavior::AlwaysRead
+    };
+    let (backend_config, cached_backend) = backend::open(repository, config, behavior)?;
 
     let index = index::build_master_index(&cached_backend)?;
 
     info!("Downloading pack list");
-    let all_packs = cached_backend.list_packs()?;
+    // We probe this once per indexed pack below.
+    let all_packs = cached_backend
+        .list_packs()?
+        .collect::<Result<Vec<_>, _>>()?;
+    pack::warn_on_size_mismatch(
+        backend_config.pack_size,
+        all_packs.iter().map(|(_, len)| *len),
+    );
     let borked_packs = AtomicU32::new(0);
     if args.read_packs {
         let stats = ReadStatus {
@@ -103,8 +173,29 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
         trouble = true;
     }
 
+    let subset = args
+        .read_data_subset
+        .or(args.read_data.then_some(Subset::ALL));
+    if let Some(subset) = subset {
+        let to_read = all_packs
+            .iter()
+            .map(|(path, _)| backend::id_from_path(path))
+            .filter(|id| id.as_ref().map_or(true, |id| subset.contains(id)))
+            .collect::<Result<Vec<_>>>()?;
+        info!(
+            "Hashing every blob in {} of {} packs",
+            to_read.len(),
+            all_packs.len()
+        );
+        let corrupt = read_data(&cached_backend, &to_read)?;
+        if corrupt > 0 {
+            error!("{corrupt} packs with corrupt or unreadable data");
+            trouble = true;
+        }
+    }
+
     info!("Checking for unreachable packs (not listed in indexes)");
-    warn_on_unreachable_packs(&index, &all_packs)?;
+    warn_on_unreachable_packs(&index, all_packs.into_iter().map(Ok))?;
 
     info!("Checking that all chunks in snapshots are reachable");
     let blob_map = index::blob_to_pack_map(&index)?;
@@ -135,6 +226,41 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
         trouble = true;
     }
 
+    if args.dereference_and_hash {
+        info!("Reassembling every file in every snapshot");
+        let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
+        let mut reader = ChunkReader::new(&cached_backend, &index, &blob_map);
+        // Identical subtrees reassemble identically, so only check each once.
+        let mut verified = FxHashSet::default();
+        for (snapshot, id) in snapshot::load_chronologically(&cached_backend)? {
+            let forest = tree::forest_from_root(&snapshot.tree, &mut tree_cache)?;
+            let mut counts = FileCounts::default();
+            reassemble_tree(
+                &snapshot.tree,
+                &forest,
+                Utf8Path::new(""),
+                &mut reader,
+                &mut verified,
+                &mut counts,
+            )?;
+            if counts.failed > 0 {
+                error!(
+                    "Snapshot {}: {} of {} files couldn't be reassembled",
+                    id.short_name(),
+                    counts.failed,
+                    counts.checked
+                );
+                trouble = true;
+            } else {
+                debug!(
+                    "Snapshot {}: {} files reassembled",
+                    id.short_name(),
+                    counts.checked
+                );
+            }
+        }
+    }
+
     if trouble {
         bail!("Check failed!");
     } else {
@@ -143,6 +269,56 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
     }
 }
 
+/// Reads every blob in the given packs, returning how many had problems.
+fn read_data(cached_backend: &backend::CachedBackend, packs: &[ObjectId]) -> Result<u32> {
+    let bad_packs = AtomicU32::new(0);
+    let manifests = packs
+        .par_iter()
+        .filter_map(|id| match pack::load_manifest(id, cached_backend) {
+            Ok(m) => Some((*id, m)),
+            Err(e) => {
+                error!("Pack {id}: {e:?}");
+                bad_packs.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let stats = ReadStatus {
+        packs_total: manifests.len() as u32,
+        blobs_total: manifests.iter().map(|(_, m)| m.len() as u64).sum(),
+        ..Default::default()
+    };
+    thread::scope(|s| {
+        let progress = ProgressThread::spawn(s, |i| {
+            print_progress(i, &Term::stdout(), &stats, &cached_backend.bytes_downloaded)
+        });
+        manifests.par_iter().for_each(|(pack_id, manifest)| {
+            let found = cached_backend.read_pack(pack_id).and_then(|mut pack| {
+                pack::find_corrupt_blobs(&mut pack, manifest, &stats.blobs_read)
+            });
+            match found {
+                Ok(corrupt) if corrupt.is_empty() => debug!("Pack {pack_id} data verified"),
+                Ok(corrupt) => {
+                    for c in &corrupt {
+                        error!(
+                            "Pack {pack_id}: blob {} at offset {} hashes to {}",
+                            c.id, c.offset, c.actual
+                        );
+                    }
+                    bad_packs.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    error!("Pack {pack_id}: {e:?}");
+                    bad_packs.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            stats.packs_read.fetch_add(1, Ordering::Relaxed);
+        });
+        progress.join();
+    });
+    Ok(bad_packs.into_inner())
+}
+
 fn check_pack(
     cached_backend: &backend::CachedBackend,
     pack_id: &ObjectId,
@@ -154,19 +330,73 @@ fn check_pack(
     Ok(())
 }
 
+#[derive(Debug, Default)]
+struct FileCounts {
+    checked: usize,
+    failed: usize,
+}
+
+/// Put every file in the given tree back together with the same read path as `restore`,
+/// discarding the results. Skips subtrees in `verified`, and adds to it those without errors.
+fn reassemble_tree(
+    tree_id: &ObjectId,
+    forest: &Forest,
+    tree_path: &Utf8Path,
+    reader: &mut ChunkReader,
+    verified: &mut FxHashSet<ObjectId>,
+    counts: &mut FileCounts,
+) -> Result<()> {
+    if verified.contains(tree_id) {
+        return Ok(());
+    }
+    let failed_before = counts.failed;
+    let tree = forest
+        .get(tree_id)
+        .ok_or_else(|| anyhow!("Missing tree {tree_id}"))?;
+    for (path, node) in tree.iter() {
+        let node_path: Utf8PathBuf = tree_path.join(path);
+        match &node.contents {
+            NodeContents::File { .. } => {
+                counts.checked += 1;
+                if let Err(e) = reassemble_file(&node_path, node, reader) {
+                    error!("{node_path}: {e:?}");
+                    counts.failed += 1;
+                }
+            }
+            NodeContents::Directory { subtree } => {
+                reassemble_tree(subtree, forest, &node_path, reader, verified, counts)?
+            }
+            NodeContents::Symlink { .. } => {}
+        }
+    }
+    if counts.failed == failed_before {
+        verified.insert(*tree_id);
+    }
+    Ok(())
+}
+
+fn reassemble_file(path: &Utf8Path, node: &tree::Node, reader: &mut ChunkReader) -> Result<()> {
+    let mut sink = DiscardSink::default();
+    super::restore::fill_file(sink.create_file(path)?, node, reader)?;
+    let written = sink.bytes_written;
+    if let Some(expected) = node.metadata.size().filter(|s| *s != written) {
+        bail!("Reassembled {written} bytes, but the snapshot says it's {expected}");
+    }
+    trace!("{path} reassembled");
+    Ok(())
+}
+
 /// Warns about unreachable packs. Returns the total pack size for usage stats.
-pub fn warn_on_unreachable_packs(index: &index::Index, all_packs: &[(String, u64)]) -> Result<u64> {
+pub fn warn_on_unreachable_packs(
+    index: &index::Index,
+    all_packs: impl IntoIterator<Item = Result<(String, u64), backend::BackendError>>,
+) -> Result<u64> {
     let mut total_pack_size = 0u64;
-    let pack_ids = all_packs
-        .iter()
-        .map(|(pack, pack_len)| {
-            total_pack_size += *pack_len;
-            pack
-        })
-        .map(backend::id_from_path)
-        .collect::<Result<Vec<_>>>()?;
     let mut unlisted_packs: usize = 0;
-    for pack_id in pack_ids {
+    for pack in all_packs {
+        let (pack, pack_len) = pack?;
+        total_pack_size += pack_len;
+        let pack_id = backend::id_from_path(&pack)?;
         if !index.packs.contains_key(&pack_id) {
             warn!("Pack {pack_id} not listed in any index");
             unlisted_packs += 1;
@@ -0,0 +1,96 @@
+use anyhow::{Result, bail};
+use camino::Utf8PathBuf;
+use clap::{Parser, Subcommand};
+use tracing::*;
+
+use crate::backend;
+use crate::config;
+
+/// Work with backpak's configuration files
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(subcommand)]
+    subcommand: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Load the repository config and your user config (see --config),
+    /// reporting anything wrong with either.
+    ///
+    /// Fails if there are any problems.
+    #[clap(verbatim_doc_comment)]
+    Check {
+        /// The repository to check (default: --repository, or the one we find)
+        repository: Option<Utf8PathBuf>,
+    },
+}
+
+/// Unlike other commands, this doesn't need a working config to get started:
+/// `main` hands over the raw `--config`, `--repository`, and `--repo-config` arguments
+/// so we can report problems with any of them.
+pub fn run(
+    user_config: Option<Utf8PathBuf>,
+    repository: Option<Utf8PathBuf>,
+    repo_config: Option<Utf8PathBuf>,
+    args: Args,
+) -> Result<()> {
+    match args.subcommand {
+        Command::Check { repository: r } => check(user_config, r.or(repository), repo_config),
+    }
+}
+
+fn check(
+    user_config: Option<Utf8PathBuf>,
+    repository: Option<Utf8PathBuf>,
+    repo_config: Option<Utf8PathBuf>,
+) -> Result<()> {
+    let mut problems = 0;
+
+    let user_path = match &user_config {
+        Some(p) if p.as_str().is_empty() => None,
+        Some(p) => Some(p.clone()),
+        None => Some(config::default_path()?),
+    };
+    match user_path {
+        None => println!("No user config (--config \"\")"),
+        Some(p) if user_config.is_none() && !p.exists() => {
+            println!("{p}: not found, using defaults")
+        }
+        Some(p) => match config::load(Some(p.clone())) {
+            Ok(_) => println!("{p}: OK"),
+            Err(e) => {
+                error!("{e:?}");
+                problems += 1;
+            }
+        },
+    }
+
+    let repo_config = match repo_config {
+        Some(p) => Ok(p),
+        None => {
+            let repository = match repository {
+                Some(r) => r,
+                None => backend::discover_repository()?,
+            };
+            backend::config_file(&repository)
+        }
+    };
+    match repo_config.and_then(check_repository) {
+        Ok(p) => println!("{p}: OK"),
+        Err(e) => {
+            error!("{e:?}");
+            problems += 1;
+        }
+    }
+
+    if problems > 0 {
+        bail!("Found problems in {problems} config file(s)");
+    }
+    Ok(())
+}
+
+fn check_repository(p: Utf8PathBuf) -> Result<Utf8PathBuf> {
+    backend::read_config(&p)?;
+    Ok(p)
+}
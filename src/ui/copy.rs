@@ -26,7 +26,8 @@ pub struct Args {
     quiet: bool,
 
     /// Skip anything whose absolute path matches the given regular expression
-    #[clap(short = 's', long = "skip", name = "regex")]
+    /// (or glob:, see `backup --help`)
+    #[clap(short = 's', long = "skip", name = "regex", verbatim_doc_comment)]
     skips: Vec<String>,
 
     /// Destination repository
@@ -53,11 +54,8 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
     assert!(args.target.all ^ !target_snapshots.is_empty());
 
     // Build the usual suspects.
-    let (_, src_cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (_, src_cached_backend) =
+        backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let src_index = index::build_master_index(&src_cached_backend)?;
     let src_blob_map = index::blob_to_pack_map(&src_index)?;
 
@@ -77,8 +75,12 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
     // Get a reader to load the chunks we're copying.
     let mut reader = read::ChunkReader::new(&src_cached_backend, &src_index, &src_blob_map);
 
-    let (dst_backend_config, dst_cached_backend) =
-        backend::open(&args.to, config.cache_size, backend::CacheBehavior::Normal)?;
+    // --repo-config is for the source repository; the destination has its own.
+    let (dst_backend_config, dst_cached_backend) = backend::open(
+        &args.to,
+        &config.without_repo_config(),
+        backend::CacheBehavior::Normal,
+    )?;
     let dst_index = index::build_master_index(&dst_cached_backend)?;
 
     // Track all the blobs already in the destination.
@@ -109,6 +111,7 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
             &dst_backend_config,
             &dst_cached_backend,
             wip_index,
+            config.checkpoint_interval,
             &back_stats,
         );
 
@@ -127,7 +130,7 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
             }
             drop(cwd_packfiles);
 
-            let filter = filter::skip_matching_paths(&args.skips)?;
+            let filter = filter::skip_matching_nodes(&args.skips)?;
 
             let new_snapshots = repack::walk_snapshots(
                 repack::Op::Copy,
@@ -1,19 +1,26 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
 use anyhow::*;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
+use serde::Serialize;
 use tracing::*;
 
 use crate::backend;
 use crate::config::Configuration;
 use crate::diff;
+use crate::filter::Glob;
 use crate::fs_tree;
 use crate::hashing::ObjectId;
 use crate::index;
 use crate::ls;
 use crate::snapshot;
-use crate::tree::{self, Forest, Node, NodeType, meta_diff_char};
+use crate::tree::{self, Forest, Node, NodeContents, NodeType, meta_diff_chars};
 
 /// Compare two snapshots, or compare a snapshot to its paths on the filesystem
+/// (or with --parent, to the snapshot it was backed up on top of)
 ///
 /// + added/file/or/dir
 /// - removed
@@ -22,7 +29,35 @@ use crate::tree::{self, Forest, Node, NodeType, meta_diff_char};
 /// P permissions changed
 /// T modify time changed
 /// A access time changed
+/// B birth (creation) time changed
 /// M other metadata changed
+/// = unchanged (with --all)
+/// (Several metadata letters at once, like OP, mean all of those changed.)
+///
+/// With --base, compare both snapshots to that one and tag each change
+/// with the side that made it:
+///   1  + only/in/the/first
+///    2 C only/in/the/second
+///   12 - the/same/on/both/sides
+///   !1 C changed/differently/on/each/side
+///   !2 - changed/differently/on/each/side
+///
+/// --path and --exclude take shell-style globs: * and ? don't match /, but ** does.
+/// Like .gitignore, a glob without a / matches a name anywhere;
+/// one with a / matches from the top of the snapshot (e.g., src/*.rs).
+///
+/// With --json, print each change as an object like
+///   {"change":"added","path":"some/file","type":"file"}
+/// where change is added, removed, contents, metadata, or (with --all) unchanged.
+/// Symlinks whose targets changed get "old_target" and "new_target",
+/// and metadata changes get "metadata" with the letters above (e.g., "OP").
+///
+/// With --summary, just count each of those (+, -, C, and all metadata as M)
+/// and print the totals on one line.
+///
+/// With --exit-code, exit with 1 if there were any differences and 0 if not,
+/// like `git diff --exit-code`. Metadata changes only count with --metadata.
+/// Errors exit with 2 so they aren't mistaken for differences, like diff(1).
 ///
 /// Type changes (e.g. dir -> file, or file -> symlink)
 /// are modeled as removing one and adding the other.
@@ -37,6 +72,81 @@ pub struct Args {
     #[clap(short, long)]
     metadata: bool,
 
+    /// Only compare metadata, trusting that files with the same chunks
+    /// have the same contents. Implies --metadata.
+    #[clap(long, verbatim_doc_comment, conflicts_with = "contents_only")]
+    metadata_only: bool,
+
+    /// Only compare contents, ignoring metadata.
+    #[clap(long, conflicts_with = "metadata")]
+    contents_only: bool,
+
+    /// Only list directories: those with changes somewhere inside (C),
+    /// and those added (+) or removed (-) wholesale.
+    #[clap(long, verbatim_doc_comment)]
+    dirs_only: bool,
+
+    /// Also print things that didn't change (=).
+    /// Unchanged directories are printed once, not everything inside them.
+    #[clap(short, long, verbatim_doc_comment, conflicts_with_all = ["summary", "dirs_only"])]
+    all: bool,
+
+    /// Just print how many things changed, like `+12 -3 C5 M2`
+    #[clap(short, long, conflicts_with = "dirs_only")]
+    summary: bool,
+
+    /// Print each change as a line of JSON.
+    /// Honors --metadata the same way the usual output does.
+    #[clap(long, verbatim_doc_comment, conflicts_with_all = ["summary", "dirs_only"])]
+    json: bool,
+
+    /// Exit with 1 if anything changed, 0 if not, and 2 on errors.
+    #[clap(long)]
+    exit_code: bool,
+
+    /// Only compare paths matching the given glob (and what's inside them).
+    /// Can be given multiple times.
+    #[clap(long = "path", name = "GLOB", verbatim_doc_comment)]
+    paths: Vec<String>,
+
+    /// Don't compare paths matching the given glob (or what's inside them).
+    /// Can be given multiple times.
+    #[clap(long = "exclude", name = "EXCLUDE_GLOB", verbatim_doc_comment)]
+    excludes: Vec<String>,
+
+    /// Compare both snapshots to a common ancestor, and show which changed what.
+    #[clap(
+        long,
+        name = "BASE_SNAPSHOT",
+        requires = "SNAPSHOT_2",
+        conflicts_with_all = ["summary", "json", "dirs_only"]
+    )]
+    base: Option<String>,
+
+    /// Compare the snapshot to its parent (the last snapshot of the same paths
+    /// when it was taken) to see what that backup changed.
+    #[clap(
+        long,
+        verbatim_doc_comment,
+        conflicts_with_all = ["SNAPSHOT_2", "BASE_SNAPSHOT", "dereference"]
+    )]
+    parent: bool,
+
+    /// When comparing to the filesystem, follow symbolic links there
+    /// instead of comparing them as links, e.g., to compare against
+    /// a backup made with `backup --dereference`.
+    ///
+    /// Off by default to match `restore`, which replaces symlinks
+    /// rather than writing through them.
+    /// Symlinks that loop back to a directory they're in are an error.
+    #[clap(short = 'L', long, verbatim_doc_comment, conflicts_with = "SNAPSHOT_2")]
+    dereference: bool,
+
+    /// Only consider snapshots with the given tag (so LAST is the last one with it).
+    /// Can be given multiple times to require several tags.
+    #[clap(long = "tag", value_name = "TAG", verbatim_doc_comment)]
+    tags: Vec<String>,
+
     #[clap(name = "SNAPSHOT_1")]
     first_snapshot: String,
 
@@ -46,79 +156,339 @@ pub struct Args {
 }
 
 pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let exit_code = args.exit_code;
+    diff(config, repository, args).map_err(|e| {
+        if exit_code && !e.is::<DifferencesFound>() {
+            e.context(DiffFailed)
+        } else {
+            e
+        }
+    })
+}
+
+fn diff(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let index = index::build_master_index(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
     let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
 
-    let snapshots = snapshot::load_chronologically(&cached_backend)?;
+    let all_snapshots = snapshot::load_chronologically(&cached_backend)?;
+    let mut snapshots = all_snapshots.clone();
+    snapshot::retain_tagged(&mut snapshots, &args.tags);
     let (snapshot1, id1) = snapshot::find(&snapshots, &args.first_snapshot)?;
-    let snapshot1_forest = tree::forest_from_root(&snapshot1.tree, &mut tree_cache)?;
-
-    let (id2, forest2) = load_snapshot2_or_paths(
-        id1,
-        snapshot1,
-        &snapshot1_forest,
-        &args.second_snapshot,
-        &snapshots,
-        &mut tree_cache,
-    )?;
-
-    diff::compare_trees(
-        (&snapshot1.tree, &snapshot1_forest),
-        (&id2, &forest2),
-        Utf8Path::new(""),
-        &mut PrintDiffs {
-            metadata: args.metadata,
+
+    let mut print_diffs = PrintDiffs {
+        metadata: args.metadata || args.metadata_only,
+        unchanged: args.all,
+        comparison: diff::Comparison {
+            contents: !args.metadata_only,
+            metadata: !args.contents_only,
         },
-    )
-}
+    };
+    let mut print_dirs = PrintDirs;
+    let mut summary = SummaryDiffs {
+        comparison: print_diffs.comparison,
+        ..Default::default()
+    };
+    let mut json_diffs = JsonDiffs {
+        metadata: print_diffs.metadata,
+        unchanged: args.all,
+        comparison: print_diffs.comparison,
+    };
+    let path_filter = diff::PathFilter {
+        includes: args
+            .paths
+            .iter()
+            .map(|g| Glob::new(g))
+            .collect::<Result<_>>()?,
+        excludes: args
+            .excludes
+            .iter()
+            .map(|g| Glob::new(g))
+            .collect::<Result<_>>()?,
+    };
 
-fn load_snapshot2_or_paths(
-    id1: &ObjectId,
-    snapshot1: &snapshot::Snapshot,
-    snapshot1_forest: &tree::Forest,
-    second_snapshot: &Option<String>,
-    snapshots: &[(snapshot::Snapshot, ObjectId)],
-    tree_cache: &mut tree::Cache,
-) -> Result<(ObjectId, tree::Forest)> {
-    if let Some(second_snapshot) = second_snapshot {
-        let (snapshot2, id2) = snapshot::find(snapshots, second_snapshot)?;
-        let snapshot2_forest = tree::forest_from_root(&snapshot2.tree, tree_cache)?;
+    if let Some(base) = &args.base {
+        let (base_snapshot, base_id) = snapshot::find(&snapshots, base)?;
+        let (snapshot2, id2) = snapshot::find(&snapshots, args.second_snapshot.as_ref().unwrap())?;
+        info!("Comparing snapshots {id1} and {id2} to their base, {base_id}");
 
+        let loader = RefCell::new(|id: &ObjectId| tree_cache.read(id));
+        let trees = diff::Trees::Lazy(&loader);
+        let record = |side: &ObjectId| -> Result<Changes> {
+            let mut recorder = RecordChanges {
+                metadata: print_diffs.metadata,
+                comparison: print_diffs.comparison,
+                changes: Changes::new(),
+            };
+            let mut filtered = diff::Filtered {
+                filter: &path_filter,
+                inner: &mut recorder,
+            };
+            diff::compare_trees_in(
+                (&base_snapshot.tree, &trees),
+                (side, &trees),
+                Utf8Path::new(""),
+                &mut filtered,
+            )?;
+            Ok(recorder.changes)
+        };
+        let first = record(&snapshot1.tree)?;
+        let second = record(&snapshot2.tree)?;
+        let changed = !first.is_empty() || !second.is_empty();
+        print_three_way(first, second, print_diffs.metadata);
+        if args.exit_code && changed {
+            return Err(DifferencesFound.into());
+        }
+        return Ok(());
+    }
+
+    let unfiltered: &mut dyn diff::Callbacks = if args.summary {
+        &mut summary
+    } else if args.json {
+        &mut json_diffs
+    } else if args.dirs_only {
+        &mut print_dirs
+    } else {
+        &mut print_diffs
+    };
+    let mut filtered = diff::Filtered {
+        filter: &path_filter,
+        inner: unfiltered,
+    };
+    let callbacks: &mut dyn diff::Callbacks =
+        if path_filter.includes.is_empty() && path_filter.excludes.is_empty() {
+            filtered.inner
+        } else {
+            &mut filtered
+        };
+    let mut noted = NoteChanges {
+        inner: callbacks,
+        metadata: args.metadata || args.metadata_only,
+        changed: false,
+    };
+    let callbacks = &mut noted;
+
+    let snapshot_pair = if let Some(second_snapshot) = &args.second_snapshot {
+        let (snapshot2, id2) = snapshot::find(&snapshots, second_snapshot)?;
         info!("Comparing snapshot {} to {}", id1, id2);
+        Some((snapshot1, snapshot2))
+    } else if args.parent {
+        let parent_id = snapshot1.parent.ok_or_else(|| {
+            anyhow!(
+                "Snapshot {id1} has no parent (it's the first of its paths, or predates parents)"
+            )
+        })?;
+        // The parent needn't have the same tags.
+        let (parent, _) = all_snapshots
+            .iter()
+            .find(|(_, id)| *id == parent_id)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Snapshot {id1}'s parent, {parent_id}, is missing \
+                     (it was forgotten, or rewritten by tag, untag, or filter-snapshot)"
+                )
+            })?;
+        info!("Comparing snapshot {parent_id} to its child, {id1}");
+        Some((parent, snapshot1))
+    } else {
+        None
+    };
 
-        Ok((snapshot2.tree, snapshot2_forest))
+    if let Some((snapshot1, snapshot2)) = snapshot_pair {
+        // Read trees as we reach them instead of loading both snapshots up front;
+        // whatever they have in common, we never need to read.
+        let loader = RefCell::new(|id: &ObjectId| tree_cache.read(id));
+        let trees = diff::Trees::Lazy(&loader);
+        diff::compare_trees_in(
+            (&snapshot1.tree, &trees),
+            (&snapshot2.tree, &trees),
+            Utf8Path::new(""),
+            callbacks,
+        )?;
     } else {
-        info!(
-            "Comparing snapshot {} to its paths, {:?}",
-            id1, snapshot1.paths
-        );
-        fs_tree::forest_from_fs(
-            // NB: We want the behavior of `diff` to match `restore`,
-            // and we do not dereference symlinks in a filesystem directory we're restoring to.
-            // See the related comments in ui/restore.rs.
-            // Maybe we should expose this rationale in help text or some other user docs...
-            tree::Symlink::Read,
-            &snapshot1.paths,
-            Some(&snapshot1.tree),
-            snapshot1_forest,
-        )
+        let snapshot1_forest = tree::forest_from_root(&snapshot1.tree, &mut tree_cache)?;
+        let symlink_behavior = if args.dereference {
+            tree::Symlink::Dereference
+        } else {
+            tree::Symlink::Read
+        };
+        let (id2, forest2) = load_paths(id1, snapshot1, &snapshot1_forest, symlink_behavior)?;
+        diff::compare_trees(
+            (&snapshot1.tree, &snapshot1_forest),
+            (&id2, &forest2),
+            Utf8Path::new(""),
+            callbacks,
+        )?;
+    }
+    let changed = noted.changed;
+    if args.summary {
+        println!("{summary}");
+    }
+    if args.exit_code && changed {
+        return Err(DifferencesFound.into());
+    }
+    Ok(())
+}
+
+/// The letters for whatever metadata changed between the two nodes, e.g., `OP`
+pub fn meta_diff_string(old_node: &Node, new_node: &Node) -> String {
+    let chars = meta_diff_chars(&old_node.metadata, &new_node.metadata);
+    assert!(!chars.is_empty(), "metadata_changed() without a change");
+    chars.into_iter().collect()
+}
+
+/// Returned (as an error) from [`run`] when `--exit-code` is given and we found differences,
+/// so that `main` can exit with 1 without complaining about it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DifferencesFound;
+
+impl std::fmt::Display for DifferencesFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("found differences")
+    }
+}
+
+impl std::error::Error for DifferencesFound {}
+
+/// Wrapped around errors from [`run`] when `--exit-code` is given,
+/// so that `main` can exit with 2 and not 1 (which means we found differences).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DiffFailed;
+
+impl std::fmt::Display for DiffFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Couldn't diff")
+    }
+}
+
+/// Notes whether any change went by on its way to the inner callbacks, for `--exit-code`.
+///
+/// Metadata changes only count if we're showing them.
+struct NoteChanges<'a> {
+    inner: &'a mut dyn diff::Callbacks,
+    metadata: bool,
+    changed: bool,
+}
+
+impl diff::Callbacks for NoteChanges<'_> {
+    fn comparison(&self) -> diff::Comparison {
+        self.inner.comparison()
+    }
+
+    fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
+        self.changed = true;
+        self.inner.node_added(node_path, new_node, forest)
+    }
+
+    fn node_removed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        forest: &Forest,
+    ) -> Result<()> {
+        self.changed = true;
+        self.inner.node_removed(node_path, old_node, forest)
+    }
+
+    fn contents_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        self.changed = true;
+        self.inner.contents_changed(node_path, old_node, new_node)
+    }
+
+    fn metadata_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        self.changed |= self.metadata;
+        self.inner.metadata_changed(node_path, old_node, new_node)
+    }
+
+    fn directory_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        // Whatever changed inside gets its own callback.
+        self.inner.directory_changed(node_path, old_node, new_node)
+    }
+
+    fn should_visit(&mut self, node_path: &Utf8Path, node: &Node) -> bool {
+        self.inner.should_visit(node_path, node)
+    }
+
+    fn nothing_changed(&mut self, node_path: &Utf8Path, node: &Node) -> Result<()> {
+        self.inner.nothing_changed(node_path, node)
+    }
+
+    fn type_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        old_forest: &Forest,
+        new_node: &Node,
+        new_forest: &Forest,
+    ) -> Result<()> {
+        self.changed = true;
+        self.inner
+            .type_changed(node_path, old_node, old_forest, new_node, new_forest)
     }
 }
 
+fn load_paths(
+    id1: &ObjectId,
+    snapshot1: &snapshot::Snapshot,
+    snapshot1_forest: &tree::Forest,
+    symlink_behavior: tree::Symlink,
+) -> Result<(ObjectId, tree::Forest)> {
+    info!(
+        "Comparing snapshot {} to its paths, {:?}",
+        id1, snapshot1.paths
+    );
+    fs_tree::forest_from_fs(
+        // NB: By default we want the behavior of `diff` to match `restore`,
+        // and we do not dereference symlinks in a filesystem directory we're restoring to.
+        // See the related comments in ui/restore.rs (and the --dereference help above).
+        symlink_behavior,
+        // Cut files the way the snapshot did so unchanged ones match.
+        &snapshot1.chunking(),
+        &snapshot1.paths,
+        // Skip what the backup skipped so it doesn't look like it was added.
+        &snapshot1.skips,
+        Some(&snapshot1.tree),
+        snapshot1_forest,
+    )
+}
+
 #[derive(Debug, Default)]
 pub struct PrintDiffs {
     pub metadata: bool,
+    /// Print unchanged nodes too
+    pub unchanged: bool,
+    pub comparison: diff::Comparison,
 }
 
 impl diff::Callbacks for PrintDiffs {
+    fn comparison(&self) -> diff::Comparison {
+        self.comparison
+    }
+
     fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
-        ls::print_node("+ ", node_path, new_node, ls::Recurse::Yes(forest));
+        ls::print_node(
+            "+ ",
+            ls::Highlight::Added,
+            node_path,
+            new_node,
+            ls::Recurse::Yes(forest),
+        );
         Ok(())
     }
 
@@ -128,7 +498,13 @@ impl diff::Callbacks for PrintDiffs {
         old_node: &Node,
         forest: &Forest,
     ) -> Result<()> {
-        ls::print_node("- ", node_path, old_node, ls::Recurse::Yes(forest));
+        ls::print_node(
+            "- ",
+            ls::Highlight::Removed,
+            node_path,
+            old_node,
+            ls::Recurse::Yes(forest),
+        );
         Ok(())
     }
 
@@ -142,10 +518,28 @@ impl diff::Callbacks for PrintDiffs {
         assert_eq!(old_node.kind(), new_node.kind());
 
         if old_node.kind() == NodeType::Symlink {
-            ls::print_node("- ", node_path, old_node, ls::Recurse::No);
-            ls::print_node("+ ", node_path, new_node, ls::Recurse::No);
+            ls::print_node(
+                "- ",
+                ls::Highlight::Removed,
+                node_path,
+                old_node,
+                ls::Recurse::No,
+            );
+            ls::print_node(
+                "+ ",
+                ls::Highlight::Added,
+                node_path,
+                new_node,
+                ls::Recurse::No,
+            );
         } else {
-            ls::print_node("C ", node_path, old_node, ls::Recurse::No);
+            ls::print_node(
+                "C ",
+                ls::Highlight::Changed,
+                node_path,
+                old_node,
+                ls::Recurse::No,
+            );
         }
         Ok(())
     }
@@ -157,12 +551,409 @@ impl diff::Callbacks for PrintDiffs {
         new_node: &Node,
     ) -> Result<()> {
         if self.metadata {
-            let leading_char = format!(
-                "{} ",
-                meta_diff_char(&old_node.metadata, &new_node.metadata).unwrap()
+            let leading_chars = format!("{} ", meta_diff_string(old_node, new_node));
+            ls::print_node(
+                &leading_chars,
+                ls::Highlight::Changed,
+                node_path,
+                new_node,
+                ls::Recurse::No,
             );
-            ls::print_node(&leading_char, node_path, new_node, ls::Recurse::No);
         }
         Ok(())
     }
+
+    fn nothing_changed(&mut self, node_path: &Utf8Path, node: &Node) -> Result<()> {
+        if self.unchanged {
+            ls::print_node("= ", ls::Highlight::Plain, node_path, node, ls::Recurse::No);
+        }
+        Ok(())
+    }
+}
+
+/// A change as a line of JSON, for `diff --json`
+#[derive(Debug, Serialize)]
+struct JsonChange<'a> {
+    /// added, removed, contents, metadata, or unchanged
+    change: &'static str,
+    path: &'a Utf8Path,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Which metadata changed, as the same letters the usual output uses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_target: Option<&'a Utf8Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_target: Option<&'a Utf8Path>,
+}
+
+impl<'a> JsonChange<'a> {
+    fn new(change: &'static str, path: &'a Utf8Path, node: &Node) -> Self {
+        Self {
+            change,
+            path,
+            kind: ls::JsonLine::new(path, node).kind,
+            metadata: None,
+            old_target: None,
+            new_target: None,
+        }
+    }
+
+    fn print(&self) -> Result<()> {
+        // Stdout is line-buffered, so each change goes out as soon as we find it.
+        let mut out = std::io::stdout().lock();
+        serde_json::to_writer(&mut out, self)?;
+        writeln!(out)?;
+        Ok(())
+    }
+}
+
+/// Prints each change as a line of JSON.
+///
+/// Like [`PrintDiffs`], added and removed directories list everything inside them,
+/// and type changes are a removal and an addition.
+#[derive(Debug, Default)]
+pub struct JsonDiffs {
+    pub metadata: bool,
+    pub unchanged: bool,
+    pub comparison: diff::Comparison,
+}
+
+fn print_json_nodes(
+    change: &'static str,
+    node_path: &Utf8Path,
+    node: &Node,
+    forest: &Forest,
+) -> Result<()> {
+    let mut res = Ok(());
+    ls::walk_node(
+        &mut |p: &Utf8Path, n: &Node| {
+            if res.is_ok() {
+                res = JsonChange::new(change, p, n).print();
+            }
+        },
+        node_path,
+        node,
+        ls::Recurse::Yes(forest),
+    );
+    res
+}
+
+impl diff::Callbacks for JsonDiffs {
+    fn comparison(&self) -> diff::Comparison {
+        self.comparison
+    }
+
+    fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
+        print_json_nodes("added", node_path, new_node, forest)
+    }
+
+    fn node_removed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        forest: &Forest,
+    ) -> Result<()> {
+        print_json_nodes("removed", node_path, old_node, forest)
+    }
+
+    fn contents_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        let mut change = JsonChange::new("contents", node_path, new_node);
+        if let (NodeContents::Symlink { target: old }, NodeContents::Symlink { target: new }) =
+            (&old_node.contents, &new_node.contents)
+        {
+            change.old_target = Some(old);
+            change.new_target = Some(new);
+        }
+        change.print()
+    }
+
+    fn metadata_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        if self.metadata {
+            let mut change = JsonChange::new("metadata", node_path, new_node);
+            change.metadata = Some(meta_diff_string(old_node, new_node));
+            change.print()?;
+        }
+        Ok(())
+    }
+
+    fn nothing_changed(&mut self, node_path: &Utf8Path, node: &Node) -> Result<()> {
+        if self.unchanged {
+            JsonChange::new("unchanged", node_path, node).print()?;
+        }
+        Ok(())
+    }
+}
+
+/// One side's changes to a path, relative to the base
+#[derive(Debug, Default)]
+pub struct Change {
+    /// What we'd print for each change (e.g., - then + for a type change),
+    /// and the node to print it with
+    marks: Vec<(String, Node)>,
+    /// What's at the path now, if anything
+    result: Option<Node>,
+}
+
+pub type Changes = BTreeMap<Utf8PathBuf, Change>;
+
+/// Records changes (with the same marks [`PrintDiffs`] would print)
+/// for a three-way diff.
+#[derive(Debug, Default)]
+pub struct RecordChanges {
+    pub metadata: bool,
+    pub comparison: diff::Comparison,
+    pub changes: Changes,
+}
+
+impl RecordChanges {
+    fn record(&mut self, path: &Utf8Path, mark: &str, node: &Node, result: Option<&Node>) {
+        let change = self.changes.entry(path.to_owned()).or_default();
+        change.marks.push((mark.to_owned(), node.clone()));
+        change.result = result.cloned();
+    }
+
+    fn record_tree(&mut self, mark: &str, path: &Utf8Path, node: &Node, forest: &Forest) {
+        let added = mark == "+";
+        ls::walk_node(
+            &mut |p: &Utf8Path, n: &Node| self.record(p, mark, n, added.then_some(n)),
+            path,
+            node,
+            ls::Recurse::Yes(forest),
+        );
+    }
+}
+
+impl diff::Callbacks for RecordChanges {
+    fn comparison(&self) -> diff::Comparison {
+        self.comparison
+    }
+
+    fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
+        self.record_tree("+", node_path, new_node, forest);
+        Ok(())
+    }
+
+    fn node_removed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        forest: &Forest,
+    ) -> Result<()> {
+        self.record_tree("-", node_path, old_node, forest);
+        Ok(())
+    }
+
+    fn contents_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        if old_node.kind() == NodeType::Symlink {
+            self.record(node_path, "-", old_node, Some(new_node));
+            self.record(node_path, "+", new_node, Some(new_node));
+        } else {
+            self.record(node_path, "C", new_node, Some(new_node));
+        }
+        Ok(())
+    }
+
+    fn metadata_changed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        new_node: &Node,
+    ) -> Result<()> {
+        if self.metadata {
+            let mark = meta_diff_string(old_node, new_node);
+            self.record(node_path, &mark, new_node, Some(new_node));
+        }
+        Ok(())
+    }
+}
+
+/// Prints the changes from both sides of a three-way diff, in path order.
+///
+/// Each line is tagged with the side it came from (`1 ` or ` 2`),
+/// `12` if both sides made the same change,
+/// or `!1` and `!2` if they changed the same path in different ways.
+/// Unless we're showing metadata, changes that only differ in metadata are the same.
+fn print_three_way(mut first: Changes, mut second: Changes, metadata: bool) {
+    let same = |one: &Change, two: &Change| match (&one.result, &two.result) {
+        (None, None) => true,
+        (Some(l), Some(r)) => {
+            l.contents == r.contents && (!metadata || tree::same_metadata(&l.metadata, &r.metadata))
+        }
+        _ => false,
+    };
+    let paths: BTreeSet<Utf8PathBuf> = first.keys().chain(second.keys()).cloned().collect();
+    let print = |tag: &str, change: &Change, path: &Utf8Path| {
+        for (mark, node) in &change.marks {
+            ls::print_node(
+                &format!("{tag} {mark} "),
+                ls::Highlight::of_mark(mark),
+                path,
+                node,
+                ls::Recurse::No,
+            );
+        }
+    };
+    for path in paths {
+        match (first.remove(&path), second.remove(&path)) {
+            (None, None) => unreachable!(),
+            (Some(one), None) => print("1 ", &one, &path),
+            (None, Some(two)) => print(" 2", &two, &path),
+            (Some(one), Some(two)) if same(&one, &two) => print("12", &one, &path),
+            (Some(one), Some(two)) => {
+                print("!1", &one, &path);
+                print("!2", &two, &path);
+            }
+        }
+    }
+}
+
+/// Counts changes instead of printing them.
+///
+/// Added and removed directories count everything inside them,
+/// just like [`PrintDiffs`] would list it all.
+#[derive(Debug, Default)]
+pub struct SummaryDiffs {
+    pub comparison: diff::Comparison,
+    pub added: usize,
+    pub removed: usize,
+    pub contents_changed: usize,
+    pub metadata_changed: usize,
+    pub type_changed: usize,
+}
+
+fn count_nodes(node_path: &Utf8Path, node: &Node, forest: &Forest) -> usize {
+    let mut count = 0;
+    ls::walk_node(
+        &mut |_: &Utf8Path, _: &Node| count += 1,
+        node_path,
+        node,
+        ls::Recurse::Yes(forest),
+    );
+    count
+}
+
+impl diff::Callbacks for SummaryDiffs {
+    fn comparison(&self) -> diff::Comparison {
+        self.comparison
+    }
+
+    fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
+        self.added += count_nodes(node_path, new_node, forest);
+        Ok(())
+    }
+
+    fn node_removed(
+        &mut self,
+        node_path: &Utf8Path,
+        old_node: &Node,
+        forest: &Forest,
+    ) -> Result<()> {
+        self.removed += count_nodes(node_path, old_node, forest);
+        Ok(())
+    }
+
+    fn contents_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+        self.contents_changed += 1;
+        Ok(())
+    }
+
+    fn metadata_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+        self.metadata_changed += 1;
+        Ok(())
+    }
+
+    fn type_changed(
+        &mut self,
+        _: &Utf8Path,
+        _: &Node,
+        _: &Forest,
+        _: &Node,
+        _: &Forest,
+    ) -> Result<()> {
+        self.type_changed += 1;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for SummaryDiffs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let types = match self.type_changed {
+            0 => String::new(),
+            1 => ", 1 changed type".to_owned(),
+            n => format!(", {n} changed types"),
+        };
+        write!(
+            f,
+            "+{} -{} C{} M{}{types}",
+            self.added, self.removed, self.contents_changed, self.metadata_changed
+        )
+    }
+}
+
+/// Prints only directories, for a collapsed view of what changed.
+#[derive(Debug, Default)]
+pub struct PrintDirs;
+
+impl diff::Callbacks for PrintDirs {
+    fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, _: &Forest) -> Result<()> {
+        if new_node.kind() == NodeType::Directory {
+            ls::print_node(
+                "+ ",
+                ls::Highlight::Added,
+                node_path,
+                new_node,
+                ls::Recurse::No,
+            );
+        }
+        Ok(())
+    }
+
+    fn node_removed(&mut self, node_path: &Utf8Path, old_node: &Node, _: &Forest) -> Result<()> {
+        if old_node.kind() == NodeType::Directory {
+            ls::print_node(
+                "- ",
+                ls::Highlight::Removed,
+                node_path,
+                old_node,
+                ls::Recurse::No,
+            );
+        }
+        Ok(())
+    }
+
+    fn contents_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+        Ok(())
+    }
+
+    fn metadata_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+        Ok(())
+    }
+
+    fn directory_changed(&mut self, node_path: &Utf8Path, _: &Node, new_node: &Node) -> Result<()> {
+        ls::print_node(
+            "C ",
+            ls::Highlight::Changed,
+            node_path,
+            new_node,
+            ls::Recurse::No,
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,178 @@
+use std::io::Read;
+use std::time::Instant;
+
+use anyhow::{Context, Result, bail, ensure};
+use clap::Parser;
+use tracing::*;
+
+use crate::backend;
+use crate::config::Configuration;
+use crate::file_util::{self, nice_size};
+
+/// Check that the repository is reachable and the local cache is usable
+///
+/// Opens the backend, checks that its unfilter (if any) undoes its filter,
+/// then writes, lists, reads back, and removes
+/// a small throwaway object (healthcheck/<random>), timing each step.
+/// Run this first when a backup mysteriously hangs.
+#[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
+pub struct Args {
+    /// Size of the throwaway object
+    #[clap(long, default_value_t = 4096)]
+    size: usize,
+}
+
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
+    // Opening B2 authorizes and looks up the bucket,
+    // so bad credentials or a missing bucket fail here.
+    let (backend_config, cached_backend) = timed("open", || {
+        backend::open(repository, config, backend::CacheBehavior::Normal)
+    })?;
+
+    // Before we write anything through it, make sure the filter undoes itself.
+    if let Some((filter, unfilter)) = &backend_config.filter {
+        let environment = backend_config.filter_environment(repository);
+        timed("filter", || {
+            backend::filter::round_trip_test(filter, unfilter, &environment)
+        })?;
+    }
+
+    let mut problems = 0;
+    match cached_backend.cache() {
+        Some(cache) => problems += check_cache(config, cache),
+        None => println!("No cache (uncached repository)"),
+    }
+
+    let raw = cached_backend.raw();
+    let key = format!(
+        "healthcheck/{}",
+        std::iter::repeat_with(fastrand::alphanumeric)
+            .take(16)
+            .collect::<String>()
+    );
+    let payload: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+        .take(args.size)
+        .collect();
+    debug!(
+        "Round-tripping {} through {key}",
+        nice_size(payload.len() as u64)
+    );
+
+    timed("write", || {
+        Ok(raw.write(
+            payload.len() as u64,
+            &mut std::io::Cursor::new(&payload),
+            &key,
+        )?)
+    })?;
+    // Clean up after ourselves even if reading it back goes wrong.
+    let checked = round_trip(raw, &key, &payload);
+    let removed = timed("remove", || Ok(raw.remove(&key)?));
+    // Filesystem backends made a directory for it; don't leave that behind either.
+    // (remove_dir() only removes empty ones, so we can't take anything else with it.)
+    if repository.is_dir() {
+        let dir = repository.join("healthcheck");
+        if let Err(e) = std::fs::remove_dir(&dir)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Couldn't remove {dir}: {e}");
+        }
+    }
+    checked?;
+    removed?;
+
+    if let backend::Kind::Backblaze { bucket, .. } = &backend_config.kind {
+        println!("Backblaze credentials work and bucket {bucket} is readable and writable");
+    }
+
+    if problems > 0 {
+        bail!("Found {problems} problem(s) with the cache");
+    }
+    println!("All good!");
+    Ok(())
+}
+
+/// List and read back what we just wrote.
+fn round_trip(raw: &dyn backend::Backend, key: &str, payload: &[u8]) -> Result<()> {
+    let listed = timed("list", || Ok(raw.list(key)?))?;
+    // Filesystem listings of a single file give its full path,
+    // and lengths are of what the filter (if any) wrote, so don't compare those.
+    ensure!(
+        listed.iter().any(|(k, _len)| k.ends_with(key)),
+        "Listing {key} didn't find it (got {listed:?})"
+    );
+
+    let read_back = timed("read", || {
+        let mut buf = vec![];
+        raw.read(key)?
+            .read_to_end(&mut buf)
+            .with_context(|| format!("Couldn't read {key}"))?;
+        Ok(buf)
+    })?;
+    ensure!(
+        read_back == payload,
+        "Read back {} from {key}, but wrote {} (and they differ)",
+        nice_size(read_back.len() as u64),
+        nice_size(payload.len() as u64)
+    );
+    Ok(())
+}
+
+/// Make sure the cache directory is writable and has room to grow;
+/// returns how many problems we found.
+fn check_cache(config: &Configuration, cache: &backend::cache::Cache) -> usize {
+    let dir = &cache.directory;
+    let mut problems = 0;
+
+    if let Err(e) = tempfile::tempfile_in(dir) {
+        warn!("Cache directory {dir} isn't writable: {e}");
+        problems += 1;
+    }
+
+    let space = || -> Result<(Option<u64>, u64)> {
+        let available = file_util::free_space(dir)?.map(|(_fsid, available)| available);
+        let used = cache.stats()?.total_bytes;
+        Ok((available, used))
+    };
+    match space() {
+        Ok((Some(available), used)) => {
+            println!(
+                "Cache at {dir} uses {} of {}, {} free on disk",
+                nice_size(used),
+                nice_size(config.cache_size.as_u64()),
+                nice_size(available)
+            );
+            let room_to_grow = config.cache_size.as_u64().saturating_sub(used);
+            if available < room_to_grow {
+                warn!(
+                    "The cache can grow by {}, but only {} is free",
+                    nice_size(room_to_grow),
+                    nice_size(available)
+                );
+                problems += 1;
+            }
+        }
+        // Can't ask this platform about free space; say what we know.
+        Ok((None, used)) => println!(
+            "Cache at {dir} uses {} of {}",
+            nice_size(used),
+            nice_size(config.cache_size.as_u64())
+        ),
+        Err(e) => {
+            warn!("{e:#}");
+            problems += 1;
+        }
+    }
+    problems
+}
+
+/// Run the given step, printing how long it took (and whether it worked).
+fn timed<T, F: FnOnce() -> Result<T>>(what: &str, f: F) -> Result<T> {
+    let start = Instant::now();
+    let res = f();
+    let ms = start.elapsed().as_secs_f64() * 1000.0;
+    let status = if res.is_ok() { "ok" } else { "FAILED" };
+    println!("{what:<6} {status:<6} {ms:>9.1} ms");
+    res.with_context(|| format!("Couldn't {what}"))
+}
@@ -1,7 +1,7 @@
 use std::io;
 use std::io::prelude::*;
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use tracing::*;
@@ -30,72 +30,44 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         crate::prettify::prettify_serialize();
     }
 
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let snapshots = snapshot::load_chronologically(&cached_backend)?;
     let (snapshot, id) = snapshot::find(&snapshots, &args.snapshot)?;
     let index = index::build_master_index(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
     let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
-    let mut current_tree_id = snapshot.tree;
-    let mut path_so_far = Utf8PathBuf::new();
 
     info!("Printing {} from snapshot {}", args.path, id);
 
-    let mut components = args.path.components().peekable();
-    while let Some(component) = components.next() {
-        let is_last_component = components.peek().is_none();
-        let component = match component {
-            Utf8Component::CurDir => continue,
-            Utf8Component::Normal(c) => Utf8Path::new(c),
-            _ => bail!("dump doesn't support absolute paths, .., etc."),
-        };
-
-        debug!(
-            "Looking for {} in {} (tree {})",
-            component,
-            if path_so_far.as_str().is_empty() {
-                Utf8Path::new("root tree")
-            } else {
-                &path_so_far
-            },
-            current_tree_id
-        );
-
-        path_so_far.push(component);
-        let current_tree = tree_cache.read(&current_tree_id)?;
-        let node = match current_tree.get(component) {
-            None => {
-                bail!("Couldn't find {} in the given snapshot", path_so_far);
-            }
-            Some(n) => n,
-        };
-
-        match &node.contents {
-            tree::NodeContents::Directory { subtree } => {
-                if is_last_component {
-                    let tree_to_dump = tree_cache.read(subtree)?;
-                    dump_dir(&tree_to_dump, &path_so_far, &args.output)?;
-                } else {
-                    current_tree_id = *subtree; // Continue our search.
-                }
-            }
-            tree::NodeContents::Symlink { target } => {
-                dump_symlink(target, &path_so_far, &args.output)?;
-            }
-            tree::NodeContents::File { chunks } => {
-                if is_last_component {
-                    dump_file(chunks, &cached_backend, &index, &blob_map, &args.output)?;
-                } else {
-                    bail!("{path_so_far} is a file, not a directory");
-                }
-            }
-        };
+    if args
+        .path
+        .components()
+        .any(|c| !matches!(c, Utf8Component::CurDir | Utf8Component::Normal(_)))
+    {
+        bail!("dump doesn't support absolute paths, .., etc.");
+    }
+    // Print paths like they are in the snapshot, without any ./
+    let path: Utf8PathBuf = args
+        .path
+        .components()
+        .filter(|c| *c != Utf8Component::CurDir)
+        .collect();
+
+    // Symlinks aren't followed, even partway through the path:
+    // they point somewhere on the machine that was backed up, not in the snapshot.
+    let node = tree::walk_path(&snapshot.tree, &path, |id| tree_cache.read(id))?
+        .ok_or_else(|| anyhow!("Couldn't find {path} in the given snapshot"))?;
+
+    match &node.contents {
+        tree::NodeContents::Directory { subtree } => {
+            let tree_to_dump = tree_cache.read(subtree)?;
+            dump_dir(&tree_to_dump, &path, &args.output)
+        }
+        tree::NodeContents::Symlink { target } => dump_symlink(target, &path, &args.output),
+        tree::NodeContents::File { chunks } => {
+            dump_file(chunks, &cached_backend, &index, &blob_map, &args.output)
+        }
     }
-    Ok(())
 }
 
 fn dump_dir(
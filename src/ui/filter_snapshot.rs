@@ -35,7 +35,14 @@ pub struct Args {
     tags: Vec<String>,
 
     /// Skip anything whose path matches the given regular expression
-    #[clap(short = 's', long = "skip", name = "regex", required = true)]
+    /// (or glob:, see `backup --help`)
+    #[clap(
+        short = 's',
+        long = "skip",
+        name = "regex",
+        required = true,
+        verbatim_doc_comment
+    )]
     skips: Vec<String>,
 
     /// The snapshot to filter
@@ -48,11 +55,8 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
     }
 
     // Build the usual suspects.
-    let (backend_config, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (backend_config, cached_backend) =
+        backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let index = index::build_master_index(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
 
@@ -86,10 +90,11 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
             &backend_config,
             &cached_backend,
             Index::default(),
+            None,
             &back_stats,
         );
 
-        let mut filter = filter::skip_matching_paths(&args.skips)?;
+        let mut filter = filter::skip_matching_nodes(&args.skips)?;
 
         let new_snapshot = walk_snapshot(
             &snapshot_and_forest,
@@ -137,10 +142,7 @@ fn walk_snapshot<Filter>(
     backup: &mut backup::Backup,
 ) -> Result<snapshot::Snapshot>
 where
-    Filter: FnMut(
-        &Utf8Path,
-        // More some day?
-    ) -> bool,
+    Filter: FnMut(&Utf8Path, &tree::Node) -> bool,
 {
     debug!("filtering snapshot {}", snapshot_and_forest.id);
     let new_root = walk_tree(
@@ -167,10 +169,7 @@ fn walk_tree<Filter>(
     backup: &mut backup::Backup,
 ) -> Result<ObjectId>
 where
-    Filter: FnMut(
-        &Utf8Path,
-        // More some day?
-    ) -> bool,
+    Filter: FnMut(&Utf8Path, &tree::Node) -> bool,
 {
     let tree: &tree::Tree = forest
         .get(tree_id)
@@ -186,7 +185,7 @@ where
     for (path, node) in tree {
         let mut node_path = tree_path.to_owned();
         node_path.push(path);
-        if !filter(&node_path) {
+        if !filter(&node_path, node) {
             debug!("  {:>8} {node_path}", "skipped");
             continue;
         }
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use camino::Utf8Path;
+use clap::Parser;
+use jiff::tz::TimeZone;
+use tracing::*;
+
+use crate::backend;
+use crate::config::Configuration;
+use crate::file_util::nice_size;
+use crate::filter::Glob;
+use crate::hashing::ObjectId;
+use crate::index;
+use crate::ls;
+use crate::snapshot;
+use crate::tree::{self, Node, NodeContents};
+
+/// Find which snapshots contain a path
+///
+/// Paths are relative to the snapshot, like the ones `ls` prints,
+/// and can be globs (`*`, `?`, `**`, `[...]`).
+/// Like .gitignore, a pattern without a `/` matches a name anywhere;
+/// one with a `/` matches from the root.
+///
+/// Prints each match, newest snapshot first:
+/// the snapshot's ID and date, then the size, modification time, and path of the match.
+#[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
+pub struct Args {
+    /// Stop looking for each pattern after the newest snapshot that has it
+    #[clap(long)]
+    newest_only: bool,
+
+    #[clap(required = true, value_name = "PATH")]
+    patterns: Vec<String>,
+}
+
+pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+    let globs = args
+        .patterns
+        .iter()
+        .map(|p| Glob::new(p))
+        .collect::<Result<Vec<_>>>()?;
+
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
+    let snapshots = snapshot::load_chronologically(&cached_backend)?;
+    let index = index::build_master_index(&cached_backend)?;
+    let blob_map = index::blob_to_pack_map(&index)?;
+    let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
+
+    // Which patterns we're still looking for
+    let mut looking = vec![true; globs.len()];
+    for (snapshot, id) in snapshots.iter().rev() {
+        if !looking.contains(&true) {
+            break;
+        }
+        debug!("Searching snapshot {id}");
+        let forest = tree::forest_from_root(&snapshot.tree, &mut tree_cache)?;
+
+        let mut found = vec![false; globs.len()];
+        let mut v = |path: &Utf8Path, node: &Node| {
+            let mut matched = false;
+            for (i, glob) in globs.iter().enumerate() {
+                if looking[i] && glob.is_match(path) {
+                    found[i] = true;
+                    matched = true;
+                }
+            }
+            if matched {
+                print_match(snapshot, id, path, node);
+            }
+        };
+        ls::walk_tree(&mut v, Utf8Path::new(""), &snapshot.tree, &forest);
+
+        if args.newest_only {
+            for (l, f) in looking.iter_mut().zip(&found) {
+                *l &= !f;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_match(snapshot: &snapshot::Snapshot, id: &ObjectId, path: &Utf8Path, node: &Node) {
+    let size = match (&node.contents, node.metadata.size()) {
+        (NodeContents::File { .. }, Some(s)) => nice_size(s),
+        _ => "-".to_owned(),
+    };
+    let mtime = node
+        .metadata
+        .modification_time()
+        .map(|t| {
+            t.to_zoned(TimeZone::system())
+                .strftime("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| "-".to_owned());
+    let mut path = path.to_string();
+    match &node.contents {
+        NodeContents::Directory { .. } => path.push(std::path::MAIN_SEPARATOR),
+        NodeContents::File { .. } => {}
+        NodeContents::Symlink { target } => path += &format!(" -> {target}"),
+    }
+    println!(
+        "{}  {}  {size:>10}  {mtime:>19}  {path}",
+        id.short_name(),
+        snapshot.time.strftime("%Y-%m-%d %H:%M:%S"),
+    );
+}
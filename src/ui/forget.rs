@@ -1,47 +1,198 @@
-use anyhow::{Result, bail};
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail, ensure};
 use clap::Parser;
+use jiff::Zoned;
+use rustc_hash::{FxHashMap, FxHashSet};
 use tracing::*;
 
 use crate::backend;
 use crate::config::Configuration;
+use crate::file_util::nice_size;
 use crate::hashing::ObjectId;
+use crate::index;
 use crate::snapshot;
+use crate::tree;
 
 /// Forget snapshots
 ///
 /// Data used by these snapshots is not immediately deleted,
 /// but will be thrown out by the next `prune`.
+///
+/// Instead of listing snapshots, you can give a retention policy
+/// with the --keep-* flags, and every snapshot it doesn't keep is forgotten.
+/// Each flag keeps the newest snapshot in each of the last N periods
+/// (days, weeks, etc., in the time zone the snapshot was taken in)
+/// that have any snapshots. A snapshot kept by any flag is kept.
+/// Policies apply to each author's snapshots of each set of paths separately,
+/// so frequent backups of one thing don't crowd out backups of another.
 #[derive(Debug, Parser)]
 #[clap(verbatim_doc_comment)]
 pub struct Args {
     #[clap(short = 'n', long)]
     dry_run: bool,
 
+    /// Print how much data each forgotten snapshot alone uses,
+    /// and how much forgetting all of them frees at the next `prune`.
+    ///
+    /// This takes a bit longer, since we have to walk every snapshot
+    /// to see which data they share.
+    #[clap(short, long, verbatim_doc_comment)]
+    sizes: bool,
+
+    #[clap(flatten)]
+    policy: Policy,
+
+    /// Only forget snapshots with the given tag;
+    /// policies and DUPLICATES ignore the rest.
+    /// Can be given multiple times to require several tags.
+    #[clap(long = "tag", value_name = "TAG", verbatim_doc_comment)]
+    tags: Vec<String>,
+
     /// The ID of a snapshot to forget or
     /// "DUPLICATES" to forget duplicate snapshots
-    #[clap(required = true, name = "SNAPSHOTS", verbatim_doc_comment)]
+    #[clap(
+        required_unless_present = "Policy",
+        conflicts_with = "Policy",
+        name = "SNAPSHOTS",
+        verbatim_doc_comment
+    )]
     to_forget: Vec<String>,
 }
 
+/// Which snapshots to keep
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct Policy {
+    /// Keep the last N snapshots
+    #[clap(long, value_name = "N")]
+    keep_last: Option<usize>,
+
+    /// Keep the newest snapshot from each of the last N days
+    #[clap(long, value_name = "DAYS")]
+    keep_daily: Option<usize>,
+
+    /// Keep the newest snapshot from each of the last N (ISO 8601) weeks
+    #[clap(long, value_name = "WEEKS")]
+    keep_weekly: Option<usize>,
+
+    /// Keep the newest snapshot from each of the last N months
+    #[clap(long, value_name = "MONTHS")]
+    keep_monthly: Option<usize>,
+
+    /// Keep the newest snapshot from each of the last N years
+    #[clap(long, value_name = "YEARS")]
+    keep_yearly: Option<usize>,
+}
+
+/// The calendar period a snapshot falls in, for one rule of a [`Policy`]
+type Bucket = (i16, i16, i8);
+
+impl Policy {
+    /// Each rule, as (its name, how many to keep, what bucket a time falls in)
+    #[expect(clippy::type_complexity)] // It's a table, it's fine.
+    fn rules(&self) -> [(&'static str, Option<usize>, fn(usize, &Zoned) -> Bucket); 5] {
+        [
+            // Every snapshot is its own bucket.
+            ("last", self.keep_last, |i, _| (i as i16, 0, 0)),
+            ("daily", self.keep_daily, |_, t| {
+                (t.year(), t.month() as i16, t.day())
+            }),
+            ("weekly", self.keep_weekly, |_, t| {
+                let w = t.date().iso_week_date();
+                (w.year(), w.week() as i16, 0)
+            }),
+            ("monthly", self.keep_monthly, |_, t| {
+                (t.year(), t.month() as i16, 0)
+            }),
+            ("yearly", self.keep_yearly, |_, t| (t.year(), 0, 0)),
+        ]
+    }
+
+    /// Given snapshot times, newest first,
+    /// returns the rules that keep each snapshot (empty if none do).
+    pub fn apply(&self, newest_first: &[&Zoned]) -> Vec<Vec<&'static str>> {
+        let mut reasons = vec![vec![]; newest_first.len()];
+        for (name, count, bucket_of) in self.rules() {
+            let Some(mut count) = count else {
+                continue;
+            };
+            let mut last_bucket = None;
+            for (i, time) in newest_first.iter().enumerate() {
+                if count == 0 {
+                    break;
+                }
+                let bucket = bucket_of(i, time);
+                if last_bucket != Some(bucket) {
+                    reasons[i].push(name);
+                    last_bucket = Some(bucket);
+                    count -= 1;
+                }
+            }
+        }
+        reasons
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rules().iter().all(|(_, count, _)| count.is_none())
+    }
+
+    /// Does every rule we were given keep zero snapshots?
+    /// (Then we'd forget everything.)
+    fn keeps_nothing(&self) -> bool {
+        self.rules()
+            .iter()
+            .all(|(_, count, _)| matches!(count, None | Some(0)))
+    }
+}
+
 pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
     unsafe {
         crate::prettify::prettify_serialize();
     }
 
-    assert!(!args.to_forget.is_empty());
+    assert!(!args.to_forget.is_empty() || !args.policy.is_empty());
+    ensure!(
+        args.policy.is_empty() || !args.policy.keeps_nothing(),
+        "That policy keeps no snapshots; list the ones to forget if you really mean it"
+    );
 
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
 
-    let snapshots = snapshot::load_chronologically(&cached_backend)?;
-    let success = if args.to_forget == ["DUPLICATES"] {
-        forget_duplicate_snapshots(&cached_backend, &snapshots, args.dry_run)?
+    let mut snapshots = snapshot::load_chronologically(&cached_backend)?;
+    // What other snapshots use counts too, even if --tag means we won't forget them.
+    let mut reclaimed = if args.sizes {
+        Some(Reclaimed::new(&cached_backend, &snapshots)?)
     } else {
-        forget_snapshot_list(&cached_backend, &snapshots, &args)
+        None
     };
+    snapshot::retain_tagged(&mut snapshots, &args.tags);
+    let success = if !args.policy.is_empty() {
+        forget_by_policy(
+            &cached_backend,
+            &snapshots,
+            &args.policy,
+            args.dry_run,
+            reclaimed.as_mut(),
+        )
+    } else if args.to_forget == ["DUPLICATES"] {
+        forget_duplicate_snapshots(
+            &cached_backend,
+            &snapshots,
+            args.dry_run,
+            reclaimed.as_mut(),
+        )?
+    } else {
+        forget_snapshot_list(&cached_backend, &snapshots, &args, reclaimed.as_mut())
+    };
+
+    if let Some(r) = reclaimed {
+        let freed = nice_size(r.total()?);
+        if args.dry_run {
+            info!("Forgetting these would free {freed} at the next prune");
+        } else {
+            info!("The next prune will free {freed}");
+        }
+    }
 
     if success {
         Ok(())
@@ -54,6 +205,7 @@ fn forget_duplicate_snapshots(
     cached_backend: &backend::CachedBackend,
     snapshots: &[(snapshot::Snapshot, ObjectId)],
     dry_run: bool,
+    mut reclaimed: Option<&mut Reclaimed>,
 ) -> Result<bool> {
     let mut success = true;
     let mut last_unique_snapshot_and_tree: Option<(ObjectId, ObjectId)> = None;
@@ -74,15 +226,58 @@ fn forget_duplicate_snapshots(
 
         // Hey, a duplicate tree!
         info!("Snapshot {} is a duplicate of {}", id, last_unique_snapshot);
-        success &= forget_snapshot(cached_backend, id, dry_run);
+        success &= forget_snapshot(cached_backend, id, dry_run, reclaimed.as_deref_mut());
     }
     Ok(success)
 }
 
+fn forget_by_policy(
+    cached_backend: &backend::CachedBackend,
+    snapshots: &[(snapshot::Snapshot, ObjectId)],
+    policy: &Policy,
+    dry_run: bool,
+    mut reclaimed: Option<&mut Reclaimed>,
+) -> bool {
+    // Like restic, bucket each author's backups of each set of paths on their own.
+    // Otherwise hourly backups of ~/src could leave nothing of last week's /etc backup.
+    let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for s in snapshots.iter().rev() {
+        groups.entry((&s.0.author, &s.0.paths)).or_default().push(s);
+    }
+
+    let mut success = true;
+    for ((author, paths), newest_first) in groups {
+        debug!(
+            "Applying policy to {author}'s snapshots of {}",
+            paths
+                .iter()
+                .map(|p| p.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let times: Vec<&Zoned> = newest_first.iter().map(|(s, _)| &s.time).collect();
+        let reasons = policy.apply(&times);
+
+        for ((snapshot, id), reasons) in newest_first.iter().zip(reasons) {
+            if reasons.is_empty() {
+                success &= forget_snapshot(cached_backend, id, dry_run, reclaimed.as_deref_mut());
+            } else {
+                info!(
+                    "Keeping {id} from {} ({})",
+                    snapshot.time.strftime("%a %F %H:%M:%S %Z"),
+                    reasons.join(", ")
+                );
+            }
+        }
+    }
+    success
+}
+
 fn forget_snapshot_list(
     cached_backend: &backend::CachedBackend,
     snapshots: &[(snapshot::Snapshot, ObjectId)],
     args: &Args,
+    mut reclaimed: Option<&mut Reclaimed>,
 ) -> bool {
     let mut success = true;
 
@@ -96,24 +291,222 @@ fn forget_snapshot_list(
             }
         };
 
-        success &= forget_snapshot(cached_backend, id, args.dry_run);
+        success &= forget_snapshot(cached_backend, id, args.dry_run, reclaimed.as_deref_mut());
     }
     success
 }
 
-fn forget_snapshot(cached_backend: &backend::CachedBackend, id: &ObjectId, dry_run: bool) -> bool {
+fn forget_snapshot(
+    cached_backend: &backend::CachedBackend,
+    id: &ObjectId,
+    dry_run: bool,
+    reclaimed: Option<&mut Reclaimed>,
+) -> bool {
+    let exclusive = match reclaimed.as_deref().map(|r| r.exclusive(id)).transpose() {
+        Ok(Some(x)) => format!(" ({} only it uses)", nice_size(x)),
+        Ok(None) => String::new(),
+        Err(e) => {
+            error!("{:?}", e);
+            return false;
+        }
+    };
     if dry_run {
-        info!("Would remove {id}");
+        info!("Would remove {id}{exclusive}");
+        if let Some(r) = reclaimed {
+            r.forgotten.insert(*id);
+        }
         return true;
     } else {
-        info!("Forgetting {id}");
+        info!("Forgetting {id}{exclusive}");
     }
 
     match cached_backend.remove_snapshot(id) {
-        Ok(()) => true,
+        Ok(()) => {
+            if let Some(r) = reclaimed {
+                r.forgotten.insert(*id);
+            }
+            true
+        }
         Err(e) => {
             error!("{:?}", e);
             false
         }
     }
 }
+
+/// For --sizes: which blobs each snapshot uses, and which snapshots we've forgotten,
+/// so we can say what the next prune frees.
+struct Reclaimed {
+    size_map: FxHashMap<ObjectId, u32>,
+    ref_counts: tree::BlobRefCounts,
+    blobs: FxHashMap<ObjectId, FxHashSet<ObjectId>>,
+    forgotten: FxHashSet<ObjectId>,
+}
+
+impl Reclaimed {
+    /// Walk all the snapshots in the repo to see which blobs they share.
+    fn new(
+        cached_backend: &backend::CachedBackend,
+        snapshots: &[(snapshot::Snapshot, ObjectId)],
+    ) -> Result<Self> {
+        let index = index::build_master_index(cached_backend)?;
+        let blob_map = index::blob_to_pack_map(&index)?;
+        let mut tree_cache = tree::Cache::new(&index, &blob_map, cached_backend);
+        let size_map = index::blob_to_size_map(&index)?;
+
+        let mut ref_counts = tree::BlobRefCounts::default();
+        let mut blobs = FxHashMap::default();
+        for (snapshot, id) in snapshots {
+            let forest = tree::forest_from_root(&snapshot.tree, &mut tree_cache)?;
+            let snapshot_blobs = tree::blobs_in_forest(&forest);
+            ref_counts.add(&snapshot_blobs);
+            blobs.insert(*id, snapshot_blobs);
+        }
+        Ok(Self {
+            size_map,
+            ref_counts,
+            blobs,
+            forgotten: FxHashSet::default(),
+        })
+    }
+
+    /// How much data only the given snapshot uses
+    fn exclusive(&self, id: &ObjectId) -> Result<u64> {
+        self.ref_counts.freed_by(&[&self.blobs[id]], &self.size_map)
+    }
+
+    /// How much data only the forgotten snapshots use
+    fn total(&self) -> Result<u64> {
+        let forgotten = self
+            .forgotten
+            .iter()
+            .map(|id| &self.blobs[id])
+            .collect::<Vec<_>>();
+        self.ref_counts.freed_by(&forgotten, &self.size_map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn times(ts: &[&str]) -> Vec<Zoned> {
+        ts.iter()
+            .map(|t| format!("{t}[America/Los_Angeles]").parse().unwrap())
+            .collect()
+    }
+
+    /// Which of the given times (newest first) the policy keeps
+    fn kept(policy: Policy, ts: &[&str]) -> Vec<usize> {
+        let ts = times(ts);
+        let refs: Vec<&Zoned> = ts.iter().collect();
+        policy
+            .apply(&refs)
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| !r.is_empty())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    const TIMES: &[&str] = &[
+        "2024-03-02T09:00:00", // 0: Saturday
+        "2024-03-02T08:00:00", // 1: Same day
+        "2024-03-01T23:00:00", // 2: Friday
+        "2024-02-26T10:00:00", // 3: Monday, same ISO week as 0-2
+        "2024-02-25T10:00:00", // 4: Sunday, the week before
+        "2024-02-01T10:00:00", // 5: Still February
+        "2024-01-15T10:00:00", // 6: January
+        "2023-12-31T10:00:00", // 7: Last year (but ISO week 52 of 2023)
+        "2022-06-01T10:00:00", // 8: The year before that
+    ];
+
+    #[test]
+    fn keep_last() {
+        let p = Policy {
+            keep_last: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(kept(p, TIMES), [0, 1, 2]);
+    }
+
+    #[test]
+    fn keep_daily() {
+        let p = Policy {
+            keep_daily: Some(3),
+            ..Default::default()
+        };
+        // Only the newest from March 2nd
+        assert_eq!(kept(p, TIMES), [0, 2, 3]);
+    }
+
+    #[test]
+    fn keep_weekly() {
+        let p = Policy {
+            keep_weekly: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(kept(p, TIMES), [0, 4, 5]);
+    }
+
+    #[test]
+    fn keep_monthly_and_yearly() {
+        let p = Policy {
+            keep_monthly: Some(2),
+            keep_yearly: Some(10),
+            ..Default::default()
+        };
+        // Monthly keeps March and February; yearly keeps 2024, 2023, and 2022.
+        assert_eq!(kept(p, TIMES), [0, 3, 7, 8]);
+    }
+
+    #[test]
+    fn rules_combine() {
+        let p = Policy {
+            keep_last: Some(1),
+            keep_daily: Some(2),
+            keep_monthly: Some(3),
+            ..Default::default()
+        };
+        let ts = times(TIMES);
+        let refs: Vec<&Zoned> = ts.iter().collect();
+        let reasons = p.apply(&refs);
+        assert_eq!(reasons[0], ["last", "daily", "monthly"]);
+        assert_eq!(reasons[2], ["daily"]);
+        assert_eq!(reasons[3], ["monthly"]);
+        assert_eq!(reasons[6], ["monthly"]);
+        assert!(reasons[1].is_empty());
+    }
+
+    #[test]
+    fn keep_zero() {
+        let p = Policy {
+            keep_daily: Some(0),
+            ..Default::default()
+        };
+        assert!(!p.is_empty());
+        assert!(p.keeps_nothing());
+
+        // Fine as long as something else keeps snapshots.
+        let p = Policy {
+            keep_daily: Some(0),
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        assert!(!p.keeps_nothing());
+        assert_eq!(kept(p, TIMES), [0]);
+    }
+
+    #[test]
+    fn local_days() {
+        // 11 PM in Los Angeles is the next day in UTC, but it's still the same day here.
+        let p = Policy {
+            keep_daily: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(p, &["2024-03-01T23:00:00", "2024-03-01T01:00:00"]),
+            [0]
+        );
+    }
+}
@@ -1,9 +1,4 @@
-use std::{
-    io::prelude::*,
-    process::{self, Stdio},
-};
-
-use anyhow::{Context, Result, bail, ensure};
+use anyhow::{Context, Result};
 use byte_unit::Byte;
 use clap::{Parser, Subcommand};
 
@@ -18,6 +13,11 @@ pub struct Args {
     #[clap(long)]
     gpg: Option<String>,
 
+    /// Let gpg see this environment variable (e.g., GNUPGHOME)
+    /// besides the defaults. Can be given more than once.
+    #[clap(long, value_name = "VAR", requires = "gpg", verbatim_doc_comment)]
+    filter_env: Vec<String>,
+
     #[clap(subcommand)]
     subcommand: Command,
 }
@@ -32,16 +32,83 @@ enum Command {
         /// use this to override that assumption.
         #[clap(long, verbatim_doc_comment)]
         force_cache: bool,
+
+        /// Reread everything after writing it to check for corruption.
+        /// Doubles disk I/O, but catches bad disks at write time.
+        #[clap(long, verbatim_doc_comment)]
+        verify_after_write: bool,
     },
     /// Backup to Backblaze B2
     Backblaze {
+        /// Leave out to read BACKPAK_B2_KEY_ID from the environment instead
         #[clap(short, long)]
-        key_id: String,
+        key_id: Option<String>,
+        /// Leave out to read BACKPAK_B2_APP_KEY from the environment instead
         #[clap(short, long)]
-        application_key: String,
+        application_key: Option<String>,
         #[clap(short, long)]
         bucket: String,
-        #[clap(short, long, default_value_t = 4)]
+        #[clap(short, long, default_value_t = backend::DEFAULT_CONNECTIONS)]
+        concurrent_connections: u32,
+        /// Connect through this proxy instead of the one in
+        /// ALL_PROXY, HTTPS_PROXY, or HTTP_PROXY (if any).
+        /// Hosts in NO_PROXY are still connected to directly.
+        #[clap(long, verbatim_doc_comment)]
+        proxy: Option<String>,
+        /// Trust the CA certificates in this PEM file instead of the built-in ones,
+        /// e.g., for a TLS-intercepting proxy.
+        #[clap(long, verbatim_doc_comment)]
+        ca_cert: Option<camino::Utf8PathBuf>,
+        /// Make at most this many requests of B2 per second
+        /// to stay under its rate limits.
+        #[clap(long, verbatim_doc_comment)]
+        max_requests_per_second: Option<f64>,
+        /// Upload packs in parts, remembering which ones made it
+        /// so that an interrupted upload resumes instead of starting over.
+        #[clap(long, verbatim_doc_comment)]
+        resume_uploads: bool,
+    },
+    /// Backup to Amazon S3 or an S3-compatible service (MinIO, Garage, etc.)
+    S3 {
+        /// Scheme and host of the S3 API, e.g., http://minio.local:9000
+        #[clap(short, long)]
+        endpoint: String,
+        #[clap(short, long, default_value = "us-east-1")]
+        region: String,
+        #[clap(short, long)]
+        bucket: String,
+        /// Leave out to read BACKPAK_S3_ACCESS_KEY from the environment instead
+        #[clap(long)]
+        access_key: Option<String>,
+        /// Leave out to read BACKPAK_S3_SECRET_KEY from the environment instead
+        #[clap(long)]
+        secret_key: Option<String>,
+        /// Address the bucket as ENDPOINT/BUCKET instead of BUCKET.ENDPOINT.
+        /// MinIO and most self-hosted services need this.
+        #[clap(long, verbatim_doc_comment)]
+        path_style: bool,
+        #[clap(short, long, default_value_t = backend::DEFAULT_CONNECTIONS)]
+        concurrent_connections: u32,
+    },
+    /// Backup to a directory on a host we can SSH into.
+    /// Uses the system's `ssh`, so ~/.ssh/config applies.
+    /// Runs shell commands on the host, so it needs a POSIX shell
+    /// (SFTP-only accounts won't work).
+    #[clap(verbatim_doc_comment)]
+    Ssh {
+        #[clap(long)]
+        host: String,
+        #[clap(short, long, default_value_t = 22)]
+        port: u16,
+        #[clap(short, long)]
+        username: Option<String>,
+        /// Log in with this private key instead of the SSH agent
+        #[clap(short, long)]
+        identity_file: Option<camino::Utf8PathBuf>,
+        /// Directory on the host to keep the repository in
+        #[clap(short, long)]
+        base_path: String,
+        #[clap(short, long, default_value_t = backend::DEFAULT_CONNECTIONS)]
         concurrent_connections: u32,
     },
 }
@@ -53,6 +120,7 @@ pub fn run(repository: &camino::Utf8Path, args: Args) -> Result<()> {
         .transpose()
         .context("Couldn't parse --pack-size")?;
     let pack_size = pack_size.unwrap_or(pack::DEFAULT_PACK_SIZE);
+    pack::check_size(pack_size).context("Bad --pack-size")?;
     let filter = args.gpg.map(|g| {
         (
             "gpg --encrypt --quiet --recipient ".to_owned() + &g,
@@ -60,70 +128,85 @@ pub fn run(repository: &camino::Utf8Path, args: Args) -> Result<()> {
         )
     });
     if let Some((f, u)) = &filter {
-        round_trip_filter_test(f, u)?;
+        // The repository doesn't exist yet, so this runs them next to where it will be.
+        let environment =
+            backend::filter::FilterEnvironment::for_repository(repository, args.filter_env.clone());
+        backend::filter::round_trip_test(f, u, &environment)?;
     }
     match args.subcommand {
-        Command::Filesystem { force_cache } => {
-            backend::fs::initialize(repository, pack_size, filter, force_cache)
-        }
+        Command::Filesystem {
+            force_cache,
+            verify_after_write,
+        } => backend::fs::initialize(
+            repository,
+            pack_size,
+            filter,
+            args.filter_env,
+            force_cache,
+            verify_after_write,
+        ),
         Command::Backblaze {
             key_id,
             application_key,
             bucket,
             concurrent_connections,
+            proxy,
+            ca_cert,
+            max_requests_per_second,
+            resume_uploads,
         } => backend::backblaze::initialize(
             repository,
             pack_size,
             filter,
-            key_id,
-            application_key,
+            args.filter_env,
+            key_id.unwrap_or_default(),
+            application_key.unwrap_or_default(),
             bucket,
             concurrent_connections,
+            proxy,
+            ca_cert,
+            max_requests_per_second,
+            resume_uploads,
+        ),
+        Command::S3 {
+            endpoint,
+            region,
+            bucket,
+            access_key,
+            secret_key,
+            path_style,
+            concurrent_connections,
+        } => backend::s3::initialize(
+            repository,
+            pack_size,
+            filter,
+            args.filter_env,
+            endpoint,
+            region,
+            bucket,
+            access_key.unwrap_or_default(),
+            secret_key.unwrap_or_default(),
+            path_style,
+            concurrent_connections,
+        ),
+        Command::Ssh {
+            host,
+            port,
+            username,
+            identity_file,
+            base_path,
+            concurrent_connections,
+        } => backend::ssh::initialize(
+            repository,
+            pack_size,
+            filter,
+            args.filter_env,
+            host,
+            port,
+            username,
+            identity_file,
+            base_path,
+            concurrent_connections,
         ),
     }
 }
-
-const PLAINTEXT: &str = r"I'd like some help remembering stuff.
-I wonder if I could come down and see you,
-and we could drink and talk and remember.
-r";
-
-fn round_trip_filter_test(filter: &str, unfilter: &str) -> Result<()> {
-    let mut f = process::Command::new("sh")
-        .arg("-c")
-        .arg(filter)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .with_context(|| format!("Couldn't run {filter}"))?;
-    f.stdin
-        .take()
-        .unwrap()
-        .write_all(PLAINTEXT.as_bytes())
-        .with_context(|| format!("Couldn't write to {filter}"))?;
-    let fr = f.wait_with_output().unwrap();
-    ensure!(fr.status.success(), "{filter} failed");
-    let filtered = fr.stdout;
-
-    let mut uf = process::Command::new("sh")
-        .arg("-c")
-        .arg(unfilter)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .with_context(|| format!("Couldn't run {unfilter}"))?;
-    uf.stdin
-        .take()
-        .unwrap()
-        .write_all(&filtered)
-        .with_context(|| format!("Couldn't write to {unfilter}"))?;
-    let ufr = uf.wait_with_output().unwrap();
-    ensure!(ufr.status.success(), "{unfilter} failed");
-
-    let unfiltered = ufr.stdout;
-    if unfiltered != PLAINTEXT.as_bytes() {
-        let nope = String::from_utf8_lossy(&unfiltered);
-        bail!("filter didn't round trip!\nExpected:\n{PLAINTEXT}\nGot:\n{nope}");
-    }
-    Ok(())
-}
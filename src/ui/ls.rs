@@ -13,15 +13,16 @@ use crate::tree;
 /// List the files in a snapshot
 #[derive(Debug, Parser)]
 pub struct Args {
+    /// Print each file as a line of JSON
+    /// (path, type, size, mtime, mode, uid, gid, and symlink target)
+    #[clap(long, verbatim_doc_comment)]
+    json_lines: bool,
+
     snapshot: String,
 }
 
 pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let snapshots = snapshot::load_chronologically(&cached_backend)?;
     let (snapshot, id) = snapshot::find(&snapshots, &args.snapshot)?;
     let index = index::build_master_index(&cached_backend)?;
@@ -31,7 +32,11 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
     info!("Listing files for snapshot {}", id);
 
     let snapshot_tree = tree::forest_from_root(&snapshot.tree, &mut tree_cache)?;
-    ls::print_tree("", Utf8Path::new(""), &snapshot.tree, &snapshot_tree);
+    if args.json_lines {
+        ls::print_tree_json_lines(Utf8Path::new(""), &snapshot.tree, &snapshot_tree);
+    } else {
+        ls::print_tree("", Utf8Path::new(""), &snapshot.tree, &snapshot_tree);
+    }
 
     Ok(())
 }
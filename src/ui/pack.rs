@@ -0,0 +1,111 @@
+use anyhow::{Result, bail};
+use clap::{Parser, Subcommand};
+use tracing::*;
+
+use crate::backend;
+use crate::blob;
+use crate::config::Configuration;
+use crate::file_util::nice_size;
+use crate::hashing::ObjectId;
+use crate::index;
+use crate::pack;
+
+/// Work with a single pack
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print what's in a pack: each blob's offset, length, type, and ID,
+    /// then totals and how well it compressed.
+    ///
+    /// Also sanity-checks the pack's manifest (and the index's copy of it, if any),
+    /// warning about anything odd.
+    #[clap(verbatim_doc_comment)]
+    Info {
+        #[clap(value_name = "PACK")]
+        id: ObjectId,
+    },
+}
+
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
+    match args.command {
+        Command::Info { id } => info(config, repository, &id),
+    }
+}
+
+fn info(config: &Configuration, repository: &camino::Utf8Path, id: &ObjectId) -> Result<()> {
+    let (backend_config, cached_backend) =
+        backend::open(repository, config, backend::CacheBehavior::Normal)?;
+
+    let pack_name = format!("{id}.pack");
+    let mut size = None;
+    for listed in cached_backend.list_packs()? {
+        let (path, len) = listed?;
+        if path.ends_with(&pack_name) {
+            size = Some(len);
+            break;
+        }
+    }
+    let Some(size) = size else {
+        bail!("Couldn't find pack {id}");
+    };
+
+    let manifest = pack::load_manifest(id, &cached_backend)?;
+
+    println!("{:>10} {:>10}  type   ID", "offset", "length");
+    let mut offset = 0u64;
+    let (mut chunks, mut trees) = (0, 0);
+    for entry in &manifest {
+        let kind = match entry.blob_type {
+            blob::Type::Chunk => {
+                chunks += 1;
+                "chunk"
+            }
+            blob::Type::Tree => {
+                trees += 1;
+                "tree "
+            }
+        };
+        println!(
+            "{:>10} {:>10}  {kind}  {}",
+            nice_size(offset),
+            nice_size(entry.length as u64),
+            entry.id
+        );
+        offset += entry.length as u64;
+    }
+
+    println!(
+        "{} blobs ({chunks} chunks, {trees} trees), {} uncompressed",
+        manifest.len(),
+        nice_size(offset)
+    );
+    let ratio = if size > 0 {
+        offset as f64 / size as f64
+    } else {
+        0.0
+    };
+    println!(
+        "{} pack ({:.0}% of the target pack size), {ratio:.2}x compression",
+        nice_size(size),
+        size as f64 / backend_config.pack_size.as_u64() as f64 * 100.0
+    );
+
+    let mut anomalies = pack::check_manifest(&manifest);
+    let index = index::build_master_index(&cached_backend)?;
+    match index.packs.get(id) {
+        Some(indexed) if *indexed != manifest => {
+            anomalies.push("The index's copy of the manifest doesn't match the pack's".to_owned())
+        }
+        Some(_) => {}
+        None => anomalies.push("No index lists this pack".to_owned()),
+    }
+    for a in &anomalies {
+        warn!("Pack {id}: {a}");
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use std::io;
+
+use anyhow::Result;
+use clap::Parser;
+use jiff::{Timestamp, tz::TimeZone};
+use serde_derive::Serialize;
+use tracing::*;
+
+use crate::backend;
+use crate::config::Configuration;
+use crate::file_util::nice_size;
+use crate::hashing::ObjectId;
+use crate::index;
+use crate::pack;
+use crate::snapshot;
+
+/// List every pack in the repository
+///
+/// Shows each pack's size, how many blobs it holds, their total (uncompressed) size,
+/// and how full it is compared to the repository's pack size.
+/// Lots of small or partly-full packs are a hint to `prune`.
+#[derive(Debug, Parser)]
+#[clap(verbatim_doc_comment)]
+pub struct Args {
+    /// Print packs as a JSON array
+    #[clap(long)]
+    json: bool,
+
+    /// Sort packs by fill ratio, size, or age (least full, smallest, and oldest first)
+    #[clap(long, value_enum)]
+    sort: Option<SortBy>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum SortBy {
+    Fill,
+    Size,
+    Age,
+}
+
+#[derive(Debug, Serialize)]
+struct PackInfo {
+    id: ObjectId,
+    /// How big the pack is on the backend
+    size: u64,
+    blobs: usize,
+    /// The sum of the (uncompressed) blobs in the pack
+    blob_bytes: u64,
+    /// `size` over the repository's pack size
+    fill: f64,
+    /// When the pack was written, if the index knows
+    created: Option<Timestamp>,
+    /// Whether any index lists the pack
+    indexed: bool,
+}
+
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
+    let (backend_config, cached_backend) =
+        backend::open(repository, config, backend::CacheBehavior::Normal)?;
+    let index = index::build_master_index(&cached_backend)?;
+    let target_size = backend_config.pack_size.as_u64() as f64;
+
+    let mut packs = cached_backend
+        .list_packs()?
+        .map(|listed| {
+            let (path, size) = listed?;
+            let id = backend::id_from_path(&path)?;
+            // The index already has the manifests of every pack it knows about.
+            // For anything else, read the manifest from the pack.
+            let (blobs, blob_bytes, indexed) = match index.packs.get(&id) {
+                Some(manifest) => (manifest.len(), manifest_bytes(manifest), true),
+                None => {
+                    warn!("Pack {id} not listed in any index");
+                    let manifest = pack::load_manifest(&id, &cached_backend)?;
+                    (manifest.len(), manifest_bytes(&manifest), false)
+                }
+            };
+            Ok(PackInfo {
+                id,
+                size,
+                blobs,
+                blob_bytes,
+                fill: size as f64 / target_size,
+                created: index.pack_times.get(&id).copied(),
+                indexed,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    match args.sort {
+        None => {}
+        Some(SortBy::Fill) => packs.sort_by(|a, b| a.fill.total_cmp(&b.fill)),
+        Some(SortBy::Size) => packs.sort_by_key(|p| p.size),
+        // Packs we don't have times for sort first, since they're probably old.
+        Some(SortBy::Age) => packs.sort_by_key(|p| p.created),
+    }
+
+    if args.json {
+        unsafe {
+            crate::prettify::prettify_serialize();
+        }
+        serde_json::to_writer(io::stdout(), &packs)?;
+        println!();
+        return Ok(());
+    }
+
+    for p in &packs {
+        let created = match &p.created {
+            Some(t) => snapshot::strftime(&t.to_zoned(TimeZone::system())).to_string(),
+            None => "unknown".to_owned(),
+        };
+        println!(
+            "{} {:>10} {:>6} blobs {:>10} {:>4.0}% full  {created}{}",
+            p.id,
+            nice_size(p.size),
+            p.blobs,
+            nice_size(p.blob_bytes),
+            p.fill * 100.0,
+            if p.indexed { "" } else { " (unindexed)" }
+        );
+    }
+    Ok(())
+}
+
+fn manifest_bytes(manifest: &pack::PackManifest) -> u64 {
+    manifest.iter().map(|e| e.length as u64).sum()
+}
@@ -11,7 +11,7 @@ use tracing::*;
 use crate::backend;
 use crate::backup;
 use crate::config::Configuration;
-use crate::file_util::nice_size;
+use crate::file_util::summary_size;
 use crate::hashing::ObjectId;
 use crate::index;
 use crate::pack;
@@ -34,17 +34,16 @@ use crate::tree;
 #[derive(Debug, Parser)]
 #[command(verbatim_doc_comment)]
 pub struct Args {
-    #[clap(short = 'n', long)]
+    /// Print what would be done, and an estimate of how much
+    /// would be downloaded and uploaded to repack, then stop.
+    #[clap(short = 'n', long, verbatim_doc_comment)]
     dry_run: bool,
 }
 
 pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
     // Build the usual suspects.
-    let (backend_config, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (backend_config, cached_backend) =
+        backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let index = index::build_master_index(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
 
@@ -68,16 +67,30 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
     let (droppable_packs, sparse_packs) =
         partition_droppable_packs(&packs_to_prune, &reachable_blobs);
 
-    // Once we've partitioned packs, we don't need our reachable blob set.
-    // Drop that, since it could be huge.
-    drop(reachable_blobs);
-
     let reusable_size = packs_blob_size(reusable_packs.values());
     if packs_to_prune.is_empty() {
         println!("All {reusable_size} in use! Nothing to do.");
         return Ok(());
     }
 
+    let pack_sizes: BTreeMap<ObjectId, u64> = cached_backend
+        .list_packs()?
+        .map(|listed| {
+            let (p, len) = listed?;
+            Ok((backend::id_from_path(p)?, len))
+        })
+        .collect::<Result<_>>()?;
+    let estimate = estimate_repack(
+        &droppable_packs,
+        &sparse_packs,
+        &reachable_blobs,
+        &pack_sizes,
+    );
+
+    // Once we've partitioned packs, we don't need our reachable blob set.
+    // Drop that, since it could be huge.
+    drop(reachable_blobs);
+
     // TODO: Should build_master_index() return some set of all packs read
     // so we don't have to traverse the backend twice?
     let superseded = cached_backend
@@ -109,17 +122,38 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         superseded.len()
     );
 
+    println!(
+        "Repacking downloads about {} and uploads about {}, reclaiming about {}",
+        summary_size(estimate.download),
+        summary_size(estimate.upload),
+        summary_size(estimate.reclaimed)
+    );
+
     // We just needed these for diagnostics; axe em.
     drop(sparse_packs);
     drop(droppable_packs);
 
+    // Repacking is where the real I/O (and cloud egress cost) happens,
+    // so a dry run stops at the estimate.
+    if args.dry_run {
+        return Ok(());
+    }
+
     let reusable_packs: BTreeMap<ObjectId, pack::PackManifest> = reusable_packs
         .into_iter()
         .map(|(id, manifest)| (*id, manifest.clone()))
         .collect();
+    // Keep the ages of the packs we're keeping.
+    let pack_times = index
+        .pack_times
+        .iter()
+        .filter(|(id, _)| reusable_packs.contains_key(id))
+        .map(|(id, t)| (*id, *t))
+        .collect();
     let mut new_index = index::Index {
         packs: reusable_packs,
         supersedes: superseded.clone(),
+        pack_times,
     };
 
     // As we repack our snapshots, skip blobs in the 100% reachable packs.
@@ -161,20 +195,18 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         }
     }
 
-    let bmode = if args.dry_run {
-        backup::Mode::DryRun
-    } else {
-        backup::Mode::LiveFire
-    };
     let back_stats = backup::BackupStatistics::default();
     let walk_stats = repack::WalkStatistics::default();
     thread::scope(|s| -> Result<()> {
         let mut backup = backup::spawn_backup_threads(
             s,
-            bmode,
+            backup::Mode::LiveFire,
             &backend_config,
             &cached_backend,
             new_index,
+            // The new index supersedes the old ones as soon as it's uploaded,
+            // so it had better be complete. No checkpoints.
+            None,
             &back_stats,
         );
 
@@ -188,16 +220,14 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
 
         let run_res = (|| {
             // Finish the WIP resume business.
-            if !args.dry_run {
-                backup::upload_cwd_packfiles(&mut backup.upload_tx, &packs_to_upload)?;
-            }
+            backup::upload_cwd_packfiles(&mut backup.upload_tx, &packs_to_upload)?;
             drop(packs_to_upload);
 
             // Get a reader to load the chunks we're repacking.
             let mut reader = read::ChunkReader::new(&cached_backend, &index, &blob_map);
 
             // We don't skip over anything as we prune; that'd leave us in a nasty state.
-            let filter = |_p: &Utf8Path| true;
+            let filter = |_p: &Utf8Path, _n: &tree::Node| true;
 
             repack::walk_snapshots(
                 repack::Op::Prune,
@@ -223,44 +253,83 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         run_res
     })?;
 
-    if !args.dry_run {
-        info!("Prune complete, removing old indexes");
-        // Remove old indexes _before_ removing packs such that we don't have
-        // indexes referring to missing packs.
-        for old_index in &superseded {
-            cached_backend.remove_index(old_index)?;
-        }
-        for old_pack in packs_to_prune.keys() {
-            cached_backend.remove_pack(old_pack)?;
-        }
-    } else {
-        info!("Prune complete");
+    info!("Prune complete, removing old indexes");
+    // Remove old indexes _before_ removing packs such that we don't have
+    // indexes referring to missing packs.
+    for old_index in &superseded {
+        cached_backend.remove_index(old_index)?;
+    }
+    for old_pack in packs_to_prune.keys() {
+        cached_backend.remove_pack(old_pack)?;
     }
 
     Ok(())
 }
 
+/// Rough I/O cost of a prune, in backend (i.e., compressed) bytes
+struct RepackEstimate {
+    /// Packs we'll have to read to repack their live blobs
+    download: u64,
+    /// Those live blobs, repacked
+    upload: u64,
+    /// Backend space freed once we're done
+    reclaimed: u64,
+}
+
+/// Estimate how much prune will move around.
+///
+/// We know each pack's size on the backend and how many (uncompressed) bytes of it are live.
+/// Assume compression is about even throughout a pack to guess how much of it we'll keep.
+fn estimate_repack(
+    droppable_packs: &BTreeMap<&ObjectId, &pack::PackManifest>,
+    sparse_packs: &BTreeMap<&ObjectId, &pack::PackManifest>,
+    reachable_blobs: &FxHashSet<ObjectId>,
+    pack_sizes: &BTreeMap<ObjectId, u64>,
+) -> RepackEstimate {
+    let size_of = |id: &ObjectId| {
+        pack_sizes.get(id).copied().unwrap_or_else(|| {
+            warn!("Pack {id} is in the index but not the backend");
+            0
+        })
+    };
+
+    let dropped: u64 = droppable_packs.keys().map(|id| size_of(id)).sum();
+
+    let mut download = 0;
+    let mut upload = 0;
+    for (id, manifest) in sparse_packs {
+        let pack_size = size_of(id);
+        let total: u64 = manifest.iter().map(|e| e.length as u64).sum();
+        let live: u64 = manifest
+            .iter()
+            .filter(|e| reachable_blobs.contains(&e.id))
+            .map(|e| e.length as u64)
+            .sum();
+        download += pack_size;
+        if total > 0 {
+            upload += (pack_size as u128 * live as u128 / total as u128) as u64;
+        }
+    }
+
+    RepackEstimate {
+        download,
+        upload,
+        reclaimed: dropped + download - upload,
+    }
+}
+
 /// Collect all blobs from the provided forests
-fn reachable_blobs<'a, I: ParallelIterator<Item = &'a tree::Forest>>(
+pub fn reachable_blobs<'a, I: ParallelIterator<Item = &'a tree::Forest>>(
     forests: I,
 ) -> FxHashSet<ObjectId> {
     forests
-        .map(blobs_in_forest)
+        .map(tree::blobs_in_forest)
         .reduce(FxHashSet::default, |mut a, b| {
             a.extend(b);
             a
         })
 }
 
-fn blobs_in_forest(forest: &tree::Forest) -> FxHashSet<ObjectId> {
-    let mut blobs = FxHashSet::default();
-    for (f, t) in forest {
-        blobs.insert(*f);
-        blobs.extend(tree::chunks_in_tree(t));
-    }
-    blobs
-}
-
 /// Partition packs into those that have 100% reachable blobs
 /// and those that don't.
 ///
@@ -308,7 +377,7 @@ fn partition_droppable_packs<'a>(
 fn packs_blob_size<'a, 'b: 'a, I: Iterator<Item = &'a &'b pack::PackManifest>>(
     manifests: I,
 ) -> String {
-    nice_size(
+    summary_size(
         manifests
             .map(|m| m.iter().map(|e| e.length as u64).sum::<u64>())
             .sum(),
@@ -0,0 +1,147 @@
+use anyhow::Result;
+use clap::Parser;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use tracing::*;
+
+use crate::backend;
+use crate::config::Configuration;
+use crate::file_util::nice_size;
+use crate::hashing::ObjectId;
+use crate::index;
+use crate::repack;
+use crate::snapshot;
+use crate::tree;
+use crate::ui::prune;
+
+/// Preview what `prune` would find: how much of each pack is still used by a snapshot
+///
+/// Counts the blobs in the index against those reachable from current snapshots,
+/// then lists packs by how much space their dead blobs waste (most first).
+/// Changes nothing.
+#[derive(Debug, Parser)]
+#[clap(verbatim_doc_comment)]
+pub struct Args {
+    /// Only list packs with dead blobs
+    #[clap(long)]
+    dead_only: bool,
+}
+
+#[derive(Debug, Default)]
+struct PackUsage {
+    live_blobs: usize,
+    live_bytes: u64,
+    dead_blobs: usize,
+    dead_bytes: u64,
+    /// Size on the backend
+    size: u64,
+}
+
+impl PackUsage {
+    /// About how much backend space the dead blobs take,
+    /// assuming compression is about even throughout the pack.
+    /// (Same guess `prune` makes.)
+    fn wasted(&self) -> u64 {
+        let total = self.live_bytes + self.dead_bytes;
+        if total == 0 {
+            return 0;
+        }
+        (self.size as u128 * self.dead_bytes as u128 / total as u128) as u64
+    }
+}
+
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
+    let index = index::build_master_index(&cached_backend)?;
+    let blob_map = index::blob_to_pack_map(&index)?;
+
+    let snapshots = snapshot::load_chronologically(&cached_backend)?;
+    let snapshot_count = snapshots.len();
+    let snapshots_and_forests = repack::load_forests(
+        snapshots,
+        &mut tree::Cache::new(&index, &blob_map, &cached_backend),
+    )?;
+    let reachable = prune::reachable_blobs(snapshots_and_forests.par_iter().map(|s| &s.forest));
+    drop(snapshots_and_forests);
+
+    let mut usage: FxHashMap<ObjectId, PackUsage> = index
+        .packs
+        .keys()
+        .map(|id| (*id, PackUsage::default()))
+        .collect();
+    for listed in cached_backend.list_packs()? {
+        let (path, size) = listed?;
+        let id = backend::id_from_path(&path)?;
+        match usage.get_mut(&id) {
+            Some(u) => u.size = size,
+            None => warn!("Pack {id} not listed in any index"),
+        }
+    }
+
+    // Sort each indexed blob into live or dead, by the pack it's in.
+    for (pack, manifest) in &index.packs {
+        let u = usage.get_mut(pack).unwrap();
+        for entry in manifest {
+            if reachable.contains(&entry.id) {
+                u.live_blobs += 1;
+                u.live_bytes += entry.length as u64;
+            } else {
+                u.dead_blobs += 1;
+                u.dead_bytes += entry.length as u64;
+            }
+        }
+    }
+    let missing = reachable
+        .iter()
+        .filter(|b| !blob_map.contains_key(*b))
+        .count();
+    if missing > 0 {
+        warn!("{missing} reachable blobs aren't in any indexed pack! Run `check`.");
+    }
+
+    let mut usage: Vec<(ObjectId, PackUsage)> = usage.into_iter().collect();
+    usage.sort_by_key(|(id, u)| (std::cmp::Reverse(u.wasted()), *id));
+
+    for (id, u) in &usage {
+        if args.dead_only && u.dead_blobs == 0 {
+            continue;
+        }
+        let blobs = u.live_blobs + u.dead_blobs;
+        println!(
+            "{id} {:>6}/{:<6} blobs live ({:>3.0}%) {:>10} dead, ~{:>10} wasted",
+            u.live_blobs,
+            blobs,
+            if blobs > 0 {
+                u.live_blobs as f64 / blobs as f64 * 100.0
+            } else {
+                0.0
+            },
+            nice_size(u.dead_bytes),
+            nice_size(u.wasted()),
+        );
+    }
+
+    let live_blobs: usize = usage.iter().map(|(_, u)| u.live_blobs).sum();
+    let dead_blobs: usize = usage.iter().map(|(_, u)| u.dead_blobs).sum();
+    let wasted: u64 = usage.iter().map(|(_, u)| u.wasted()).sum();
+    let total: u64 = usage.iter().map(|(_, u)| u.size).sum();
+    let all_dead = usage
+        .iter()
+        .filter(|(_, u)| u.live_blobs == 0 && u.dead_blobs > 0)
+        .count();
+    let partly_dead = usage
+        .iter()
+        .filter(|(_, u)| u.live_blobs > 0 && u.dead_blobs > 0)
+        .count();
+    println!(
+        "{} blobs indexed, {live_blobs} reachable from {snapshot_count} snapshots, {dead_blobs} dead",
+        live_blobs + dead_blobs,
+    );
+    println!(
+        "{all_dead} packs entirely dead, {partly_dead} partly dead; \
+         pruning would reclaim about {} of {}",
+        nice_size(wasted),
+        nice_size(total)
+    );
+    Ok(())
+}
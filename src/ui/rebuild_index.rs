@@ -1,9 +1,14 @@
 use std::collections::BTreeSet;
-use std::sync::{atomic::AtomicU64, mpsc::sync_channel};
+use std::num::NonZeroUsize;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::sync_channel,
+};
 use std::thread;
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
 use clap::Parser;
+use console::Term;
 use rayon::prelude::*;
 use tracing::*;
 
@@ -12,6 +17,7 @@ use crate::config::Configuration;
 use crate::hashing::ObjectId;
 use crate::index;
 use crate::pack;
+use crate::progress::{ProgressThread, print_download_line, spinner};
 use crate::upload;
 
 /// Copy a snapshot, filtering out given paths
@@ -20,14 +26,15 @@ use crate::upload;
 pub struct Args {
     #[clap(short = 'n', long)]
     dry_run: bool,
+
+    /// Read at most this many packs at once (default: one per CPU).
+    /// Lower it if your backend rate-limits you.
+    #[clap(short, long, value_name = "N", verbatim_doc_comment)]
+    jobs: Option<NonZeroUsize>,
 }
 
 pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
 
     let superseded = cached_backend
         .list_indexes()?
@@ -46,30 +53,63 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
     let (pack_tx, pack_rx) = sync_channel(num_cpus::get_physical());
     let (upload_tx, upload_rx) = sync_channel(0);
 
-    let indexed_packs = AtomicU64::new(0); // TODO: Progress CLI!
+    let indexed_packs = AtomicU64::new(0);
     let indexer = thread::spawn(move || {
         index::index(
             index::Resumable::No,
             replacing,
+            None, // No checkpoints, we're replacing everything anyways.
             pack_rx,
             upload_tx,
             &indexed_packs,
         )
     });
 
-    info!("Reading all packs to build a new index");
-    cached_backend
+    info!("Listing packs");
+    let all_packs = cached_backend
         .list_packs()?
-        .par_iter()
-        .try_for_each_with::<_, _, Result<()>>(pack_tx, |pack_tx, (pack_file, _pack_len)| {
-            let id = backend::id_from_path(pack_file)?;
-            let manifest = pack::load_manifest(&id, &cached_backend)?;
-            let metadata = pack::PackMetadata { id, manifest };
-            pack_tx
-                .send(metadata)
-                .context("Pack thread closed unexpectedly")?;
-            Ok(())
-        })?;
+        .map(|listed| backend::id_from_path(&listed?.0))
+        .collect::<Result<Vec<ObjectId>>>()?;
+
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(j) = args.jobs {
+        pool = pool.num_threads(j.get());
+    }
+    let pool = pool.build().context("Couldn't start reader threads")?;
+
+    info!("Reading {} packs to build a new index", all_packs.len());
+    let read_packs = AtomicU64::new(0);
+    thread::scope(|s| {
+        let progress = ProgressThread::spawn(s, |i| {
+            print_progress(
+                i,
+                &Term::stdout(),
+                read_packs.load(Ordering::Relaxed),
+                all_packs.len(),
+                cached_backend.bytes_downloaded.load(Ordering::Relaxed),
+            )
+        });
+        let res = pool.install(|| {
+            all_packs
+                .par_iter()
+                .try_for_each_with::<_, _, Result<()>>(pack_tx, |pack_tx, id| {
+                    let manifest = pack::load_manifest(id, &cached_backend)?;
+                    read_packs.fetch_add(1, Ordering::Relaxed);
+                    // Packs don't record when they were made, so that's lost here.
+                    let metadata = pack::PackMetadata {
+                        id: *id,
+                        manifest,
+                        created: None,
+                    };
+                    pack_tx
+                        .send(metadata)
+                        .context("Pack thread closed unexpectedly")?;
+                    Ok(())
+                })
+        });
+        progress.join();
+        res
+    })?;
 
     let umode = if args.dry_run {
         upload::Mode::DryRun
@@ -85,9 +125,17 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
     //     Any concurrent writers (writing a backup at the same time)
     //     will upload their own index only after all packs are uploaded,
     //     making sure indexes never refer to missing packs. (I hope...)
-    ensure!(indexer.join().unwrap()?, "No new index built");
+    let Some(new_index) = indexer.join().unwrap()? else {
+        bail!("No new index built");
+    };
 
     if !args.dry_run {
+        // Read it back from the backend itself, not whatever we just cached.
+        let (_cfg, uncached_backend) =
+            backend::open(repository, config, backend::CacheBehavior::AlwaysRead)?;
+        check_coverage(&uncached_backend, &new_index, &superseded, &all_packs)
+            .context("Keeping previous indexes")?;
+
         info!("Uploaded a new index; removing previous ones");
         for old_index in superseded {
             cached_backend.remove_index(&old_index)?;
@@ -96,3 +144,51 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
 
     Ok(())
 }
+
+/// Make sure the index we just uploaded supersedes all the old ones
+/// and lists every pack we found.
+fn check_coverage(
+    backend: &backend::CachedBackend,
+    id: &ObjectId,
+    superseded: &BTreeSet<ObjectId>,
+    packs: &[ObjectId],
+) -> Result<()> {
+    info!("Checking the new index");
+    let new_index = index::load(id, backend)?;
+    ensure!(
+        new_index.supersedes == *superseded,
+        "New index {id} doesn't supersede the previous ones"
+    );
+
+    let missing: Vec<_> = packs
+        .iter()
+        .filter(|p| !new_index.packs.contains_key(p))
+        .collect();
+    if !missing.is_empty() {
+        for p in &missing {
+            error!("Pack {p} is missing from new index {id}");
+        }
+        bail!(
+            "New index {id} is missing {} of {} packs",
+            missing.len(),
+            packs.len()
+        );
+    }
+    debug!("New index {id} covers all {} packs", packs.len());
+    Ok(())
+}
+
+fn print_progress(i: usize, term: &Term, read: u64, total: usize, down: u64) -> Result<()> {
+    if i > 0 {
+        term.clear_last_lines(2)?;
+    }
+    let s = spinner(i);
+    let perc = if total > 0 {
+        read as f64 / total as f64 * 100.0
+    } else {
+        100.0
+    };
+    println!("{s} indexed {read}/{total} packs ({perc:.0}%)");
+    print_download_line(down);
+    Ok(())
+}
@@ -1,25 +1,22 @@
-use std::{
-    collections::BTreeSet,
-    fs::{self, File},
-    io::prelude::*,
-    sync::Arc,
-};
+use std::{collections::BTreeSet, io::prelude::*, sync::Arc};
 
-use anyhow::{Context, Result, anyhow};
-use camino::{Utf8Path, Utf8PathBuf};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use jiff::Timestamp;
-use rustc_hash::FxHashMap;
-use rustix::fs::Timespec;
+use rustc_hash::{FxHashMap, FxHashSet};
 use tracing::*;
 
 use crate::{
     backend,
     config::Configuration,
-    diff, fs_tree,
+    diff,
+    file_util::{self, summary_size},
+    fs_tree,
     hashing::ObjectId,
     index,
-    read::ChunkReader,
+    read::{ChunkReader, Prefetch},
+    restore::{FilesystemSink, RestoreSink},
     snapshot,
     tree::{self, Forest, Node, NodeContents, NodeMetadata, NodeType, Tree},
 };
@@ -34,6 +31,7 @@ use crate::{
 /// P permissions changed
 /// T modify time changed
 /// A access time changed
+/// B birth (creation) time changed
 /// M other metadata changed
 ///
 /// Type changes (e.g. dir -> file, or file -> symlink)
@@ -67,6 +65,26 @@ pub struct Args {
     #[clap(short, long, verbatim_doc_comment)]
     output: Option<Utf8PathBuf>,
 
+    /// Restore each path in the snapshot under the given directory,
+    /// keeping the whole path: a snapshot of `/home/me/work`
+    /// restored `--into /tmp/recovery` goes to `/tmp/recovery/home/me/work`.
+    ///
+    /// The directory must already exist; anything under it is created as needed.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with = "output",
+        verbatim_doc_comment
+    )]
+    into: Option<Utf8PathBuf>,
+
+    /// With --into, remove this prefix from each path in the snapshot first:
+    /// `--strip-prefix /home/me --into /tmp/recovery`
+    /// restores `/home/me/work` to `/tmp/recovery/work`.
+    /// Every path in the snapshot must start with it.
+    #[clap(long, value_name = "PREFIX", requires = "into", verbatim_doc_comment)]
+    strip_prefix: Option<Utf8PathBuf>,
+
     #[clap(short = 'n', long)]
     dry_run: bool,
 
@@ -87,16 +105,26 @@ pub struct Args {
     #[clap(short, long)]
     permissions: bool,
 
+    /// Before writing anything, make sure there's enough free space
+    /// to hold every file in the snapshot, and abort if not.
+    #[clap(long, verbatim_doc_comment)]
+    check_space: bool,
+
+    /// Download this many packs ahead of the one being restored.
+    ///
+    /// Packs are prefetched into the local cache,
+    /// so this is capped to however many fit there at once.
+    /// Has no effect on unfiltered filesystem repositories, which aren't cached.
+    #[clap(long, default_value_t = 4, value_name = "PACKS", verbatim_doc_comment)]
+    prefetch: usize,
+
     #[clap(name = "SNAPSHOT")]
     restore_from: String,
 }
 
 pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (backend_config, cached_backend) =
+        backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let index = index::build_master_index(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
 
@@ -107,25 +135,220 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         &mut tree::Cache::new(&index, &blob_map, &cached_backend),
     )?;
 
-    let tree_and_mapping = load_fs_tree_and_mapping(id, snapshot, &snapshot_forest, &args.output)?;
+    let tree_and_mapping = match &args.into {
+        Some(into) => {
+            let remapped = load_remapped(
+                id,
+                snapshot,
+                &snapshot_forest,
+                into,
+                args.strip_prefix.as_deref(),
+            )?;
+            if !args.dry_run {
+                for to in remapped.path_map.values() {
+                    let parent = to.parent().unwrap();
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("Couldn't create {parent}"))?;
+                }
+            }
+            remapped
+        }
+        None => load_fs_tree_and_mapping(id, snapshot, &snapshot_forest, &args.output)?,
+    };
+
+    if args.check_space {
+        check_space(
+            snapshot,
+            &snapshot_forest,
+            &index,
+            &tree_and_mapping.path_map,
+        )?;
+    }
 
     let metadata = args.times || args.permissions;
 
+    // How far ahead can we download without evicting packs from the cache
+    // before we get to them?
+    let window = match cached_backend.cache() {
+        Some(_) if !args.dry_run => {
+            let fits = (config.cache_size.as_u64() / backend_config.pack_size.as_u64().max(1))
+                .saturating_sub(1) as usize;
+            if args.prefetch > fits {
+                info!(
+                    "Only prefetching {fits} packs at a time to fit in the {} cache",
+                    summary_size(config.cache_size.as_u64())
+                );
+            }
+            args.prefetch.min(fits)
+        }
+        _ => 0,
+    };
+    let prefetch = if window > 0 {
+        let mut order = PackOrder {
+            blob_map: &blob_map,
+            seen: FxHashSet::default(),
+            packs: vec![],
+        };
+        diff::compare_trees(
+            (&tree_and_mapping.fs_id, &tree_and_mapping.fs_forest),
+            (&snapshot.tree, &snapshot_forest),
+            Utf8Path::new(""),
+            &mut order,
+        )?;
+        debug!(
+            "Restore needs {} packs; prefetching {window} at a time",
+            order.packs.len()
+        );
+        Some(Prefetch::new(order.packs, window))
+    } else {
+        None
+    };
+
+    let mut blob_reader = ChunkReader::new(&cached_backend, &index, &blob_map);
+    if let Some(p) = &prefetch {
+        blob_reader = blob_reader.with_prefetch(p);
+    }
     let mut res = Restorer {
-        printer: super::diff::PrintDiffs { metadata },
+        printer: super::diff::PrintDiffs {
+            metadata,
+            ..Default::default()
+        },
         path_map: tree_and_mapping.path_map,
-        blob_reader: ChunkReader::new(&cached_backend, &index, &blob_map),
+        blob_reader,
+        sink: FilesystemSink,
         args: &args,
     };
 
-    // The filesystem tree is the "older" one,
-    // since the backup is the desired end state.
-    diff::compare_trees(
-        (&tree_and_mapping.fs_id, &tree_and_mapping.fs_forest),
-        (&snapshot.tree, &snapshot_forest),
-        Utf8Path::new(""),
-        &mut res,
-    )
+    std::thread::scope(|s| {
+        if let Some(p) = &prefetch {
+            for _ in 0..window {
+                s.spawn(|| p.run(&cached_backend));
+            }
+        }
+        // The filesystem tree is the "older" one,
+        // since the backup is the desired end state.
+        let restored = diff::compare_trees(
+            (&tree_and_mapping.fs_id, &tree_and_mapping.fs_forest),
+            (&snapshot.tree, &snapshot_forest),
+            Utf8Path::new(""),
+            &mut res,
+        );
+        if let Some(p) = &prefetch {
+            p.finish();
+        }
+        restored
+    })
+}
+
+/// Finds the packs a restore will read, in the order it'll read them,
+/// by making the same comparison the [`Restorer`] does ahead of time.
+struct PackOrder<'a> {
+    blob_map: &'a index::BlobMap,
+    seen: FxHashSet<ObjectId>,
+    packs: Vec<ObjectId>,
+}
+
+impl PackOrder<'_> {
+    fn add_node(&mut self, node: &Node, forest: &Forest) -> Result<()> {
+        match &node.contents {
+            NodeContents::File { chunks } => {
+                let blob_map = self.blob_map;
+                // Missing chunks are the restore's problem to report, not ours.
+                for pack in chunks.iter().filter_map(|c| blob_map.get(c)) {
+                    if self.seen.insert(*pack) {
+                        self.packs.push(*pack);
+                    }
+                }
+            }
+            NodeContents::Symlink { .. } => {}
+            NodeContents::Directory { subtree } => {
+                let subtree = forest
+                    .get(subtree)
+                    .ok_or_else(|| anyhow!("Missing tree {subtree}"))?;
+                for child in subtree.values() {
+                    self.add_node(child, forest)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl diff::Callbacks for PackOrder<'_> {
+    fn node_added(&mut self, _: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
+        self.add_node(new_node, forest)
+    }
+
+    fn node_removed(&mut self, _: &Utf8Path, _: &Node, _: &Forest) -> Result<()> {
+        Ok(())
+    }
+
+    fn contents_changed(&mut self, _: &Utf8Path, _: &Node, new_node: &Node) -> Result<()> {
+        // Directories don't get this callback, so the forest is never needed.
+        self.add_node(new_node, &Forest::default())
+    }
+
+    fn metadata_changed(&mut self, _: &Utf8Path, _: &Node, _: &Node) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Make sure each filesystem we're restoring to has room for the files we're putting there.
+///
+/// Pessimistic: we don't account for files that are already there and unchanged.
+fn check_space(
+    snapshot: &snapshot::Snapshot,
+    snapshot_forest: &Forest,
+    index: &index::Index,
+    path_map: &FxHashMap<&str, Utf8PathBuf>,
+) -> Result<()> {
+    let size_map = index::blob_to_size_map(index)?;
+    let sizes = tree::forest_sizes(
+        &snapshot.tree,
+        snapshot_forest,
+        &size_map,
+        &mut FxHashSet::default(),
+    )?;
+
+    // Bytes needed per top-level node...
+    let mut needed: FxHashMap<&str, u64> = FxHashMap::default();
+    for (path, size) in &sizes.per_file {
+        let top = path.components().next().unwrap().as_str();
+        *needed.entry(top).or_default() += size.introduced + size.reused;
+    }
+
+    // ...and per filesystem they're going to.
+    // (fsid -> (needed, available, some path on that FS))
+    let mut per_fs: FxHashMap<u64, (u64, u64, &Utf8Path)> = FxHashMap::default();
+    for (top, to) in path_map {
+        // Find the closest thing to the destination that exists,
+        // since we might be creating it.
+        let existing = to
+            .ancestors()
+            .find(|a| a.exists())
+            .ok_or_else(|| anyhow!("Couldn't find any existing parent of {to}"))?;
+        let Some((fsid, available)) = file_util::free_space(existing)? else {
+            debug!("Can't check free space on this platform; hope there's enough");
+            return Ok(());
+        };
+        let entry = per_fs.entry(fsid).or_insert((0, available, to.as_path()));
+        entry.0 += needed.get(top).copied().unwrap_or(0);
+    }
+
+    for (needed, available, to) in per_fs.values() {
+        debug!(
+            "Restoring {} to {to}'s filesystem, {} available",
+            summary_size(*needed),
+            summary_size(*available)
+        );
+        ensure!(
+            needed <= available,
+            "Restoring needs {} on the filesystem holding {to}, but only {} is available",
+            summary_size(*needed),
+            summary_size(*available)
+        );
+    }
+    Ok(())
 }
 
 struct FsTreeAndMapping<'a> {
@@ -192,7 +415,11 @@ fn load_fs_tree_and_mapping<'a>(
                 //
                 // Let's not mess with that.
                 tree::Symlink::Read,
+                // Cut files the way the snapshot did so unchanged ones match.
+                &snapshot.chunking(),
                 &BTreeSet::from([canonical_to.clone()]),
+                // Skips are absolute paths in the snapshot, not the output directory.
+                &[],
                 Some(&snapshot.tree),
                 snapshot_forest,
             )?;
@@ -239,7 +466,9 @@ fn load_fs_tree_and_mapping<'a>(
 
             fs_tree::forest_from_fs(
                 tree::Symlink::Read, // See above
+                &snapshot.chunking(),
                 &paths,
+                &[], // Ditto
                 Some(&snapshot.tree),
                 snapshot_forest,
             )?
@@ -257,7 +486,10 @@ fn load_fs_tree_and_mapping<'a>(
         );
         let (fs_id, fs_forest) = fs_tree::forest_from_fs(
             tree::Symlink::Read, // See above
+            &snapshot.chunking(),
             &snapshot.paths,
+            // Leave whatever the backup skipped alone (instead of deleting it).
+            &snapshot.skips,
             Some(&snapshot.tree),
             snapshot_forest,
         )?;
@@ -277,22 +509,102 @@ fn load_fs_tree_and_mapping<'a>(
     }
 }
 
-#[cfg(unix)]
-fn to_timespec(t: Timestamp) -> Timespec {
-    Timespec {
-        tv_sec: t.as_second(),
-        tv_nsec: t.subsec_nanosecond() as i64,
+/// Where `--into` (and `--strip-prefix`) put the given path from the snapshot
+fn remap(path: &Utf8Path, strip_prefix: Option<&Utf8Path>, into: &Utf8Path) -> Result<Utf8PathBuf> {
+    let rest = match strip_prefix {
+        Some(prefix) => path
+            .strip_prefix(prefix)
+            .map_err(|_| anyhow!("{path} doesn't start with --strip-prefix {prefix}"))?,
+        None => path,
+    };
+    // Absolute or relative, it all goes under `into`.
+    let mut to = into.to_owned();
+    for c in rest.components() {
+        match c {
+            Utf8Component::Normal(n) => to.push(n),
+            Utf8Component::RootDir | Utf8Component::Prefix(_) | Utf8Component::CurDir => {}
+            Utf8Component::ParentDir => bail!("{path} would be restored outside of {into}"),
+        }
     }
+    Ok(to)
 }
 
-struct Restorer<'a> {
+/// Like [`load_fs_tree_and_mapping()`], but for `--into`:
+/// every snapshot path gets its own destination somewhere under `into`.
+fn load_remapped<'a>(
+    id: &ObjectId,
+    snapshot: &'a snapshot::Snapshot,
+    snapshot_forest: &tree::Forest,
+    into: &Utf8Path,
+    strip_prefix: Option<&Utf8Path>,
+) -> Result<FsTreeAndMapping<'a>> {
+    // Canonicalize so "." and friends have file names.
+    let into = into
+        .canonicalize_utf8()
+        .with_context(|| format!("Couldn't canonicalize {into}"))?;
+    info!("Restoring snapshot {id} into {into}");
+
+    let mut path_map: FxHashMap<&str, Utf8PathBuf> = FxHashMap::default();
+    let mut mapped: Vec<(&Utf8Path, Utf8PathBuf)> = vec![];
+    for path in &snapshot.paths {
+        let to = remap(path, strip_prefix, &into)?;
+        // Two paths going to the same place (or one inside the other) would trample each other.
+        for (other, other_to) in &mapped {
+            ensure!(
+                !to.starts_with(other_to) && !other_to.starts_with(&to),
+                "{path} and {other} would both be restored to {}",
+                if to.starts_with(other_to) {
+                    other_to
+                } else {
+                    &to
+                }
+            );
+        }
+        debug!("Restoring {path} to {to}");
+        mapped.push((path, to.clone()));
+        assert!(path_map.insert(path.file_name().unwrap(), to).is_none());
+    }
+
+    // Walk whatever's already at each destination,
+    // naming it after the snapshot path it's standing in for so the trees compare cleanly.
+    let mut fs_top = Tree::new();
+    let mut fs_forest = Forest::default();
+    for (name, to) in &path_map {
+        if !to.exists() {
+            continue;
+        }
+        let (top_id, mut forest) = fs_tree::forest_from_fs(
+            tree::Symlink::Read, // See load_fs_tree_and_mapping()
+            &snapshot.chunking(),
+            &BTreeSet::from([to.clone()]),
+            &[],
+            Some(&snapshot.tree),
+            snapshot_forest,
+        )?;
+        let mut top = Arc::unwrap_or_clone(forest.remove(&top_id).unwrap());
+        let node = top.remove(Utf8Path::new(to.file_name().unwrap())).unwrap();
+        fs_top.insert((*name).into(), node);
+        fs_forest.extend(forest);
+    }
+    let (_bytes, fs_id) = tree::serialize_and_hash(&fs_top)?;
+    fs_forest.insert(fs_id, Arc::new(fs_top));
+
+    Ok(FsTreeAndMapping {
+        fs_id,
+        fs_forest,
+        path_map,
+    })
+}
+
+struct Restorer<'a, S> {
     printer: super::diff::PrintDiffs,
     path_map: FxHashMap<&'a str, Utf8PathBuf>,
     blob_reader: ChunkReader<'a>,
+    sink: S,
     args: &'a Args,
 }
 
-impl Restorer<'_> {
+impl<S: RestoreSink> Restorer<'_, S> {
     fn translate_path(&self, node_path: &Utf8Path) -> Utf8PathBuf {
         let components: Vec<&str> = node_path.iter().collect();
         let first_component = &components[0];
@@ -315,8 +627,7 @@ impl Restorer<'_> {
 
     // NB: node_path is already translated for all of these
 
-    #[cfg(unix)]
-    fn set_metadata(&self, node_path: &Utf8Path, node: &Node) -> Result<()> {
+    fn set_metadata(&mut self, node_path: &Utf8Path, node: &Node) -> Result<()> {
         let mtime = node.metadata.modification_time();
         let atime = node.metadata.access_time();
 
@@ -328,47 +639,35 @@ impl Restorer<'_> {
                 let atime = atime.unwrap_or(now);
                 let mtime = mtime.unwrap_or(now);
                 trace!("setting timestamps for {node_path}");
-                // trace!("    atime: {:?}", atime);
-                // trace!("    tmtime: {:?}", mtime);
-                use rustix::fs::*;
-                let stamps = Timestamps {
-                    last_access: to_timespec(atime),
-                    last_modification: to_timespec(mtime),
-                };
-                utimensat(CWD, node_path.as_str(), &stamps, AtFlags::SYMLINK_NOFOLLOW)
-                    .with_context(|| format!("Couldn't set timestamps for {node_path}"))?;
+                self.sink.set_times(node_path, atime, mtime)?;
             }
+            // Birth time is saved (see `backup --btime`) but there's nowhere we can put it back:
+            // Linux has no way to set it, and macOS only has setattrlist(),
+            // which we don't have bindings for. Skip it quietly.
         }
         // chmod is unsupported on Linux symlinks (without dereferencing). The more you know.
         if self.args.permissions && node.kind() != tree::NodeType::Symlink {
-            use std::os::unix::fs::PermissionsExt;
-            let permissions = match &node.metadata {
-                NodeMetadata::Posix(p) => fs::Permissions::from_mode(p.mode),
+            let mode = match &node.metadata {
+                NodeMetadata::Posix(p) => p.mode,
                 NodeMetadata::Windows(_w) => todo!("Windows -> Posix perms mapping"),
             };
-            trace!("chmod {:o} {node_path}", permissions.mode());
-            fs::set_permissions(node_path, permissions)
-                .with_context(|| format!("Couldn't chmod {node_path}"))?;
+            trace!("chmod {mode:o} {node_path}");
+            self.sink.set_permissions(node_path, mode)?;
         }
         Ok(())
     }
 
-    #[cfg(windows)]
-    fn set_metadata(&self, _node_path: &Utf8Path, _node: &Node) -> Result<()> {
-        todo!("lol windows metadata");
-    }
-
     fn remove_node(&mut self, node_path: &Utf8Path, old_node: &Node) -> Result<()> {
         if old_node.kind() == NodeType::Directory {
             trace!("Removing whole dir {node_path}");
-            fs::remove_dir_all(node_path)?;
+            self.sink.remove_dir_all(node_path)?;
         } else {
             trace!("Removing {node_path}");
             // NB: Because we model node type changing as "remove old, add new"
             // (see the defaulted type_changed() callback in diff.rs),
             // destination symlinks are *NOT* followed.
             // See the comments on forest_from_fs() above.
-            fs::remove_file(node_path)?;
+            self.sink.remove_file(node_path)?;
         }
         Ok(())
     }
@@ -376,16 +675,14 @@ impl Restorer<'_> {
     fn add_node(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
         match &new_node.contents {
             NodeContents::File { .. } => {
-                let fh = File::create(node_path)
-                    .with_context(|| format!("Couldn't create file {node_path}"))?;
+                let fh = self.sink.create_file(node_path)?;
                 fill_file(fh, new_node, &mut self.blob_reader)?;
             }
             NodeContents::Symlink { target } => {
-                symlink(target, node_path)?;
+                self.sink.symlink(target, node_path)?;
             }
             NodeContents::Directory { subtree } => {
-                fs::create_dir(node_path)
-                    .with_context(|| format!("Couldn't create dir {node_path}"))?;
+                self.sink.create_dir(node_path)?;
 
                 let subtree: &tree::Tree = forest
                     .get(subtree)
@@ -413,17 +710,17 @@ impl Restorer<'_> {
     ) -> Result<()> {
         match &new_node.contents {
             NodeContents::File { .. } => {
-                let fh = File::create(node_path)
-                    .with_context(|| format!("Couldn't create file {node_path}"))?;
+                let fh = self.sink.create_file(node_path)?;
                 fill_file(fh, new_node, &mut self.blob_reader)?;
 
                 // Don't try to set metadata on a symlink! We can't lol
                 self.set_metadata(node_path, new_node)?;
             }
             NodeContents::Symlink { target } => {
-                fs::remove_file(node_path)
+                self.sink
+                    .remove_file(node_path)
                     .with_context(|| format!("Couldn't remove previous symlink at {node_path}"))?;
-                symlink(target, node_path)?;
+                self.sink.symlink(target, node_path)?;
             }
             NodeContents::Directory { .. } => {
                 // This callback isn't called on directories
@@ -434,7 +731,8 @@ impl Restorer<'_> {
     }
 }
 
-fn fill_file(mut fh: File, node: &Node, bl: &mut ChunkReader<'_>) -> Result<()> {
+/// Write the given file node's chunks, in order, to `fh`.
+pub fn fill_file(mut fh: Box<dyn Write + '_>, node: &Node, bl: &mut ChunkReader<'_>) -> Result<()> {
     let chunks = node.contents.chunks();
     for c in chunks {
         fh.write_all(&bl.read_blob(c)?)?;
@@ -442,22 +740,7 @@ fn fill_file(mut fh: File, node: &Node, bl: &mut ChunkReader<'_>) -> Result<()>
     Ok(())
 }
 
-#[cfg(windows)]
-fn symlink(_target: &Utf8Path, _from: &Utf8Path) -> Result<()> {
-    // Uhh, we need to figure out if it's a directory?
-    // This is likely to fail without elevated perms?
-    // https://doc.rust-lang.org/std/os/windows/fs/fn.symlink_file.html
-    todo!("Windows symlink creation is tricky");
-}
-
-#[cfg(unix)]
-fn symlink(target: &Utf8Path, from: &Utf8Path) -> Result<()> {
-    std::os::unix::fs::symlink(target, from)
-        .with_context(|| format!("Couldn't create symlink {from}"))?;
-    Ok(())
-}
-
-impl diff::Callbacks for Restorer<'_> {
+impl<S: RestoreSink> diff::Callbacks for Restorer<'_, S> {
     fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
         let node_path = self.translate_path(node_path);
 
@@ -563,3 +846,154 @@ impl diff::Callbacks for Restorer<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::restore::{MemoryContents, MemorySink};
+    use crate::tree::PosixMetadata;
+
+    fn node(contents: NodeContents, mode: u32) -> Node {
+        Node {
+            contents,
+            metadata: NodeMetadata::Posix(PosixMetadata {
+                mode,
+                size: None,
+                user_id: 1000,
+                group_id: 1000,
+                access_time: "2024-01-01T00:00:00Z".parse().unwrap(),
+                modify_time: "2024-01-02T00:00:00Z".parse().unwrap(),
+                birth_time: None,
+            }),
+        }
+    }
+
+    fn add_tree(forest: &mut Forest, tree: Tree) -> ObjectId {
+        let (_, id) = tree::serialize_and_hash(&tree).unwrap();
+        forest.insert(id, Arc::new(tree));
+        id
+    }
+
+    fn restore_to_memory(args: &[&str]) -> Result<MemorySink> {
+        let args = Args::parse_from(["restore"].iter().chain(args).chain(&["LAST"]));
+
+        let mut snapshot_forest = Forest::default();
+        let subtree = add_tree(
+            &mut snapshot_forest,
+            Tree::from([
+                (
+                    "empty".into(),
+                    // No chunks, so we don't need anything in the backend.
+                    node(NodeContents::File { chunks: vec![] }, 0o100600),
+                ),
+                (
+                    "link".into(),
+                    node(
+                        NodeContents::Symlink {
+                            target: "empty".into(),
+                        },
+                        0o120777,
+                    ),
+                ),
+            ]),
+        );
+        let snapshot_root = add_tree(
+            &mut snapshot_forest,
+            Tree::from([(
+                "top".into(),
+                node(NodeContents::Directory { subtree }, 0o040700),
+            )]),
+        );
+
+        let mut fs_forest = Forest::default();
+        let fs_root = add_tree(&mut fs_forest, Tree::new());
+
+        let backend = backend::in_memory();
+        let index = index::Index::default();
+        let blob_map = index::BlobMap::default();
+        let mut res = Restorer {
+            printer: super::super::diff::PrintDiffs {
+                metadata: true,
+                ..Default::default()
+            },
+            path_map: FxHashMap::from_iter([("top", Utf8PathBuf::from("/out/top"))]),
+            blob_reader: ChunkReader::new(&backend, &index, &blob_map),
+            sink: MemorySink::new().with_dir("/out"),
+            args: &args,
+        };
+        diff::compare_trees(
+            (&fs_root, &fs_forest),
+            (&snapshot_root, &snapshot_forest),
+            Utf8Path::new(""),
+            &mut res,
+        )?;
+        Ok(res.sink)
+    }
+
+    #[test]
+    fn restore_in_memory() -> Result<()> {
+        let sink = restore_to_memory(&["--times", "--permissions"])?;
+        let paths: Vec<&str> = sink.entries.keys().map(|p| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["/out", "/out/top", "/out/top/empty", "/out/top/link"]
+        );
+
+        let top = &sink.entries[Utf8Path::new("/out/top")];
+        assert_eq!(top.contents, MemoryContents::Directory);
+        assert_eq!(top.mode, Some(0o040700));
+
+        let empty = &sink.entries[Utf8Path::new("/out/top/empty")];
+        assert_eq!(empty.contents, MemoryContents::File(vec![]));
+        assert_eq!(empty.mode, Some(0o100600));
+        assert_eq!(
+            empty.times,
+            Some((
+                "2024-01-01T00:00:00Z".parse()?,
+                "2024-01-02T00:00:00Z".parse()?
+            ))
+        );
+
+        // We don't touch symlink metadata.
+        let link = &sink.entries[Utf8Path::new("/out/top/link")];
+        assert_eq!(link.contents, MemoryContents::Symlink("empty".into()));
+        assert_eq!(link.mode, None);
+        assert_eq!(link.times, None);
+        Ok(())
+    }
+
+    #[test]
+    fn remapping() -> Result<()> {
+        let into = Utf8Path::new("/tmp/recovery");
+        let remapped =
+            |p: &str, strip: Option<&str>| remap(p.into(), strip.map(Utf8Path::new), into);
+
+        assert_eq!(
+            remapped("/home/me/work", None)?,
+            "/tmp/recovery/home/me/work"
+        );
+        assert_eq!(
+            remapped("/home/me/work", Some("/home/me"))?,
+            "/tmp/recovery/work"
+        );
+        // Stripping the whole thing restores right into the target.
+        assert_eq!(
+            remapped("/home/me/work", Some("/home/me/work"))?,
+            "/tmp/recovery"
+        );
+        // Relative paths (from older snapshots) go under it too.
+        assert_eq!(remapped("./me/work", None)?, "/tmp/recovery/me/work");
+
+        assert!(remapped("/etc", Some("/home")).is_err());
+        assert!(remapped("../work", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() -> Result<()> {
+        let sink = restore_to_memory(&["--dry-run"])?;
+        assert_eq!(sink.entries.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeSet;
+use std::io::{self, prelude::*};
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use tracing::*;
+
+use crate::backend;
+use crate::config::Configuration;
+use crate::snapshot;
+
+/// Work with a single snapshot
+#[derive(Debug, Parser)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Delete one snapshot
+    ///
+    /// Like `forget`, data used by the snapshot isn't deleted
+    /// until the next `prune`.
+    #[clap(verbatim_doc_comment)]
+    Rm {
+        /// Don't ask for confirmation
+        #[clap(short, long)]
+        yes: bool,
+
+        #[clap(name = "SNAPSHOT")]
+        snapshot: String,
+    },
+    /// Add tags to a snapshot
+    ///
+    /// Snapshots are identified by their contents,
+    /// so this replaces the snapshot with a new one (with a new ID).
+    #[clap(verbatim_doc_comment)]
+    Tag {
+        #[clap(name = "SNAPSHOT")]
+        snapshot: String,
+
+        #[clap(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a snapshot
+    ///
+    /// Like `tag`, this replaces the snapshot with a new one.
+    #[clap(verbatim_doc_comment)]
+    Untag {
+        #[clap(name = "SNAPSHOT")]
+        snapshot: String,
+
+        #[clap(required = true)]
+        tags: Vec<String>,
+    },
+}
+
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
+    match args.command {
+        Command::Rm { yes, snapshot } => rm(config, repository, yes, &snapshot),
+        Command::Tag { snapshot, tags } => retag(config, repository, &snapshot, |t| {
+            t.extend(tags);
+        }),
+        Command::Untag { snapshot, tags } => retag(config, repository, &snapshot, |t| {
+            for tag in &tags {
+                t.remove(tag);
+            }
+        }),
+    }
+}
+
+/// Edit a snapshot's tags, upload it, and remove the old one.
+fn retag<F: FnOnce(&mut BTreeSet<String>)>(
+    config: &Configuration,
+    repository: &camino::Utf8Path,
+    which: &str,
+    edit: F,
+) -> Result<()> {
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
+
+    let snapshots = snapshot::load_chronologically(&cached_backend)?;
+    let (snap, id) = snapshot::find(&snapshots, which)?;
+
+    let mut retagged = snap.clone();
+    edit(&mut retagged.tags);
+    if retagged.tags == snap.tags {
+        info!("Snapshot {id} already has those tags");
+        return Ok(());
+    }
+
+    // Upload the new one before removing the old one so we never lose it.
+    let new_id = snapshot::upload(&retagged, &cached_backend)?;
+    info!("Replacing snapshot {id} with {new_id}");
+    cached_backend.remove_snapshot(id)?;
+    println!("{new_id}");
+    Ok(())
+}
+
+fn rm(config: &Configuration, repository: &camino::Utf8Path, yes: bool, which: &str) -> Result<()> {
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
+
+    let snapshots = snapshot::load_chronologically(&cached_backend)?;
+    let (snap, id) = snapshot::find(&snapshots, which)?;
+
+    if !yes
+        && !confirm(&format!(
+            "Remove snapshot {id} of {:?} from {}?",
+            snap.paths,
+            snapshot::strftime(&snap.time)
+        ))?
+    {
+        bail!("Not removing {id}");
+    }
+
+    info!("Removing snapshot {id}");
+    cached_backend.remove_snapshot(id)
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("Couldn't read confirmation")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
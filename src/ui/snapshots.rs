@@ -1,8 +1,8 @@
-use std::collections::hash_map::Entry;
+use std::cell::RefCell;
 
 use anyhow::Result;
 use camino::Utf8Path;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use rustc_hash::{FxHashMap, FxHashSet};
 use unicode_segmentation::UnicodeSegmentation;
 
@@ -10,19 +10,41 @@ use crate::{
     backend,
     config::Configuration,
     diff,
-    file_util::nice_size,
+    file_util::{nice_size, summary_size},
     hashing::ObjectId,
     index, ls, snapshot,
-    tree::{self, FileSize, Forest, ForestSizes, Node, NodeContents, NodeType, meta_diff_char},
+    tree::{self, FileSize, Forest, ForestSizes, Node, NodeContents, NodeType},
 };
 
 /// List the snapshots in this repository from oldest to newest.
 #[derive(Debug, Parser)]
 pub struct Args {
-    /// Print newest to oldest.
+    /// Print newest to oldest (or in reverse ID order with --sort id).
     #[clap(short, long)]
     reverse: bool,
 
+    /// Print snapshots in this order.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = SortBy::Time,
+        conflicts_with_all = ["stat", "sizes", "file_sizes"]
+    )]
+    sort: SortBy,
+
+    /// Only consider the last N snapshots (after filtering by tag).
+    #[clap(long, value_name = "N")]
+    last: Option<usize>,
+
+    /// Print one line per snapshot in aligned columns:
+    /// short ID, date, author, tags, and paths.
+    #[clap(
+        long,
+        verbatim_doc_comment,
+        conflicts_with_all = ["stat", "sizes", "file_sizes"]
+    )]
+    table: bool,
+
     /// Print files added, removed, or changed by each snapshot.
     ///
     /// + added/file/or/dir
@@ -34,6 +56,8 @@ pub struct Args {
     /// A access time changed
     /// M other metadata changed
     ///
+    /// (Several metadata letters at once, like OP, mean all of those changed.)
+    ///
     /// Essentially `backpak diff` for multiple snapshots.
     #[clap(long, verbatim_doc_comment)]
     stat: bool,
@@ -42,7 +66,8 @@ pub struct Args {
     #[clap(short, long)]
     metadata: bool,
 
-    /// Print how much data each snapshot adds to the repository.
+    /// Print how much data each snapshot adds to the repository,
+    /// and how much only it uses (what forgetting it would free).
     ///
     /// This takes a bit longer - regardless of which snapshots are shown,
     /// we have to walk them all to see which introduced what data.
@@ -56,9 +81,22 @@ pub struct Args {
     #[clap(short, long)]
     file_sizes: bool,
 
+    /// Only consider snapshots with the given tag.
+    /// Can be given multiple times to require several tags.
+    #[clap(long = "tag", value_name = "TAG", verbatim_doc_comment)]
+    tags: Vec<String>,
+
     snapshots: Vec<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortBy {
+    /// Oldest to newest
+    Time,
+    /// By snapshot ID, for finding one in a long list
+    Id,
+}
+
 pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args) -> Result<()> {
     unsafe {
         crate::prettify::prettify_serialize();
@@ -67,20 +105,19 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
         args.sizes = true;
     }
 
-    let (_cfg, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (_cfg, cached_backend) = backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let snapshots = snapshot::load_chronologically(&cached_backend)?;
     let snapshots_to_print = {
-        let sal = snapshot::from_args_list(&snapshots, &args.snapshots)?;
+        // Tags pick which we print, but we still want all of them to diff against and size up.
+        let mut tagged = snapshots.clone();
+        snapshot::retain_tagged(&mut tagged, &args.tags);
+        let sal = snapshot::from_args_list(&tagged, &args.snapshots)?;
         // If the args list no snapshots, print them all.
-        if sal.is_empty() {
-            snapshots.clone()
-        } else {
-            sal
+        let mut to_print = if sal.is_empty() { tagged } else { sal };
+        if let Some(n) = args.last {
+            to_print.drain(..to_print.len().saturating_sub(n));
         }
+        to_print
     };
 
     // This is a mess. Sorry.
@@ -90,15 +127,23 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
     if !args.sizes {
         // Simplest case: we just walk the snapshots. We don't need their trees or anything. EZ.
         if !args.stat {
-            let it = snapshots_to_print.into_iter();
+            let mut snapshots_to_print = snapshots_to_print;
+            if args.sort == SortBy::Id {
+                snapshots_to_print.sort_by_key(|(_, id)| id.to_string());
+            }
+            let it = snapshots_to_print.iter();
             let it: Box<dyn Iterator<Item = _>> = if args.reverse {
                 Box::new(it.rev())
             } else {
                 Box::new(it)
             };
 
-            for (snap, id) in it {
-                print_snapshot(&snap, &id, None);
+            if args.table {
+                print_table(it);
+            } else {
+                for (snap, id) in it {
+                    print_snapshot(snap, id, None);
+                }
             }
         }
         // Slightly harder: We need an index to look at the trees in each snapshot,
@@ -112,39 +157,15 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
             let blob_map = index::blob_to_pack_map(&index)?;
             let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
 
-            // We could naively call forest_from_root() on each snapshot we want to print,
-            // and on the previous snapshot to generate a diff. But iterating through sequential
-            // snapshots would make us call forest_from_root() *twice* on all but one.
-            //
-            // Instead of convoluted logic to avoid that, make the assumption we do elsewhere
-            // (e.g., the tree cache): that trees, once built, are cheap to hang onto.
-            // Just make a map of all the ones we need by index.
-            let mut indexed_forests: FxHashMap<isize, (ObjectId, Forest)> = FxHashMap::default();
-            indexed_forests.insert(-1, diff::null_forest().clone()); // For comparing the 0th
-
-            let mut needed_indices = FxHashSet::default();
-            for (i, (_snap, id)) in snapshots.iter().enumerate() {
-                if snapshots_to_print.contains(id) {
-                    let i = i as isize;
-                    needed_indices.insert(i - 1);
-                    needed_indices.insert(i);
-                }
-            }
-            for (i, (snap, _id)) in snapshots.iter().enumerate() {
-                let i = i as isize;
-                if needed_indices.contains(&i) {
-                    if let Entry::Vacant(e) = indexed_forests.entry(i) {
-                        e.insert((
-                            snap.tree,
-                            tree::forest_from_root(&snap.tree, &mut tree_cache)?,
-                        ));
-                    }
-                }
-            }
-            drop(needed_indices);
+            // Compare each snapshot to the one before it (or to nothing, for the first),
+            // reading trees as the walk reaches them.
+            // Whatever two snapshots have in common, we never read at all,
+            // and the tree cache hangs onto what we did read for the next comparison.
+            let loader = RefCell::new(|id: &ObjectId| tree_cache.read(id));
+            let lazy = diff::Trees::Lazy(&loader);
+            let (null_root, null_forest) = diff::null_forest();
+            let nothing = diff::Trees::Loaded(null_forest);
 
-            // Okay, we have all the trees we need to compare.
-            // Back to actually walking the snapshots we want to print.
             let it = snapshots.iter().enumerate();
             let it: Box<dyn Iterator<Item = _>> = if args.reverse {
                 Box::new(it.rev())
@@ -154,15 +175,16 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
 
             for (i, (snap, id)) in it {
                 if snapshots_to_print.contains(id) {
-                    let i = i as isize;
-                    let (previous_root, previous_forest) = &indexed_forests[&(i - 1)];
-                    let (current_root, current_forest) = &indexed_forests[&i];
-                    assert_eq!(*current_root, snap.tree);
+                    let previous = if i == 0 {
+                        (null_root, &nothing)
+                    } else {
+                        (&snapshots[i - 1].0.tree, &lazy)
+                    };
                     print_snapshot(snap, id, None);
                     // The --stat part:
                     tree_diff(
-                        (previous_root, previous_forest),
-                        (current_root, current_forest),
+                        previous,
+                        (&snap.tree, &lazy),
                         args.metadata,
                         0,    // pad
                         None, // sizes
@@ -193,6 +215,8 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
             // Hang onto it in case the user passed --stat so we can print diffs like above.
             forest: tree::Forest,
             sizes: ForestSizes,
+            // Every blob in the forest, to see which ones no other snapshot uses
+            blobs: FxHashSet<ObjectId>,
         }
 
         let mut visited_blobs = FxHashSet::default();
@@ -207,16 +231,23 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
                 let forest = tree::forest_from_root(&snapshot.tree, &mut tree_cache)?;
                 let sizes =
                     tree::forest_sizes(&snapshot.tree, &forest, &size_map, &mut visited_blobs)?;
+                let blobs = tree::blobs_in_forest(&forest);
                 Ok(DecoratedSnapshot {
                     index,
                     snapshot,
                     id,
                     forest,
                     sizes,
+                    blobs,
                 })
             })
             .collect::<Result<Vec<_>>>()?;
 
+        let mut ref_counts = tree::BlobRefCounts::default();
+        for s in &snaps {
+            ref_counts.add(&s.blobs);
+        }
+
         // We have everything we need. Start walkin'
         let it: Box<dyn Iterator<Item = &DecoratedSnapshot>> = if !args.reverse {
             Box::new(snaps.iter())
@@ -233,12 +264,14 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
             id,
             forest,
             sizes,
+            blobs,
         } in it
         {
             if !snapshots_to_print.contains(id) {
                 continue;
             }
-            print_snapshot(snapshot, id, Some(sizes));
+            let exclusive = ref_counts.freed_by(&[blobs], &size_map)?;
+            print_snapshot(snapshot, id, Some((sizes, exclusive)));
             if args.stat {
                 // Time to compare trees.
                 let (previous_root, previous_forest) = if *index == 0 {
@@ -268,8 +301,8 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
                     )?;
                     // Finally, print our diff *with* sizes.
                     tree_diff(
-                        (previous_root, previous_forest),
-                        (current_root, current_forest),
+                        (previous_root, &diff::Trees::Loaded(previous_forest)),
+                        (current_root, &diff::Trees::Loaded(current_forest)),
                         args.metadata,
                         pad,
                         Some(sizes),
@@ -278,8 +311,8 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
                     // Easier case - normal --stat printout, with per-snapshot size
                     // printed by print_snapshot().
                     tree_diff(
-                        (previous_root, previous_forest),
-                        (current_root, current_forest),
+                        (previous_root, &diff::Trees::Loaded(previous_forest)),
+                        (current_root, &diff::Trees::Loaded(current_forest)),
                         args.metadata,
                         0,    // pad
                         None, // sizes
@@ -319,7 +352,14 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
     Ok(())
 }
 
-fn print_snapshot(snapshot: &snapshot::Snapshot, id: &ObjectId, sizes: Option<&ForestSizes>) {
+/// Print a snapshot, git-log style.
+///
+/// `sizes` are its [`ForestSizes`] and how much data only it uses.
+fn print_snapshot(
+    snapshot: &snapshot::Snapshot,
+    id: &ObjectId,
+    sizes: Option<(&ForestSizes, u64)>,
+) {
     print!("snapshot {}", id);
     if snapshot.tags.is_empty() {
         println!();
@@ -334,13 +374,16 @@ fn print_snapshot(snapshot: &snapshot::Snapshot, id: &ObjectId, sizes: Option<&F
                 .join(" ")
         );
     }
-    if let Some(s) = sizes {
-        let t = nice_size(s.tree_bytes + s.chunk_bytes);
-        let m = nice_size(s.tree_bytes);
-        let c = nice_size(s.chunk_bytes);
-        let i = nice_size(s.introduced);
-        let r = nice_size(s.reused);
-        println!("Sizes: {t} total ({c} files, {m} metadata / {i} new, {r} reused)");
+    if let Some((s, exclusive)) = sizes {
+        let t = summary_size(s.tree_bytes + s.chunk_bytes);
+        let m = summary_size(s.tree_bytes);
+        let c = summary_size(s.chunk_bytes);
+        let i = summary_size(s.introduced);
+        let r = summary_size(s.reused);
+        let x = summary_size(exclusive);
+        println!(
+            "Sizes: {t} total ({c} files, {m} metadata / {i} new, {r} reused / {x} exclusive)"
+        );
     }
     println!("Author: {}", snapshot.author);
 
@@ -352,12 +395,56 @@ fn print_snapshot(snapshot: &snapshot::Snapshot, id: &ObjectId, sizes: Option<&F
         println!();
     }
     for path in &snapshot.paths {
-        println!("  - {path}");
+        if snapshot.from_stdin {
+            println!("  - {path} (from stdin)");
+        } else {
+            println!("  - {path}");
+        }
     }
     println!();
 }
 
-/// Tree walk for measuring the longest path
+/// Print each snapshot on its own line, columns lined up.
+fn print_table<'a>(snapshots: impl Iterator<Item = &'a (snapshot::Snapshot, ObjectId)>) {
+    let header = ["ID", "Date", "Author", "Tags", "Paths"].map(str::to_owned);
+    let rows: Vec<[String; 5]> = std::iter::once(header)
+        .chain(snapshots.map(|(snap, id)| {
+            let tags = snap.tags.iter().map(String::as_str).collect::<Vec<_>>();
+            let paths = snap.paths.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+            let mut paths = paths.join(" ");
+            if snap.from_stdin {
+                paths.push_str(" (from stdin)");
+            }
+            [
+                id.short_name(),
+                snap.time.strftime("%Y-%m-%d %H:%M:%S").to_string(),
+                snap.author.clone(),
+                tags.join(","),
+                paths,
+            ]
+        }))
+        .collect();
+
+    let width = |column: usize| {
+        rows.iter()
+            .map(|r| r[column].graphemes(true).count())
+            .max()
+            .unwrap_or(0)
+    };
+    let widths: Vec<usize> = (0..4).map(width).collect();
+    for row in &rows {
+        let mut line = String::new();
+        for (cell, w) in row.iter().zip(&widths) {
+            // Don't trust a std::format!() pad; see above.
+            line.push_str(cell);
+            line.push_str(&" ".repeat(w - cell.graphemes(true).count() + 2));
+        }
+        line.push_str(&row[4]);
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Tree walk for measuring the longest line (mark and path) `--stat` will print
 fn measure_path_pad(
     (id1, forest1): (&ObjectId, &Forest),
     (id2, forest2): (&ObjectId, &Forest),
@@ -394,16 +481,27 @@ fn path_length(path: &Utf8Path, node: &Node) -> usize {
 }
 
 impl PadMeasure {
-    fn measure_node(&mut self, path: &Utf8Path, node: &Node, should_recurse: ls::Recurse) {
+    /// Measure the node (and maybe its children) as printed after the given prefix,
+    /// which can be longer than three columns for metadata changes (` OPTA `).
+    fn measure_node(
+        &mut self,
+        prefix: &str,
+        path: &Utf8Path,
+        node: &Node,
+        should_recurse: ls::Recurse,
+    ) {
+        let prefix_length = prefix.graphemes(true).count();
         // We have Foldable and Sum Monoids at home.
-        let mut v = |p: &Utf8Path, n: &Node| self.longest = self.longest.max(path_length(p, n));
+        let mut v = |p: &Utf8Path, n: &Node| {
+            self.longest = self.longest.max(prefix_length + path_length(p, n))
+        };
         ls::walk_node(&mut v, path, node, should_recurse);
     }
 }
 
 impl diff::Callbacks for PadMeasure {
     fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
-        self.measure_node(node_path, new_node, ls::Recurse::Yes(forest));
+        self.measure_node(" + ", node_path, new_node, ls::Recurse::Yes(forest));
         Ok(())
     }
 
@@ -413,7 +511,7 @@ impl diff::Callbacks for PadMeasure {
         old_node: &Node,
         forest: &Forest,
     ) -> Result<()> {
-        self.measure_node(node_path, old_node, ls::Recurse::Yes(forest));
+        self.measure_node(" - ", node_path, old_node, ls::Recurse::Yes(forest));
         Ok(())
     }
 
@@ -426,10 +524,10 @@ impl diff::Callbacks for PadMeasure {
         assert!(old_node.kind() == NodeType::File || old_node.kind() == NodeType::Symlink);
         assert_eq!(old_node.kind(), new_node.kind());
 
-        self.measure_node(node_path, old_node, ls::Recurse::No);
+        self.measure_node(" C ", node_path, old_node, ls::Recurse::No);
         // If it's a symlink, it might have a new target of a different length
         if old_node.kind() == NodeType::Symlink {
-            self.measure_node(node_path, new_node, ls::Recurse::No);
+            self.measure_node(" + ", node_path, new_node, ls::Recurse::No);
         }
         Ok(())
     }
@@ -437,20 +535,26 @@ impl diff::Callbacks for PadMeasure {
     fn metadata_changed(
         &mut self,
         node_path: &Utf8Path,
-        _old_node: &Node,
+        old_node: &Node,
         new_node: &Node,
     ) -> Result<()> {
         if self.metadata {
-            self.measure_node(node_path, new_node, ls::Recurse::No);
+            let prefix = metadata_prefix(old_node, new_node);
+            self.measure_node(&prefix, node_path, new_node, ls::Recurse::No);
         }
         Ok(())
     }
 }
 
+/// The mark for a metadata change, like ` OP `
+fn metadata_prefix(old_node: &Node, new_node: &Node) -> String {
+    format!(" {} ", super::diff::meta_diff_string(old_node, new_node))
+}
+
 /// Print diffs between two trees, optionally including size changes.
 fn tree_diff(
-    (id1, forest1): (&ObjectId, &Forest),
-    (id2, forest2): (&ObjectId, &Forest),
+    first: (&ObjectId, &diff::Trees),
+    second: (&ObjectId, &diff::Trees),
     metadata: bool,
     pad: usize,
     sizes: Option<FxHashMap<&Utf8Path, &FileSize>>,
@@ -460,7 +564,7 @@ fn tree_diff(
         pad,
         sizes,
     };
-    diff::compare_trees((id1, forest1), (id2, forest2), Utf8Path::new(""), &mut cb)
+    diff::compare_trees_in(first, second, Utf8Path::new(""), &mut cb)
 }
 
 /// Just `ui::diff` machinery but with extras space in the prefixes and optional size suffixes.
@@ -471,8 +575,7 @@ struct PrintDiffs<'a> {
 }
 
 impl PrintDiffs<'_> {
-    fn printer(&self, prefix: &str, path: &Utf8Path, node: &Node) {
-        print!("{prefix}");
+    fn printer(&self, prefix: &str, highlight: ls::Highlight, path: &Utf8Path, node: &Node) {
         let mut p = path.as_str().to_owned();
         match &node.contents {
             NodeContents::Directory { .. } => {
@@ -485,6 +588,10 @@ impl PrintDiffs<'_> {
                 p += &format!(" -> {target}");
             }
         };
+        // Don't trust a std::format!() pad
+        // https://stackoverflow.com/a/65822500
+        let plen = prefix.graphemes(true).count() + p.graphemes(true).count();
+        let p = highlight.apply(format!("{prefix}{p}"));
         // Sentinel values are bad, mmmk.
         // But also, if we haven't measured the longest path length in a previous pass,
         // we're not printing file sizes.
@@ -493,9 +600,6 @@ impl PrintDiffs<'_> {
             println!("{p}");
         } else {
             let sizes = self.sizes.as_ref().unwrap();
-            // Don't trust a std::format!() pad
-            // https://stackoverflow.com/a/65822500
-            let plen = p.graphemes(true).count();
             assert!(plen <= self.pad);
             let pad: String = " ".repeat(self.pad - plen);
             print!("{p}{pad}");
@@ -510,15 +614,28 @@ impl PrintDiffs<'_> {
         }
     }
 
-    fn print_node(&self, prefix: &str, path: &Utf8Path, node: &Node, should_recurse: ls::Recurse) {
-        let mut v = |p: &Utf8Path, n: &Node| self.printer(prefix, p, n);
+    fn print_node(
+        &self,
+        prefix: &str,
+        highlight: ls::Highlight,
+        path: &Utf8Path,
+        node: &Node,
+        should_recurse: ls::Recurse,
+    ) {
+        let mut v = |p: &Utf8Path, n: &Node| self.printer(prefix, highlight, p, n);
         ls::walk_node(&mut v, path, node, should_recurse);
     }
 }
 
 impl diff::Callbacks for PrintDiffs<'_> {
     fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
-        self.print_node(" + ", node_path, new_node, ls::Recurse::Yes(forest));
+        self.print_node(
+            " + ",
+            ls::Highlight::Added,
+            node_path,
+            new_node,
+            ls::Recurse::Yes(forest),
+        );
         Ok(())
     }
 
@@ -528,7 +645,13 @@ impl diff::Callbacks for PrintDiffs<'_> {
         old_node: &Node,
         forest: &Forest,
     ) -> Result<()> {
-        self.print_node(" - ", node_path, old_node, ls::Recurse::Yes(forest));
+        self.print_node(
+            " - ",
+            ls::Highlight::Removed,
+            node_path,
+            old_node,
+            ls::Recurse::Yes(forest),
+        );
         Ok(())
     }
 
@@ -542,10 +665,28 @@ impl diff::Callbacks for PrintDiffs<'_> {
         assert_eq!(old_node.kind(), new_node.kind());
 
         if old_node.kind() == NodeType::Symlink {
-            self.print_node(" - ", node_path, old_node, ls::Recurse::No);
-            self.print_node(" + ", node_path, new_node, ls::Recurse::No);
+            self.print_node(
+                " - ",
+                ls::Highlight::Removed,
+                node_path,
+                old_node,
+                ls::Recurse::No,
+            );
+            self.print_node(
+                " + ",
+                ls::Highlight::Added,
+                node_path,
+                new_node,
+                ls::Recurse::No,
+            );
         } else {
-            self.print_node(" C ", node_path, old_node, ls::Recurse::No);
+            self.print_node(
+                " C ",
+                ls::Highlight::Changed,
+                node_path,
+                old_node,
+                ls::Recurse::No,
+            );
         }
         Ok(())
     }
@@ -557,11 +698,13 @@ impl diff::Callbacks for PrintDiffs<'_> {
         new_node: &Node,
     ) -> Result<()> {
         if self.metadata {
-            let leading_char = format!(
-                " {} ",
-                meta_diff_char(&old_node.metadata, &new_node.metadata).unwrap()
+            self.print_node(
+                &metadata_prefix(old_node, new_node),
+                ls::Highlight::Changed,
+                node_path,
+                new_node,
+                ls::Recurse::No,
             );
-            self.print_node(&leading_char, node_path, new_node, ls::Recurse::No);
         }
         Ok(())
     }
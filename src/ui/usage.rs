@@ -1,17 +1,22 @@
 use anyhow::Result;
-
+use clap::Parser;
+use jiff::{SignedDuration, Timestamp};
 use rustc_hash::FxHashSet;
 use tracing::warn;
 
-use crate::{backend, config::Configuration, file_util::nice_size, index, snapshot, tree};
+use crate::{backend, config::Configuration, file_util::summary_size, index, snapshot, tree};
+
+#[derive(Debug, Parser)]
+pub struct Args {
+    /// Also show how old packs are, to help decide when to prune.
+    #[clap(long)]
+    pack_ages: bool,
+}
 
-pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()> {
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
     // Build the usual suspects.
-    let (backend_config, cached_backend) = backend::open(
-        repository,
-        config.cache_size,
-        backend::CacheBehavior::Normal,
-    )?;
+    let (backend_config, cached_backend) =
+        backend::open(repository, config, backend::CacheBehavior::Normal)?;
     let (index, index_sizes) = index::build_master_index_with_sizes(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
     let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
@@ -45,8 +50,8 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()>
 
         // Refactor out of ui/snapshots.rs (into snapshots.rs itself?)
         reachable_blob_size = totals.introduced;
-        let u = nice_size(totals.introduced);
-        let r = nice_size(totals.reused);
+        let u = summary_size(totals.introduced);
+        let r = summary_size(totals.reused);
         println!("{u} unique data");
         println!("{r} reused (deduplicated)");
     }
@@ -63,22 +68,24 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()>
         .sum();
     print!("\n{num_indexes} {index_str} reference {reachable_packs} packs");
     if packed_blob_size > reachable_blob_size {
-        let ds = nice_size(packed_blob_size - reachable_blob_size);
+        let ds = summary_size(packed_blob_size - reachable_blob_size);
         println!(", including {ds} unused data.\nConsider running `backpak prune`.");
     } else {
         println!();
     }
     if packed_blob_size < reachable_blob_size {
-        let ds = nice_size(reachable_blob_size - packed_blob_size);
+        let ds = summary_size(reachable_blob_size - packed_blob_size);
         warn!("Snapshots contain {ds} more than packs! Consider running `backpak check`.")
     }
-    let all_packs = cached_backend.list_packs()?;
-    let pack_size = super::check::warn_on_unreachable_packs(&index, &all_packs)?;
+    let pack_size = super::check::warn_on_unreachable_packs(&index, cached_backend.list_packs()?)?;
     let index_size = index_sizes.iter().sum();
 
     let backend_kind = match backend_config.kind {
         backend::Kind::Filesystem { .. } => "Filesystem",
         backend::Kind::Backblaze { .. } => "Backblaze",
+        backend::Kind::S3 { .. } => "S3",
+        backend::Kind::Ssh { .. } => "SSH",
+        backend::Kind::Mirror { .. } => "Mirrored",
     };
     let filter_str = if let Some((f, _)) = &backend_config.filter {
         let fname = f.split_whitespace().next().expect("empty filter");
@@ -87,11 +94,56 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()>
         String::new()
     };
     println!("\n{backend_kind} usage after zstd compression{filter_str}:");
-    println!("snapshots: {}", nice_size(snapshot_size));
-    println!("indexes:   {}", nice_size(index_size));
-    println!("packs:     {}", nice_size(pack_size));
+    println!("snapshots: {}", summary_size(snapshot_size));
+    println!("indexes:   {}", summary_size(index_size));
+    println!("packs:     {}", summary_size(pack_size));
     #[rustfmt::skip]
-    println!("total:     {}", nice_size(pack_size + index_size + snapshot_size) );
+    println!("total:     {}", summary_size(pack_size + index_size + snapshot_size) );
+
+    if args.pack_ages {
+        print_pack_ages(&index);
+    }
 
     Ok(())
 }
+
+fn print_pack_ages(index: &index::Index) {
+    const DAY: i64 = 24 * 60 * 60;
+    // (label, max age in seconds)
+    let buckets = [
+        ("< 1 day", DAY),
+        ("< 1 week", 7 * DAY),
+        ("< 1 month", 30 * DAY),
+        ("< 1 year", 365 * DAY),
+        (">= 1 year", i64::MAX),
+    ];
+    let mut counts = [(0usize, 0u64); 5];
+    let mut unknown = (0usize, 0u64);
+
+    let now = Timestamp::now();
+    for (pack_id, manifest) in &index.packs {
+        let size: u64 = manifest.iter().map(|e| e.length as u64).sum();
+        let slot = match index.pack_times.get(pack_id) {
+            Some(t) => {
+                let age: SignedDuration = now.duration_since(*t);
+                let b = buckets
+                    .iter()
+                    .position(|(_, max)| age.as_secs() < *max)
+                    .unwrap();
+                &mut counts[b]
+            }
+            None => &mut unknown,
+        };
+        slot.0 += 1;
+        slot.1 += size;
+    }
+
+    println!("\nPack ages:");
+    for ((label, _), (n, size)) in buckets.iter().zip(counts) {
+        println!("{label:>10}: {n} packs ({})", summary_size(size));
+    }
+    if unknown.0 > 0 {
+        let (n, size) = unknown;
+        println!("{:>10}: {n} packs ({})", "unknown", summary_size(size));
+    }
+}
@@ -49,7 +49,7 @@ fn rebuild_index() -> Result<()> {
 
     // Check that everything backed up alright.
     cli_run(working_path, backup_path)?
-        .args(&["check", "--read-packs"])
+        .args(&["check", "--read-packs", "--dereference-and-hash"])
         .assert()
         .success();
 
@@ -68,10 +68,11 @@ fn rebuild_index() -> Result<()> {
     assert_eq!(count_directory_entries(&indexes_dir), 2);
 
     // Consolodate indexes
-    cli_run(working_path, backup_path)?
-        .arg("rebuild-index")
+    let rebuild = cli_run(working_path, backup_path)?
+        .args(["rebuild-index", "--jobs", "2"])
         .assert()
         .success();
+    assert!(stdout(&rebuild).contains("indexed "));
 
     assert_eq!(count_directory_entries(&indexes_dir), 1);
 
@@ -85,3 +86,41 @@ fn rebuild_index() -> Result<()> {
     // std::mem::forget(backup_dir);
     Ok(())
 }
+
+#[test]
+fn bad_index() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "meow")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+
+    let index = files_in(backup_path.join("indexes")).next().unwrap();
+    fs::write(&index, "not an index")?;
+    let id = index.file_stem().unwrap().to_str().unwrap();
+
+    let check = cli_run(working_path, backup_path)?
+        .arg("check")
+        .assert()
+        .failure();
+    let err = stderr(&check);
+    // We say what went wrong with each index, then which ones were bad.
+    let what = err.find(&format!("Couldn't load index {id}")).expect(err);
+    let which = err.find("Errors loading indexes").expect(err);
+    assert!(what < which, "{err}");
+    Ok(())
+}
@@ -38,6 +38,7 @@ fn bad_filename() -> Result<()> {
         .failure();
 
     println!("{}", stderr(&fails_on_utf8));
+    assert!(stderr(&fails_on_utf8).contains("isn't valid UTF-8"));
     println!("{:?}", files_in(&working_path).collect::<Vec<_>>());
 
     // We should fail fast - _before_ we start the backup process and spit out
@@ -54,6 +55,20 @@ fn bad_filename() -> Result<()> {
         "Files weren't validated before backup, "
     );
 
+    // We can opt into skipping the bad file instead.
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--on-invalid-name", "skip"])
+        .arg(working_path.join("foo"))
+        .assert()
+        .success();
+
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    let listed: Vec<&str> = stdout(&ls_run).lines().collect();
+    assert_eq!(listed, ["foo/", "foo/bar"]);
+
     // To examine results
     // std::mem::forget(working_dir);
     // std::mem::forget(backup_dir);
@@ -0,0 +1,294 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn cache_stats() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    // Keep our cache to ourselves.
+    let home = working_path.join("home");
+    fs::create_dir(&home)?;
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "cache me if you can")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    // Plain filesystem repos don't have a cache, and we should say so.
+    let no_cache = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "stats"])
+        .assert()
+        .success();
+    assert!(stdout(&no_cache).contains("doesn't use a cache"));
+
+    // Filtered ones do.
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(
+        &config_path,
+        format!("filter = \"cat\"\nunfilter = \"cat\"\n{config}"),
+    )?;
+
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+
+    let stats = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "stats"])
+        .assert()
+        .success();
+    let stats = stdout(&stats);
+    assert!(stats.contains(".cache/backpak"), "{stats}");
+    assert!(!stats.contains("Entries: 0"), "{stats}");
+    assert!(!stats.contains("no reads yet"), "{stats}");
+    assert!(stats.contains("least-recently used"), "{stats}");
+
+    let list = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "list"])
+        .assert()
+        .success();
+    assert!(stdout(&list).contains(".snapshot"), "{}", stdout(&list));
+
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "verify"])
+        .assert()
+        .success();
+
+    // Corrupt a snapshot in the cache; verify should catch (and evict) it.
+    let cache_dir = home.join(".cache/backpak");
+    let snapshot = fs::read_dir(&cache_dir)?
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "snapshot"))
+        .expect("no cached snapshot");
+    fs::write(&snapshot, "garbage")?;
+    let verify = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "verify"])
+        .assert()
+        .failure();
+    assert!(stdout(&verify).contains("1 evicted"));
+    assert!(!snapshot.exists());
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "verify"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "clear"])
+        .assert()
+        .success();
+    let stats = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "stats"])
+        .assert()
+        .success();
+    assert!(stdout(&stats).contains("Entries: 0"));
+
+    // Without a cache, clearing is a no-op.
+    fs::write(&config_path, config)?;
+    let no_cache = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "clear"])
+        .assert()
+        .success();
+    assert!(stdout(&no_cache).contains("doesn't use a cache"));
+    Ok(())
+}
+
+#[test]
+fn check_without_cache() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home = working_path.join("home");
+    fs::create_dir(&home)?;
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "not worth caching")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+
+    // Filter the repo so it's cached, but start with an empty cache.
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(
+        &config_path,
+        format!("filter = \"cat\"\nunfilter = \"cat\"\n{config}"),
+    )?;
+
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["check", "--read-packs", "--no-cache"])
+        .assert()
+        .success();
+    let stats = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "stats"])
+        .assert()
+        .success();
+    assert!(stdout(&stats).contains("Entries: 0"), "{}", stdout(&stats));
+
+    // Versus the usual
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+    let stats = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "stats"])
+        .assert()
+        .success();
+    assert!(!stdout(&stats).contains("Entries: 0"));
+    Ok(())
+}
+
+#[test]
+fn verify_on_open() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home = working_path.join("home");
+    fs::create_dir(&home)?;
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "cut short")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(
+        &config_path,
+        format!("filter = \"cat\"\nunfilter = \"cat\"\nverify_cache_on_open = true\n{config}"),
+    )?;
+
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+
+    // Pretend we crashed halfway through caching the snapshot.
+    let cache_dir = home.join(".cache/backpak");
+    let snapshot = fs::read_dir(&cache_dir)?
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "snapshot"))
+        .expect("no cached snapshot");
+    let contents = fs::read(&snapshot)?;
+    fs::write(&snapshot, &contents[..contents.len() / 2])?;
+
+    // We should evict it and go back to the repo instead of reading garbage.
+    let ls = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    assert!(stderr(&ls).contains("Evicting"), "{}", stderr(&ls));
+    assert!(stdout(&ls).contains("a.txt"));
+    assert_eq!(fs::read(&snapshot)?, contents);
+    Ok(())
+}
+
+#[test]
+fn cache_dir() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home = working_path.join("home");
+    fs::create_dir(&home)?;
+    let elsewhere = working_path.join("fast-disk");
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "somewhere else")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(
+        &config_path,
+        format!("filter = \"cat\"\nunfilter = \"cat\"\n{config}"),
+    )?;
+
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .arg("--cache-dir")
+        .arg(&elsewhere)
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    assert!(!home.join(".cache/backpak").exists());
+    let stats = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .arg("--cache-dir")
+        .arg(&elsewhere)
+        .args(["cache", "stats"])
+        .assert()
+        .success();
+    let stats = stdout(&stats);
+    assert!(stats.contains("fast-disk"), "{stats}");
+    assert!(!stats.contains("Entries: 0"), "{stats}");
+
+    // Somewhere we can't write is an error up front.
+    let not_a_dir = working_path.join("not-a-dir");
+    fs::write(&not_a_dir, "")?;
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .arg("--cache-dir")
+        .arg(&not_a_dir)
+        .args(["ls", "LAST"])
+        .assert()
+        .failure();
+    Ok(())
+}
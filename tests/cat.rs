@@ -0,0 +1,79 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn cat_objects() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "meow")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+
+    let cat = |args: &[&str]| {
+        cli_run(working_path, backup_path)
+            .unwrap()
+            .arg("cat")
+            .args(args)
+            .assert()
+    };
+
+    let snapshot_run = cat(&["snapshot", "LAST"]).success();
+    let snapshot: serde_json::Value = serde_json::from_str(stdout(&snapshot_run))?;
+    let root = snapshot["tree"].as_str().unwrap();
+
+    // The root tree holds the directory we backed up...
+    let root_run = cat(&["--pretty", "tree", root]).success();
+    assert!(stdout(&root_run).contains('\n'));
+    let root_tree: serde_json::Value = serde_json::from_str(stdout(&root_run))?;
+    let src_tree = root_tree["src"]["tree"].as_str().unwrap();
+
+    // ...which holds our file.
+    let src_run = cat(&["tree", src_tree]).success();
+    let src_tree: serde_json::Value = serde_json::from_str(stdout(&src_run))?;
+    let chunk = src_tree["a.txt"]["chunks"][0].as_str().unwrap();
+    assert_eq!(stdout(&cat(&["blob", chunk]).success()), "meow");
+    // Chunks aren't trees.
+    cat(&["tree", chunk]).failure();
+
+    // Unique prefixes work as well as whole IDs...
+    assert_eq!(stdout(&cat(&["blob", &chunk[..8]]).success()), "meow");
+    let pack_file = files_in(backup_path.join("packs")).next().unwrap();
+    let pack = pack_file.file_stem().unwrap().to_str().unwrap();
+    let by_prefix = cat(&["pack", &pack[..8]]).success();
+    assert!(stderr(&by_prefix).contains("Querying backend for packs"));
+    // (And whole IDs don't need a listing.)
+    let by_id = cat(&["pack", pack]).success();
+    assert_eq!(stdout(&by_id), stdout(&by_prefix));
+    assert!(!stderr(&by_id).contains("Querying backend for packs"));
+    // ...but not ones so short they're likely to be ambiguous.
+    cat(&["blob", &chunk[..3]]).failure();
+
+    // Raw objects come out byte-for-byte.
+    let snapshot_file = fs::read_dir(backup_path.join("snapshots"))?
+        .next()
+        .unwrap()?
+        .path();
+    let raw_run = cat(&["--raw", "snapshot", "LAST"]).success();
+    assert_eq!(raw_run.get_output().stdout, fs::read(snapshot_file)?);
+    Ok(())
+}
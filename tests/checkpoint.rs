@@ -0,0 +1,78 @@
+use std::collections::BTreeSet;
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+fn index_ids(backup_path: &std::path::Path) -> BTreeSet<String> {
+    dir_entries(backup_path.join("indexes"))
+        .map(|p| p.file_stem().unwrap().to_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+fn checkpoints() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let config = working_path.join("config.toml");
+    fs::write(&config, "checkpoint_interval = 2\n")?;
+    // Like cli_run, but with our config instead of none.
+    let run = || -> Result<assert_cmd::Command> {
+        let mut cmd = assert_cmd::Command::cargo_bin(env!("CARGO_PKG_NAME"))?;
+        cmd.arg("-C").arg(working_path);
+        cmd.arg("--config").arg(&config);
+        cmd.arg("--repository").arg(backup_path);
+        cmd.arg("-vvv");
+        cmd.env("BACKPAK_TEST_TINY_PACKS", "1");
+        Ok(cmd)
+    };
+
+    // Tiny packs so we make a few checkpoints.
+    cli_run(working_path, backup_path)?
+        .args(["init", "--pack-size", "20KB", "filesystem"])
+        .assert()
+        .success();
+
+    let src = std::env::current_dir()?.join("src");
+    run()?.arg("backup").arg(&src).assert().success();
+    run()?.arg("check").assert().success();
+
+    // Sort out the final index from the checkpoints it supersedes.
+    let ids = index_ids(backup_path);
+    let mut superseded = BTreeSet::new();
+    let mut first_checkpoint = None;
+    for id in &ids {
+        let cat_run = run()?.args(["cat", "index", id]).assert().success();
+        let index: serde_json::Value = serde_json::from_str(stdout(&cat_run))?;
+        let supersedes = index["supersedes"].as_array().unwrap();
+        if supersedes.is_empty() {
+            first_checkpoint = Some(id.clone());
+        }
+        superseded.extend(supersedes.iter().map(|s| s.as_str().unwrap().to_owned()));
+    }
+    assert!(superseded.len() > 1);
+    assert_eq!(ids.len(), superseded.len() + 1);
+    let first_checkpoint = first_checkpoint.unwrap();
+
+    // Pretend we crashed right after the first checkpoint:
+    // no snapshot, no later indexes, just packs.
+    for id in ids.iter().filter(|i| **i != first_checkpoint) {
+        fs::remove_file(backup_path.join("indexes").join(format!("{id}.index")))?;
+    }
+    fs::remove_dir_all(backup_path.join("snapshots"))?;
+    fs::create_dir(backup_path.join("snapshots"))?;
+
+    // What's left is valid (if incomplete), and we can carry on from it.
+    run()?.args(["check", "--read-packs"]).assert().success();
+    run()?.arg("backup").arg(&src).assert().success();
+    run()?.args(["check", "--read-packs"]).assert().success();
+    Ok(())
+}
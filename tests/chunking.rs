@@ -0,0 +1,92 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn configured_chunking() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    // Something that doesn't compress down to nothing and takes a few small chunks
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    let mut x: u32 = 2463534242;
+    let noise: Vec<u8> = (0..300_000)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect();
+    fs::write(src.join("noise.bin"), &noise)?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    let with_chunking = |c: &str| format!("chunking = {{ {c} }}\n{config}");
+
+    // Sizes get checked.
+    fs::write(
+        &config_path,
+        with_chunking(r#"min = "64 KiB", avg = "32 KiB", max = "128 KiB""#),
+    )?;
+    let backwards = cli_run(working_path, backup_path)?
+        .arg("snapshots")
+        .assert()
+        .failure();
+    assert!(stderr(&backwards).contains("Bad chunking"));
+
+    fs::write(
+        &config_path,
+        with_chunking(r#"min = "16 KiB", avg = "32 KiB", max = "64 KiB""#),
+    )?;
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+
+    // Go back to the defaults, then touch the file without changing it.
+    fs::write(&config_path, &config)?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    fs::write(src.join("noise.bin"), &noise)?;
+
+    // Diffing against the snapshot cuts the file like the snapshot did,
+    // so it hasn't changed.
+    let diff = cli_run(working_path, backup_path)?
+        .args(["diff", "LAST"])
+        .assert()
+        .success();
+    assert!(!stdout(&diff).contains("C "), "{}", stdout(&diff));
+
+    // But the next backup cuts it differently, and we should hear about it.
+    let second = cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    assert!(
+        stderr(&second).contains("Chunk sizes (min / avg / max) changed"),
+        "{}",
+        stderr(&second)
+    );
+
+    cli_run(working_path, backup_path)?
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+    Ok(())
+}
@@ -13,6 +13,18 @@ pub fn cli_run(working_dir: &Path, backup_path: &Path) -> Result<assert_cmd::Com
     cmd.arg("--config").arg(""); // NB: Ignore test machine state
     cmd.arg("--repository").arg(backup_path);
     cmd.arg("-vvv");
+    // Let tests make lots of packs out of a little data.
+    cmd.env("BACKPAK_TEST_TINY_PACKS", "1");
+    Ok(cmd)
+}
+
+/// Like [`cli_run`], but without passing a repository.
+pub fn cli_run_without_repository(working_dir: &Path) -> Result<assert_cmd::Command> {
+    let bin_name = env!("CARGO_PKG_NAME");
+    let mut cmd = Command::cargo_bin(bin_name)?;
+    cmd.arg("-C").arg(working_dir);
+    cmd.arg("--config").arg(""); // NB: Ignore test machine state
+    cmd.arg("-vvv");
     Ok(cmd)
 }
 
@@ -52,3 +64,14 @@ pub fn normalize(o: &str) -> Vec<&str> {
     // Strip Opening... Building a master index... snapshot <hash>...
     o.trim().lines().skip(3).collect()
 }
+
+/// Drop access time changes from a line of `diff` or `restore` output
+/// (they change if you sneeze, and based on mount options),
+/// or the whole line if that's all that changed.
+pub fn ignore_atime(line: &str) -> Option<String> {
+    let (mark, rest) = line.split_once(' ')?;
+    match mark.replace('A', "").as_str() {
+        "" => None,
+        m => Some(format!("{m} {rest}")),
+    }
+}
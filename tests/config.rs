@@ -0,0 +1,133 @@
+use std::fs;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn check_configs() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let fine = cli_run(working_path, backup_path)?
+        .args(["config", "check"])
+        .assert()
+        .success();
+    assert!(stdout(&fine).contains("config.toml: OK"));
+
+    // Typos get a suggestion...
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(&config_path, format!("pakc_size = \"10 MB\"\n{config}"))?;
+    let typo = cli_run(working_path, backup_path)?
+        .args(["config", "check"])
+        .assert()
+        .failure();
+    let typo = stderr(&typo);
+    assert!(
+        typo.contains("unknown key `pakc_size`, did you mean `pack_size`?"),
+        "{typo}"
+    );
+
+    // ...including in the backend table...
+    fs::write(&config_path, config.replace("force_cache", "force_cahce"))?;
+    let typo = cli_run(working_path, backup_path)?
+        .args(["config", "check"])
+        .assert()
+        .failure();
+    let typo = stderr(&typo);
+    assert!(typo.contains("did you mean `force_cache`?"), "{typo}");
+
+    // ...and other commands fail with the same message.
+    let backup = cli_run(working_path, backup_path)?
+        .arg("snapshots")
+        .assert()
+        .failure();
+    assert!(stderr(&backup).contains("did you mean `force_cache`?"));
+
+    fs::write(&config_path, format!("filter = \"cat\"\n{config}"))?;
+    let half_filtered = cli_run(working_path, backup_path)?
+        .args(["config", "check"])
+        .assert()
+        .failure();
+    assert!(stderr(&half_filtered).contains("`filter` and `unfilter` or neither"));
+
+    // The user config gets checked too.
+    fs::write(&config_path, &config)?;
+    let user_config = working_path.join("backpak.toml");
+    fs::write(&user_config, "cache_sise = \"1 GB\"\n")?;
+    let bad_user = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+        .arg("--config")
+        .arg(&user_config)
+        .args(["config", "check"])
+        .arg(backup_path)
+        .assert()
+        .failure();
+    assert!(
+        stderr(&bad_user).contains("unknown key `cache_sise`, did you mean `cache_size`?"),
+        "{}",
+        stderr(&bad_user)
+    );
+    assert!(stdout(&bad_user).contains("config.toml: OK"));
+    Ok(())
+}
+
+#[test]
+fn separate_repo_config() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "configured elsewhere")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+
+    // Pretend the repository is somewhere we can't keep a config.
+    let elsewhere = working_path.join("repo-config.toml");
+    fs::rename(backup_path.join("config.toml"), &elsewhere)?;
+    cli_run(working_path, backup_path)?
+        .arg("snapshots")
+        .assert()
+        .failure();
+
+    let ls = cli_run(working_path, backup_path)?
+        .arg("--repo-config")
+        .arg(&elsewhere)
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    assert!(stdout(&ls).contains("a.txt"));
+
+    let check = cli_run(working_path, backup_path)?
+        .arg("--repo-config")
+        .arg(&elsewhere)
+        .args(["config", "check"])
+        .assert()
+        .success();
+    assert!(stdout(&check).contains("repo-config.toml: OK"));
+    Ok(())
+}
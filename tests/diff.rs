@@ -49,9 +49,7 @@ fn diff_src() -> Result<()> {
         let diff_output: Vec<_> = stdout(&diff_run)
             .trim()
             .lines()
-            // I don't care about atime. They change if you sneeze (and based on mount opts).
-            .filter(|l| !l.starts_with("A "))
-            .map(str::to_owned)
+            .filter_map(ignore_atime)
             .collect();
         diff_output
     };
@@ -80,23 +78,113 @@ fn diff_src() -> Result<()> {
         "- src/backend/",
         "- src/backend/backblaze.rs",
         "- src/backend/cache.rs",
+        "- src/backend/error.rs",
         "- src/backend/filter.rs",
         "- src/backend/fs.rs",
+        "- src/backend/listings.rs",
         "- src/backend/memory.rs",
+        "- src/backend/mirror.rs",
+        "- src/backend/rate_limited.rs",
+        "- src/backend/retry.rs",
+        "- src/backend/s3.rs",
         "- src/backend/semaphored.rs",
+        "- src/backend/ssh.rs",
+        "- src/backend/throttle.rs",
         "- src/diff.rs",
         "C src/lib.rs",
         "P src/main.rs",
         "+ src/wackend/",
         "+ src/wackend/backblaze.rs",
         "+ src/wackend/cache.rs",
+        "+ src/wackend/error.rs",
         "+ src/wackend/filter.rs",
         "+ src/wackend/fs.rs",
+        "+ src/wackend/listings.rs",
         "+ src/wackend/memory.rs",
+        "+ src/wackend/mirror.rs",
+        "+ src/wackend/rate_limited.rs",
+        "+ src/wackend/retry.rs",
+        "+ src/wackend/s3.rs",
         "+ src/wackend/semaphored.rs",
+        "+ src/wackend/ssh.rs",
+        "+ src/wackend/throttle.rs",
         "T src/",
     ]);
 
+    // Same thing, collapsed to directories.
+    // (Other dirs might show up as changed since we read files and bumped their atimes.)
+    let dirs_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--dirs-only", "LAST"])
+        .assert()
+        .success();
+    let dirs: Vec<&str> = stdout(&dirs_run).trim().lines().collect();
+    assert_eq!(dirs.first(), Some(&"C src/"));
+    assert!(dirs.contains(&"- src/backend/"));
+    assert!(dirs.contains(&"+ src/wackend/"));
+    assert!(!dirs.iter().any(|d| d.contains(".rs")));
+
+    // Or just counted. (Metadata counts vary with atimes; see above.)
+    let summary_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--summary", "LAST"])
+        .assert()
+        .success();
+    let summary = stdout(&summary_run);
+    assert!(summary.starts_with("+16 -16 C1 M"), "{summary}");
+    assert_eq!(summary.lines().count(), 1);
+
+    let json_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--json", "LAST"])
+        .assert()
+        .success();
+    let json = stdout(&json_run);
+    assert!(json.contains(r#"{"change":"added","path":"src/wackend","type":"directory"}"#));
+    assert!(json.contains(r#"{"change":"removed","path":"src/diff.rs","type":"file"}"#));
+    assert!(json.contains(r#"{"change":"contents","path":"src/lib.rs","type":"file"}"#));
+    // No --metadata, no metadata
+    assert!(!json.contains("main.rs"));
+
+    // Narrowed down with globs
+    let filtered = |globs: &[&str]| {
+        let run = cli_run(working_path, backup_path)
+            .unwrap()
+            .arg("diff")
+            .args(globs)
+            .arg("LAST")
+            .assert()
+            .success();
+        stdout(&run)
+            .trim()
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        filtered(&["--path", "src/wackend", "--exclude", "[bcfm]*.rs"]),
+        [
+            "+ src/wackend/",
+            "+ src/wackend/error.rs",
+            "+ src/wackend/listings.rs",
+            "+ src/wackend/rate_limited.rs",
+            "+ src/wackend/retry.rs",
+            "+ src/wackend/s3.rs",
+            "+ src/wackend/semaphored.rs",
+            "+ src/wackend/ssh.rs",
+            "+ src/wackend/throttle.rs",
+        ]
+    );
+    assert_eq!(
+        filtered(&["--path", "s*.rs", "--exclude", "src/backend"]),
+        [
+            "+ src/wackend/s3.rs",
+            "+ src/wackend/semaphored.rs",
+            "+ src/wackend/ssh.rs",
+        ]
+    );
+    assert_eq!(
+        filtered(&["--path", "src/*.rs"]),
+        ["- src/diff.rs", "C src/lib.rs"]
+    );
+
     // Wipe the slate.
     cli_run(working_path, backup_path)?
         .arg("backup")
@@ -111,6 +199,13 @@ fn diff_src() -> Result<()> {
     unix::fs::symlink("/dev/null", working_path.join("src/ls.rs"))?;
 
     compare(&["- src/ls.rs", "+ src/ls.rs -> /dev/null", "T src/"]);
+    let summary_run = cli_run(working_path, backup_path)?
+        .args(["diff", "-s", "LAST"])
+        .assert()
+        .success();
+    let summary = stdout(&summary_run);
+    assert!(summary.starts_with("+0 -0 C0 M"), "{summary}");
+    assert!(summary.trim().ends_with(", 1 changed type"), "{summary}");
 
     // Wipe the slate.
     cli_run(working_path, backup_path)?
@@ -129,5 +224,343 @@ fn diff_src() -> Result<()> {
         "T src/",
     ]);
 
+    let json_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--json", "LAST"])
+        .assert()
+        .success();
+    let changes: Vec<serde_json::Value> = stdout(&json_run)
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(
+        changes,
+        [serde_json::json!({
+            "change": "contents",
+            "path": "src/ls.rs",
+            "type": "symlink",
+            "old_target": "/dev/null",
+            "new_target": "/dev/urandom",
+        })]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn diff_respects_skips() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let stuff = working_path.join("stuff");
+    fs::create_dir(&stuff)?;
+    fs::write(stuff.join("keep.txt"), "keep me")?;
+    fs::write(stuff.join("skip.log"), "skip me")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--skip", r"\.log$"])
+        .arg(&stuff)
+        .assert()
+        .success();
+
+    // The skipped file is still on disk, but it shouldn't look new.
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["diff", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&diff_run).trim(), "");
+
+    // Other new files should still show up, though.
+    fs::write(stuff.join("new.txt"), "new")?;
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["diff", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&diff_run).trim(), "+ stuff/new.txt");
+    Ok(())
+}
+
+#[test]
+fn three_way_diff() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let dir = working_path.join("dir");
+    fs::create_dir(&dir)?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let snapshot = |files: &[(&str, Option<&str>)]| -> Result<()> {
+        for (name, contents) in files {
+            let p = dir.join(name);
+            match contents {
+                Some(c) => fs::write(p, c)?,
+                None => fs::remove_file(p)?,
+            }
+        }
+        cli_run(working_path, backup_path)?
+            .arg("backup")
+            .arg(&dir)
+            .assert()
+            .success();
+        Ok(())
+    };
+
+    // The base...
+    snapshot(&[
+        ("a", Some("a")),
+        ("b", Some("b")),
+        ("c", Some("c")),
+        ("d", Some("d")),
+    ])?;
+    // ...one branch...
+    snapshot(&[("a", Some("A1")), ("b", None), ("d", Some("D"))])?;
+    // ...and the other.
+    snapshot(&[
+        ("a", Some("A2")),
+        ("b", Some("b")),
+        ("c", Some("C")),
+        ("d", Some("D")),
+    ])?;
+
+    let run = cli_run(working_path, backup_path)?
+        .args(["diff", "--base", "LAST~2", "LAST~", "LAST"])
+        .assert()
+        .success();
+    let lines: Vec<&str> = stdout(&run).trim().lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "!1 C dir/a",
+            "!2 C dir/a",
+            "1  - dir/b",
+            " 2 C dir/c",
+            "12 C dir/d",
+        ]
+    );
+
+    // Show what didn't change, too.
+    // (Contents only, since reading files for the backups bumps atimes.)
+    let run = cli_run(working_path, backup_path)?
+        .args(["diff", "--all", "--contents-only", "LAST~2", "LAST~"])
+        .assert()
+        .success();
+    let lines: Vec<&str> = stdout(&run).trim().lines().collect();
+    assert_eq!(lines, ["C dir/a", "- dir/b", "= dir/c", "C dir/d"]);
+
+    // Unchanged directories are printed once.
+    let run = cli_run(working_path, backup_path)?
+        .args(["diff", "-a", "--contents-only", "LAST", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&run).trim(), "= dir/");
+
+    // Needs two snapshots to compare
+    cli_run(working_path, backup_path)?
+        .args(["diff", "--base", "LAST~2", "LAST"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn diff_dereference() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    fs::create_dir_all(src.join("real"))?;
+    fs::write(src.join("real/a.txt"), "followed")?;
+    unix::fs::symlink("real", src.join("link"))?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--dereference"])
+        .arg(&src)
+        .assert()
+        .success();
+
+    let diff = |args: &[&str]| {
+        cli_run(working_path, backup_path)
+            .unwrap()
+            .arg("diff")
+            .args(args)
+            .arg("LAST")
+            .assert()
+    };
+
+    // Comparing links as links, the directory we backed up is now a symlink...
+    let as_links = diff(&[]).success();
+    assert!(stdout(&as_links).contains("+ src/link -> real"));
+    // ...but following them, nothing changed.
+    let followed = diff(&["-L"]).success();
+    assert_eq!(stdout(&followed).trim(), "");
+
+    // Loops are an error, not a stack overflow.
+    unix::fs::symlink("..", src.join("real/up"))?;
+    let looped = diff(&["--dereference"]).failure();
+    assert!(
+        stderr(&looped).contains("Symlink loop"),
+        "{}",
+        stderr(&looped)
+    );
+    Ok(())
+}
+
+#[test]
+fn diff_to_parent() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let stuff = working_path.join("stuff");
+    fs::create_dir(&stuff)?;
+    fs::write(stuff.join("old.txt"), "old")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&stuff)
+        .assert()
+        .success();
+
+    // The first backup of some paths has nothing to compare to.
+    cli_run(working_path, backup_path)?
+        .args(["diff", "--parent", "LAST"])
+        .assert()
+        .failure();
+
+    fs::write(stuff.join("new.txt"), "new")?;
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&stuff)
+        .assert()
+        .success();
+
+    // Even with nothing changed on the filesystem since,
+    // we can see what the last backup picked up.
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["diff", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&diff_run).trim(), "");
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--parent", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&diff_run).trim(), "+ stuff/new.txt");
+
+    // Additions are green, if we ask for color.
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["--color", "always", "diff", "--parent", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&diff_run).trim(), "\x1b[32m+ stuff/new.txt\x1b[0m");
+
+    // Tagging the parent gives it a new ID, and the child still points at the old one.
+    cli_run(working_path, backup_path)?
+        .args(["snapshot", "tag", "LAST~", "old"])
+        .assert()
+        .success();
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--parent", "LAST"])
+        .assert()
+        .failure();
+    assert!(
+        stderr(&diff_run).contains("is missing (it was forgotten, or rewritten"),
+        "{}",
+        stderr(&diff_run)
+    );
+    Ok(())
+}
+
+#[test]
+fn diff_exit_code() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let stuff = working_path.join("stuff");
+    fs::create_dir(&stuff)?;
+    fs::write(stuff.join("old.txt"), "old")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&stuff)
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .args(["diff", "--exit-code", "LAST"])
+        .assert()
+        .success();
+
+    // Metadata changes only count if we're looking at them.
+    fs::File::options()
+        .write(true)
+        .open(stuff.join("old.txt"))?
+        .set_modified(std::time::SystemTime::UNIX_EPOCH)?;
+    cli_run(working_path, backup_path)?
+        .args(["diff", "--exit-code", "LAST"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .args(["diff", "--exit-code", "--metadata", "LAST"])
+        .assert()
+        .code(1);
+
+    fs::write(stuff.join("new.txt"), "new")?;
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--exit-code", "LAST"])
+        .assert()
+        .code(1);
+    assert_eq!(stdout(&diff_run).trim(), "+ stuff/new.txt");
+    // It's not an error.
+    assert!(
+        !stderr(&diff_run).contains("ERROR"),
+        "{}",
+        stderr(&diff_run)
+    );
+
+    // Errors are, and shouldn't look like differences.
+    cli_run(working_path, backup_path)?
+        .args(["diff", "--exit-code", "no-such-snapshot"])
+        .assert()
+        .code(2);
+    cli_run(working_path, backup_path)?
+        .args(["diff", "no-such-snapshot"])
+        .assert()
+        .code(1);
     Ok(())
 }
@@ -0,0 +1,40 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn discover_repository() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    // Not inside a repo? Complain.
+    let lost = cli_run_without_repository(working_path)?
+        .arg("snapshots")
+        .assert()
+        .failure();
+    assert!(stderr(&lost).contains("No repository found"));
+
+    // But we can find it from a subdirectory.
+    cli_run_without_repository(&backup_path.join("packs"))?
+        .arg("snapshots")
+        .assert()
+        .success();
+
+    // Init never goes looking.
+    cli_run_without_repository(backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .failure();
+    Ok(())
+}
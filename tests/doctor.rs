@@ -0,0 +1,69 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn round_trip() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let doctor_run = cli_run(working_path, backup_path)?
+        .arg("doctor")
+        .assert()
+        .success();
+    let out = stdout(&doctor_run);
+    for step in ["open", "write", "list", "read", "remove"] {
+        assert!(
+            out.lines().any(|l| l.starts_with(step) && l.contains("ok")),
+            "{out}"
+        );
+    }
+
+    // It cleaned up after itself.
+    assert!(!backup_path.join("healthcheck").exists());
+    Ok(())
+}
+
+#[test]
+fn mismatched_filters() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    let config_path = backup_path.join("config.toml");
+    let config = std::fs::read_to_string(&config_path)?;
+    std::fs::write(
+        &config_path,
+        format!("filter = \"gzip\"\nunfilter = \"cat\"\n{config}"),
+    )?;
+
+    let doctor_run = cli_run(working_path, backup_path)?
+        .arg("doctor")
+        .assert()
+        .failure();
+    assert!(
+        stderr(&doctor_run).contains("`cat` doesn't undo `gzip`"),
+        "{}",
+        stderr(&doctor_run)
+    );
+    // And it didn't get as far as writing anything.
+    assert!(!backup_path.join("healthcheck").exists());
+    Ok(())
+}
@@ -85,3 +85,52 @@ fn dump_src() -> Result<()> {
     // std::mem::forget(backup_dir);
     Ok(())
 }
+
+#[cfg(unix)]
+#[test]
+fn dump_paths() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    std::fs::create_dir_all(src.join("dir"))?;
+    std::fs::write(src.join("dir/a.txt"), "meow")?;
+    std::os::unix::fs::symlink("dir", src.join("link"))?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+
+    let dump = |path: &str| {
+        cli_run(working_path, backup_path)
+            .unwrap()
+            .args(["dump", "LAST", path])
+            .assert()
+    };
+
+    // ./ is dropped from what we print.
+    let dir = dump("./src/./dir").success();
+    assert_eq!(stdout(&dir), "src/dir/\nsrc/dir/a.txt\n");
+
+    // A symlink at the end of the path is dumped as such...
+    let link = dump("src/link").success();
+    assert_eq!(stdout(&link), "src/link -> dir\n");
+
+    // ...but we don't follow one partway through.
+    let through = dump("src/link/a.txt").failure();
+    assert!(
+        stderr(&through).contains("src/link is a symlink, not a directory"),
+        "{}",
+        stderr(&through)
+    );
+    Ok(())
+}
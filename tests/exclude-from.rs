@@ -0,0 +1,49 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn exclude_from() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let foo = working_path.join("foo");
+    fs::create_dir_all(foo.join("target"))?;
+    fs::write(foo.join("keep.txt"), "keep me")?;
+    fs::write(foo.join("noisy.log"), "leave me")?;
+    fs::write(foo.join("target/junk"), "leave me too")?;
+
+    let patterns = working_path.join("patterns.txt");
+    fs::write(&patterns, "# Build stuff\n/target$\n\n# Logs\n\\.log$\n")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg("--exclude-from")
+        .arg(&patterns)
+        .arg(&foo)
+        .assert()
+        .success();
+
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&ls_run).trim().lines().collect::<Vec<_>>(),
+        ["foo/", "foo/keep.txt"]
+    );
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn exclude_if_present() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let foo = working_path.join("foo");
+    fs::create_dir_all(foo.join("keep"))?;
+    fs::create_dir_all(foo.join("skipme/deeper"))?;
+    fs::write(foo.join("keep/a.txt"), "keep me")?;
+    fs::write(foo.join("skipme/.nobackup"), "")?;
+    fs::write(foo.join("skipme/deeper/b.txt"), "leave me")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--exclude-if-present", ".nobackup"])
+        .arg(&foo)
+        .assert()
+        .success();
+
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    let ls_output = stdout(&ls_run);
+    assert!(ls_output.contains("keep/a.txt"), "{ls_output}");
+    assert!(!ls_output.contains("skipme"), "{ls_output}");
+
+    // The skipped directory is skipped when diffing too,
+    // so its contents aren't reported as added.
+    let diff_run = cli_run(working_path, backup_path)?
+        .args(["diff", "LAST"])
+        .assert()
+        .success();
+    let diff_output = stdout(&diff_run);
+    assert!(!diff_output.contains("skipme"), "{diff_output}");
+    Ok(())
+}
@@ -52,3 +52,170 @@ fn backup_src() -> Result<()> {
     // std::mem::forget(backup_dir);
     Ok(())
 }
+
+#[test]
+fn backup_with_globs() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    std::fs::create_dir_all(src.join("crate/target/debug"))?;
+    std::fs::create_dir_all(src.join("notes"))?;
+    std::fs::write(src.join("crate/target/debug/big"), "build junk")?;
+    std::fs::write(src.join("crate/main.rs"), "fn main() {}")?;
+    // A file named target isn't a target/ directory.
+    std::fs::write(src.join("notes/target"), "goals")?;
+    std::fs::write(src.join("notes/today.log"), "stuff")?;
+    std::fs::write(src.join("notes/keep.log"), "important")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .args([
+            "backup",
+            "--skip",
+            "glob:**/target/",
+            "--skip",
+            "glob:*.log",
+            "--skip",
+            "!glob:keep.log",
+        ])
+        .arg(&src)
+        .assert()
+        .success();
+
+    let ls = cli_run(working_path, backup_path)?
+        .args(["ls", "HEAD"])
+        .assert()
+        .success();
+    let ls = stdout(&ls);
+    let mut files: Vec<&str> = ls.lines().collect();
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            "src/",
+            "src/crate/",
+            "src/crate/main.rs",
+            "src/notes/",
+            "src/notes/keep.log",
+            "src/notes/target"
+        ],
+        "{ls}"
+    );
+    Ok(())
+}
+
+#[test]
+fn backup_with_excludes() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    std::fs::create_dir_all(src.join(".cache"))?;
+    std::fs::write(src.join(".cache/junk"), "junk")?;
+    std::fs::write(src.join("a.tmp"), "temporary")?;
+    std::fs::write(src.join("b.tmp"), "also temporary")?;
+    std::fs::write(src.join("c.txt"), "keeper")?;
+
+    let ignore_file = working_path.join("ignore");
+    std::fs::write(&ignore_file, "# Like a .gitignore\n.cache/\n*.tmp\n")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    // Command-line rules come after the file's, so they win.
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg("--exclude-file")
+        .arg(&ignore_file)
+        .args(["--exclude", "!b.tmp"])
+        .arg(&src)
+        .assert()
+        .success();
+
+    let ls = cli_run(working_path, backup_path)?
+        .args(["ls", "HEAD"])
+        .assert()
+        .success();
+    let ls = stdout(&ls);
+    let mut files: Vec<&str> = ls.lines().collect();
+    files.sort();
+    assert_eq!(files, ["src/", "src/b.tmp", "src/c.txt"], "{ls}");
+    Ok(())
+}
+
+#[test]
+fn init_gpg_filter_env() -> Result<()> {
+    let gnupg_dir = tempdir()?;
+    let gnupg_path = gnupg_dir.path();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(gnupg_path, std::fs::Permissions::from_mode(0o700))?;
+    }
+    let gpg = |args: &[&str]| {
+        std::process::Command::new("gpg")
+            .env("GNUPGHOME", gnupg_path)
+            .args(args)
+            .output()
+    };
+    match gpg(&[
+        "--batch",
+        "--passphrase",
+        "",
+        "--quick-gen-key",
+        "backpak-test@example.com",
+        "default",
+        "default",
+        "never",
+    ]) {
+        Ok(o) if o.status.success() => {}
+        _ => {
+            eprintln!("Couldn't make a gpg key; skipping");
+            return Ok(());
+        }
+    }
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .env("GNUPGHOME", gnupg_path)
+        .args(["init", "--gpg", "backpak-test@example.com"])
+        .args(["--filter-env", "GPG_AGENT_INFO"])
+        .arg("filesystem")
+        .assert()
+        .success();
+    let config = std::fs::read_to_string(backup_path.join("config.toml"))?;
+    assert!(
+        config.contains("filter_env = [\"GPG_AGENT_INFO\"]"),
+        "{config}"
+    );
+
+    cli_run(working_path, backup_path)?
+        .env("GNUPGHOME", gnupg_path)
+        .arg("doctor")
+        .assert()
+        .success();
+
+    // Don't leave its agent running.
+    std::process::Command::new("gpgconf")
+        .env("GNUPGHOME", gnupg_path)
+        .args(["--kill", "gpg-agent"])
+        .output()
+        .ok();
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn find_paths() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "meow")?;
+    fs::write(src.join("b.md"), "woof")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let backup = || {
+        cli_run(working_path, backup_path)
+            .unwrap()
+            .arg("backup")
+            .arg(&src)
+            .assert()
+            .success();
+    };
+    backup();
+    fs::write(src.join("a.txt"), "meow meow")?;
+    backup();
+    fs::remove_file(src.join("a.txt"))?;
+    backup();
+
+    let find = |args: &[&str]| -> Result<Vec<String>> {
+        let run = cli_run(working_path, backup_path)?
+            .arg("find")
+            .args(args)
+            .assert()
+            .success();
+        Ok(stdout(&run).lines().map(str::to_owned).collect())
+    };
+
+    // Newest first: the second backup (with the longer file), then the first.
+    let found = find(&["src/a.txt"])?;
+    assert_eq!(found.len(), 2, "{found:?}");
+    assert!(found[0].contains(" 9 B "), "{found:?}");
+    assert!(found[1].contains(" 4 B "), "{found:?}");
+    assert!(found.iter().all(|l| l.ends_with(" src/a.txt")), "{found:?}");
+
+    // Names without a slash match anywhere.
+    assert_eq!(find(&["a.txt"])?, found);
+
+    let newest = find(&["--newest-only", "src/a.txt"])?;
+    assert_eq!(newest, &found[..1]);
+
+    // Each pattern stops on its own.
+    let both = find(&["--newest-only", "*.txt", "*.md"])?;
+    assert_eq!(both.len(), 2, "{both:?}");
+    assert!(both[0].ends_with(" src/b.md"), "{both:?}");
+    assert_eq!(both[1], found[0]);
+
+    // Directories are marked as such.
+    let dirs = find(&["--newest-only", "src"])?;
+    assert_eq!(dirs.len(), 1, "{dirs:?}");
+    assert!(dirs[0].ends_with(" src/"), "{dirs:?}");
+
+    assert!(find(&["nope.txt"])?.is_empty());
+    Ok(())
+}
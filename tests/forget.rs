@@ -0,0 +1,140 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn forget_by_policy() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    for file in ["README.md", "Cargo.toml", "Cargo.toml"] {
+        cli_run(working_path, backup_path)?
+            .arg("backup")
+            .arg(std::env::current_dir()?.join(file))
+            .assert()
+            .success();
+    }
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 3);
+
+    // Policies and snapshot lists don't mix, and you need one or the other.
+    cli_run(working_path, backup_path)?
+        .args(["forget", "--keep-last", "1", "LAST"])
+        .assert()
+        .failure();
+    cli_run(working_path, backup_path)?
+        .arg("forget")
+        .assert()
+        .failure();
+
+    // Nor do policies that keep nothing.
+    cli_run(working_path, backup_path)?
+        .args(["forget", "--keep-daily", "0"])
+        .assert()
+        .failure();
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 3);
+
+    // Policies apply to each set of paths separately,
+    // so keeping two keeps both Cargo.toml backups (and the README one).
+    let dry_run = cli_run(working_path, backup_path)?
+        .args(["forget", "-n", "--keep-last", "2"])
+        .assert()
+        .success();
+    assert!(!stderr(&dry_run).contains("Would remove"));
+
+    let dry_run = cli_run(working_path, backup_path)?
+        .args(["forget", "-n", "--keep-last", "1"])
+        .assert()
+        .success();
+    assert_eq!(stderr(&dry_run).matches("Would remove").count(), 1);
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 3);
+
+    // All three were taken today, so keeping one a day
+    // keeps just the last of each.
+    cli_run(working_path, backup_path)?
+        .args(["forget", "--keep-daily", "7"])
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 2);
+
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&ls_run).trim(), "Cargo.toml");
+    Ok(())
+}
+
+#[test]
+fn forget_sizes() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    // Two snapshots with the same data, then one with different data.
+    // (All of the same file, so forget's policy sees them together.)
+    let source_dir = tempdir()?;
+    let source = source_dir.path().join("notes.txt");
+    for contents in ["README.md", "", "LICENSE.txt"] {
+        // Leave the file alone the second time so nothing about it changes -
+        // not even its atime, which reading it won't bump if it's newer than its mtime.
+        if !contents.is_empty() {
+            std::fs::copy(std::env::current_dir()?.join(contents), &source)?;
+            std::fs::File::options()
+                .write(true)
+                .open(&source)?
+                .set_times(
+                    std::fs::FileTimes::new()
+                        .set_modified(std::time::UNIX_EPOCH)
+                        .set_accessed(std::time::SystemTime::now()),
+                )?;
+        }
+        cli_run(working_path, backup_path)?
+            .arg("backup")
+            .arg(&source)
+            .assert()
+            .success();
+    }
+
+    let dry_run = cli_run(working_path, backup_path)?
+        .args(["forget", "-n", "--sizes", "--keep-last", "1"])
+        .assert()
+        .success();
+    let out = stderr(&dry_run);
+    // Neither README snapshot has anything the other doesn't...
+    assert_eq!(out.matches("(0 B only it uses)").count(), 2, "{out}");
+    // ...but forgetting both frees what they share.
+    assert!(out.contains("Forgetting these would free"), "{out}");
+    assert!(!out.contains("would free 0 B"), "{out}");
+
+    let sizes_run = cli_run(working_path, backup_path)?
+        .args(["snapshots", "--sizes"])
+        .assert()
+        .success();
+    let sizes = stdout(&sizes_run)
+        .lines()
+        .filter(|l| l.starts_with("Sizes:"))
+        .collect::<Vec<_>>();
+    assert_eq!(sizes.len(), 3, "{sizes:?}");
+    assert!(sizes[0].ends_with("/ 0 B exclusive)"), "{sizes:?}");
+    assert!(sizes[1].ends_with("/ 0 B exclusive)"), "{sizes:?}");
+    assert!(!sizes[2].ends_with("/ 0 B exclusive)"), "{sizes:?}");
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn legacy_filter() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let old = working_path.join("old");
+    let new = working_path.join("new");
+    fs::create_dir_all(&old)?;
+    fs::create_dir_all(&new)?;
+    fs::write(old.join("a.txt"), "written with gzip")?;
+    fs::write(new.join("b.txt"), "written with xz")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(
+        &config_path,
+        format!("filter = \"gzip\"\nunfilter = \"gzip -d\"\n{config}"),
+    )?;
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&old)
+        .assert()
+        .success();
+
+    // Switch filters. Without telling backpak about the old one, we can't read old objects...
+    fs::write(
+        &config_path,
+        format!("filter = \"xz\"\nunfilter = \"xz -d\"\n{config}"),
+    )?;
+    cli_run(working_path, backup_path)?
+        .args(["check", "--read-packs"])
+        .assert()
+        .failure();
+
+    // ...but with it, we can read both.
+    fs::write(
+        &config_path,
+        format!(
+            "filter = \"xz\"\nunfilter = \"xz -d\"\nlegacy_unfilters = [\"gzip -d\"]\n{config}"
+        ),
+    )?;
+    let new_run = cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&new)
+        .assert()
+        .success();
+    // We notice the change, but since we have the old unfilter, no need to nag about it.
+    assert!(stderr(&new_run).contains("Filter changed since snapshot"));
+    assert!(!stderr(&new_run).contains("Add the previous unfilter"));
+    // The commands might hold secrets; snapshots only get their hashes.
+    let snapshots = cli_run(working_path, backup_path)?
+        .args(["cat", "snapshot", "LAST"])
+        .assert()
+        .success();
+    assert!(stdout(&snapshots).contains("\"unfilter\""));
+    assert!(!stdout(&snapshots).contains("gzip"));
+
+    cli_run(working_path, backup_path)?
+        .args(["check", "--read-packs", "--dereference-and-hash"])
+        .assert()
+        .success();
+    Ok(())
+}
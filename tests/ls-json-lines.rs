@@ -0,0 +1,65 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn ls_json_lines() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let foo = working_path.join("foo");
+    fs::create_dir_all(foo.join("sub"))?;
+    fs::write(foo.join("b.txt"), "bee")?;
+    fs::write(foo.join("sub/a.txt"), "ay")?;
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("b.txt", foo.join("c"))?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&foo)
+        .assert()
+        .success();
+
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", "--json-lines", "LAST"])
+        .assert()
+        .success();
+    let lines: Vec<serde_json::Value> = stdout(&ls_run)
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+
+    // Sorted, with directories before their contents.
+    let paths: Vec<&str> = lines.iter().map(|l| l["path"].as_str().unwrap()).collect();
+    #[cfg(unix)]
+    assert_eq!(
+        paths,
+        ["foo", "foo/b.txt", "foo/c", "foo/sub", "foo/sub/a.txt"]
+    );
+
+    let b = &lines[1];
+    assert_eq!(b["type"], "file");
+    assert_eq!(b["size"], 3);
+    assert!(b["mtime"].is_string());
+    assert_eq!(lines[0]["type"], "directory");
+    assert!(lines[0].get("size").is_none());
+    #[cfg(unix)]
+    {
+        assert_eq!(lines[2]["type"], "symlink");
+        assert_eq!(lines[2]["target"], "b.txt");
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn list_packs() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "--pack-size", "20KB", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(std::env::current_dir()?.join("src"))
+        .assert()
+        .success();
+
+    let pack_count = files_in(&backup_path.join("packs")).count();
+    assert!(pack_count > 1);
+
+    let packs_run = cli_run(working_path, backup_path)?
+        .args(["packs", "--sort", "size"])
+        .assert()
+        .success();
+    let lines: Vec<_> = stdout(&packs_run).lines().collect();
+    assert_eq!(lines.len(), pack_count);
+    assert!(lines.iter().all(|l| l.contains("blobs")));
+
+    let json_run = cli_run(working_path, backup_path)?
+        .args(["packs", "--json", "--sort", "fill"])
+        .assert()
+        .success();
+    let packs: serde_json::Value = serde_json::from_str(stdout(&json_run))?;
+    let packs = packs.as_array().unwrap();
+    assert_eq!(packs.len(), pack_count);
+    let fills: Vec<f64> = packs.iter().map(|p| p["fill"].as_f64().unwrap()).collect();
+    assert!(fills.is_sorted());
+    assert!(packs.iter().all(|p| p["indexed"] == true));
+
+    let first = packs[0]["id"].as_str().unwrap();
+    let info_run = cli_run(working_path, backup_path)?
+        .args(["pack", "info", first])
+        .assert()
+        .success();
+    let info = stdout(&info_run);
+    assert!(info.contains("compression"), "{info}");
+    assert!(info.lines().any(|l| l.contains("chunk")), "{info}");
+    let anomaly = format!("Pack {first}:");
+    assert!(
+        !stderr(&info_run).contains(&anomaly),
+        "{}",
+        stderr(&info_run)
+    );
+
+    cli_run(working_path, backup_path)?
+        .args([
+            "pack",
+            "info",
+            "000000000000000000000000000000000000000000000000000",
+        ])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn pack_size_limits() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let tiny = cli_run(working_path, backup_path)?
+        .args(["init", "--pack-size", "1KB", "filesystem"])
+        .assert()
+        .failure();
+    assert!(stderr(&tiny).contains("out of range"));
+
+    // Outside the tests, packs are at least a MiB.
+    let small = cli_run(working_path, backup_path)?
+        .env_remove("BACKPAK_TEST_TINY_PACKS")
+        .args(["init", "--pack-size", "20KB", "filesystem"])
+        .assert()
+        .failure();
+    assert!(stderr(&small).contains("out of range"));
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    // Hand-edited configs still let us read the repository (with a warning)...
+    let config_path = backup_path.join("config.toml");
+    let config = std::fs::read_to_string(&config_path)?;
+    let huge = config
+        .lines()
+        .map(|l| {
+            if l.starts_with("pack_size") {
+                "pack_size = \"50 GB\""
+            } else {
+                l
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(&config_path, huge)?;
+    let huge_check = cli_run(working_path, backup_path)?
+        .arg("check")
+        .assert()
+        .success();
+    assert_eq!(
+        stderr(&huge_check).matches("out of range").count(),
+        1,
+        "{}",
+        stderr(&huge_check)
+    );
+
+    // ...but not write packs to it.
+    let huge_backup = cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(std::env::current_dir()?.join("README.md"))
+        .assert()
+        .failure();
+    assert!(stderr(&huge_backup).contains("out of range"));
+    Ok(())
+}
+
+#[test]
+fn read_data() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "--pack-size", "20KB", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(std::env::current_dir()?.join("src"))
+        .assert()
+        .success();
+
+    let pack_count = files_in(&backup_path.join("packs")).count();
+
+    let all = cli_run(working_path, backup_path)?
+        .args(["check", "--read-data"])
+        .assert()
+        .success();
+    let all = stderr(&all);
+    assert!(
+        all.contains(&format!("in {pack_count} of {pack_count} packs")),
+        "{all}"
+    );
+
+    // Every pack lands in exactly one subset.
+    let mut sampled = 0;
+    for n in 1..=3 {
+        let subset = cli_run(working_path, backup_path)?
+            .args(["check", "--read-data-subset", &format!("{n}/3")])
+            .assert()
+            .success();
+        let subset = stderr(&subset);
+        let (_, count) = subset.split_once("Hashing every blob in ").unwrap();
+        sampled += count.split(' ').next().unwrap().parse::<usize>()?;
+    }
+    assert_eq!(sampled, pack_count);
+
+    cli_run(working_path, backup_path)?
+        .args(["check", "--read-data-subset", "4/3"])
+        .assert()
+        .failure();
+
+    // Mess up a pack and make sure we notice.
+    let victim = files_in(&backup_path.join("packs")).next().unwrap();
+    let mut contents = std::fs::read(&victim)?;
+    // Just past the magic bytes, in the blob stream
+    contents[20] ^= 0xff;
+    std::fs::write(&victim, contents)?;
+
+    let borked = cli_run(working_path, backup_path)?
+        .args(["check", "--read-data"])
+        .assert()
+        .failure();
+    assert!(stderr(&borked).contains("1 packs with corrupt"));
+    Ok(())
+}
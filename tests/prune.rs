@@ -66,6 +66,26 @@ fn backup_src() -> Result<()> {
     let before_packs = files_in(backup_path.join("packs")).collect::<HashSet<_>>();
     assert_eq!(3, before_packs.len());
 
+    // Previewing agrees with what prune's about to do (and doesn't change anything).
+    let preview = cli_run(working_path, backup_path)?
+        .args(["reachable", "--dead-only"])
+        .assert()
+        .success();
+    let preview = stdout(&preview);
+    assert_eq!(
+        preview.lines().filter(|l| l.contains("blobs live")).count(),
+        2,
+        "{preview}"
+    );
+    assert!(
+        preview.contains("0 packs entirely dead, 2 partly dead"),
+        "{preview}"
+    );
+    assert_eq!(
+        before_packs,
+        files_in(backup_path.join("packs")).collect::<HashSet<_>>()
+    );
+
     // Dry run shouldn't do anything!
     cli_run(working_path, backup_path)?
         .args(&["prune", "-n"])
@@ -74,7 +94,8 @@ fn backup_src() -> Result<()> {
         .stdout(
             contains("Keep 1 packs")
                 .and(contains("rewrite 2"))
-                .and(contains("drop 0 (0 B), and replace the 2 current indexes")),
+                .and(contains("drop 0 (0 B), and replace the 2 current indexes"))
+                .and(contains("Repacking downloads about")),
         );
 
     // They're the same!
@@ -179,3 +200,82 @@ fn no_repacks_needed() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn keeps_packs_until_new_index_is_uploaded() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(project_dir.join("src"))
+        .assert()
+        .success();
+    let snapshots = files_in(backup_path.join("snapshots")).collect::<Vec<_>>();
+    let first_snapshot = snapshots[0].file_stem().unwrap().to_str().unwrap();
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(project_dir.join("src/ui"))
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .args(["forget", first_snapshot])
+        .assert()
+        .success();
+
+    let before_packs = files_in(backup_path.join("packs")).collect::<HashSet<_>>();
+    let before_indexes = files_in(backup_path.join("indexes")).collect::<HashSet<_>>();
+
+    // Let every pack through but refuse to upload the replacement index
+    // (going by its magic bytes).
+    let config_path = backup_path.join("config.toml");
+    let config = std::fs::read_to_string(&config_path)?;
+    std::fs::write(
+        &config_path,
+        "filter = 'm=$(dd bs=1 count=9 2>/dev/null); \
+                   [ \"$m\" = MKBAKIDX1 ] && exit 1; printf %s \"$m\"; exec cat'\n\
+         unfilter = 'cat'\n"
+            .to_owned()
+            + &config,
+    )?;
+
+    cli_run(working_path, backup_path)?
+        .arg("prune")
+        .assert()
+        .failure();
+
+    // Everything we had is still there, so the old indexes still hold.
+    let after_packs = files_in(backup_path.join("packs")).collect::<HashSet<_>>();
+    let after_indexes = files_in(backup_path.join("indexes")).collect::<HashSet<_>>();
+    assert!(before_packs.is_subset(&after_packs));
+    assert_eq!(before_indexes, after_indexes);
+
+    std::fs::write(&config_path, config)?;
+    cli_run(working_path, backup_path)?
+        .arg("check")
+        .assert()
+        .success();
+
+    // And once the index can go up, the prune goes through.
+    cli_run(working_path, backup_path)?
+        .arg("prune")
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+    Ok(())
+}
@@ -54,9 +54,7 @@ fn restore_src() -> Result<()> {
             .lines()
             // Strip Opening... Building a master index... snapshot <hash>...
             .skip(3)
-            // I don't care about atime. They change if you sneeze (and based on mount opts).
-            .filter(|l| !l.starts_with("A "))
-            .map(str::to_owned)
+            .filter_map(ignore_atime)
             .collect();
         restore_output
     };
@@ -100,20 +98,36 @@ fn restore_src() -> Result<()> {
             "+ src/backend/",
             "+ src/backend/backblaze.rs",
             "+ src/backend/cache.rs",
+            "+ src/backend/error.rs",
             "+ src/backend/filter.rs",
             "+ src/backend/fs.rs",
+            "+ src/backend/listings.rs",
             "+ src/backend/memory.rs",
+            "+ src/backend/mirror.rs",
+            "+ src/backend/rate_limited.rs",
+            "+ src/backend/retry.rs",
+            "+ src/backend/s3.rs",
             "+ src/backend/semaphored.rs",
+            "+ src/backend/ssh.rs",
+            "+ src/backend/throttle.rs",
             "+ src/diff.rs",
             "C src/lib.rs",
             "P src/main.rs",
             "- src/wackend/",
             "- src/wackend/backblaze.rs",
             "- src/wackend/cache.rs",
+            "- src/wackend/error.rs",
             "- src/wackend/filter.rs",
             "- src/wackend/fs.rs",
+            "- src/wackend/listings.rs",
             "- src/wackend/memory.rs",
+            "- src/wackend/mirror.rs",
+            "- src/wackend/rate_limited.rs",
+            "- src/wackend/retry.rs",
+            "- src/wackend/s3.rs",
             "- src/wackend/semaphored.rs",
+            "- src/wackend/ssh.rs",
+            "- src/wackend/throttle.rs",
             "T src/",
         ],
         &[],
@@ -167,10 +181,18 @@ fn restore_src() -> Result<()> {
             "+ elsewhere/backend/",
             "+ elsewhere/backend/backblaze.rs",
             "+ elsewhere/backend/cache.rs",
+            "+ elsewhere/backend/error.rs",
             "+ elsewhere/backend/filter.rs",
             "+ elsewhere/backend/fs.rs",
+            "+ elsewhere/backend/listings.rs",
             "+ elsewhere/backend/memory.rs",
+            "+ elsewhere/backend/mirror.rs",
+            "+ elsewhere/backend/rate_limited.rs",
+            "+ elsewhere/backend/retry.rs",
+            "+ elsewhere/backend/s3.rs",
             "+ elsewhere/backend/semaphored.rs",
+            "+ elsewhere/backend/ssh.rs",
+            "+ elsewhere/backend/throttle.rs",
             "T elsewhere/",
         ],
         &["-o", moved_to],
@@ -228,9 +250,7 @@ fn restore_multipath() -> Result<()> {
             .lines()
             // Strip Opening... Building a master index... snapshot <hash>...
             .skip(3)
-            // I don't care about atime. They change if you sneeze (and based on mount opts).
-            .filter(|l| !l.starts_with("A "))
-            .map(str::to_owned)
+            .filter_map(ignore_atime)
             .collect();
         restore_output
     };
@@ -261,7 +281,7 @@ fn restore_multipath() -> Result<()> {
         "We spend eternity looking at pleasant moments—like today at the zoo. \
         Isn't this a nice moment?",
     )?;
-    compare(&["C LICENSE.txt", "C README.md"], &[]);
+    compare(&["C LICENSE.txt", "C README.md"], &["--check-space"]);
 
     // Multi-path retargeting:
     let out_path = &working_path.join("elsewhere");
@@ -275,3 +295,153 @@ fn restore_multipath() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn restore_with_prefetch() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home = working_path.join("home");
+    fs::create_dir(&home)?;
+
+    // Lots of little packs, in a repo we cache.
+    cli_run(working_path, backup_path)?
+        .args(["init", "--pack-size", "20KB", "filesystem"])
+        .assert()
+        .success();
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(
+        &config_path,
+        format!("filter = \"cat\"\nunfilter = \"cat\"\n{config}"),
+    )?;
+
+    let src = std::env::current_dir()?.join("src");
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    // Start cold so there's something to prefetch.
+    cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["cache", "clear"])
+        .assert()
+        .success();
+
+    let out = working_path.join("out");
+    fs::create_dir(&out)?;
+    let restore_run = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["restore", "--prefetch", "3", "--output"])
+        .arg(&out)
+        .arg("LAST")
+        .assert()
+        .success();
+    assert!(stderr(&restore_run).contains("Prefetching"));
+
+    assert!(
+        Command::new("diff")
+            .arg("-r")
+            .arg(&src)
+            .arg(&out)
+            .status()?
+            .success()
+    );
+    Ok(())
+}
+
+#[test]
+fn restore_into() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path().canonicalize()?;
+
+    let me = working_path.join("home/me");
+    fs::create_dir_all(me.join("work"))?;
+    fs::create_dir_all(me.join("play"))?;
+    fs::write(me.join("work/report.txt"), "due Friday")?;
+    fs::write(me.join("play/game.txt"), "high score")?;
+
+    cli_run(&working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(&working_path, backup_path)?
+        .arg("backup")
+        .arg(me.join("work"))
+        .arg(me.join("play"))
+        .assert()
+        .success();
+
+    let recovery = working_path.join("recovery");
+    fs::create_dir(&recovery)?;
+
+    // Whole paths...
+    cli_run(&working_path, backup_path)?
+        .args(["restore", "--into"])
+        .arg(&recovery)
+        .arg("LAST")
+        .assert()
+        .success();
+    let whole = recovery.join(me.strip_prefix("/")?);
+    assert_eq!(
+        fs::read_to_string(whole.join("work/report.txt"))?,
+        "due Friday"
+    );
+    assert_eq!(
+        fs::read_to_string(whole.join("play/game.txt"))?,
+        "high score"
+    );
+
+    // ...or with some taken off the front.
+    fs::remove_dir_all(&recovery)?;
+    fs::create_dir(&recovery)?;
+    cli_run(&working_path, backup_path)?
+        .args(["restore", "--strip-prefix"])
+        .arg(&me)
+        .arg("--into")
+        .arg(&recovery)
+        .arg("LAST")
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(recovery.join("work/report.txt"))?,
+        "due Friday"
+    );
+    assert_eq!(
+        fs::read_to_string(recovery.join("play/game.txt"))?,
+        "high score"
+    );
+
+    // Restoring again finds nothing to do.
+    let again = cli_run(&working_path, backup_path)?
+        .args(["restore", "--strip-prefix"])
+        .arg(&me)
+        .arg("--into")
+        .arg(&recovery)
+        .arg("LAST")
+        .assert()
+        .success();
+    assert!(
+        !stdout(&again)
+            .lines()
+            .any(|l| l.starts_with("+ ") || l.starts_with("C ")),
+        "{}",
+        stdout(&again)
+    );
+
+    // Prefixes have to match.
+    cli_run(&working_path, backup_path)?
+        .args(["restore", "--strip-prefix", "/nope", "--into"])
+        .arg(&recovery)
+        .arg("LAST")
+        .assert()
+        .failure();
+    Ok(())
+}
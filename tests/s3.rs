@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Context, Result, bail, ensure};
+use data_encoding::HEXLOWER;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+type Bucket = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+/// How many keys we list at once, so a few packs need a few pages.
+const PAGE_SIZE: usize = 2;
+
+/// Just enough of S3 (path-style, one bucket) to back up to,
+/// checking along the way that uploads over plain HTTP are signed.
+fn fake_s3() -> Result<(String, Bucket)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    let bucket = Bucket::default();
+    let b = bucket.clone();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let b = b.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve(conn?, &b) {
+                    eprintln!("Fake S3 failed: {e:?}");
+                }
+                anyhow::Ok(())
+            });
+        }
+    });
+    Ok((endpoint, bucket))
+}
+
+/// Answer requests on a connection until the client hangs up.
+fn serve(conn: TcpStream, bucket: &Bucket) -> Result<()> {
+    let mut reader = BufReader::new(conn.try_clone()?);
+    let mut conn = conn;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().context("No method")?.to_owned();
+        let target = parts.next().context("No target")?.to_owned();
+
+        let mut headers = BTreeMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (k, v) = line.split_once(':').context("Bad header")?;
+            headers.insert(k.to_ascii_lowercase(), v.trim().to_owned());
+        }
+        ensure!(
+            headers
+                .get("authorization")
+                .is_some_and(|a| a.contains("Credential=key/")),
+            "{method} {target} wasn't signed"
+        );
+        let len = headers
+            .get("content-length")
+            .map(|l| l.parse::<usize>())
+            .transpose()?
+            .unwrap_or(0);
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let key = path
+            .strip_prefix("/buck")
+            .context("Wrong bucket")?
+            .trim_start_matches('/');
+        let key = decode(key);
+        let (status, response) = match method.as_str() {
+            "PUT" => {
+                let hash = HEXLOWER.encode(&Sha256::digest(&body));
+                let claimed = headers.get("x-amz-content-sha256");
+                ensure!(
+                    claimed == Some(&hash),
+                    "Payload of {key} should be signed over HTTP, got {claimed:?}"
+                );
+                bucket.lock().unwrap().insert(key, body);
+                (200, vec![])
+            }
+            "GET" if key.is_empty() => (200, list(bucket, query)),
+            "GET" => match bucket.lock().unwrap().get(&key) {
+                Some(contents) => (200, contents.clone()),
+                None => (
+                    404,
+                    b"<Error><Code>NoSuchKey</Code><Message>Nope</Message></Error>".to_vec(),
+                ),
+            },
+            "DELETE" => {
+                bucket.lock().unwrap().remove(&key);
+                (204, vec![])
+            }
+            other => bail!("Unexpected {other}"),
+        };
+        write!(
+            conn,
+            "HTTP/1.1 {status} Whatever\r\nContent-Length: {}\r\n\r\n",
+            response.len()
+        )?;
+        conn.write_all(&response)?;
+    }
+}
+
+fn list(bucket: &Bucket, query: &str) -> Vec<u8> {
+    let params: BTreeMap<_, _> = query
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .map(|(k, v)| (k, decode(v)))
+        .collect();
+    let prefix = params.get("prefix").map(String::as_str).unwrap_or("");
+    let skip = params
+        .get("continuation-token")
+        .map(|t| t.parse().unwrap())
+        .unwrap_or(0);
+    let bucket = bucket.lock().unwrap();
+    let matching: Vec<_> = bucket
+        .iter()
+        .filter(|(k, _)| k.starts_with(prefix))
+        .collect();
+    let page = matching.iter().skip(skip).take(PAGE_SIZE);
+
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?><ListBucketResult>"#);
+    for (k, v) in page {
+        xml += &format!(
+            "<Contents><Key>{k}</Key><Size>{}</Size></Contents>",
+            v.len()
+        );
+    }
+    if skip + PAGE_SIZE < matching.len() {
+        xml += &format!(
+            "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+            skip + PAGE_SIZE
+        );
+    } else {
+        xml += "<IsTruncated>false</IsTruncated>";
+    }
+    xml += "</ListBucketResult>";
+    xml.into_bytes()
+}
+
+/// Undo percent-encoding
+fn decode(s: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' && tail.len() >= 2 {
+            let hex = std::str::from_utf8(&tail[..2]).unwrap();
+            bytes.push(u8::from_str_radix(hex, 16).unwrap());
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+#[test]
+fn s3_round_trip() -> Result<()> {
+    let (endpoint, bucket) = fake_s3()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "into the bucket")?;
+    fs::write(src.join("b.txt"), "and out again")?;
+
+    let repo = working_path.join("repo.toml");
+    cli_run(working_path, &repo)?
+        .args(["init", "--pack-size", "20KB", "s3", "--endpoint", &endpoint])
+        .args([
+            "--bucket",
+            "buck",
+            "--access-key",
+            "key",
+            "--secret-key",
+            "shh",
+        ])
+        .arg("--path-style")
+        .assert()
+        .success();
+
+    cli_run(working_path, &repo)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    // Back up some more so listing takes a few pages.
+    cli_run(working_path, &repo)?
+        .arg("backup")
+        .arg(std::env::current_dir()?.join("src"))
+        .assert()
+        .success();
+    let count = |prefix| {
+        bucket
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .count()
+    };
+    assert_eq!(count("snapshots/"), 2);
+    assert!(count("packs/") > PAGE_SIZE);
+
+    cli_run(working_path, &repo)?
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+
+    let restored = working_path.join("restored");
+    fs::create_dir(&restored)?;
+    cli_run(working_path, &repo)?
+        .args(["restore", "--output"])
+        .arg(&restored)
+        .arg("LAST~")
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(restored.join("a.txt"))?,
+        "into the bucket"
+    );
+    assert_eq!(fs::read_to_string(restored.join("b.txt"))?, "and out again");
+
+    // Removing a snapshot removes it from the bucket.
+    cli_run(working_path, &repo)?
+        .args(["snapshot", "rm", "--yes", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(count("snapshots/"), 1);
+    Ok(())
+}
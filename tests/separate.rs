@@ -0,0 +1,87 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn separate_snapshots() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    // Same name, different places - fine when they're in separate snapshots.
+    let etc = working_path.join("a/etc");
+    let other_etc = working_path.join("b/etc");
+    let srv = working_path.join("srv");
+    for d in [&etc, &other_etc, &srv] {
+        fs::create_dir_all(d)?;
+    }
+    fs::write(etc.join("hosts"), "127.0.0.1 localhost")?;
+    fs::write(other_etc.join("hosts"), "127.0.0.1 localhost")?;
+    fs::write(srv.join("index.html"), "<h1>Hi</h1>")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    // Not together though.
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .args([&etc, &other_etc, &srv])
+        .assert()
+        .failure();
+
+    let backup_run = cli_run(working_path, backup_path)?
+        .args(["backup", "--separate"])
+        .args([&etc, &other_etc, &srv])
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&backup_run).matches("Snaphsot").count(),
+        3,
+        "{}",
+        stdout(&backup_run)
+    );
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 3);
+    // All packed in one go.
+    assert_eq!(count_directory_entries(backup_path.join("indexes")), 1);
+
+    // Each snapshot is just its own path.
+    let srv_snapshot = stdout(&backup_run)
+        .lines()
+        .find(|l| l.ends_with("srv done"))
+        .unwrap()
+        .split_whitespace()
+        .nth(1)
+        .unwrap();
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", srv_snapshot])
+        .assert()
+        .success();
+    assert_eq!(
+        stdout(&ls_run).trim().lines().collect::<Vec<_>>(),
+        ["srv/", "srv/index.html"]
+    );
+
+    // Each one finds its own parent next time around.
+    fs::write(srv.join("index.html"), "<h1>Hello</h1>")?;
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--one-snapshot-per-path"])
+        .args([&etc, &other_etc, &srv])
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 6);
+
+    cli_run(working_path, backup_path)?
+        .arg("check")
+        .assert()
+        .success();
+    Ok(())
+}
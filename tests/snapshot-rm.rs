@@ -0,0 +1,51 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn remove_snapshot() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    for file in ["README.md", "LICENSE.txt"] {
+        cli_run(working_path, backup_path)?
+            .arg("backup")
+            .arg(std::env::current_dir()?.join(file))
+            .assert()
+            .success();
+    }
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 2);
+
+    // Saying no leaves it be.
+    cli_run(working_path, backup_path)?
+        .args(["snapshot", "rm", "LAST"])
+        .write_stdin("n\n")
+        .assert()
+        .failure();
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 2);
+
+    cli_run(working_path, backup_path)?
+        .args(["snapshot", "rm", "--yes", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 1);
+
+    // The one left is the first one.
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&ls_run).trim(), "README.md");
+    Ok(())
+}
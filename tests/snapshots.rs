@@ -0,0 +1,139 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn table() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    for (file, tag) in [
+        ("README.md", Some("nightly")),
+        ("LICENSE.txt", None),
+        ("Cargo.toml", Some("nightly")),
+    ] {
+        let mut cmd = cli_run(working_path, backup_path)?;
+        cmd.arg("backup");
+        if let Some(t) = tag {
+            cmd.args(["--tag", t]);
+        }
+        cmd.arg(std::env::current_dir()?.join(file))
+            .assert()
+            .success();
+    }
+
+    let table = |args: &[&str]| -> Result<Vec<String>> {
+        let run = cli_run(working_path, backup_path)?
+            .args(["snapshots", "--table"])
+            .args(args)
+            .assert()
+            .success();
+        // Skip past "Opening repository..."
+        Ok(stdout(&run)
+            .lines()
+            .skip_while(|l| !l.starts_with("ID "))
+            .map(str::to_owned)
+            .collect())
+    };
+    // The short ID each row starts with
+    let ids = |rows: &[String]| -> Vec<String> {
+        rows[1..]
+            .iter()
+            .map(|r| r.split_whitespace().next().unwrap().to_owned())
+            .collect()
+    };
+
+    let all = table(&[])?;
+    assert_eq!(all.len(), 4, "{all:?}");
+    // Columns line up under their headers.
+    let paths_column = all[0].find("Paths").unwrap();
+    for (row, file) in all[1..]
+        .iter()
+        .zip(["README.md", "LICENSE.txt", "Cargo.toml"])
+    {
+        assert!(row[paths_column..].ends_with(file), "{all:?}");
+    }
+    assert!(all[1].contains(" nightly "), "{all:?}");
+    let chronological = ids(&all);
+    assert!(chronological.iter().all(|id| id.len() == 8));
+
+    let mut reversed = chronological.clone();
+    reversed.reverse();
+    assert_eq!(ids(&table(&["--reverse"])?), reversed);
+
+    assert_eq!(ids(&table(&["--last", "2"])?), &chronological[1..]);
+    assert_eq!(
+        ids(&table(&["--tag", "nightly", "--last", "1"])?),
+        &chronological[2..]
+    );
+
+    let mut sorted = chronological.clone();
+    sorted.sort();
+    assert_eq!(ids(&table(&["--sort", "id"])?), sorted);
+    sorted.reverse();
+    assert_eq!(ids(&table(&["--sort", "id", "--reverse"])?), sorted);
+
+    // Diffs need snapshots in order.
+    cli_run(working_path, backup_path)?
+        .args(["snapshots", "--sort", "id", "--stat"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn aligned_sizes() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let src = working_path.join("src");
+    std::fs::create_dir(&src)?;
+    std::fs::write(src.join("a-much-longer-name.txt"), "meow")?;
+    std::fs::write(src.join("b.txt"), "woof")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    let backup = || {
+        cli_run(working_path, backup_path)
+            .unwrap()
+            .arg("backup")
+            .arg(&src)
+            .assert()
+            .success();
+    };
+    backup();
+    // A metadata change gets a wider mark than the usual " + ".
+    std::fs::set_permissions(src.join("b.txt"), std::fs::Permissions::from_mode(0o600))?;
+    std::fs::write(src.join("a-much-longer-name.txt"), "meow meow")?;
+    backup();
+
+    let run = cli_run(working_path, backup_path)?
+        .args(["snapshots", "--file-sizes", "--stat", "--metadata"])
+        .assert()
+        .success();
+    let out = stdout(&run);
+    let columns: Vec<usize> = out.lines().filter_map(|l| l.find(" | ")).collect();
+    assert!(columns.len() > 3, "{out}");
+    assert!(columns.iter().all(|c| *c == columns[0]), "{out}");
+    assert!(out.lines().any(|l| l.starts_with(" P")), "{out}");
+    Ok(())
+}
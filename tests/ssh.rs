@@ -0,0 +1,81 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+/// An `ssh` that ignores the host and runs the command locally.
+const FAKE_SSH: &str = r#"#!/bin/sh
+for command; do :; done
+exec sh -c "$command"
+"#;
+
+#[test]
+fn ssh_round_trip() -> Result<()> {
+    let remote_dir = tempdir()?;
+    let remote_path = remote_dir.path().join("backups");
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let bin = working_path.join("bin");
+    fs::create_dir(&bin)?;
+    let ssh = bin.join("ssh");
+    fs::write(&ssh, FAKE_SSH)?;
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755))?;
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH")?);
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "over the wire")?;
+    fs::write(src.join("b.txt"), "and back again")?;
+
+    let repo = working_path.join("repo.toml");
+    cli_run(working_path, &repo)?
+        .env("PATH", &path)
+        .args(["init", "ssh", "--host", "backup-box", "--base-path"])
+        .arg(&remote_path)
+        .assert()
+        .success();
+    let config = fs::read_to_string(&repo)?;
+    assert!(config.contains("type = \"Ssh\""));
+    assert!(config.contains("host = \"backup-box\""));
+
+    cli_run(working_path, &repo)?
+        .env("PATH", &path)
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(remote_path.join("snapshots")), 1);
+    assert_eq!(count_directory_entries(remote_path.join("indexes")), 1);
+    assert!(count_directory_entries(remote_path.join("packs")) > 0);
+
+    cli_run(working_path, &repo)?
+        .env("PATH", &path)
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+
+    let restored = working_path.join("restored");
+    fs::create_dir(&restored)?;
+    cli_run(working_path, &repo)?
+        .env("PATH", &path)
+        .args(["restore", "--output"])
+        .arg(&restored)
+        .arg("LAST")
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(restored.join("a.txt"))?, "over the wire");
+    assert_eq!(
+        fs::read_to_string(restored.join("b.txt"))?,
+        "and back again"
+    );
+    Ok(())
+}
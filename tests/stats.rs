@@ -0,0 +1,76 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn transfer_stats() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home = working_path.join("home");
+    fs::create_dir(&home)?;
+
+    let src = working_path.join("src");
+    fs::create_dir(&src)?;
+    fs::write(src.join("a.txt"), "how much did we send?")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    // Nothing unless asked
+    let quiet = cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    assert!(!stderr(&quiet).contains("Uploaded"));
+
+    let fs_stats = cli_run(working_path, backup_path)?
+        .args(["--stats", "check", "--read-packs"])
+        .assert()
+        .success();
+    let fs_stats = stderr(&fs_stats);
+    assert!(fs_stats.contains("Uploaded 0 B, downloaded"), "{fs_stats}");
+    assert!(fs_stats.contains("approximately"), "{fs_stats}");
+
+    // Cached backends count exactly.
+    let config_path = backup_path.join("config.toml");
+    let config = fs::read_to_string(&config_path)?;
+    fs::write(
+        &config_path,
+        format!("filter = \"cat\"\nunfilter = \"cat\"\n{config}"),
+    )?;
+    let cached_stats = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["--stats", "check", "--read-packs"])
+        .assert()
+        .success();
+    let cached_stats = stderr(&cached_stats);
+    assert!(
+        cached_stats.contains("Uploaded 0 B, downloaded"),
+        "{cached_stats}"
+    );
+    assert!(!cached_stats.contains("downloaded 0 B"), "{cached_stats}");
+    assert!(!cached_stats.contains("approximately"), "{cached_stats}");
+
+    let counters = cli_run(working_path, backup_path)?
+        .env("HOME", &home)
+        .args(["--counters", "check", "--read-packs"])
+        .assert()
+        .success();
+    let counters = stderr(&counters);
+    assert!(counters.contains("Operation counts:"), "{counters}");
+    // Everything's listed, even what didn't happen.
+    assert!(counters.contains(" backend reads"), "{counters}");
+    assert!(counters.contains("       0 packs reread"), "{counters}");
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn backup_stdin() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    // A few chunks' worth of not-so-compressible junk
+    let mut x = 1u32;
+    let dump: Vec<u8> = (0..3 * 1024 * 1024)
+        .map(|_| {
+            x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+            (x >> 24) as u8
+        })
+        .collect();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--stdin", "--stdin-name", "db/dump.sql"])
+        .write_stdin(dump.clone())
+        .assert()
+        .success();
+
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&ls_run).trim(), "dump.sql");
+
+    let snapshots_run = cli_run(working_path, backup_path)?
+        .arg("snapshots")
+        .assert()
+        .success();
+    assert!(stdout(&snapshots_run).contains("dump.sql (from stdin)"));
+
+    cli_run(working_path, backup_path)?
+        .args(["check", "--dereference-and-hash"])
+        .assert()
+        .success();
+
+    // It goes back where we said it was.
+    fs::create_dir(working_path.join("db"))?;
+    fs::write(working_path.join("db/dump.sql"), "stale")?;
+    cli_run(working_path, backup_path)?
+        .args(["restore", "LAST"])
+        .assert()
+        .success();
+    assert_eq!(fs::read(working_path.join("db/dump.sql"))?, dump);
+
+    // Paths and --stdin don't mix.
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--stdin", "--stdin-name", "foo", "db"])
+        .assert()
+        .failure();
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--stdin"])
+        .assert()
+        .failure();
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn tags() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    for (file, tag) in [
+        ("README.md", Some("nightly")),
+        ("LICENSE.txt", None),
+        ("Cargo.toml", Some("nightly")),
+    ] {
+        let mut cmd = cli_run(working_path, backup_path)?;
+        cmd.arg("backup");
+        if let Some(t) = tag {
+            cmd.args(["--tag", t]);
+        }
+        cmd.arg(std::env::current_dir()?.join(file))
+            .assert()
+            .success();
+    }
+
+    let nightlies = |args: &[&str]| -> Result<usize> {
+        let run = cli_run(working_path, backup_path)?
+            .arg("snapshots")
+            .args(args)
+            .assert()
+            .success();
+        Ok(stdout(&run)
+            .lines()
+            .filter(|l| l.starts_with("snapshot"))
+            .count())
+    };
+    assert_eq!(nightlies(&[])?, 3);
+    assert_eq!(nightlies(&["--tag", "nightly"])?, 2);
+    assert_eq!(nightlies(&["--tag", "nightly", "--tag", "weekly"])?, 0);
+
+    // LAST is the last one with the tag.
+    let ls_run = cli_run(working_path, backup_path)?
+        .args(["diff", "--tag", "nightly", "LAST~", "LAST"])
+        .assert()
+        .success();
+    let diff = stdout(&ls_run);
+    assert!(diff.contains("- README.md"), "{diff}");
+    assert!(diff.contains("+ Cargo.toml"), "{diff}");
+
+    // Tag the untagged one...
+    cli_run(working_path, backup_path)?
+        .args(["snapshot", "tag", "LAST~", "nightly", "weekly"])
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 3);
+    assert_eq!(nightlies(&["--tag", "nightly"])?, 3);
+    assert_eq!(nightlies(&["--tag", "weekly"])?, 1);
+
+    // ...and untag it.
+    cli_run(working_path, backup_path)?
+        .args(["snapshot", "untag", "LAST~", "nightly"])
+        .assert()
+        .success();
+    assert_eq!(nightlies(&["--tag", "nightly"])?, 2);
+    assert_eq!(nightlies(&["--tag", "weekly"])?, 1);
+
+    // Forgetting with a tag leaves the others alone.
+    // (Policies apply to each set of paths, so back up Cargo.toml again
+    // to give it one to forget.)
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--tag", "nightly"])
+        .arg(std::env::current_dir()?.join("Cargo.toml"))
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .args(["forget", "--tag", "nightly", "--keep-last", "1"])
+        .assert()
+        .success();
+    assert_eq!(nightlies(&[])?, 3);
+    assert_eq!(nightlies(&["--tag", "nightly"])?, 2);
+    assert_eq!(nightlies(&["--tag", "weekly"])?, 1);
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn upload_budget() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    // Tiny packs so we upload a few before we're done.
+    cli_run(working_path, backup_path)?
+        .args(["init", "--pack-size", "20KB", "filesystem"])
+        .assert()
+        .success();
+
+    let src = std::env::current_dir()?.join("src");
+
+    let budget_run = cli_run(working_path, backup_path)?
+        .args(["backup", "--upload-budget", "1B"])
+        .arg(&src)
+        .assert()
+        .failure();
+    assert!(stderr(&budget_run).contains("--upload-budget"));
+    // We stopped before making a snapshot,
+    // but kept the packs (and an index of them) we uploaded along the way.
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 0);
+    assert!(count_directory_entries(backup_path.join("indexes")) > 0);
+
+    // Without a budget we pick back up and finish.
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&src)
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 1);
+
+    cli_run(working_path, backup_path)?
+        .arg("check")
+        .assert()
+        .success();
+    Ok(())
+}